@@ -1,8 +1,11 @@
 use futures::SinkExt;
-use sqldb_rs::error::Result;
+use sqldb_rs::error::{Error, Result};
 use sqldb_rs::sql;
 use sqldb_rs::sql::engine::kv::KVEngine;
-use sqldb_rs::storage::disk::DiskEngine;
+use sqldb_rs::storage::btree::BTreeEngine;
+use sqldb_rs::storage::disk::{DiskEngine, SyncPolicy};
+use sqldb_rs::storage::engine::Engine as StorageEngine;
+use sqldb_rs::storage::lsm::LsmEngine;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Framed, LinesCodec};
@@ -11,9 +14,12 @@ use sqldb_rs::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, p
 use std::env;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 // 临时目录
 const DB_PATH: &str = "/tmp/sqldb-test/sqldb-log";
+const LSM_PATH: &str = "/tmp/sqldb-test/sqldb-lsm";
+const BTREE_PATH: &str = "/tmp/sqldb-test/sqldb-btree";
 const RESPONSE_END: &str = "!!!end!!!";
 
 /// Possible requests our clients can send us
@@ -21,6 +27,7 @@ enum SqlRequest {
     SQL(String),       //一个简单的sql语句
     ListTables,        //查看所有表
     TableInfo(String), //查看当前的表结构
+    Status,            //查看存储引擎的运行状态
 }
 
 impl SqlRequest {
@@ -33,6 +40,10 @@ impl SqlRequest {
             if upper_cmd.starts_with("SHOW TABLES")&&upper_cmd.ends_with(";") {
             return SqlRequest::ListTables;
         }
+        // 展示存储引擎的运行状态，包括日志大小、无效数据大小、压缩次数等
+        if upper_cmd.starts_with("SHOW STATUS") && upper_cmd.ends_with(";") {
+            return SqlRequest::Status;
+        }
         // "SHOW TABLE table_name"
         // 展示某一个表的信息，这里应该是3个参数，不是的话就报错了！
         if upper_cmd.starts_with("SHOW TABLE")&&upper_cmd.ends_with(";") {
@@ -74,7 +85,8 @@ impl<E: sql::engine::Engine + 'static> ServerSession<E> {
 
                     // 执行请求
                     let response = match req {
-                        SqlRequest::SQL(sql) => match self.session.execute(&sql) {
+                        // 执行的时候可能会阻塞等待行锁，不能占住 tokio 的工作线程
+                        SqlRequest::SQL(sql) => match tokio::task::block_in_place(|| self.session.execute(&sql)) {
                             Ok(rs) => {
                                 pppr!(rs);
                                 rs.to_string()
@@ -91,6 +103,10 @@ impl<E: sql::engine::Engine + 'static> ServerSession<E> {
                                 Err(e) => e.to_string(),
                             }
                         }
+                        SqlRequest::Status => match self.session.get_status() {
+                            Ok(status) => status,
+                            Err(e) => e.to_string(),
+                        },
                     };
 
                     // 发送执行结果
@@ -120,6 +136,21 @@ async fn main() -> Result<()> {
     let addr = env::args()
         .nth(1) //监听 的端口
         .unwrap_or_else(|| "127.0.0.1:8080".to_string()); //启动的服务！
+    // 刷盘策略：always（默认，每次提交都刷盘）、never、periodic:<毫秒>
+    let sync_policy = match env::args().nth(2) {
+        Some(policy) => policy.parse::<SyncPolicy>()?,
+        None => SyncPolicy::Always,
+    };
+    // 存储引擎：disk（默认，Bitcask 模型，所有的 key 都在内存中）、lsm（LSM 树，key 的数量可以超过内存）、btree（B+ 树）
+    let engine = env::args().nth(3).unwrap_or_else(|| "disk".to_string());
+    if !matches!(engine.as_str(), "disk" | "lsm" | "btree") {
+        return Err(Error::Internal(format!("unknown storage engine {}", engine)));
+    }
+    // 等待行锁的超时时间（毫秒），给出时才开启行锁；默认（或者 off）不开启，写冲突时直接报错
+    let lock_timeout = match env::args().nth(4).as_deref() {
+        None | Some("off") => None,
+        Some(ms) => Some(Duration::from_millis(ms.parse::<u64>()?)),
+    };
 
     let listener = TcpListener::bind(&addr).await?;
     // println!("\x1B[31msqldb server starts, listening on: {addr}\x1B[0m");
@@ -127,8 +158,24 @@ async fn main() -> Result<()> {
     println!("\x1B[31m{:■^60}\x1B[0m", addr);
 
     // 服务器端~初始化 DB~和启动执行引擎是一样的代码
-    let p = PathBuf::from(DB_PATH);
-    let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+    match engine.as_str() {
+        "disk" => serve(listener, DiskEngine::new_with_sync(PathBuf::from(DB_PATH), sync_policy)?, lock_timeout).await,
+        "lsm" => serve(listener, LsmEngine::new_with_sync(PathBuf::from(LSM_PATH), sync_policy)?, lock_timeout).await,
+        "btree" => serve(listener, BTreeEngine::new_with_sync(PathBuf::from(BTREE_PATH), sync_policy)?, lock_timeout).await,
+        _ => unreachable!(),
+    }
+}
+
+// 在给定的存储引擎上启动执行引擎，处理客户端的连接
+async fn serve<E: StorageEngine + Send + 'static>(
+    listener: TcpListener,
+    eng: E,
+    lock_timeout: Option<Duration>,
+) -> Result<()> {
+    let kvengine = match lock_timeout {
+        Some(timeout) => KVEngine::new_with_lock_timeout(eng, timeout)?,
+        None => KVEngine::new(eng)?,
+    };
     //这里的引擎是 并发下的 访问引擎了~其实是共享所有权的
     let shared_engine = Arc::new(Mutex::new(kvengine));
 
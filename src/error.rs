@@ -11,6 +11,9 @@ pub enum Error {
     Parse(String),
     Internal(String),
     WriteConflict,
+    SerializationFailure,
+    LockTimeout,
+    Deadlock,
 }
 
 impl From<std::num::ParseIntError> for Error {
@@ -75,6 +78,9 @@ impl Display for Error {
             Error::Parse(err) => write!(f, "parse error {}", err),
             Error::Internal(err) => write!(f, "internal error {}", err),
             Error::WriteConflict => write!(f, "write conflict, try transaction"),
+            Error::SerializationFailure => write!(f, "serialization failure, retry transaction"),
+            Error::LockTimeout => write!(f, "lock wait timeout, try again"),
+            Error::Deadlock => write!(f, "deadlock detected, retry transaction"),
         }
     }
 }
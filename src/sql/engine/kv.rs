@@ -1,4 +1,12 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use crate::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, pr, py};
 use serde::{Deserialize, Serialize};
@@ -9,17 +17,32 @@ use crate::{
     // pppb, pppg, pppr,
     sql::{
         parser::ast::{evaluate_expr, Expression},
-        schema::Table,
-        types::{Row, Value},
+        schema::{Index, Table, TableChange},
+        types::{Row, Rows, Value},
+    },
+    storage::{
+        self,
+        engine::{prefix_range, Engine as StorageEngine, Status},
+        keycode::serialize_key,
+        lock::LockMode,
+        mvcc::{IsolationLevel, Version},
     },
-    storage::{self, engine::Engine as StorageEngine, keycode::serialize_key},
 };
 
-use super::{Engine, Transaction};
+use super::{Engine, RetryPolicy, Transaction};
+
+// 数据的格式版本号，存储的编码发生不兼容的变化时加一，打开版本号不一致的数据目录时报错
+// 1：最早的格式，没有记录版本号
+// 2：整数和浮点数按照数值大小编码，key 的字节序和数值顺序一致；
+//    表结构中的索引和主键不再是列的属性，改为记录在 Table.indexes 和 Table.primary_key 中
+pub const FORMAT_VERSION: u32 = 2;
 
 // KV Engine 定义
 pub struct KVEngine<E: StorageEngine> {
     pub kv: storage::mvcc::Mvcc<E>,
+    retry_policy: RetryPolicy,
+    // 所有会话自动重试的总次数
+    retries: Arc<AtomicU64>,
 }
 
 // 为k-v存储引擎实现必要的k-v操作！
@@ -27,23 +50,67 @@ impl<E: StorageEngine> Clone for KVEngine<E> {
     fn clone(&self) -> Self {
         Self {
             kv: self.kv.clone(),
+            retry_policy: self.retry_policy,
+            retries: self.retries.clone(),
         }
     }
 }
 
 impl<E: StorageEngine> KVEngine<E> {
-    pub fn new(engine: E) -> Self {
-        Self {
-            kv: storage::mvcc::Mvcc::new(engine),
-        }
+    pub fn new(engine: E) -> Result<Self> {
+        Self::open(storage::mvcc::Mvcc::new(engine))
+    }
+
+    // 开启行锁，写入和 SELECT ... FOR UPDATE 遇到其他事务持有的锁时最多等待 timeout
+    pub fn new_with_lock_timeout(engine: E, timeout: Duration) -> Result<Self> {
+        Self::open(storage::mvcc::Mvcc::new_with_lock_timeout(engine, timeout))
+    }
+
+    // 打开之前先检查数据的格式版本号，不能按照新的格式读取旧的数据
+    fn open(kv: storage::mvcc::Mvcc<E>) -> Result<Self> {
+        kv.check_format(FORMAT_VERSION)?;
+        Ok(Self {
+            kv,
+            retry_policy: RetryPolicy::default(),
+            retries: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    // 设置自动提交的语句遇到写冲突时的重试策略，对之后创建的会话生效
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
     }
 }
 
-impl<E: StorageEngine> Engine for KVEngine<E> {
+impl<E: StorageEngine + 'static> Engine for KVEngine<E> {
     type Transaction = KVTransaction<E>;
 
-    fn begin(&self) -> Result<Self::Transaction> {
-        Ok(Self::Transaction::new(self.kv.begin()?))
+    fn begin_with(&self, isolation: IsolationLevel) -> Result<Self::Transaction> {
+        Ok(Self::Transaction::new(self.kv.begin_with(isolation)?))
+    }
+
+    fn begin_read_only(&self, as_of: Option<Version>) -> Result<Self::Transaction> {
+        Ok(Self::Transaction::new(self.kv.begin_read_only(as_of)?))
+    }
+
+    fn vacuum(&self) -> Result<usize> {
+        self.kv.vacuum()
+    }
+
+    fn status(&self) -> Result<Status> {
+        self.kv.status()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
     }
 }
 
@@ -52,14 +119,80 @@ pub struct KVTransaction<E: StorageEngine> {
     txn: storage::mvcc::MvccTransaction<E>,
 }
 
-impl<E: StorageEngine> KVTransaction<E> {
+impl<E: StorageEngine + 'static> KVTransaction<E> {
     pub fn new(txn: storage::mvcc::MvccTransaction<E>) -> Self {
         Self { txn }
     }
+
+    // 保存表结构
+    fn save_table(&mut self, table: &Table) -> Result<()> {
+        let key = Key::Table(table.name.clone()).encode()?;
+        self.txn.set(key, bincode::serialize(table)?)
+    }
+
+    // 读出表中所有的行，DDL 修改数据之前先全部读出来，避免边扫描边修改
+    fn read_all_rows(&self, table_name: &str) -> Result<Vec<Row>> {
+        let prefix = KeyPrefix::Row(table_name.to_string()).encode()?;
+        self.txn
+            .scan_prefix(prefix)?
+            .map(|r| Ok(bincode::deserialize(&r?.value)?))
+            .collect()
+    }
+
+    // 索引名在整个库中唯一，DROP INDEX 只需要给出索引名
+    fn check_index_name(&self, index_name: &str) -> Result<()> {
+        for table in self.get_table_names()? {
+            let table = self.must_get_table(table)?;
+            if table.indexes.iter().any(|i| i.name == index_name) {
+                return Err(Error::Internal(format!(
+                    "index {} already exists",
+                    index_name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // 回填索引，把表中已有的数据按照索引列的值分组，每组值写入一条索引
+    fn backfill_index(&self, table: &Table, index: &Index) -> Result<()> {
+        let mut entries: HashMap<Vec<Value>, HashSet<Vec<Value>>> = HashMap::new();
+        for row in self.read_all_rows(&table.name)? {
+            let pk = table.get_primary_key(&row)?;
+            entries.entry(index_values(table, index, &row)?).or_default().insert(pk);
+        }
+        for (values, pks) in entries {
+            self.save_index(&table.name, &index.name, &values, pks)?;
+        }
+        Ok(())
+    }
+
+    // 删除索引的全部数据
+    fn delete_index_entries(&mut self, table_name: &str, index_name: &str) -> Result<()> {
+        let prefix = KeyPrefix::Index(table_name.to_string(), index_name.to_string()).encode()?;
+        let keys = self
+            .txn
+            .scan_prefix(prefix)?
+            .map(|r| r.map(|r| r.key))
+            .collect::<Result<Vec<_>>>()?;
+        for key in keys {
+            self.txn.delete(key)?;
+        }
+        Ok(())
+    }
+
+    // 按照新的表结构重写表中的每一行，主键不变，f 负责把旧行转换成新行
+    fn rewrite_rows<F: Fn(&mut Row)>(&mut self, table: &Table, f: F) -> Result<()> {
+        for mut row in self.read_all_rows(&table.name)? {
+            f(&mut row);
+            let key = Key::Row(table.name.clone(), table.get_primary_key(&row)?).encode()?;
+            self.txn.set(key, bincode::serialize(&row)?)?;
+        }
+        Ok(())
+    }
 }
 
 // this is k-v Transaction 
-impl<E: StorageEngine> Transaction for KVTransaction<E> {
+impl<E: StorageEngine + 'static> Transaction for KVTransaction<E> {
     fn commit(&self) -> Result<()> {
         self.txn.commit()
     }
@@ -72,6 +205,23 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         self.txn.version()
     }
 
+    fn savepoint(&self, name: String) -> Result<()> {
+        self.txn.savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.txn.rollback_to_savepoint(name)
+    }
+
+    fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.txn.release_savepoint(name)
+    }
+
+    fn lock_row(&self, table: &Table, id: &[Value], mode: LockMode) -> Result<()> {
+        let key = Key::Row(table.name.clone(), id.to_vec()).encode()?;
+        self.txn.lock(key, mode)
+    }
+
     fn create_row(&mut self, table_name: String, row: Row) -> Result<()> {
         __function!("开始创建行~");
         let table = self.must_get_table(table_name.clone())?;
@@ -102,8 +252,9 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         let id = Key::Row(table_name.clone(), pk.clone()).encode()?;
         if self.txn.get(id.clone())?.is_some() {
             return Err(Error::Internal(format!(
-                "Duplicate data for primary key {} in table {}",
-                pk, table_name
+                "Duplicate data for primary key ({}) in table {}",
+                format_values(&pk),
+                table_name
             )));
         }
         pppg!("主键对应的数据不存在，可以存入数据~");
@@ -113,53 +264,43 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         self.txn.set(id, value)?;
 
         // 维护索引
-        let index_cols = table
-            .columns
-            .iter()
-            .enumerate()
-            .filter(|(_, c)| c.index)
-            .collect::<Vec<_>>();
-        for (i, index_col) in index_cols {
+        for index in table.indexes.iter() {
             // 这里使用的哈希表索引
-            let mut index = self.load_index(&table_name, &index_col.name, &row[i])?;
-            index.insert(pk.clone());
-            self.save_index(&table_name, &index_col.name, &row[i], index)?;
+            let values = index_values(&table, index, &row)?;
+            let mut pks = self.load_index(&table_name, &index.name, &values)?;
+            pks.insert(pk.clone());
+            self.save_index(&table_name, &index.name, &values, pks)?;
         }
 
         Ok(())
     }
 
-    fn update_row(&mut self, table: &Table, id: &Value, row: Row) -> Result<()> {
+    fn update_row(&mut self, table: &Table, id: &[Value], row: Row) -> Result<()> {
         let new_pk = table.get_primary_key(&row)?;
         // 更新了主键，则删除旧的数据，加一条新的数据
-        if *id != new_pk {
+        if id != new_pk.as_slice() {
             self.delete_row(table, id)?;
             self.create_row(table.name.clone(), row)?;
             return Ok(());
         }
 
         // 维护索引
-        let index_cols = table
-            .columns
-            .iter()
-            .enumerate()
-            .filter(|(_, c)| c.index)
-            .collect::<Vec<_>>();
-
-        for (i, index_col) in index_cols {
-            if let Some(old_row) = self.read_by_id(&table.name, id)? {
+        if let Some(old_row) = self.read_by_id(&table.name, id)? {
+            for index in table.indexes.iter() {
+                let old_values = index_values(table, index, &old_row)?;
+                let new_values = index_values(table, index, &row)?;
                 // 索引列没有被更新
-                if old_row[i] == row[i] {
+                if old_values == new_values {
                     continue;
                 }
 
-                let mut old_index = self.load_index(&table.name, &index_col.name, &old_row[i])?;
+                let mut old_index = self.load_index(&table.name, &index.name, &old_values)?;
                 old_index.remove(id);
-                self.save_index(&table.name, &index_col.name, &old_row[i], old_index)?;
+                self.save_index(&table.name, &index.name, &old_values, old_index)?;
 
-                let mut new_index = self.load_index(&table.name, &index_col.name, &row[i])?;
-                new_index.insert(id.clone());
-                self.save_index(&table.name, &index_col.name, &row[i], new_index)?;
+                let mut new_index = self.load_index(&table.name, &index.name, &new_values)?;
+                new_index.insert(id.to_vec());
+                self.save_index(&table.name, &index.name, &new_values, new_index)?;
             }
         }
 
@@ -170,34 +311,29 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         Ok(())
     }
 
-    fn delete_row(&mut self, table: &Table, id: &Value) -> Result<()> {
+    fn delete_row(&mut self, table: &Table, id: &[Value]) -> Result<()> {
         // 维护索引
         // 这里是删除数据对应的索引！
-        let index_cols = table
-            .columns
-            .iter()
-            .enumerate()
-            .filter(|(_, c)| c.index)
-            .collect::<Vec<_>>();
-        for (i, index_col) in index_cols {
-            if let Some(row) = self.read_by_id(&table.name, id)? {
-                let mut index = self.load_index(&table.name, &index_col.name, &row[i])?;
-                index.remove(id); //删除数据对应索引
-                self.save_index(&table.name, &index_col.name, &row[i], index)?;
+        if let Some(row) = self.read_by_id(&table.name, id)? {
+            for index in table.indexes.iter() {
+                let values = index_values(table, index, &row)?;
+                let mut pks = self.load_index(&table.name, &index.name, &values)?;
+                pks.remove(id); //删除数据对应索引
+                self.save_index(&table.name, &index.name, &values, pks)?;
             }
         }
 
-        let key = Key::Row(table.name.clone(), id.clone()).encode()?;
+        let key = Key::Row(table.name.clone(), id.to_vec()).encode()?;
         self.txn.delete(key)
     }
 
     fn load_index(
         &self,
         table_name: &str,
-        col_name: &str,
-        col_value: &Value,
-    ) -> Result<HashSet<Value>> {
-        let key = Key::Index(table_name.into(), col_name.into(), col_value.clone()).encode()?;
+        index_name: &str,
+        values: &[Value],
+    ) -> Result<HashSet<Vec<Value>>> {
+        let key = Key::Index(table_name.into(), index_name.into(), values.to_vec()).encode()?;
         Ok(self
             .txn
             .get(key)?
@@ -209,11 +345,11 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
     fn save_index(
         &self,
         table_name: &str,
-        col_name: &str,
-        col_value: &Value,
-        index: HashSet<Value>,
+        index_name: &str,
+        values: &[Value],
+        index: HashSet<Vec<Value>>,
     ) -> Result<()> {
-        let key = Key::Index(table_name.into(), col_name.into(), col_value.clone()).encode()?;
+        let key = Key::Index(table_name.into(), index_name.into(), values.to_vec()).encode()?;
         if index.is_empty() {
             self.txn.delete(key)
         } else {
@@ -221,38 +357,84 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         }
     }
 
-    fn read_by_id(&self, table_name: &str, id: &Value) -> Result<Option<Row>> {
+    fn scan_index(
+        &self,
+        table_name: &str,
+        index_name: &str,
+        range: (Bound<Vec<Value>>, Bound<Vec<Value>>),
+    ) -> Result<Vec<HashSet<Vec<Value>>>> {
+        // 索引 key 是按照列值的顺序编码的，把列值的范围换算成索引 key 的范围即可
+        // 边界可以只包含前面的几列，这时边界代表的是以这几列的值为前缀的所有 key：
+        // Included(p) 作为上界、Excluded(p) 作为下界时，都要越过所有以 p 为前缀的 key
+        // 没有边界的一侧，使用该索引所有 key 的公共前缀作为边界
+        let (prefix_start, prefix_end) =
+            prefix_range(KeyPrefix::Index(table_name.into(), index_name.into()).encode()?);
+        let encode = |v: Vec<Value>| Key::Index(table_name.into(), index_name.into(), v).encode();
+        let start = match range.0 {
+            Bound::Included(v) => Bound::Included(encode(v)?),
+            Bound::Excluded(v) => match prefix_range(encode(v)?).1 {
+                Bound::Excluded(k) => Bound::Included(k),
+                _ => return Ok(Vec::new()),
+            },
+            Bound::Unbounded => prefix_start,
+        };
+        let end = match range.1 {
+            Bound::Included(v) => match prefix_range(encode(v)?).1 {
+                Bound::Unbounded => prefix_end,
+                end => end,
+            },
+            Bound::Excluded(v) => Bound::Excluded(encode(v)?),
+            Bound::Unbounded => prefix_end,
+        };
+
+        let mut results = Vec::new();
+        for result in self.txn.scan((start, end))? {
+            results.push(bincode::deserialize(&result?.value)?);
+        }
+        Ok(results)
+    }
+
+    fn read_by_id(&self, table_name: &str, id: &[Value]) -> Result<Option<Row>> {
         Ok(self
             .txn
-            .get(Key::Row(table_name.into(), id.clone()).encode()?)?
+            .get(Key::Row(table_name.into(), id.to_vec()).encode()?)?
             .map(|v| bincode::deserialize(&v))
             .transpose()?)
     }
 
-    fn scan_table(&self, table_name: String, filter: Option<Expression>) -> Result<Vec<Row>> {
+    fn scan_by_id_prefix(&self, table_name: &str, prefix: &[Value]) -> Result<Rows> {
+        // 行数据的 key 按照主键的列依次编码，联合主键前面几列的值相同的行在存储引擎中是相邻的
+        let prefix = Key::Row(table_name.into(), prefix.to_vec()).encode()?;
+        let results = self.txn.scan_prefix(prefix)?;
+        Ok(Box::new(results.map(|result| {
+            result.and_then(|r| Ok(bincode::deserialize(&r.value)?))
+        })))
+    }
+
+    fn scan_table(&self, table_name: String, filter: Option<Expression>) -> Result<Rows> {
         let prefix = KeyPrefix::Row(table_name.clone()).encode()?;
         let table = self.must_get_table(table_name)?;
+        let cols = table.columns.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
         let results = self.txn.scan_prefix(prefix)?;
 
-        let mut rows = Vec::new();
-        for result in results {
+        // 这里并不会真正去读数据，只是构造好迭代器，上层拉取一行才会解码、过滤一行
+        Ok(Box::new(results.filter_map(move |result| {
+            let row: Row = match result.and_then(|r| Ok(bincode::deserialize(&r.value)?)) {
+                Ok(row) => row,
+                Err(err) => return Some(Err(err)),
+            };
             // 过滤数据
-            let row: Row = bincode::deserialize(&result.value)?;
-            if let Some(expr) = &filter {
-                let cols = table.columns.iter().map(|c| c.name.clone()).collect();
-                match evaluate_expr(expr, &cols, &row, &cols, &row)? {
-                    Value::Null => {}
-                    Value::Boolean(false) => {}
-                    Value::Boolean(true) => {
-                        rows.push(row);
-                    }
-                    _ => return Err(Error::Internal("Unexpected expression".into())),
-                }
-            } else {
-                rows.push(row);
+            match &filter {
+                Some(expr) => match evaluate_expr(expr, &cols, &row, &cols, &row) {
+                    Ok(Value::Null) => None,
+                    Ok(Value::Boolean(false)) => None,
+                    Ok(Value::Boolean(true)) => Some(Ok(row)),
+                    Ok(_) => Some(Err(Error::Internal("Unexpected expression".into()))),
+                    Err(err) => Some(Err(err)),
+                },
+                None => Some(Ok(row)),
             }
-        }
-        Ok(rows)
+        })))
     }
 
     fn create_table(&mut self, table: Table) -> Result<()> {
@@ -276,8 +458,10 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
 
     fn drop_table(&mut self, table_name: String) -> Result<()> {
         let table = self.must_get_table(table_name.clone())?;
-        // 删除表的数据
-        let rows = self.scan_table(table_name, None)?;
+        // 删除表的数据，先把数据都读出来再删除，避免边扫描边修改
+        let rows = self
+            .scan_table(table_name, None)?
+            .collect::<Result<Vec<_>>>()?;
         for row in rows {
             self.delete_row(&table, &table.get_primary_key(&row)?)?;
         }
@@ -287,6 +471,169 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         self.txn.delete(key)
     }
 
+    fn create_index(&mut self, table_name: String, index: Index) -> Result<()> {
+        let mut table = self.must_get_table(table_name)?;
+        self.check_index_name(&index.name)?;
+        table.indexes.push(index);
+        table.validate()?;
+
+        self.backfill_index(&table, table.indexes.last().unwrap())?;
+        // 保存新的表结构
+        self.save_table(&table)
+    }
+
+    fn drop_index(&mut self, index_name: String) -> Result<String> {
+        // 找到索引所在的表
+        let mut table = None;
+        for name in self.get_table_names()? {
+            let t = self.must_get_table(name)?;
+            if t.indexes.iter().any(|i| i.name == index_name) {
+                table = Some(t);
+                break;
+            }
+        }
+        let mut table = match table {
+            Some(table) => table,
+            None => {
+                return Err(Error::Internal(format!(
+                    "index {} does not exist",
+                    index_name
+                )))
+            }
+        };
+        let pos = table.indexes.iter().position(|i| i.name == index_name).unwrap();
+        let index = table.indexes.remove(pos);
+
+        self.delete_index_entries(&table.name, &index.name)?;
+        // 保存新的表结构
+        self.save_table(&table)?;
+        Ok(table.name)
+    }
+
+    fn alter_table(&mut self, table_name: String, change: TableChange) -> Result<String> {
+        let mut table = self.must_get_table(table_name)?;
+        match change {
+            TableChange::AddColumn { column, index } => {
+                if table.get_col_index(&column.name).is_ok() {
+                    return Err(Error::Internal(format!(
+                        "column {} already exists in table {}",
+                        column.name, table.name
+                    )));
+                }
+                // 已有的行需要用默认值填充新列
+                let default = match &column.default {
+                    Some(v) => v.clone(),
+                    None => {
+                        return Err(Error::Internal(format!(
+                            "column {} must be nullable or have a default value",
+                            column.name
+                        )))
+                    }
+                };
+                if !column.nullable && default == Value::Null {
+                    return Err(Error::Internal(format!(
+                        "column {} cannot be null",
+                        column.name
+                    )));
+                }
+                let col_name = column.name.clone();
+                table.columns.push(column);
+                if index {
+                    let index_name = format!("{}_{}_idx", table.name, col_name);
+                    self.check_index_name(&index_name)?;
+                    table.indexes.push(Index {
+                        name: index_name,
+                        columns: vec![col_name],
+                    });
+                }
+                table.validate()?;
+
+                // 新列追加在行的末尾
+                self.rewrite_rows(&table, |row| row.push(default.clone()))?;
+                if index {
+                    self.backfill_index(&table, table.indexes.last().unwrap())?;
+                }
+            }
+            TableChange::DropColumn(name) => {
+                let pos = table.get_col_index(&name)?;
+                if table.is_primary_key(&name) {
+                    return Err(Error::Internal(format!(
+                        "cannot drop primary key column {} of table {}",
+                        name, table.name
+                    )));
+                }
+                // 包含这一列的索引一起删除
+                let (dropped, kept): (Vec<_>, Vec<_>) = table
+                    .indexes
+                    .into_iter()
+                    .partition(|i| i.columns.contains(&name));
+                table.indexes = kept;
+                for index in dropped {
+                    self.delete_index_entries(&table.name, &index.name)?;
+                }
+                table.columns.remove(pos);
+                table.validate()?;
+
+                self.rewrite_rows(&table, |row| {
+                    row.remove(pos);
+                })?;
+            }
+            TableChange::RenameColumn { from, to } => {
+                let pos = table.get_col_index(&from)?;
+                if table.get_col_index(&to).is_ok() {
+                    return Err(Error::Internal(format!(
+                        "column {} already exists in table {}",
+                        to, table.name
+                    )));
+                }
+                // 行中只按位置保存列的值，只需要修改表结构
+                table.columns[pos].name = to.clone();
+                let rename = |c: &mut String| {
+                    if *c == from {
+                        *c = to.clone();
+                    }
+                };
+                table.primary_key.iter_mut().for_each(rename);
+                for index in table.indexes.iter_mut() {
+                    index.columns.iter_mut().for_each(rename);
+                }
+                table.validate()?;
+            }
+            TableChange::RenameTable(new_name) => {
+                if self.get_table(new_name.clone())?.is_some() {
+                    return Err(Error::Internal(format!(
+                        "table {} already exists",
+                        new_name
+                    )));
+                }
+                // 行和索引的 key 中都带有表名，需要把数据搬到新的表名下
+                let rows = self.read_all_rows(&table.name)?;
+                for index in table.indexes.iter() {
+                    self.delete_index_entries(&table.name, &index.name)?;
+                }
+                for row in rows.iter() {
+                    let pk = table.get_primary_key(row)?;
+                    self.txn.delete(Key::Row(table.name.clone(), pk).encode()?)?;
+                }
+                self.txn.delete(Key::Table(table.name.clone()).encode()?)?;
+
+                table.name = new_name;
+                for row in rows {
+                    let pk = table.get_primary_key(&row)?;
+                    let key = Key::Row(table.name.clone(), pk).encode()?;
+                    self.txn.set(key, bincode::serialize(&row)?)?;
+                }
+                for index in table.indexes.iter() {
+                    self.backfill_index(&table, index)?;
+                }
+            }
+        }
+
+        // 保存新的表结构
+        self.save_table(&table)?;
+        Ok(table.name)
+    }
+
     fn get_table(&self, table_name: String) -> Result<Option<Table>> {
         let key = Key::Table(table_name).encode()?;
         Ok(self
@@ -301,7 +648,7 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
         let results = self.txn.scan_prefix(prefix)?;
         let mut names = Vec::new();
         for result in results {
-            let table: Table = bincode::deserialize(&result.value)?;
+            let table: Table = bincode::deserialize(&result?.value)?;
             names.push(table.name);
         }
         Ok(names)
@@ -311,8 +658,10 @@ impl<E: StorageEngine> Transaction for KVTransaction<E> {
 #[derive(Debug, Serialize, Deserialize)]
 enum Key {
     Table(String),
-    Row(String, Value),
-    Index(String, String, Value),
+    // 表名，主键各列的值
+    Row(String, Vec<Value>),
+    // 表名，索引名，索引各列的值
+    Index(String, String, Vec<Value>),
 }
 
 impl Key {
@@ -325,6 +674,7 @@ impl Key {
 enum KeyPrefix {
     Table,
     Row(String),
+    Index(String, String),
 }
 
 impl KeyPrefix {
@@ -333,8 +683,24 @@ impl KeyPrefix {
     }
 }
 
+// 取出一行数据在索引各列上的值
+fn index_values(table: &Table, index: &Index, row: &Row) -> Result<Vec<Value>> {
+    index
+        .columns
+        .iter()
+        .map(|c| Ok(row[table.get_col_index(c)?].clone()))
+        .collect()
+}
+
+// 主键的值格式化成 1, 'a' 的形式，用于错误信息
+fn format_values(values: &[Value]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{thread, time::Duration};
+
     use futures::executor;
     use tokio::select;
 
@@ -346,13 +712,13 @@ mod tests {
     use super::KVEngine;
     use crate::storage::engine::Engine as StorageEngine;
     use crate::{
-        error::Result,
+        error::{Error, Result},
         sql::{
-            engine::{Engine, Session},
+            engine::{Engine, RetryPolicy, Session},
             executor::ResultSet,
             types::{Row, Value},
         },
-        storage::disk::DiskEngine,
+        storage::{disk::DiskEngine, mvcc::MvccKey},
     };
     // 提取出来的总的建表语句~~
     fn setup_table<E: StorageEngine + 'static>(s: &mut Session<KVEngine<E>>) -> Result<()> {
@@ -430,7 +796,7 @@ mod tests {
     fn my_test() -> Result<()> {
         __function!("自己的测试函数");
         let p = tempfile::tempdir()?.into_path().join("eyk.log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         setup_table(&mut s)?;
         pppg!("先插入20条数据");
@@ -478,7 +844,7 @@ mod tests {
     fn test_create_table() -> Result<()> {
         __function!("建表测试函数");
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         setup_table(&mut s)?;
         std::fs::remove_dir_all(p.parent().unwrap())?;
@@ -488,7 +854,7 @@ mod tests {
     #[test]
     fn test_insert() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         setup_table(&mut s)?;
 
@@ -568,7 +934,7 @@ mod tests {
     #[test]
     fn test_update() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         setup_table(&mut s)?;
 
@@ -636,7 +1002,7 @@ mod tests {
     #[test]
     fn test_delete() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         setup_table(&mut s)?;
 
@@ -713,7 +1079,7 @@ mod tests {
     fn test_sort() -> Result<()> {
         __function!("排序语句");
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         setup_table(&mut s)?;
 
@@ -745,7 +1111,7 @@ mod tests {
     #[test]
     fn test_cross_join() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t1 (a int primary key);")?;
         s.execute("create table t2 (b int primary key);")?;
@@ -775,7 +1141,7 @@ mod tests {
     #[test]
     fn test_join() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t1 (a int primary key);")?;
         s.execute("create table t2 (b int primary key);")?;
@@ -803,7 +1169,7 @@ mod tests {
     #[test]
     fn test_agg() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t1 (a int primary key, b text, c float);")?;
 
@@ -860,7 +1226,7 @@ mod tests {
     #[test]
     fn test_group_by() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t1 (a int primary key, b text, c float);")?;
 
@@ -911,10 +1277,224 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_group_by_exprs() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table sales (id int primary key, region text, product text, price int, qty int);")?;
+        s.execute("insert into sales values (1, 'n', 'a', 10, 2), (2, 'n', 'a', 5, 1), (3, 'n', 'b', 3, 4), (4, 's', 'a', 10, 1), (5, 's', 'b', null, 3), (6, 's', 'b', 2, 2);")?;
+
+        let mut query = |sql: &str| -> Result<(Vec<String>, Vec<Row>)> {
+            match s.execute(sql)? {
+                ResultSet::Scan { columns, rows } => Ok((columns, rows)),
+                _ => unreachable!(),
+            }
+        };
+        let text = |s: &str| Value::String(s.to_string());
+
+        // 多列分组，聚集函数的参数是表达式
+        let (columns, rows) = query("select region, product, sum(price * qty) as total, count(*) from sales group by region, product order by region, product;")?;
+        assert_eq!(columns, vec!["region", "product", "total", "count"]);
+        assert_eq!(
+            rows,
+            vec![
+                vec![text("n"), text("a"), Value::Float(25.0), Value::Integer(2)],
+                vec![text("n"), text("b"), Value::Float(12.0), Value::Integer(1)],
+                vec![text("s"), text("a"), Value::Float(10.0), Value::Integer(1)],
+                vec![text("s"), text("b"), Value::Float(4.0), Value::Integer(2)],
+            ]
+        );
+        // COUNT(DISTINCT)，NULL 不计数；聚集函数和分组列组成的表达式
+        let (_, rows) = query("select region, count(distinct price) as prices, max(price) - min(price) as spread from sales group by region order by region;")?;
+        assert_eq!(
+            rows,
+            vec![
+                vec![text("n"), Value::Integer(3), Value::Integer(7)],
+                vec![text("s"), Value::Integer(2), Value::Integer(8)],
+            ]
+        );
+        // HAVING 和 ORDER BY 中使用 SELECT 中没有的聚集函数
+        let (columns, rows) = query("select region from sales group by region having avg(qty) > 2.2;")?;
+        assert_eq!(columns, vec!["region"]);
+        assert_eq!(rows, vec![vec![text("n")]]);
+        let (columns, rows) = query("select product, count(*) as c from sales group by product order by sum(qty) desc;")?;
+        assert_eq!(columns, vec!["product", "c"]);
+        assert_eq!(
+            rows,
+            vec![
+                vec![text("b"), Value::Integer(3)],
+                vec![text("a"), Value::Integer(3)],
+            ]
+        );
+        // SELECT 中有重名的列时，ORDER BY 按位置或者表达式找到对应的列
+        let (columns, rows) = query("select count(*), count(distinct price) from sales group by region order by count(*), 2;")?;
+        assert_eq!(columns, vec!["count", "count"]);
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(3), Value::Integer(2)],
+                vec![Value::Integer(3), Value::Integer(3)],
+            ]
+        );
+        let (columns, rows) = query("select max(price), max(qty) from sales group by region having max(qty) > 3 order by max(price);")?;
+        assert_eq!(columns, vec!["max", "max"]);
+        assert_eq!(rows, vec![vec![Value::Integer(10), Value::Integer(4)]]);
+        let (columns, rows) = query("select region, count(*), count(price) from sales group by region order by sum(qty), 3;")?;
+        assert_eq!(columns, vec!["region", "count", "count"]);
+        assert_eq!(
+            rows,
+            vec![
+                vec![text("s"), Value::Integer(3), Value::Integer(2)],
+                vec![text("n"), Value::Integer(3), Value::Integer(3)],
+            ]
+        );
+        // 没有数据时，没有 GROUP BY 的聚集也输出一行
+        let (_, rows) = query("select count(*), sum(qty) from sales where id > 100;")?;
+        assert_eq!(rows, vec![vec![Value::Integer(0), Value::Null]]);
+        let (_, rows) = query("select region, count(*) from sales where id > 100 group by region;")?;
+        assert!(rows.is_empty());
+
+        assert_eq!(
+            query("select price from sales group by region;"),
+            Err(Error::Internal(
+                "price must appear in the GROUP BY clause or aggregate function".into()
+            ))
+        );
+        assert_eq!(
+            query("select sum(count(*)) from sales;"),
+            Err(Error::Internal("aggregate function calls cannot be nested".into()))
+        );
+        assert_eq!(
+            query("select * from sales where count(*) > 1;"),
+            Err(Error::Internal("aggregate functions are not allowed in WHERE".into()))
+        );
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_by_exprs() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int, c text);")?;
+        s.execute("insert into t values (1, 3, 'x'), (2, null, 'y'), (3, 1, 'x'), (4, 2, null);")?;
+
+        let mut query = |sql: &str| -> Result<Vec<Row>> {
+            match s.execute(sql)? {
+                ResultSet::Scan { rows, .. } => Ok(rows),
+                _ => unreachable!(),
+            }
+        };
+        let ids = |rows: Vec<Row>| rows.into_iter().map(|r| r[0].clone()).collect::<Vec<_>>();
+        let ints = |v: &[i64]| v.iter().map(|i| Value::Integer(*i)).collect::<Vec<_>>();
+
+        // 默认 NULL 最小：升序在前面，降序在后面
+        assert_eq!(ids(query("select a from t order by b;")?), ints(&[2, 3, 4, 1]));
+        assert_eq!(ids(query("select a from t order by b desc;")?), ints(&[1, 4, 3, 2]));
+        assert_eq!(ids(query("select a from t order by b nulls last;")?), ints(&[3, 4, 1, 2]));
+        assert_eq!(
+            ids(query("select a from t order by b desc nulls first;")?),
+            ints(&[2, 1, 4, 3])
+        );
+        // 表达式、SELECT 中的位置和别名
+        assert_eq!(ids(query("select a from t order by a % 2, a desc;")?), ints(&[4, 2, 3, 1]));
+        assert_eq!(ids(query("select a, c from t order by 2 desc, 1;")?), ints(&[2, 1, 3, 4]));
+        assert_eq!(ids(query("select * from t order by 3, 2;")?), ints(&[4, 3, 1, 2]));
+        assert_eq!(
+            ids(query("select a, a * -1 as neg from t order by neg;")?),
+            ints(&[4, 3, 2, 1])
+        );
+        // 聚集之后按表达式、别名和位置排序
+        let rows = query("select c, count(*) as n, sum(a) from t group by c order by n desc, sum(a) * -1;")?;
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::String("x".into()), Value::Integer(2), Value::Float(4.0)],
+                vec![Value::Null, Value::Integer(1), Value::Float(4.0)],
+                vec![Value::String("y".into()), Value::Integer(1), Value::Float(2.0)],
+            ]
+        );
+        let rows = query("select c, count(*) from t group by c order by 1 nulls last;")?;
+        assert_eq!(rows[2], vec![Value::Null, Value::Integer(1)]);
+
+        assert_eq!(
+            query("select a from t order by 2;"),
+            Err(Error::Internal("ORDER BY position 2 is not in select list".into()))
+        );
+        assert_eq!(
+            query("select a from t order by d;"),
+            Err(Error::Internal("column d is not in table".into()))
+        );
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_n() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int);")?;
+        let values = (1..=50)
+            .map(|i| match i % 9 {
+                0 => format!("({}, null)", i),
+                _ => format!("({}, {})", i, i * 7 % 13),
+            })
+            .collect::<Vec<_>>();
+        s.execute(&format!("insert into t values {};", values.join(", ")))?;
+
+        match s.execute("explain select a from t order by b desc limit 5 offset 3;")? {
+            ResultSet::Explain { plan } => assert!(plan.contains("Top 8 Order By (b desc)")),
+            _ => unreachable!(),
+        }
+        // 主键本来就是有序的，不需要排序
+        match s.execute("explain select a from t order by a limit 5;")? {
+            ResultSet::Explain { plan } => assert!(!plan.contains("Top")),
+            _ => unreachable!(),
+        }
+
+        let mut query = |sql: &str| -> Result<Vec<Row>> {
+            match s.execute(sql)? {
+                ResultSet::Scan { rows, .. } => Ok(rows),
+                _ => unreachable!(),
+            }
+        };
+        // Top-N 的结果和全部排序之后再取的结果一样，相等的行也保持同样的顺序
+        for order in ["b", "b desc", "b nulls last", "b desc nulls first, a desc"] {
+            let all = query(&format!("select a, b from t order by {};", order))?;
+            for (limit, offset) in [(0, 0), (1, 0), (5, 3), (10, 45), (100, 0)] {
+                let rows = query(&format!(
+                    "select a, b from t order by {} limit {} offset {};",
+                    order, limit, offset
+                ))?;
+                let expected = all.iter().skip(offset).take(limit).cloned().collect::<Vec<_>>();
+                assert_eq!(rows, expected);
+            }
+        }
+
+        // 很大的 LIMIT 和 OFFSET 不用 Top-N，用外部排序
+        let huge = i64::MAX;
+        let rows = query(&format!("select a from t order by b limit 2 offset {};", huge))?;
+        assert!(rows.is_empty());
+        let rows = query(&format!("select a, b from t order by b limit {} offset 3;", huge))?;
+        assert_eq!(rows, query("select a, b from t order by b;")?[3..].to_vec());
+        match s.execute(&format!("explain select a from t order by b limit {};", huge))? {
+            ResultSet::Explain { plan } => assert!(plan.contains("Order By") && !plan.contains("Top")),
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
     #[test]
     fn test_filter() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t1 (a int primary key, b text, c float, d bool);")?;
 
@@ -945,10 +1525,149 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_arithmetic() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int, c float, d text);")?;
+        s.execute("insert into t values (1, 10, 1.5, 'x');")?;
+        s.execute("insert into t values (2, 2 * 3 + 1, -2.5, 'y');")?;
+        s.execute("insert into t values (3, null, 4.0, null);")?;
+
+        match s.execute("select a, b * 2 + 1, b / 4, b % 4, -b, b + c, d || '-' || a from t;")? {
+            ResultSet::Scan { columns, rows } => {
+                assert_eq!(columns[1], "((b * 2) + 1)");
+                assert_eq!(
+                    rows,
+                    vec![
+                        vec![
+                            Value::Integer(1),
+                            Value::Integer(21),
+                            Value::Integer(2),
+                            Value::Integer(2),
+                            Value::Integer(-10),
+                            Value::Float(11.5),
+                            Value::String("x-1".to_string()),
+                        ],
+                        vec![
+                            Value::Integer(2),
+                            Value::Integer(15),
+                            Value::Integer(1),
+                            Value::Integer(3),
+                            Value::Integer(-7),
+                            Value::Float(4.5),
+                            Value::String("y-2".to_string()),
+                        ],
+                        vec![
+                            Value::Integer(3),
+                            Value::Null,
+                            Value::Null,
+                            Value::Null,
+                            Value::Null,
+                            Value::Null,
+                            Value::Null,
+                        ],
+                    ]
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        match s.execute("select a from t where b - a > 5;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(1)]]);
+            }
+            _ => unreachable!(),
+        }
+
+        let res = s.execute("update t set b = b + 1, c = c * 2 where a = 2;")?;
+        assert_eq!(res, ResultSet::Update { count: 1 });
+        match s.execute("select b, c from t where a = 2;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(8), Value::Float(-5.0)]]);
+            }
+            _ => unreachable!(),
+        }
+
+        assert!(s.execute("select b / 0 from t;").is_err());
+        assert!(s.execute("select d + 1 from t;").is_err());
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_predicate() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t1 (a int primary key, b text, c float, d bool);")?;
+        s.execute("insert into t1 values (1, 'aa', 3.1, true);")?;
+        s.execute("insert into t1 values (2, 'bb', 5.3, true);")?;
+        s.execute("insert into t1 values (3, null, NULL, false);")?;
+        s.execute("insert into t1 values (4, null, 4.6, null);")?;
+        s.execute("insert into t1 values (5, 'abc', 5.8, true);")?;
+        s.execute("insert into t1 values (6, 'dd', 1.4, false);")?;
+
+        let mut select_a = |sql: &str| -> Result<Vec<Value>> {
+            match s.execute(sql)? {
+                ResultSet::Scan { columns: _, rows } => Ok(rows.into_iter().map(|r| r[0].clone()).collect()),
+                _ => unreachable!(),
+            }
+        };
+        let ints = |v: Vec<i64>| v.into_iter().map(Value::Integer).collect::<Vec<_>>();
+
+        assert_eq!(select_a("select a from t1 where a >= 2 and a <= 4;")?, ints(vec![2, 3, 4]));
+        assert_eq!(select_a("select a from t1 where a < 2 or a > 5;")?, ints(vec![1, 6]));
+        assert_eq!(select_a("select a from t1 where a != 1 and d;")?, ints(vec![2, 5]));
+        assert_eq!(select_a("select a from t1 where b is null;")?, ints(vec![3, 4]));
+        assert_eq!(select_a("select a from t1 where b is not null and c > 5;")?, ints(vec![2, 5]));
+        assert_eq!(select_a("select a from t1 where a in (1, 3, 7);")?, ints(vec![1, 3]));
+        assert_eq!(select_a("select a from t1 where c between 3 and 5;")?, ints(vec![1, 4]));
+        assert_eq!(select_a("select a from t1 where b like 'a%';")?, ints(vec![1, 5]));
+        assert_eq!(select_a("select a from t1 where b like '_b';")?, ints(vec![2]));
+        assert_eq!(select_a("select a from t1 where b not like '%b%';")?, ints(vec![1, 6]));
+
+        // 三值逻辑：NULL 参与的比较结果是 NULL，NOT NULL 还是 NULL，不会被当作 true
+        assert_eq!(select_a("select a from t1 where not d;")?, ints(vec![3, 6]));
+        assert_eq!(select_a("select a from t1 where d or a = 4;")?, ints(vec![1, 2, 4, 5]));
+        assert_eq!(select_a("select a from t1 where not (c > 4);")?, ints(vec![1, 6]));
+        assert_eq!(select_a("select a from t1 where a not in (1, null);")?, ints(vec![]));
+        assert_eq!(select_a("select a from t1 where a in (1, null);")?, ints(vec![1]));
+
+        match s.execute("select a, b is null, d and null, d or null from t1 where a = 1;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(
+                rows,
+                vec![vec![
+                    Value::Integer(1),
+                    Value::Boolean(false),
+                    Value::Null,
+                    Value::Boolean(true),
+                ]]
+            ),
+            _ => unreachable!(),
+        }
+
+        // 非等值的 Join 条件
+        s.execute("create table t2 (x int primary key, y int);")?;
+        s.execute("insert into t2 values (1, 2), (2, 5);")?;
+        match s.execute("select * from t1 join t2 on a >= y and c > 5;")? {
+            ResultSet::Scan { columns, rows } => {
+                assert_eq!(columns.len(), 6);
+                assert_eq!(rows.len(), 3);
+            }
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
     #[test]
     fn test_index() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t (a int primary key, b text index, c float index, d bool);")?;
         s.execute("insert into t values (1, 'a', 1.1, true);")?;
@@ -982,7 +1701,7 @@ mod tests {
     #[test]
     fn test_primary_key_scan() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t (a int primary key, b text index, c float index, d bool);")?;
         s.execute("insert into t values (1, 'a', 1.1, true);")?;
@@ -1001,10 +1720,378 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_format_version() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        kvengine.session()?.execute("create table t (a int primary key);")?;
+        drop(kvengine);
+        // 重新打开时版本号一致
+        drop(KVEngine::new(DiskEngine::new(p.clone())?)?);
+
+        // 没有记录版本号的旧数据，以及版本号不一致的数据都不能打开
+        let format = MvccKey::Format.encode()?;
+        for version in [None, Some(super::FORMAT_VERSION + 1)] {
+            let mut eng = DiskEngine::new(p.clone())?;
+            match version {
+                Some(version) => eng.set(format.clone(), bincode::serialize(&version)?)?,
+                None => eng.delete(format.clone())?,
+            }
+            assert!(KVEngine::new(eng).is_err());
+        }
+
+        // 新的数据目录记录当前的版本号
+        let p2 = p.with_file_name("sqldb-log-new");
+        drop(KVEngine::new(DiskEngine::new(p2.clone())?)?);
+        let mut eng = DiskEngine::new(p2)?;
+        assert_eq!(eng.get(format)?, Some(bincode::serialize(&super::FORMAT_VERSION)?));
+        drop(eng);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_range_scan() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int index, c float index, d text index);")?;
+        s.execute("insert into t values (1, 5, 1.5, 'b');")?;
+        s.execute("insert into t values (2, -3, -2.5, 'a');")?;
+        s.execute("insert into t values (3, 10, 0.0, 'd');")?;
+        s.execute("insert into t values (4, null, -0.5, null);")?;
+        s.execute("insert into t values (5, 5, 8.25, 'c');")?;
+        s.execute("insert into t values (6, -20, null, 'ab');")?;
+
+        let mut select_a = |sql: &str| -> Result<Vec<Value>> {
+            match s.execute(sql)? {
+                ResultSet::Scan { columns: _, rows } => Ok(rows.into_iter().map(|r| r[0].clone()).collect()),
+                _ => unreachable!(),
+            }
+        };
+        let ints = |v: Vec<i64>| v.into_iter().map(Value::Integer).collect::<Vec<_>>();
+
+        // 负数也要按照大小顺序排列，结果按照索引列的顺序输出
+        assert_eq!(select_a("select a from t where b > -5;")?, ints(vec![2, 1, 5, 3]));
+        assert_eq!(select_a("select a from t where b < 5;")?, ints(vec![6, 2]));
+        assert_eq!(select_a("select a from t where b <= 5 and b > -20;")?, ints(vec![2, 1, 5]));
+        assert_eq!(select_a("select a from t where 5 <= b;")?, ints(vec![1, 5, 3]));
+        assert_eq!(select_a("select a from t where b between -3 and 5 and a != 5;")?, ints(vec![2, 1]));
+        assert_eq!(select_a("select a from t where b > 5 and b < 3;")?, ints(vec![]));
+        assert_eq!(select_a("select a from t where c >= -1 and c < 2;")?, ints(vec![4, 3, 1]));
+        assert_eq!(select_a("select a from t where c > 1;")?, ints(vec![1, 5]));
+        assert_eq!(select_a("select a from t where d >= 'ab';")?, ints(vec![6, 1, 5, 3]));
+
+        // 按照索引列排序，NULL 排在最前面
+        assert_eq!(select_a("select a from t order by b;")?, ints(vec![4, 6, 2, 1, 5, 3]));
+        assert_eq!(select_a("select a from t where a > 2 order by c;")?, ints(vec![6, 4, 3, 5]));
+        assert_eq!(select_a("select a from t order by b desc limit 2;")?, ints(vec![3, 1]));
+
+        // 删除和更新之后，索引范围扫描能看到最新的数据
+        s.execute("delete from t where b >= 10;")?;
+        s.execute("update t set b = 7 where b > 0 and b < 6;")?;
+        match s.execute("select a, b from t where b > 0;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(1), Value::Integer(7)],
+                    vec![Value::Integer(5), Value::Integer(7)],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+
+        match s.execute("explain select * from t where b > 1 order by b;")? {
+            ResultSet::Explain { plan } => {
+                assert!(plan.contains("Index Range Scan On t.b"));
+                assert!(!plan.contains("Order By"));
+            }
+            _ => unreachable!(),
+        }
+        match s.execute("explain select * from t order by d;")? {
+            ResultSet::Explain { plan } => assert!(plan.contains("Index Range Scan On t.d")),
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_drop_index() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int, c text index);")?;
+        s.execute("insert into t values (1, 3, 'x'), (2, 1, 'y'), (3, 3, null), (4, 2, 'x');")?;
+
+        // 在已有数据的表上创建索引，已有的数据会回填到索引中
+        s.execute("create index idx_b on t (b);")?;
+        match s.execute("explain select * from t where b = 3;")? {
+            ResultSet::Explain { plan } => assert!(plan.contains("Index Scan On t.b")),
+            _ => unreachable!(),
+        }
+        match s.execute("select a from t where b = 3;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(1)], vec![Value::Integer(3)]])
+            }
+            _ => unreachable!(),
+        }
+        // 之后写入的数据同样会维护索引
+        s.execute("insert into t values (5, 2, 'z');")?;
+        match s.execute("select a from t where b >= 2;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(4)],
+                    vec![Value::Integer(5)],
+                    vec![Value::Integer(1)],
+                    vec![Value::Integer(3)],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+
+        // 索引名重复、主键列、不存在的列、已经有索引的列都不能创建索引
+        assert!(s.execute("create index idx_b on t (c);").is_err());
+        assert!(s.execute("create index idx_a on t (a);").is_err());
+        assert!(s.execute("create index idx_d on t (d);").is_err());
+        assert!(s.execute("create index idx_c on t (c);").is_err());
+
+        // 建表时声明的索引也可以删除
+        s.execute("drop index t_c_idx;")?;
+        s.execute("drop index idx_b;")?;
+        assert!(s.execute("drop index idx_b;").is_err());
+        match s.execute("explain select * from t where b = 3;")? {
+            ResultSet::Explain { plan } => assert!(plan.contains("Seq Scan On t")),
+            _ => unreachable!(),
+        }
+        match s.execute("select a from t where b = 3;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows.len(), 2),
+            _ => unreachable!(),
+        }
+        assert_eq!(s.get_table("t".into())?, "CREATE TABLE t (\n    a Integer PRIMARY KEY,\n    b Integer DEFAULT NULL,\n    c String DEFAULT NULL\n)");
+
+        // 事务回滚之后，索引和回填的数据都不存在
+        s.execute("begin;")?;
+        s.execute("create index idx_c on t (c);")?;
+        s.execute("rollback;")?;
+        assert!(s.execute("drop index idx_c;").is_err());
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_alter_table() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int, c text index);")?;
+        s.execute("insert into t values (1, 3, 'x'), (2, 1, 'y');")?;
+
+        // 新增列，已有的行使用默认值填充，新列上声明的索引会回填
+        s.execute("alter table t add column d int default 7 index;")?;
+        s.execute("insert into t values (3, 2, 'z', 8);")?;
+        match s.execute("select a, d from t where d = 7;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(1), Value::Integer(7)],
+                    vec![Value::Integer(2), Value::Integer(7)],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+        assert!(s.execute("alter table t add column d int;").is_err());
+        assert!(s.execute("alter table t add column e int not null;").is_err());
+        assert!(s.execute("alter table t add column e int primary key;").is_err());
+
+        // 删除列，列上的索引一起删除
+        s.execute("alter table t drop column c;")?;
+        assert!(s.execute("alter table t drop column a;").is_err());
+        assert!(s.execute("drop index t_c_idx;").is_err());
+        match s.execute("select * from t;")? {
+            ResultSet::Scan { columns, rows } => {
+                assert_eq!(columns, vec!["a", "b", "d"]);
+                assert_eq!(
+                    rows[2],
+                    vec![Value::Integer(3), Value::Integer(2), Value::Integer(8)]
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        // 重命名列，主键和索引中的列名一起修改
+        s.execute("alter table t rename column a to id;")?;
+        s.execute("alter table t rename d to e;")?;
+        assert!(s.execute("alter table t rename column b to id;").is_err());
+        match s.execute("select id from t where e = 8;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(3)]])
+            }
+            _ => unreachable!(),
+        }
+
+        // 重命名表，数据和索引都迁移到新表名下
+        s.execute("create table t2 (a int primary key);")?;
+        assert!(s.execute("alter table t rename to t2;").is_err());
+        s.execute("alter table t rename to t3;")?;
+        assert!(s.execute("select * from t;").is_err());
+        match s.execute("select id from t3 where e = 7;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(1)], vec![Value::Integer(2)]])
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(s.get_table("t3".into())?, "CREATE TABLE t3 (\n    id Integer PRIMARY KEY,\n    b Integer DEFAULT NULL,\n    e Integer DEFAULT 7\n);\nCREATE INDEX t_d_idx ON t3 (e)");
+
+        // 事务回滚之后，表结构和数据都不变
+        s.execute("begin;")?;
+        s.execute("alter table t3 drop column b;")?;
+        s.execute("rollback;")?;
+        match s.execute("select b from t3 where id = 3;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(2)]])
+            }
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int);")?;
+        s.execute("insert into t values (1, 1), (2, 2);")?;
+        s.execute("update t set b = 3 where a = 1;")?;
+        s.execute("delete from t where a = 2;")?;
+
+        // 行 1 的旧版本，行 2 的旧版本和删除标记
+        match s.execute("vacuum;")? {
+            ResultSet::Vacuum { count } => assert_eq!(count, 3),
+            _ => unreachable!(),
+        }
+        match s.execute("select * from t;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(1), Value::Integer(3)]])
+            }
+            _ => unreachable!(),
+        }
+
+        s.execute("begin;")?;
+        assert!(s.execute("vacuum;").is_err());
+        s.execute("commit;")?;
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_composite_key() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (user_id int, item_id int, score int, tag text, primary key (user_id, item_id));")?;
+        s.execute("insert into t values (2, 1, 10, 'a'), (1, 3, 20, 'b'), (1, -2, 30, 'a'), (2, 5, 40, 'c');")?;
+        s.execute("insert into t values (3, 1, 50, 'b');")?;
+        // 联合主键中的每一列都不能为空，主键整体不能重复
+        assert!(s.execute("insert into t values (1, 3, 60, 'd');").is_err());
+        assert!(s.execute("insert into t values (null, 3, 60, 'd');").is_err());
+        assert!(s.execute("create table t2 (a int primary key, b int, primary key (a, b));").is_err());
+        assert!(s.execute("create table t2 (a int, b int, primary key (a, c));").is_err());
+
+        let mut select = |sql: &str| -> Result<Vec<Row>> {
+            match s.execute(sql)? {
+                ResultSet::Scan { columns: _, rows } => Ok(rows),
+                _ => unreachable!(),
+            }
+        };
+        let ints = |v: Vec<Vec<i64>>| {
+            v.into_iter()
+                .map(|r| r.into_iter().map(Value::Integer).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        // 行按照联合主键的顺序存储
+        assert_eq!(
+            select("select user_id, item_id from t;")?,
+            ints(vec![vec![1, -2], vec![1, 3], vec![2, 1], vec![2, 5], vec![3, 1]])
+        );
+        assert_eq!(select("select score from t where item_id = 5 and user_id = 2;")?, ints(vec![vec![40]]));
+        // 只给出主键的第一列，按照前缀扫描
+        assert_eq!(select("select item_id from t where user_id = 1;")?, ints(vec![vec![-2], vec![3]]));
+        assert_eq!(select("select item_id from t where user_id = 2 and score > 10;")?, ints(vec![vec![5]]));
+        // 只给出主键的第二列，不能使用主键
+        assert_eq!(select("select user_id from t where item_id = 1;")?, ints(vec![vec![2], vec![3]]));
+
+        s.execute("update t set item_id = 4 where user_id = 1 and item_id = 3;")?;
+        s.execute("delete from t where user_id = 2 and item_id = 1;")?;
+        match s.execute("select user_id, item_id from t where user_id <= 2;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(
+                rows,
+                ints(vec![vec![1, -2], vec![1, 4], vec![2, 5]])
+            ),
+            _ => unreachable!(),
+        }
+
+        // 多列索引，前面几列有等值条件时可以按照前缀扫描，索引的第一列可以做范围扫描
+        s.execute("create index idx_tag_score on t (tag, score);")?;
+        match s.execute("select score from t where tag = 'a';")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows, ints(vec![vec![30]])),
+            _ => unreachable!(),
+        }
+        s.execute("insert into t values (4, 4, 5, 'b'), (5, 5, 25, 'a');")?;
+        match s.execute("select score from t where tag = 'b' and score = 20;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows, ints(vec![vec![20]])),
+            _ => unreachable!(),
+        }
+        match s.execute("select score from t where tag > 'a' order by tag;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, ints(vec![vec![5], vec![20], vec![50], vec![40]]))
+            }
+            _ => unreachable!(),
+        }
+        // 前一列等值时，按照后一列排序不需要再排序
+        match s.execute("select score from t where tag = 'b' order by score;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, ints(vec![vec![5], vec![20], vec![50]]))
+            }
+            _ => unreachable!(),
+        }
+
+        let explain = |s: &mut Session<KVEngine<DiskEngine>>, sql: &str| -> Result<String> {
+            match s.execute(sql)? {
+                ResultSet::Explain { plan } => Ok(plan),
+                _ => unreachable!(),
+            }
+        };
+        let plan = explain(&mut s, "explain select * from t where user_id = 1 and item_id = 4;")?;
+        assert!(plan.contains("Primary Key Scan On t(1, 4)"));
+        assert!(!plan.contains("Filter"));
+        let plan = explain(&mut s, "explain select * from t where user_id = 1 order by item_id;")?;
+        assert!(plan.contains("Primary Key Scan On t(1)"));
+        assert!(!plan.contains("Order By"));
+        let plan = explain(&mut s, "explain select * from t where tag = 'b' order by score;")?;
+        assert!(plan.contains("Index Scan On t.(tag, score)"));
+        assert!(!plan.contains("Order By"));
+        let plan = explain(&mut s, "explain select * from t where tag >= 'b';")?;
+        assert!(plan.contains("Index Range Scan On t.(tag, score)"));
+        assert!(s.get_table("t".into())?.contains("PRIMARY KEY (user_id, item_id)"));
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
     #[test]
     fn test_hash_join() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         // s.execute("create table t1 (a int primary key);")?;
         // s.execute("create table t2 (b int primary key);")?;
@@ -1029,7 +2116,7 @@ mod tests {
     #[test]
     fn test_explain() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut s = kvengine.session()?;
         s.execute("create table t1 (a int primary key);")?;
         s.execute("create table t2 (b int primary key);")?;
@@ -1050,4 +2137,504 @@ mod tests {
         std::fs::remove_dir_all(p.parent().unwrap())?;
         Ok(())
     }
+
+    #[test]
+    fn test_serializable() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s1 = kvengine.session()?;
+        let mut s2 = kvengine.session()?;
+        s1.execute("create table oncall (id int primary key, name text, on_duty bool);")?;
+        s1.execute("insert into oncall values (1, 'alice', true), (2, 'bob', true);")?;
+
+        // 两个事务都确认还有两个人值班，然后各自让一个人下班
+        s1.execute("begin isolation level serializable;")?;
+        s2.execute("begin transaction isolation level serializable;")?;
+        for s in [&mut s1, &mut s2] {
+            match s.execute("select * from oncall where on_duty = true;")? {
+                ResultSet::Scan { columns: _, rows } => assert_eq!(rows.len(), 2),
+                _ => unreachable!(),
+            }
+        }
+        s1.execute("update oncall set on_duty = false where id = 1;")?;
+        s2.execute("update oncall set on_duty = false where id = 2;")?;
+        s1.execute("commit;")?;
+        assert_eq!(s2.execute("commit;"), Err(Error::SerializationFailure));
+
+        // 提交失败之后会话已经不在事务中，可以直接重试
+        assert!(s2.execute("commit;").is_err());
+        match s2.execute("select * from oncall where on_duty = true;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows.len(), 1),
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int);")?;
+        let version = match s.execute("insert into t values (1, 1), (2, 2);")? {
+            ResultSet::Insert { .. } => kvengine.kv.begin_read_only(None)?.version(),
+            _ => unreachable!(),
+        };
+        s.execute("update t set b = 10;")?;
+        s.execute("delete from t where a = 2;")?;
+
+        let rows = |s: &mut Session<_>, sql: &str| -> Result<Vec<Row>> {
+            match s.execute(sql)? {
+                ResultSet::Scan { columns: _, rows } => Ok(rows),
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(
+            rows(&mut s, &format!("select * from t as of {};", version))?,
+            vec![
+                vec![Value::Integer(1), Value::Integer(1)],
+                vec![Value::Integer(2), Value::Integer(2)],
+            ]
+        );
+        assert_eq!(rows(&mut s, "select * from t;")?, vec![vec![Value::Integer(1), Value::Integer(10)]]);
+        // 建表之前的版本里表还不存在
+        assert!(s.execute("select * from t as of 0;").is_err());
+        assert_eq!(rows(&mut s, "select * from t as of 1;")?, Vec::<Row>::new());
+        assert!(s.execute("select * from t as of 100;").is_err());
+
+        // 只读事务中不能写入，也不能再指定 AS OF
+        match s.execute(&format!("begin read only as of system time {};", version))? {
+            ResultSet::Begin { version: v } => assert_eq!(v, version),
+            _ => unreachable!(),
+        }
+        assert_eq!(rows(&mut s, "select b from t where a = 2;")?, vec![vec![Value::Integer(2)]]);
+        assert!(s.execute("insert into t values (3, 3);").is_err());
+        assert!(s.execute("select * from t as of 1;").is_err());
+        s.execute("commit;")?;
+
+        // 普通的查询不会分配新的版本
+        let next = kvengine.kv.begin_read_only(None)?.version();
+        s.execute("select * from t;")?;
+        s.execute("begin read only;")?;
+        s.execute("commit;")?;
+        assert_eq!(kvengine.kv.begin_read_only(None)?.version(), next);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoint() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table t (a int primary key, b int index);")?;
+        assert!(s.execute("savepoint sp1;").is_err());
+
+        s.execute("begin;")?;
+        s.execute("insert into t values (1, 1);")?;
+        s.execute("savepoint sp1;")?;
+        s.execute("insert into t values (2, 2);")?;
+        s.execute("update t set b = 10 where a = 1;")?;
+        s.execute("savepoint sp2;")?;
+        s.execute("delete from t;")?;
+        s.execute("rollback to savepoint sp2;")?;
+        match s.execute("select * from t where b = 10;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(1), Value::Integer(10)]])
+            }
+            _ => unreachable!(),
+        }
+        s.execute("release savepoint sp2;")?;
+        assert!(s.execute("rollback to sp2;").is_err());
+
+        // 回滚到 sp1，索引也一起恢复
+        s.execute("rollback to sp1;")?;
+        match s.execute("select * from t where b = 1;")? {
+            ResultSet::Scan { columns: _, rows } => {
+                assert_eq!(rows, vec![vec![Value::Integer(1), Value::Integer(1)]])
+            }
+            _ => unreachable!(),
+        }
+        s.execute("commit;")?;
+        match s.execute("select * from t;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows.len(), 1),
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_statement_atomicity() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s1 = kvengine.session()?;
+        let mut s2 = kvengine.session()?;
+        s1.execute("create table t (a int primary key, b int index);")?;
+        let count = |s: &mut Session<_>| -> Result<usize> {
+            match s.execute("select * from t;")? {
+                ResultSet::Scan { columns: _, rows } => Ok(rows.len()),
+                _ => unreachable!(),
+            }
+        };
+
+        // 多行插入中途失败，已经插入的行也被撤销，事务可以继续执行
+        s1.execute("begin;")?;
+        s1.execute("insert into t values (1, 1);")?;
+        assert!(s1.execute("insert into t values (2, 2), (1, 1);").is_err());
+        assert_eq!(count(&mut s1)?, 1);
+        s1.execute("savepoint sp;")?;
+        s1.execute("insert into t values (2, 2);")?;
+        s1.execute("commit;")?;
+        assert_eq!(count(&mut s1)?, 2);
+
+        // 写冲突之后事务被中止，只能回滚
+        s1.execute("begin;")?;
+        s1.execute("insert into t values (3, 3);")?;
+        s2.execute("update t set b = 10 where a = 1;")?;
+        assert_eq!(
+            s1.execute("update t set b = 20 where a = 1;"),
+            Err(Error::WriteConflict)
+        );
+        assert!(s1.execute("select * from t;").is_err());
+        assert!(s1.execute("savepoint sp;").is_err());
+        match s1.execute("commit;")? {
+            ResultSet::Rollback { .. } => {}
+            _ => unreachable!(),
+        }
+        assert_eq!(count(&mut s1)?, 2);
+
+        // 回滚到中止之前的保存点，事务可以继续执行
+        s1.execute("begin;")?;
+        s1.execute("savepoint sp;")?;
+        s1.execute("insert into t values (3, 3);")?;
+        s2.execute("update t set b = 11 where a = 1;")?;
+        assert!(s1.execute("delete from t where a = 1;").is_err());
+        assert!(s1.execute("insert into t values (4, 4);").is_err());
+        s1.execute("rollback to sp;")?;
+        s1.execute("insert into t values (4, 4);")?;
+        s1.execute("commit;")?;
+        match s1.execute("select a from t where b = 4;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows, vec![vec![Value::Integer(4)]]),
+            _ => unreachable!(),
+        }
+        assert_eq!(count(&mut s1)?, 3);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_row_lock() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine =
+            KVEngine::new_with_lock_timeout(DiskEngine::new(p.clone())?, Duration::from_millis(100))?;
+        let mut s1 = kvengine.session()?;
+        let mut s2 = kvengine.session()?;
+        s1.execute("create table t (a int primary key, b int);")?;
+        s1.execute("insert into t values (1, 1), (2, 2), (3, 3);")?;
+
+        // 共享锁可以同时持有，排他锁等待超时，超时的语句被撤销，事务继续执行
+        s1.execute("begin;")?;
+        s2.execute("begin;")?;
+        s1.execute("select * from t where a = 1 for share;")?;
+        s2.execute("select * from t where a = 1 for share;")?;
+        s2.execute("update t set b = 20 where a = 2;")?;
+        assert_eq!(s2.execute("update t set b = 10 where a <= 2;"), Err(Error::LockTimeout));
+        s2.execute("commit;")?;
+        assert_eq!(s1.execute("select * from t where a = 2 for update;"), Err(Error::WriteConflict));
+        s1.execute("rollback;")?;
+
+        // 等待中的写入在持有锁的事务回滚之后继续执行
+        s1.execute("begin;")?;
+        match s1.execute("select b from t where a >= 2 order by a limit 1 for update;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows, vec![vec![Value::Integer(20)]]),
+            _ => unreachable!(),
+        }
+        let waiter = {
+            let kvengine = kvengine.clone();
+            thread::spawn(move || -> Result<ResultSet> {
+                let mut s = kvengine.session()?;
+                s.execute("begin;")?;
+                let rs = s.execute("update t set b = 200 where a = 2;")?;
+                s.execute("commit;")?;
+                Ok(rs)
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        s1.execute("rollback;")?;
+        assert_eq!(waiter.join().unwrap()?, ResultSet::Update { count: 1 });
+
+        // 死锁：后等待的事务报错并被中止，回滚之后另一个事务继续执行
+        s1.execute("begin;")?;
+        s2.execute("begin;")?;
+        s1.execute("update t set b = 100 where a = 1;")?;
+        s2.execute("update t set b = 300 where a = 3;")?;
+        let waiter = thread::spawn(move || -> Result<ResultSet> {
+            s1.execute("update t set b = 300 where a = 3;")?;
+            s1.execute("commit;")
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(s2.execute("select * from t where a = 1 for update;"), Err(Error::Deadlock));
+        assert!(s2.execute("select * from t;").is_err());
+        s2.execute("rollback;")?;
+        waiter.join().unwrap()?;
+
+        // 加锁只支持单表查询，只读事务中不能加锁
+        assert!(s2.execute("select count(a) from t for update;").is_err());
+        assert!(s2.execute("select * from t as of 1 for update;").is_err());
+        match s2.execute("explain select * from t for update;")? {
+            ResultSet::Explain { plan } => assert!(plan.contains("Lock Rows On t For Update")),
+            _ => unreachable!(),
+        }
+        match s2.execute("select b from t order by a;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(
+                rows,
+                vec![vec![Value::Integer(100)], vec![Value::Integer(200)], vec![Value::Integer(300)]]
+            ),
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_autocommit_retry() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let mut kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        kvengine.set_retry_policy(RetryPolicy {
+            max_retries: 100,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        });
+        let mut s1 = kvengine.session()?;
+        let mut s2 = kvengine.session()?;
+        s1.execute("create table t (a int primary key, b int);")?;
+        s1.execute("insert into t values (1, 1);")?;
+
+        // s1 的事务还没有提交，s2 的语句写冲突之后不断重试，直到 s1 提交
+        s1.execute("begin;")?;
+        s1.execute("update t set b = 2 where a = 1;")?;
+        let committer = thread::spawn(move || -> Result<ResultSet> {
+            thread::sleep(Duration::from_millis(50));
+            s1.execute("commit;")
+        });
+        assert_eq!(s2.execute("update t set b = b + 10 where a = 1;")?, ResultSet::Update { count: 1 });
+        committer.join().unwrap()?;
+        match s2.execute("select b from t;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(rows, vec![vec![Value::Integer(12)]]),
+            _ => unreachable!(),
+        }
+        let retries = kvengine.retries();
+        assert!(retries > 0);
+        assert!(s2.get_status()?.ends_with(&format!("retries: {}", retries)));
+
+        // 不重试的时候直接返回写冲突，显式事务中的语句也不会重试
+        kvengine.set_retry_policy(RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        });
+        let mut s1 = kvengine.session()?;
+        let mut s2 = kvengine.session()?;
+        s1.execute("begin;")?;
+        s1.execute("update t set b = 3 where a = 1;")?;
+        assert_eq!(s2.execute("update t set b = 4 where a = 1;"), Err(Error::WriteConflict));
+        s2.execute("begin;")?;
+        assert_eq!(s2.execute("update t set b = 4 where a = 1;"), Err(Error::WriteConflict));
+        s2.execute("rollback;")?;
+        s1.execute("commit;")?;
+        assert_eq!(kvengine.retries(), retries);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_subquery() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table dept (did int primary key, dname text);")?;
+        s.execute("create table emp (eid int primary key, ename text, dept int, salary int);")?;
+        s.execute("insert into dept values (1, 'eng'), (2, 'sales'), (3, 'hr');")?;
+        s.execute(
+            "insert into emp values (1, 'a', 1, 100), (2, 'b', 1, 200), (3, 'c', 2, 50), (4, 'd', null, 80);",
+        )?;
+
+        let mut query = |sql: &str| -> Result<Vec<Row>> {
+            match s.execute(sql)? {
+                ResultSet::Scan { columns: _, rows } => Ok(rows),
+                _ => unreachable!(),
+            }
+        };
+        let strings = |v: &[&str]| -> Vec<Row> {
+            v.iter().map(|s| vec![Value::String(s.to_string())]).collect()
+        };
+
+        // IN / NOT IN
+        let rows = query("select ename from emp where dept in (select did from dept where dname != 'hr') order by ename;")?;
+        assert_eq!(rows, strings(&["a", "b", "c"]));
+        let rows = query("select ename from emp where dept not in (select did from dept where dname = 'eng') order by ename;")?;
+        assert_eq!(rows, strings(&["c"]));
+        let rows = query("select ename from emp where dept in (select did from dept where did > 10);")?;
+        assert_eq!(rows, strings(&[]));
+
+        // 相关的 EXISTS / NOT EXISTS
+        let rows = query("select dname from dept where exists (select eid from emp where dept = did) order by dname;")?;
+        assert_eq!(rows, strings(&["eng", "sales"]));
+        let rows = query("select dname from dept where not exists (select * from emp where dept = did);")?;
+        assert_eq!(rows, strings(&["hr"]));
+
+        // 标量子查询：WHERE 中不相关的，SELECT 中相关的
+        let rows = query("select ename from emp where salary > (select avg(salary) from emp) order by ename;")?;
+        assert_eq!(rows, strings(&["b"]));
+        let rows = query("select dname, (select count(eid) from emp where dept = did) as cnt, (select max(salary) from emp where dept = did) as top from dept order by did;")?;
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::String("eng".into()), Value::Integer(2), Value::Integer(200)],
+                vec![Value::String("sales".into()), Value::Integer(1), Value::Integer(50)],
+                vec![Value::String("hr".into()), Value::Integer(0), Value::Null],
+            ]
+        );
+        // 嵌套的子查询引用最外层的列
+        let rows = query("select dname from dept where exists (select eid from emp where dept = did and salary > (select min(salary) from emp where dept = did));")?;
+        assert_eq!(rows, strings(&["eng"]));
+
+        // FROM 中的子查询
+        let rows = query("select * from (select dept, max(salary) as top from emp group by dept) as t where top >= 100;")?;
+        assert_eq!(rows, vec![vec![Value::Integer(1), Value::Integer(200)]]);
+        let rows = query("select ename from (select * from emp where salary < 150) t where dept is not null order by ename;")?;
+        assert_eq!(rows, strings(&["a", "c"]));
+
+        // 标量子查询的结果只能有一行一列
+        assert_eq!(
+            query("select ename from emp where salary = (select salary from emp);"),
+            Err(Error::Internal(
+                "more than one row returned by a subquery used as an expression".into()
+            ))
+        );
+        assert_eq!(
+            query("select ename from emp where dept in (select * from dept);"),
+            Err(Error::Internal("subquery must return only one column".into()))
+        );
+        assert!(query("select * from (select * from emp);").is_err());
+
+        // UPDATE、DELETE 中的子查询
+        s.execute("update emp set salary = (select max(salary) from emp) where dept in (select did from dept where dname = 'sales');")?;
+        s.execute("delete from emp where not exists (select did from dept where did = dept);")?;
+        match s.execute("select eid, salary from emp;")? {
+            ResultSet::Scan { columns: _, rows } => assert_eq!(
+                rows,
+                vec![
+                    vec![Value::Integer(1), Value::Integer(100)],
+                    vec![Value::Integer(2), Value::Integer(200)],
+                    vec![Value::Integer(3), Value::Integer(200)],
+                ]
+            ),
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_alias() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
+        let mut s = kvengine.session()?;
+        s.execute("create table users (id int primary key, name text);")?;
+        s.execute("create table orders (id int primary key, user_id int index, amount int);")?;
+        s.execute("insert into users values (1, 'a'), (2, 'b'), (3, 'c');")?;
+        s.execute("insert into orders values (10, 1, 100), (11, 1, 50), (12, 2, 70);")?;
+
+        let mut query = |sql: &str| -> Result<(Vec<String>, Vec<Row>)> {
+            match s.execute(sql)? {
+                ResultSet::Scan { columns, rows } => Ok((columns, rows)),
+                _ => unreachable!(),
+            }
+        };
+
+        // 两张表都有 id 列，通过别名区分
+        let (columns, rows) = query("select u.id, o.id, amount from users as u join orders o on u.id = o.user_id where o.amount > 60 order by o.id;")?;
+        assert_eq!(columns, vec!["u.id", "o.id", "o.amount"]);
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Integer(1), Value::Integer(10), Value::Integer(100)],
+                vec![Value::Integer(2), Value::Integer(12), Value::Integer(70)],
+            ]
+        );
+        // 没有别名时用表名限定，只在一张表中出现的列可以不加表名
+        let (_, rows) = query("select name, count(orders.id) as cnt from users left join orders on users.id = user_id group by name order by name;")?;
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::String("a".into()), Value::Integer(2)],
+                vec![Value::String("b".into()), Value::Integer(1)],
+                vec![Value::String("c".into()), Value::Integer(0)],
+            ]
+        );
+        // 自连接，WHERE 中只涉及一张表的条件下推到这张表的扫描中
+        let (columns, rows) = query("select * from users a join users b on a.id + 1 = b.id where a.id >= 2;")?;
+        assert_eq!(columns, vec!["a.id", "a.name", "b.id", "b.name"]);
+        assert_eq!(
+            rows,
+            vec![vec![
+                Value::Integer(2),
+                Value::String("b".into()),
+                Value::Integer(3),
+                Value::String("c".into()),
+            ]]
+        );
+        // 单表查询中也可以用别名限定列
+        let (columns, rows) = query("select u.name from users u where u.id = 3;")?;
+        assert_eq!(columns, vec!["name"]);
+        assert_eq!(rows, vec![vec![Value::String("c".into())]]);
+
+        // 相关子查询引用外层同名表中的列
+        let (_, rows) = query("select name from users u where exists (select * from orders o where o.user_id = u.id and o.amount > u.id * 60) order by name;")?;
+        assert_eq!(rows, vec![vec![Value::String("a".into())]]);
+        let (_, rows) = query("select name from users u where not exists (select * from users v where v.id > u.id);")?;
+        assert_eq!(rows, vec![vec![Value::String("c".into())]]);
+        // FROM 中的子查询
+        let (columns, _) = query("select t.id, u.name from (select o.id, o.user_id from users u join orders o on u.id = o.user_id) t join users u on t.user_id = u.id;")?;
+        assert_eq!(columns, vec!["t.id", "u.name"]);
+
+        assert_eq!(
+            query("select id from users join orders on users.id = user_id;"),
+            Err(Error::Internal("column reference id is ambiguous".into()))
+        );
+        assert_eq!(
+            query("select * from users u join orders u on u.id = user_id;"),
+            Err(Error::Internal("table name u specified more than once".into()))
+        );
+        assert_eq!(
+            query("select x.id from users u;"),
+            Err(Error::Internal("column x.id is not in table".into()))
+        );
+        // 有别名之后就不能再用表名了
+        assert_eq!(
+            query("select users.id from users u;"),
+            Err(Error::Internal("column users.id is not in table".into()))
+        );
+
+        // WHERE 中只涉及一张表的条件下推到这张表的扫描中，可以走索引
+        match s.execute("explain select * from users u join orders o on u.id = o.user_id where o.user_id = 1 and u.name != o.amount;")? {
+            ResultSet::Explain { plan } => {
+                assert!(plan.contains("Index Scan On orders.user_id As o"));
+                assert!(plan.contains("Filter (u.name != o.amount)"));
+            }
+            _ => unreachable!(),
+        }
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
 }
@@ -1,6 +1,9 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, ops::Bound, thread, time::Duration};
 
 use crate::error::{Error, Result};
+use crate::storage::engine::Status;
+use crate::storage::lock::LockMode;
+use crate::storage::mvcc::{IsolationLevel, Version};
 use crate::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, pr, py};
 
 use super::{
@@ -10,8 +13,8 @@ use super::{
         Parser,
     },
     plan::Plan,
-    schema::Table,
-    types::{Row, Value},
+    schema::{Index, Table, TableChange},
+    types::{Row, Rows, Value},
 };
 
 pub mod kv;
@@ -20,17 +23,61 @@ pub mod kv;
 pub trait Engine: Clone {
     type Transaction: Transaction;
 
-    fn begin(&self) -> Result<Self::Transaction>;
+    fn begin(&self) -> Result<Self::Transaction> {
+        self.begin_with(IsolationLevel::Snapshot)
+    }
+
+    // 按照给定的隔离级别开启事务
+    fn begin_with(&self, isolation: IsolationLevel) -> Result<Self::Transaction>;
+
+    // 开启只读事务，as_of 指定读取的历史版本
+    fn begin_read_only(&self, as_of: Option<Version>) -> Result<Self::Transaction>;
+
+    // 清理旧版本数据，返回删除的版本数
+    fn vacuum(&self) -> Result<usize>;
+
+    // 存储引擎的运行状态
+    fn status(&self) -> Result<Status>;
+
+    // 自动提交的语句遇到写冲突时的重试策略
+    fn retry_policy(&self) -> RetryPolicy;
+
+    // 记录一次自动重试
+    fn record_retry(&self);
+
+    // 自动重试的总次数
+    fn retries(&self) -> u64;
 
     fn session(&self) -> Result<Session<Self>> {
         __function!("客户端的事务session建立成功~");
         Ok(Session {
             engine: self.clone(),
             txn: None,
+            aborted: false,
         })
     }
 }
 
+// 自动提交的语句（隐式事务）遇到写冲突或者死锁时的重试策略
+// 第一次重试之前等待 backoff，之后每次等待的时间翻倍，最多等待 max_backoff
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // 最多重试的次数，0 表示不重试
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(1000),
+        }
+    }
+}
+
 // 抽象的事务信息，包含了 DDL 和 DML 操作
 // 底层可以接入普通的 KV 存储引擎，也可以接入分布式存储引擎
 pub trait Transaction {
@@ -40,40 +87,64 @@ pub trait Transaction {
     fn rollback(&self) -> Result<()>;
     // 版本号
     fn version(&self) -> u64;
+    // 创建保存点
+    fn savepoint(&self, name: String) -> Result<()>;
+    // 回滚到保存点，撤销保存点之后的修改
+    fn rollback_to_savepoint(&self, name: &str) -> Result<()>;
+    // 释放保存点
+    fn release_savepoint(&self, name: &str) -> Result<()>;
+    // 给行加锁，锁一直持有到事务结束
+    fn lock_row(&self, table: &Table, id: &[Value], mode: LockMode) -> Result<()>;
 
     // 创建行
     fn create_row(&mut self, table_name: String, row: Row) -> Result<()>;
-    // 更新行
-    fn update_row(&mut self, table: &Table, id: &Value, row: Row) -> Result<()>;
+    // 更新行，id 为行的主键（联合主键时按照主键声明的顺序）
+    fn update_row(&mut self, table: &Table, id: &[Value], row: Row) -> Result<()>;
     // 删除行
-    fn delete_row(&mut self, table: &Table, id: &Value) -> Result<()>;
-    // 扫描表
-    fn scan_table(&self, table_name: String, filter: Option<Expression>) -> Result<Vec<Row>>;
+    fn delete_row(&mut self, table: &Table, id: &[Value]) -> Result<()>;
+    // 扫描表，返回的是行迭代器，数据在迭代的时候才会从存储引擎中读取
+    fn scan_table(&self, table_name: String, filter: Option<Expression>) -> Result<Rows>;
     // 索引的操作！
     // 获取索引
     fn load_index(
-        // 表名列名列值
+        // 表名索引名索引列值
         &self,
         table_name: &str,
-        col_name: &str,
-        col_value: &Value,//这里是主键值！
-    ) -> Result<HashSet<Value>>;
+        index_name: &str,
+        values: &[Value],
+    ) -> Result<HashSet<Vec<Value>>>;//返回的是主键的集合！
     // 保存索引
     fn save_index(
         &self,
         table_name: &str,
-        col_name: &str,
-        col_value: &Value,
-        index: HashSet<Value>,
+        index_name: &str,
+        values: &[Value],
+        index: HashSet<Vec<Value>>,
     ) -> Result<()>;
-    // 根据 索引id 获取行
-    fn read_by_id(&self, table_name: &str, id: &Value) -> Result<Option<Row>>;
+    // 按照索引列值的范围扫描索引，返回按照列值从小到大排列的主键集合
+    // 边界可以只给出索引前面的几列，这时按照前缀来比较
+    fn scan_index(
+        &self,
+        table_name: &str,
+        index_name: &str,
+        range: (Bound<Vec<Value>>, Bound<Vec<Value>>),
+    ) -> Result<Vec<HashSet<Vec<Value>>>>;
+    // 根据 主键 获取行
+    fn read_by_id(&self, table_name: &str, id: &[Value]) -> Result<Option<Row>>;
+    // 扫描联合主键前面几列等于给定值的所有行
+    fn scan_by_id_prefix(&self, table_name: &str, prefix: &[Value]) -> Result<Rows>;
 
     // DDL 相关操作
     // 创建表
     fn create_table(&mut self, table: Table) -> Result<()>;
     // 删除表
     fn drop_table(&mut self, table_name: String) -> Result<()>;
+    // 在已有的表上创建索引，并且为表中已有的数据回填索引
+    fn create_index(&mut self, table_name: String, index: Index) -> Result<()>;
+    // 删除索引，返回索引所在的表名
+    fn drop_index(&mut self, index_name: String) -> Result<String>;
+    // 修改表结构，已有的行会按照新的表结构重写，返回修改后的表名
+    fn alter_table(&mut self, table_name: String, change: TableChange) -> Result<String>;
     // 获取所有的表名
     fn get_table_names(&self) -> Result<Vec<String>>;
     // 获取表信息
@@ -92,8 +163,13 @@ pub trait Transaction {
 pub struct Session<E: Engine> {
     engine: E,
     txn: Option<E::Transaction>,
+    // 事务已经中止，只能回滚整个事务或者回滚到保存点
+    aborted: bool,
 }
 
+// 事务中每个语句执行前创建的隐式保存点，标识符必须以字母开头，所以不会和用户的保存点重名
+const STATEMENT_SAVEPOINT: &str = "_statement";
+
 // 每次执行一个语句就是一个会话~所以执行是在会话里面执行的
 impl<E: Engine + 'static> Session<E> {
     // 执行客户端 SQL 语句
@@ -102,20 +178,41 @@ impl<E: Engine + 'static> Session<E> {
         match Parser::new(sql).parse()? {
             // 直接在这里对事务进行处理
             // 如果开启的事务本身就再一个事务里面，那么直接报错，因为当前事务还没有结束
-            ast::Statement::Begin if self.txn.is_some() => {
+            ast::Statement::Begin { .. } if self.txn.is_some() => {
                 Err(Error::Internal("Already in transaction".into()))
             }
             // 如果事务不存在的话，提交与回滚操作就是不存在的！
-            ast::Statement::Commit | ast::Statement::Rollback if self.txn.is_none() => {
+            ast::Statement::Commit
+            | ast::Statement::Rollback
+            | ast::Statement::Savepoint { .. }
+            | ast::Statement::RollbackToSavepoint { .. }
+            | ast::Statement::ReleaseSavepoint { .. }
+                if self.txn.is_none() =>
+            {
                 Err(Error::Internal("Not in transaction".into()))
             }
             // 接下来就是正式的执行事务的命令
-            ast::Statement::Begin => {
-                let txn = self.engine.begin()?;
+            ast::Statement::Begin {
+                isolation,
+                read_only,
+                as_of,
+            } => {
+                let txn = match read_only {
+                    true => self.engine.begin_read_only(as_of)?,
+                    false => self.engine.begin_with(isolation)?,
+                };
                 let version = txn.version();
                 self.txn = Some(txn);
                 Ok(ResultSet::Begin { version })
             }
+            // 中止的事务提交时直接回滚
+            ast::Statement::Commit if self.aborted => {
+                let txn = self.txn.take().unwrap();
+                self.aborted = false;
+                let version = txn.version();
+                txn.rollback()?;
+                Ok(ResultSet::Rollback { version })
+            }
             ast::Statement::Commit => {
                 let txn = self.txn.take().unwrap();
                 let version = txn.version();
@@ -124,15 +221,41 @@ impl<E: Engine + 'static> Session<E> {
             }
             ast::Statement::Rollback => {
                 let txn = self.txn.take().unwrap();
+                self.aborted = false;
                 let version = txn.version();
                 txn.rollback()?;
                 Ok(ResultSet::Rollback { version })
             }
+            // 回滚到中止之前创建的保存点，事务可以继续执行
+            ast::Statement::RollbackToSavepoint { name } => {
+                self.txn.as_ref().unwrap().rollback_to_savepoint(&name)?;
+                self.aborted = false;
+                Ok(ResultSet::RollbackToSavepoint { name })
+            }
+            _ if self.aborted => Err(Error::Internal(
+                "current transaction is aborted, commands ignored until end of transaction block"
+                    .into(),
+            )),
+            ast::Statement::Savepoint { name } => {
+                self.txn.as_ref().unwrap().savepoint(name.clone())?;
+                Ok(ResultSet::Savepoint { name })
+            }
+            ast::Statement::ReleaseSavepoint { name } => {
+                self.txn.as_ref().unwrap().release_savepoint(&name)?;
+                Ok(ResultSet::ReleaseSavepoint { name })
+            }
+            // VACUUM 不属于任何事务，需要根据所有活跃事务来判断哪些版本可以删除
+            ast::Statement::Vacuum if self.txn.is_some() => {
+                Err(Error::Internal("VACUUM cannot run inside a transaction".into()))
+            }
+            ast::Statement::Vacuum => Ok(ResultSet::Vacuum {
+                count: self.engine.vacuum()?,
+            }),
             ast::Statement::Explain { stmt } => {
                 let plan = match self.txn.as_ref() {
                     Some(_) => Plan::build(*stmt, self.txn.as_mut().unwrap())?,
                     None => {
-                        let mut txn = self.engine.begin()?;
+                        let mut txn = self.engine.begin_read_only(None)?;
                         let plan = Plan::build(*stmt, &mut txn)?;
                         txn.commit()?;
                         plan
@@ -142,25 +265,93 @@ impl<E: Engine + 'static> Session<E> {
                     plan: plan.0.to_string(),
                 })
             }
-            stmt if self.txn.is_some() => {
-                Plan::build(stmt, self.txn.as_mut().unwrap())?.execute(self.txn.as_mut().unwrap())
+            // 事务中的查询只能读取事务自己的快照
+            ast::Statement::Select { as_of: Some(_), .. } if self.txn.is_some() => Err(
+                Error::Internal("AS OF cannot be used inside a transaction".into()),
+            ),
+            stmt if self.txn.is_some() => self.execute_in_txn(stmt),
+
+            stmt => self.execute_autocommit(sql, stmt),
+        }
+    }
+
+    // 单独执行的语句在隐式事务中执行，出错时回滚整个事务
+    // 写冲突和死锁说明有其他事务并发修改了同样的数据，隐式事务中只有这一个语句，还没有结果返回给客户端，
+    // 所以可以安全地在新的事务中重新执行，按照重试策略每次重试之前等待的时间翻倍
+    fn execute_autocommit(&mut self, sql: &str, mut stmt: ast::Statement) -> Result<ResultSet> {
+        let policy = self.engine.retry_policy();
+        let mut backoff = policy.backoff;
+        let mut retries = 0;
+        loop {
+            match self.execute_implicit(stmt) {
+                Err(err)
+                    if (err == Error::WriteConflict || err == Error::Deadlock)
+                        && retries < policy.max_retries =>
+                {
+                    retries += 1;
+                    self.engine.record_retry();
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(policy.max_backoff);
+                    // 语句在执行的时候已经被消耗掉了，重新解析一遍
+                    stmt = Parser::new(sql).parse()?;
+                }
+                result => return result,
             }
+        }
+    }
 
-            stmt => {
-                let mut txn = self.engine.begin()?;
-                // 构建 plan，执行 SQL 语句
-                // 先build在execute的
-                // 这里execute后，立马传到planner里面执行节点里面去执行节点
-                match Plan::build(stmt, &mut txn)?.execute(&mut txn) {
-                    Ok(result) => {
-                        txn.commit()?;
-                        Ok(result)
-                    }
-                    Err(err) => {
-                        txn.rollback()?;
-                        Err(err)
-                    }
+    fn execute_implicit(&mut self, stmt: ast::Statement) -> Result<ResultSet> {
+        // 单独执行的查询语句不会写入数据，使用只读事务，不需要分配新的版本
+        let mut txn = match stmt {
+            ast::Statement::Select {
+                as_of, lock: None, ..
+            } => self.engine.begin_read_only(as_of)?,
+            _ => self.engine.begin()?,
+        };
+        // 构建 plan，执行 SQL 语句
+        // 先build在execute的
+        // 这里execute后，立马传到planner里面执行节点里面去执行节点
+        let result = match Plan::build(stmt, &mut txn) {
+            Ok(plan) => plan.execute(&mut txn),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(result) => {
+                txn.commit()?;
+                Ok(result)
+            }
+            Err(err) => {
+                txn.rollback()?;
+                Err(err)
+            }
+        }
+    }
+
+    // 事务中的每个语句都是原子的：执行前创建一个隐式的保存点，失败时回滚到这个保存点，
+    // 撤销这个语句已经做的修改（例如多行 INSERT 中已经插入的行），事务可以继续执行。
+    // 写冲突说明事务读取的快照已经过期，死锁时当前事务被选为牺牲者，需要尽快回滚释放持有的锁，
+    // 撤销语句的修改失败时事务的状态也无法确定，
+    // 这几种情况下把事务标记为中止，之后只能回滚整个事务或者回滚到之前的保存点
+    fn execute_in_txn(&mut self, stmt: ast::Statement) -> Result<ResultSet> {
+        let txn = self.txn.as_mut().unwrap();
+        txn.savepoint(STATEMENT_SAVEPOINT.into())?;
+        let result = match Plan::build(stmt, txn) {
+            Ok(plan) => plan.execute(txn),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(result) => {
+                txn.release_savepoint(STATEMENT_SAVEPOINT)?;
+                Ok(result)
+            }
+            Err(err) => {
+                let undo = txn
+                    .rollback_to_savepoint(STATEMENT_SAVEPOINT)
+                    .and_then(|_| txn.release_savepoint(STATEMENT_SAVEPOINT));
+                if undo.is_err() || err == Error::WriteConflict || err == Error::Deadlock {
+                    self.aborted = true;
                 }
+                Err(err)
             }
         }
     }
@@ -169,7 +360,7 @@ impl<E: Engine + 'static> Session<E> {
         let table = match self.txn.as_ref() {
             Some(txn) => txn.must_get_table(table_name)?,
             None => {
-                let txn = self.engine.begin()?;
+                let txn = self.engine.begin_read_only(None)?;
                 let table = txn.must_get_table(table_name)?;
                 txn.commit()?;
                 table
@@ -179,12 +370,21 @@ impl<E: Engine + 'static> Session<E> {
         Ok(table.to_string())
     }
 
+    // 获取存储引擎的运行状态，以及自动重试的次数
+    pub fn get_status(&self) -> Result<String> {
+        Ok(format!(
+            "{}\nretries: {}",
+            self.engine.status()?,
+            self.engine.retries()
+        ))
+    }
+
     // 获取到所有的表名字
     pub fn get_table_names(&self) -> Result<String> {
         let names = match self.txn.as_ref() {
             Some(txn) => txn.get_table_names()?,
             None => {
-                let txn = self.engine.begin()?;
+                let txn = self.engine.begin_read_only(None)?;
                 let names = txn.get_table_names()?;
                 txn.commit()?;
                 names
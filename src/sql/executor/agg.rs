@@ -1,27 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
     error::{Error, Result},
     sql::{
         engine::Transaction,
-        parser::ast::{self, Expression},
+        parser::ast::{evaluate_const_expr, evaluate_expr, Expression},
         types::Value,
     },
 };
 
-use super::{Executor, ResultSet};
+use super::{ExecuteResult, Executor};
 
 pub struct Aggregate<T: Transaction> {
     source: Box<dyn Executor<T>>,
     exprs: Vec<(Expression, Option<String>)>,
-    group_by: Option<Expression>,
+    group_by: Vec<Expression>,
 }
 
 impl<T: Transaction> Aggregate<T> {
     pub fn new(
         source: Box<dyn Executor<T>>,
         exprs: Vec<(Expression, Option<String>)>,
-        group_by: Option<Expression>,
+        group_by: Vec<Expression>,
     ) -> Box<Self> {
         Box::new(Self {
             source,
@@ -31,57 +31,28 @@ impl<T: Transaction> Aggregate<T> {
     }
 }
 
-impl<T: Transaction> Executor<T> for Aggregate<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
-        if let ResultSet::Scan { columns, rows } = self.source.execute(txn)? {
-            let mut new_cols = Vec::new();
-            let mut new_rows = Vec::new();
-
-            // 计算函数
-            let mut calc = |col_val: Option<&Value>,
-                            rows: &Vec<Vec<Value>>|
-             -> Result<Vec<Value>> {
-                let mut new_row = Vec::new();
-                for (expr, alias) in &self.exprs {
-                    match expr {
-                        ast::Expression::Function(func_name, col_name) => {
-                            let calculator = <dyn Calculator>::build(&func_name)?;
-                            let val = calculator.calc(&col_name, &columns, rows)?;
-
-                            // min(a)            -> min
-                            // min(a) as min_val -> min_val
-                            if new_cols.len() < self.exprs.len() {
-                                new_cols.push(if let Some(a) = alias {
-                                    a.clone()
-                                } else {
-                                    func_name.clone()
-                                });
-                            }
-                            new_row.push(val);
-                        }
-                        ast::Expression::Field(col) => {
-                            if let Some(ast::Expression::Field(group_col)) = &self.group_by {
-                                if *col != *group_col {
-                                    return Err(Error::Internal(format!("{} must appear in the GROUP BY clause or aggregate function", col)));
-                                }
-                            }
+// 聚集输出的列名
+// min(a)            -> min
+// min(a) as min_val -> min_val
+// a + 1             -> (a + 1)
+pub fn aggregate_output_name(expr: &Expression, alias: &Option<String>) -> String {
+    match (alias, expr) {
+        (Some(alias), _) => alias.clone(),
+        (None, Expression::Function(name, _, _)) => name.clone(),
+        (None, expr) => expr.to_string(),
+    }
+}
 
-                            if new_cols.len() < self.exprs.len() {
-                                new_cols.push(if let Some(a) = alias {
-                                    a.clone()
-                                } else {
-                                    col.clone()
-                                });
-                            }
-                            new_row.push(col_val.unwrap().clone());
-                        }
-                        _ => return Err(Error::Internal("unexpected expression".into())),
-                    }
-                }
-                Ok(new_row)
-            };
+impl<T: Transaction> Executor<T> for Aggregate<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        if let ExecuteResult::Rows { columns, rows } = self.source.execute(txn)? {
+            // 要计算的所有聚集函数，相同的只计算一次
+            let mut aggs = Vec::new();
+            for (expr, _) in self.exprs.iter() {
+                check_grouped(expr, &self.group_by)?;
+                collect_aggregates(expr, &mut aggs)?;
+            }
 
-            // 判断有没有 Group By
             // select c2, min(c1), max(c3) from t group by c2;
             // c1 c2 c3
             // 1 aa 4.6
@@ -99,38 +70,69 @@ impl<T: Transaction> Executor<T> for Aggregate<T> {
             //
             // 3 cc 3.4
             // 4 cc 6.1
-            if let Some(ast::Expression::Field(group_col)) = &self.group_by {
-                // 对数据进行分组，然后计算每组的统计
-                let pos = match columns.iter().position(|c| *c == *group_col) {
-                    Some(pos) => pos,
-                    None => {
-                        return Err(Error::Internal(format!(
-                            "group by column {} not in table",
-                            group_col
-                        )))
-                    }
-                };
-
-                // 针对 Group By 的列进行分组
-                let mut agg_map = HashMap::new();
-                for row in rows.iter() {
-                    let key = &row[pos];
-                    let value = agg_map.entry(key).or_insert(Vec::new());
-                    value.push(row.clone());
+            // 按照分组表达式的值（多列时是一个元组）分组，每一组记下每个聚集函数的参数值
+            // 分组按照第一次出现的顺序输出；没有 GROUP BY 时所有行是一组，没有数据时也输出一行
+            let mut groups: Vec<(Vec<Value>, Vec<Vec<Value>>)> = Vec::new();
+            let mut index: HashMap<Vec<Value>, usize> = HashMap::new();
+            if self.group_by.is_empty() {
+                groups.push((Vec::new(), vec![Vec::new(); aggs.len()]));
+                index.insert(Vec::new(), 0);
+            }
+            for row in rows {
+                let row = row?;
+                let key = self
+                    .group_by
+                    .iter()
+                    .map(|expr| evaluate_expr(expr, &columns, &row, &columns, &row))
+                    .collect::<Result<Vec<_>>>()?;
+                let i = *index.entry(key.clone()).or_insert_with(|| {
+                    groups.push((key, vec![Vec::new(); aggs.len()]));
+                    groups.len() - 1
+                });
+                for (agg, values) in aggs.iter().zip(groups[i].1.iter_mut()) {
+                    values.push(match agg {
+                        // COUNT(*) 统计所有行，任意一个非 NULL 的值都可以
+                        Expression::Function(_, None, _) => Value::Boolean(true),
+                        Expression::Function(_, Some(arg), _) => {
+                            evaluate_expr(arg, &columns, &row, &columns, &row)?
+                        }
+                        _ => return Err(Error::Internal("unexpected expression".into())),
+                    });
                 }
+            }
 
-                for (key, row) in agg_map {
-                    let row = calc(Some(key), &row)?;
-                    new_rows.push(row);
+            let new_cols = self
+                .exprs
+                .iter()
+                .map(|(expr, alias)| aggregate_output_name(expr, alias))
+                .collect::<Vec<_>>();
+            let mut new_rows = Vec::new();
+            for (key, values) in groups {
+                let results = aggs
+                    .iter()
+                    .zip(values)
+                    .map(|(agg, values)| calc_aggregate(agg, values))
+                    .collect::<Result<Vec<_>>>()?;
+                // 把分组表达式换成这一组的值，聚集函数换成计算的结果，剩下的就是常量表达式了
+                let mut new_row = Vec::new();
+                for (expr, _) in self.exprs.iter() {
+                    let mut expr = expr.clone();
+                    expr.transform(&mut |e| {
+                        if let Some(pos) = self.group_by.iter().position(|g| g == e) {
+                            *e = key[pos].clone().into();
+                        } else if let Some(pos) = aggs.iter().position(|a| a == e) {
+                            *e = results[pos].clone().into();
+                        }
+                        Ok(())
+                    })?;
+                    new_row.push(evaluate_const_expr(&expr)?);
                 }
-            } else {
-                let row = calc(None, &rows)?;
-                new_rows.push(row);
+                new_rows.push(new_row);
             }
 
-            return Ok(ResultSet::Scan {
+            return Ok(ExecuteResult::Rows {
                 columns: new_cols,
-                rows: new_rows,
+                rows: Box::new(new_rows.into_iter().map(Ok)),
             });
         }
 
@@ -138,9 +140,66 @@ impl<T: Transaction> Executor<T> for Aggregate<T> {
     }
 }
 
+// 输出的表达式中，聚集函数之外的列必须出现在 GROUP BY 中
+fn check_grouped(expr: &Expression, group_by: &[Expression]) -> Result<()> {
+    let mut expr = expr.clone();
+    expr.transform(&mut |e| {
+        match e {
+            e if group_by.contains(e) => *e = Value::Null.into(),
+            Expression::Function(_, _, _) => *e = Value::Null.into(),
+            Expression::Field(col) => {
+                return Err(Error::Internal(format!(
+                    "{} must appear in the GROUP BY clause or aggregate function",
+                    col
+                )))
+            }
+            _ => {}
+        }
+        Ok(())
+    })
+}
+
+// 找到表达式中的聚集函数，聚集函数的参数中不能再有聚集函数
+fn collect_aggregates(expr: &Expression, aggs: &mut Vec<Expression>) -> Result<()> {
+    match expr {
+        Expression::Function(_, arg, _) => {
+            if arg.as_ref().is_some_and(|arg| arg.contains_aggregate()) {
+                return Err(Error::Internal(
+                    "aggregate function calls cannot be nested".into(),
+                ));
+            }
+            if !aggs.contains(expr) {
+                aggs.push(expr.clone());
+            }
+        }
+        Expression::Operation(operation) => {
+            for operand in operation.operands() {
+                collect_aggregates(operand, aggs)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// 计算一个分组中的聚集函数，DISTINCT 时先去掉重复的值
+fn calc_aggregate(agg: &Expression, mut values: Vec<Value>) -> Result<Value> {
+    match agg {
+        Expression::Function(func_name, _, distinct) => {
+            if *distinct {
+                let mut seen = HashSet::new();
+                values.retain(|v| seen.insert(v.clone()));
+            }
+            <dyn Calculator>::build(func_name)?.calc(&values)
+        }
+        _ => Err(Error::Internal("unexpected expression".into())),
+    }
+}
+
 // 通用 Agg 计算定义
+// values 是一个分组中所有行的参数值
 pub trait Calculator {
-    fn calc(&self, col_name: &String, cols: &Vec<String>, rows: &Vec<Vec<Value>>) -> Result<Value>;
+    fn calc(&self, values: &[Value]) -> Result<Value>;
 }
 
 impl dyn Calculator {
@@ -165,19 +224,15 @@ impl Count {
 }
 
 impl Calculator for Count {
-    fn calc(&self, col_name: &String, cols: &Vec<String>, rows: &Vec<Vec<Value>>) -> Result<Value> {
-        let pos = match cols.iter().position(|c| *c == *col_name) {//返回这一列的位置~
-            Some(pos) => pos,
-            None => return Err(Error::Internal(format!("column {} not in table", col_name))),
-        };
-
+    fn calc(&self, values: &[Value]) -> Result<Value> {
         // a b      c
         // 1 X     3.1
         // 2 NULL  6.4
         // 3 Z     1.5
         let mut count = 0;
-        for row in rows.iter() {//然后遍历所有行，判断每一行的这一列是否为null即可，不是计数就递增！
-            if row[pos] != Value::Null {
+        for value in values {
+            //然后遍历所有行，判断每一行的这个值是否为null即可，不是计数就递增！
+            if *value != Value::Null {
                 count += 1;
             }
         }
@@ -194,23 +249,16 @@ impl Min {
 }
 
 impl Calculator for Min {
-    fn calc(&self, col_name: &String, cols: &Vec<String>, rows: &Vec<Vec<Value>>) -> Result<Value> {
-        let pos = match cols.iter().position(|c| *c == *col_name) {
-            Some(pos) => pos,
-            None => return Err(Error::Internal(format!("column {} not in table", col_name))),
-        };
-
+    fn calc(&self, values: &[Value]) -> Result<Value> {
         // a b      c
         // 1 X     NULL
         // 2 NULL  6.4
         // 3 Z     1.5
         let mut min_val = Value::Null;
-        let mut values = Vec::new();
-        for row in rows.iter() {
-            if row[pos] != Value::Null {
-                values.push(&row[pos]);
-            }
-        }
+        let mut values = values
+            .iter()
+            .filter(|v| **v != Value::Null)
+            .collect::<Vec<_>>();
         if !values.is_empty() {
             values.sort_by(|a, b| a.partial_cmp(b).unwrap());
             min_val = values[0].clone();
@@ -228,23 +276,16 @@ impl Max {
 }
 
 impl Calculator for Max {
-    fn calc(&self, col_name: &String, cols: &Vec<String>, rows: &Vec<Vec<Value>>) -> Result<Value> {
-        let pos = match cols.iter().position(|c| *c == *col_name) {
-            Some(pos) => pos,
-            None => return Err(Error::Internal(format!("column {} not in table", col_name))),
-        };
-
+    fn calc(&self, values: &[Value]) -> Result<Value> {
         // a b      c
         // 1 X     NULL
         // 2 NULL  6.4
         // 3 Z     1.5
         let mut max_val = Value::Null;
-        let mut values = Vec::new();
-        for row in rows.iter() {
-            if row[pos] != Value::Null {
-                values.push(&row[pos]);
-            }
-        }
+        let mut values = values
+            .iter()
+            .filter(|v| **v != Value::Null)
+            .collect::<Vec<_>>();
         if !values.is_empty() {
             values.sort_by(|a, b| a.partial_cmp(b).unwrap());
             max_val = values[values.len() - 1].clone();
@@ -260,19 +301,14 @@ impl Sum {
     }
 }
 impl Calculator for Sum {
-    fn calc(&self, col_name: &String, cols: &Vec<String>, rows: &Vec<Vec<Value>>) -> Result<Value> {
-        let pos = match cols.iter().position(|c| *c == *col_name) {
-            Some(pos) => pos,
-            None => return Err(Error::Internal(format!("column {} not in table", col_name))),
-        };
-
+    fn calc(&self, values: &[Value]) -> Result<Value> {
         // a b      c
         // 1 X     NULL
         // 2 NULL  6.4
         // 3 Z     1.5
         let mut sum = None;
-        for row in rows.iter() {
-            match row[pos] {
+        for value in values {
+            match *value {
                 Value::Null => {}
                 Value::Integer(v) => {
                     if sum == None {
@@ -286,7 +322,7 @@ impl Calculator for Sum {
                     }
                     sum = Some(sum.unwrap() + v);
                 }
-                _ => return Err(Error::Internal(format!("can not calc column {}", col_name))),
+                ref v => return Err(Error::Internal(format!("can not calc sum of {}", v))),
             }
         }
 
@@ -306,9 +342,9 @@ impl Avg {
 }
 
 impl Calculator for Avg {
-    fn calc(&self, col_name: &String, cols: &Vec<String>, rows: &Vec<Vec<Value>>) -> Result<Value> {
-        let sum = Sum::new().calc(col_name, cols, rows)?;
-        let count = Count::new().calc(col_name, cols, rows)?;
+    fn calc(&self, values: &[Value]) -> Result<Value> {
+        let sum = Sum::new().calc(values)?;
+        let count = Count::new().calc(values)?;
         Ok(match (sum, count) {
             (Value::Float(s), Value::Integer(c)) => Value::Float(s / c as f64),
             _ => Value::Null,
@@ -9,7 +9,7 @@ use crate::{
     },
 };
 
-use super::{Executor, ResultSet};
+use super::{ExecuteResult, Executor};
 // join语句的执行语句显然的，使用 a left join b on a.id=b.id;类似语句，这里就是这样的
 pub struct NestedLoopJoin<T: Transaction> {
     left: Box<dyn Executor<T>>,
@@ -35,29 +35,34 @@ impl<T: Transaction> NestedLoopJoin<T> {
 }
 
 impl<T: Transaction> Executor<T> for NestedLoopJoin<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         // 先执行左边的
-        // 获取左表的执行总的结果
-        if let ResultSet::Scan {
+        // 获取左表的行迭代器，左表是一行一行拉取的
+        if let ExecuteResult::Rows {
             columns: lcols,
             rows: lrows,
         } = self.left.execute(txn)?
-
         {
-            let mut new_rows = Vec::new();
             let mut new_cols = lcols.clone();//将左表的列赋值给新列
             // 再执行右边的
-            // 获取右表的执行的总的结果
-            if let ResultSet::Scan {
+            // 右表需要被左表的每一行重复遍历，所以右表的数据需要全部读出来
+            if let ExecuteResult::Rows {
                 columns: rcols,
                 rows: rrows,
             } = self.right.execute(txn)?
-            
-            // 这个作用域内全部是匹配工作，做得是左右交互的匹配工作
             {
                 new_cols.extend(rcols.clone());//将右表的列合并在新列上
-
-                for lrow in &lrows {
+                let rrows = rrows.collect::<Result<Vec<_>>>()?;
+                let predicate = self.predicate;
+                let outer = self.outer;
+
+                // 这个闭包里面全部是匹配工作，做得是左表的一行与右表所有行的匹配工作
+                let rows = lrows.flat_map(move |lrow| {
+                    let lrow = match lrow {
+                        Ok(lrow) => lrow,
+                        Err(err) => return vec![Err(err)],
+                    };
+                    let mut new_rows = Vec::new();
                     let mut matched = false;
 
                     for rrow in &rrows {
@@ -68,41 +73,49 @@ impl<T: Transaction> Executor<T> for NestedLoopJoin<T> {
                         // Value::Null：跳过当前行。
                         // Value::Boolean(false)：跳过当前行。
                         // Value::Boolean(true)：如果条件为真，将右表的行 rrow 与左表的行 lrow 合并，并添加到 new_rows 中，同时设置 matched 为 true。
-                        if let Some(expr) = &self.predicate {//然后评估表达式！
-                            match evaluate_expr(expr, &lcols, lrow, &rcols, rrow)? {
-                                Value::Null => {}
-                                Value::Boolean(false) => {}
-                                Value::Boolean(true) => {
+                        if let Some(expr) = &predicate {//然后评估表达式！
+                            match evaluate_expr(expr, &lcols, &lrow, &rcols, rrow) {
+                                Ok(Value::Null) => {}
+                                Ok(Value::Boolean(false)) => {}
+                                Ok(Value::Boolean(true)) => {
                                     row.extend(rrow.clone());
-                                    new_rows.push(row);
+                                    new_rows.push(Ok(row));
                                     matched = true;
                                 }
-                                _ => return Err(Error::Internal("Unexpected expression".into())),
+                                Ok(_) => {
+                                    new_rows.push(Err(Error::Internal(
+                                        "Unexpected expression".into(),
+                                    )));
+                                    return new_rows;
+                                }
+                                Err(err) => {
+                                    new_rows.push(Err(err));
+                                    return new_rows;
+                                }
                             }
                         } else {
                             // 如果没有条件（None），表示这是一个 Cross Join（笛卡尔积），即两个表的所有行都会组合在一起。
                             // 就是默认的两个for循环
                             row.extend(rrow.clone());
-                            new_rows.push(row);
+                            new_rows.push(Ok(row));
                         }
                     }
                     // 外连接（Outer Join）处理：(代表着一边没有数据~~)
-                    // 如果是外连接（self.outer 为 true）且当前行没有匹配（!matched），
+                    // 如果是外连接（outer 为 true）且当前行没有匹配（!matched），
                     // 则将左表的行 lrow 添加到 new_rows 中，并在右表的列位置填充 NULL 值，表示右表没有数据。
-                    if self.outer && !matched {
-                        let mut row = lrow.clone();
-                        for _ in 0..rrows[0].len() {
-                            row.push(Value::Null);
-                        }
-                        new_rows.push(row);
+                    if outer && !matched {
+                        let mut row = lrow;
+                        row.extend(std::iter::repeat_n(Value::Null, rcols.len()));
+                        new_rows.push(Ok(row));
                     }
-                }
+                    new_rows
+                });
+                // 返回结果~
+                return Ok(ExecuteResult::Rows {
+                    columns: new_cols,
+                    rows: Box::new(rows),
+                });
             }
-            // 返回结果~
-            return Ok(ResultSet::Scan {
-                columns: new_cols,
-                rows: new_rows,
-            });
         }
 
         Err(Error::Internal("Unexpected result set".into()))
@@ -134,24 +147,23 @@ impl<T: Transaction> HashJoin<T> {
 }
 
 impl<T: Transaction> Executor<T> for HashJoin<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
-        // 先执行左边的，这里是获取左边的结果集合。self.left.execute(txn) 执行左侧表（self.left 是 NestedLoopJoin 中的左表），并获取结果集。
-        if let ResultSet::Scan {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        // 先执行左边的，这里是获取左边的行迭代器。self.left.execute(txn) 执行左侧表（self.left 是 HashJoin 中的左表）。
+        if let ExecuteResult::Rows {
             columns: lcols,
             rows: lrows,
         } = self.left.execute(txn)?
-        //这个语句到这里就结束了！至此顺利获得了左边的结果集合！
-        //再执行右边的，这里是获取右边的结果集合。执行右侧表（self.right 是 NestedLoopJoin 中的右表），并获取结果集。
+        //这个语句到这里就结束了！至此顺利获得了左边的行迭代器！
+        //再执行右边的，这里是获取右边的结果集合。执行右侧表（self.right 是 HashJoin 中的右表），右表用来构建哈希表。
         {
             //这里 这个大括号完成总的匹配任务！
-            let mut new_rows = Vec::new();
             let mut new_cols = lcols.clone(); //这里是新列，复制的是左表的列，下面还有合并右表的列！
 
-            if let ResultSet::Scan {
+            if let ExecuteResult::Rows {
                 columns: rcols,
                 rows: rrows,
             } = self.right.execute(txn)?
-            //至此顺利获取到了右边的结果集合！
+            //至此顺利获取到了右边的行迭代器！
             {
                 //将右表的列名（rcols）添加到左表的列名（lcols）中，new_cols 就是合并后的列名。
                 new_cols.extend(rcols.clone());
@@ -160,60 +172,64 @@ impl<T: Transaction> Executor<T> for HashJoin<T> {
                     Some(filter) => filter,
                     None => return Err(Error::Internal("failed to parse join predicate".into())),
                 };
-                // 获取 join 列在表中列的位置
-                let lpos = match lcols.iter().position(|c| *c == lfield) {
-                    Some(pos) => pos,
-                    None => {
-                        return Err(Error::Internal(format!(
-                            "column {} not exist in table",
-                            lfield
-                        )))
-                    }
-                };
-                let rpos = match rcols.iter().position(|c| *c == rfield) {
-                    Some(pos) => pos,
-                    None => {
-                        return Err(Error::Internal(format!(
-                            "column {} not exist in table",
-                            rfield
-                        )))
-                    }
+                // 获取 join 列在表中列的位置，条件中的两列不一定是按照 左表 = 右表 的顺序写的
+                let (lpos, rpos) = match (
+                    lcols.iter().position(|c| *c == lfield),
+                    rcols.iter().position(|c| *c == rfield),
+                ) {
+                    (Some(lpos), Some(rpos)) => (lpos, rpos),
+                    _ => match (
+                        lcols.iter().position(|c| *c == rfield),
+                        rcols.iter().position(|c| *c == lfield),
+                    ) {
+                        (Some(lpos), Some(rpos)) => (lpos, rpos),
+                        _ => {
+                            return Err(Error::Internal(format!(
+                                "column {} or {} not exist in table",
+                                lfield, rfield
+                            )))
+                        }
+                    },
                 };
 
                 // 构建哈希表   key为列的值，value为行数据
                 let mut table = HashMap::new();
-                for row in &rrows {
+                for row in rrows {
+                    let row = row?;
                     let rows = table.entry(row[rpos].clone()).or_insert(Vec::new());
-                    rows.push(row.clone());
+                    rows.push(row);
                 }
 
                 // 扫描左边获取记录
-                // 嵌套循环遍历左右表的行
-                // 外层循环遍历左表的每一行（lrow），内层循环遍历右表的每一行（rrow），通过嵌套循环实现连接操作。
-                for lrow in lrows {
+                // 左表每拉取一行，就去哈希表中找到与之匹配的右表的行
+                let outer = self.outer;
+                let rwidth = rcols.len();
+                let rows = lrows.flat_map(move |lrow| {
+                    let lrow = match lrow {
+                        Ok(lrow) => lrow,
+                        Err(err) => return vec![Err(err)],
+                    };
                     match table.get(&lrow[lpos]) {
-                        Some(rows) => {
-                            for r in rows {
+                        Some(rows) => rows
+                            .iter()
+                            .map(|r| {
                                 let mut row = lrow.clone();
                                 row.extend(r.clone());
-                                new_rows.push(row);
-                            }
-                        }
-                        None => {
-                            if self.outer {
-                                let mut row = lrow.clone();
-                                for _ in 0..rrows[0].len() {
-                                    row.push(Value::Null);
-                                }
-                                new_rows.push(row);
-                            }
+                                Ok(row)
+                            })
+                            .collect(),
+                        None if outer => {
+                            let mut row = lrow;
+                            row.extend(std::iter::repeat_n(Value::Null, rwidth));
+                            vec![Ok(row)]
                         }
+                        None => vec![],
                     }
-                }
+                });
 
-                return Ok(ResultSet::Scan {
+                return Ok(ExecuteResult::Rows {
                     columns: new_cols,
-                    rows: new_rows,
+                    rows: Box::new(rows),
                 });
             }
         }
@@ -221,21 +237,13 @@ impl<T: Transaction> Executor<T> for HashJoin<T> {
     }
 }
 
-fn parse_join_filter(predicate: Option<Expression>) -> Option<(String, String)> {
+// 解析 HashJoin 的条件，只支持 列 = 列 的形式
+pub fn parse_join_filter(predicate: Option<Expression>) -> Option<(String, String)> {
     match predicate {
-        Some(expr) => match expr {
-            Expression::Field(f) => Some((f, "".into())),
-            Expression::Operation(operation) => match operation {
-                ast::Operation::Equal(l, r) => {
-                    let lv = parse_join_filter(Some(*l));
-                    let rv = parse_join_filter(Some(*r));
-
-                    Some((lv.unwrap().0, rv.unwrap().0))
-                }
-                _ => None,
-            },
+        Some(Expression::Operation(ast::Operation::Equal(l, r))) => match (*l, *r) {
+            (Expression::Field(l), Expression::Field(r)) => Some((l, r)),
             _ => None,
         },
-        None => None,
+        _ => None,
     }
 }
@@ -1,20 +1,52 @@
-use super::{engine::Transaction, plan::Node, types::Row};
+use super::{
+    engine::Transaction,
+    plan::Node,
+    types::{Row, Rows},
+};
 use crate::error::Result;
 use agg::Aggregate;
+pub use agg::aggregate_output_name;
 use join::{HashJoin, NestedLoopJoin};
-use mutation::{Delete, Insert, Update};
-use query::{Filter, IndexScan, Limit, Offset, Order, PrimaryKeyScan, Projection, Scan};
-use schema::{CreateTable, DropTable};
+pub use join::parse_join_filter;
+use mutation::{Delete, Insert, Lock, Update};
+use query::{
+    Filter, IndexRangeScan, IndexScan, Limit, Offset, Order, PrimaryKeyScan, Projection, Scan,
+    SubqueryScan, TopN,
+};
+use schema::{AlterTable, CreateIndex, CreateTable, DropIndex, DropTable};
 
 mod agg;
 mod join;
 mod mutation;
 mod query;
 mod schema;
+mod sort;
+mod subquery;
 
 // 执行器定义
 pub trait Executor<T: Transaction> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet>;
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult>;
+}
+
+// 执行器之间传递的执行结果
+// 查询类的执行器返回的是行迭代器（火山模型），上层执行器按需一行一行地拉取，
+// 例如 Limit 只会拉取前 n 行，下层的 Scan 也就只会读取这么多数据
+pub enum ExecuteResult {
+    Rows { columns: Vec<String>, rows: Rows },
+    ResultSet(ResultSet),
+}
+
+impl ExecuteResult {
+    // 执行结束后，把行迭代器物化成返回给客户端的结果集
+    pub fn into_result_set(self) -> Result<ResultSet> {
+        Ok(match self {
+            ExecuteResult::Rows { columns, rows } => ResultSet::Scan {
+                columns,
+                rows: rows.collect::<Result<Vec<_>>>()?,
+            },
+            ExecuteResult::ResultSet(rs) => rs,
+        })
+    }
 }
 
 // 在这里才是真真正正的统一执行，但是下面还有分支！，这里只是统一执行分类了
@@ -23,12 +55,19 @@ impl<T: Transaction + 'static> dyn Executor<T> {
         match node {
             Node::CreateTable { schema } => CreateTable::new(schema),
             Node::DropTable { name } => DropTable::new(name),
+            Node::CreateIndex { table_name, index } => CreateIndex::new(table_name, index),
+            Node::DropIndex { name } => DropIndex::new(name),
+            Node::AlterTable { table_name, change } => AlterTable::new(table_name, change),
             Node::Insert {
                 table_name,
                 columns,
                 values,
             } => Insert::new(table_name, columns, values),
-            Node::Scan { table_name, filter } => Scan::new(table_name, filter),
+            Node::Scan {
+                table_name,
+                alias,
+                filter,
+            } => Scan::new(table_name, alias, filter),
             Node::Update {
                 table_name,
                 source,
@@ -36,9 +75,24 @@ impl<T: Transaction + 'static> dyn Executor<T> {
             } => Update::new(table_name, Self::build(*source), columns),
             Node::Delete { table_name, source } => Delete::new(table_name, Self::build(*source)),
             Node::Order { source, order_by } => Order::new(Self::build(*source), order_by),
+            Node::TopN {
+                source,
+                order_by,
+                limit,
+            } => TopN::new(Self::build(*source), order_by, limit),
             Node::Limit { source, limit } => Limit::new(Self::build(*source), limit),
             Node::Offset { source, offset } => Offset::new(Self::build(*source), offset),
+            Node::Lock {
+                source,
+                table_name,
+                mode,
+            } => Lock::new(table_name, Self::build(*source), mode),
             Node::Projection { source, exprs } => Projection::new(Self::build(*source), exprs),
+            Node::SubqueryScan {
+                source,
+                alias,
+                qualified,
+            } => SubqueryScan::new(Self::build(*source), alias, qualified),
             // 执行连接语句
             Node::NestedLoopJoin {
                 left,
@@ -54,10 +108,21 @@ impl<T: Transaction + 'static> dyn Executor<T> {
             Node::Filter { source, predicate } => Filter::new(Self::build(*source), predicate),
             Node::IndexScan {
                 table_name,
-                field,
-                value,
-            } => IndexScan::new(table_name, field, value),
-            Node::PrimaryKeyScan { table_name, value } => PrimaryKeyScan::new(table_name, value),
+                alias,
+                index,
+                values,
+            } => IndexScan::new(table_name, alias, index, values),
+            Node::IndexRangeScan {
+                table_name,
+                alias,
+                index,
+                range,
+            } => IndexRangeScan::new(table_name, alias, index, range),
+            Node::PrimaryKeyScan {
+                table_name,
+                alias,
+                values,
+            } => PrimaryKeyScan::new(table_name, alias, values),
             Node::HashJoin {
                 left,
                 right,
@@ -77,6 +142,17 @@ pub enum ResultSet {
     DropTable {
         table_name: String,
     },
+    CreateIndex {
+        table_name: String,
+        index_name: String,
+    },
+    DropIndex {
+        table_name: String,
+        index_name: String,
+    },
+    AlterTable {
+        table_name: String,
+    },
     Insert {
         count: usize,
     },
@@ -99,9 +175,21 @@ pub enum ResultSet {
     Rollback {
         version: u64,
     },
+    Savepoint {
+        name: String,
+    },
+    RollbackToSavepoint {
+        name: String,
+    },
+    ReleaseSavepoint {
+        name: String,
+    },
     Explain {
         plan: String,
     },
+    Vacuum {
+        count: usize,
+    },
 }
 
 // 这里就是对应实现的执行结果返回的呈现方式！
@@ -113,6 +201,17 @@ impl ResultSet {
                 format!("CREATE TABLE {} SUCCESSED!", table_name)
             }
             ResultSet::DropTable { table_name } => format!("DROP TABLE {} SUCCESSED!", table_name),
+            ResultSet::CreateIndex {
+                table_name,
+                index_name,
+            } => format!("CREATE INDEX {} ON {} SUCCESSED!", index_name, table_name),
+            ResultSet::DropIndex {
+                table_name,
+                index_name,
+            } => format!("DROP INDEX {} ON {} SUCCESSED!", index_name, table_name),
+            ResultSet::AlterTable { table_name } => {
+                format!("ALTER TABLE {} SUCCESSED!", table_name)
+            }
             ResultSet::Insert { count } => format!("INSERT {} rows", count),
             // 这里就是我们平时的查询表的输出内容~方方正正的框子
             // +-------------+---------------+---------------+
@@ -199,7 +298,11 @@ impl ResultSet {
             ResultSet::Begin { version } => format!("TRANSACTION {} BEGIN", version),
             ResultSet::Commit { version } => format!("TRANSACTION {} COMMIT", version),
             ResultSet::Rollback { version } => format!("TRANSACTION {} ROLLBACK", version),
+            ResultSet::Savepoint { name } => format!("SAVEPOINT {}", name),
+            ResultSet::RollbackToSavepoint { name } => format!("ROLLBACK TO SAVEPOINT {}", name),
+            ResultSet::ReleaseSavepoint { name } => format!("RELEASE SAVEPOINT {}", name),
             ResultSet::Explain { plan } => plan.to_string(),
+            ResultSet::Vacuum { count } => format!("VACUUM {} versions", count),
         }
     }
 }
@@ -5,13 +5,14 @@ use crate::{
     error::{Error, Result},
     sql::{
         engine::Transaction,
-        parser::ast::Expression,
+        parser::ast::{evaluate_const_expr, evaluate_expr, Expression},
         schema::Table,
-        types::{Row, Value},
+        types::Row,
     },
+    storage::lock::LockMode,
 };
 
-use super::{Executor, ResultSet};
+use super::{subquery::resolve_subqueries, ExecuteResult, Executor, ResultSet};
 
 pub struct Insert {
     table_name: String,
@@ -87,17 +88,17 @@ fn make_row(table: &Table, columns: &Vec<String>, values: &Row) -> Result<Row> {
 }
 
 impl<T: Transaction> Executor<T> for Insert {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         __function!("这里就是真正的执行插入语句了~执行器执行插入操作");
         let mut count = 0;
         // 先取出表信息
         let table = txn.must_get_table(self.table_name.clone())?;
         for exprs in self.values {
-            // 将表达式转换成 value
+            // 将表达式计算成 value
             let row = exprs
-                .into_iter()
-                .map(|e| Value::from_expression(e))
-                .collect::<Vec<_>>();
+                .iter()
+                .map(evaluate_const_expr)
+                .collect::<Result<Vec<_>>>()?;
             // 如果没有指定插入的列
             let insert_row = if self.columns.is_empty() {
                 pad_row(&table, &row)?
@@ -111,7 +112,7 @@ impl<T: Transaction> Executor<T> for Insert {
             count += 1;
         }
 
-        Ok(ResultSet::Insert { count })
+        Ok(ExecuteResult::ResultSet(ResultSet::Insert { count }))
     }
 }
 
@@ -136,22 +137,32 @@ impl<T: Transaction> Update<T> {
     }
 }
 
-impl<T: Transaction> Executor<T> for Update<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+impl<T: Transaction + 'static> Executor<T> for Update<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         __function!("这里就是真正的执行更新语句了~执行器执行更新语句");
         let mut updated = 0;
         // 执行扫描操作，获取到扫描的结果
         match self.source.execute(txn)? {
-            ResultSet::Scan { columns, rows } => {
+            ExecuteResult::Rows { columns, rows } => {
+                // 先把需要更新的行全部读出来再更新，边扫描边修改的话，可能会再次扫描到刚刚更新过的数据
+                let rows = rows.collect::<Result<Vec<_>>>()?;
                 let table = txn.must_get_table(self.table_name)?;
+                // SET 中的不相关子查询只需要计算一次
+                let mut exprs = BTreeMap::new();
+                for (col, expr) in self.columns.iter() {
+                    exprs.insert(col, resolve_subqueries(txn, expr, &columns, None)?);
+                }
                 // 遍历所有需要更新的行
                 for row in rows {
                     let mut new_row = row.clone();
                     let pk = table.get_primary_key(&row)?;
 
                     for (i, col) in columns.iter().enumerate() {
-                        if let Some(expr) = self.columns.get(col) {
-                            new_row[i] = Value::from_expression(expr.clone());
+                        if let Some(expr) = exprs.get(col) {
+                            // 相关子查询需要对每一行重新计算
+                            let expr = resolve_subqueries(txn, expr, &columns, Some(&row))?;
+                            // 基于更新前的行计算新值，例如 set n = n + 1
+                            new_row[i] = evaluate_expr(&expr, &columns, &row, &columns, &row)?;
                         }
                     }
                     // 执行更新操作
@@ -163,7 +174,7 @@ impl<T: Transaction> Executor<T> for Update<T> {
             }
             _ => return Err(Error::Internal("Unexpected result set".into())),
         }
-        Ok(ResultSet::Update { count: updated })
+        Ok(ExecuteResult::ResultSet(ResultSet::Update { count: updated }))
     }
 }
 
@@ -181,10 +192,12 @@ impl<T: Transaction> Delete<T> {
 
 impl<T: Transaction> Executor<T> for Delete<T> {
 
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         __function!("这里就是真正的执行删除语句了~执行器执行删除语句");
         match self.source.execute(txn)? {
-            ResultSet::Scan { columns: _, rows } => {
+            ExecuteResult::Rows { columns: _, rows } => {
+                // 同样先读出所有需要删除的行，再执行删除
+                let rows = rows.collect::<Result<Vec<_>>>()?;
                 let mut count = 0;
                 let table = txn.must_get_table(self.table_name)?;
                 for row in rows {
@@ -194,9 +207,46 @@ impl<T: Transaction> Executor<T> for Delete<T> {
                     count += 1;
                 }
 
-                Ok(ResultSet::Delete { count })
+                Ok(ExecuteResult::ResultSet(ResultSet::Delete { count }))
             }
             _ => return Err(Error::Internal("Unexpected result set".into())),
         }
     }
 }
+
+// 行锁执行器：读出所有的行，逐行加锁之后再原样输出
+pub struct Lock<T: Transaction> {
+    table_name: String,
+    source: Box<dyn Executor<T>>,
+    mode: LockMode,
+}
+
+impl<T: Transaction> Lock<T> {
+    pub fn new(table_name: String, source: Box<dyn Executor<T>>, mode: LockMode) -> Box<Self> {
+        Box::new(Self {
+            table_name,
+            source,
+            mode,
+        })
+    }
+}
+
+impl<T: Transaction> Executor<T> for Lock<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        match self.source.execute(txn)? {
+            ExecuteResult::Rows { columns, rows } => {
+                // 和删除一样先读出所有的行，再加锁
+                let rows = rows.collect::<Result<Vec<_>>>()?;
+                let table = txn.must_get_table(self.table_name)?;
+                for row in rows.iter() {
+                    txn.lock_row(&table, &table.get_primary_key(row)?, self.mode)?;
+                }
+                Ok(ExecuteResult::Rows {
+                    columns,
+                    rows: Box::new(rows.into_iter().map(Ok)),
+                })
+            }
+            _ => Err(Error::Internal("Unexpected result set".into())),
+        }
+    }
+}
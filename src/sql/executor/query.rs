@@ -1,125 +1,243 @@
-use std::{cmp::Ordering, collections::HashMap};
+use std::{cmp::Ordering, ops::Bound};
 
 use crate::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, pr, py};
 use crate::{
     error::{Error, Result},
     sql::{
         engine::Transaction,
-        parser::ast::{evaluate_expr, Expression, OrderDirection},
-        types::Value,
+        parser::ast::{evaluate_expr, Expression, NullsOrder, OrderDirection},
+        plan::column_name,
+        schema::{Index, Table},
+        types::{Row, Rows, Value},
     },
 };
 
-use super::{Executor, ResultSet};
+use super::{
+    sort::{sort, sort_keys, sort_order, top_n, SORT_MEMORY_BUDGET},
+    subquery::resolve_subqueries,
+    ExecuteResult, Executor,
+};
 
 pub struct Scan {
     table_name: String,
+    alias: Option<String>,
     filter: Option<Expression>,
 }
 
 impl Scan {
-    pub fn new(table_name: String, filter: Option<Expression>) -> Box<Self> {
-        Box::new(Self { table_name, filter })
+    pub fn new(table_name: String, alias: Option<String>, filter: Option<Expression>) -> Box<Self> {
+        Box::new(Self {
+            table_name,
+            alias,
+            filter,
+        })
     }
 }
 
+// 扫描输出的列名，有别名时为 别名.列名
+fn column_labels(table: Table, alias: &Option<String>) -> Vec<String> {
+    table
+        .columns
+        .into_iter()
+        .map(|c| match alias {
+            Some(alias) => format!("{}.{}", alias, c.name),
+            None => c.name,
+        })
+        .collect()
+}
+
 impl<T: Transaction> Executor<T> for Scan {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         let table = txn.must_get_table(self.table_name.clone())?;
+        // 这里拿到的只是行迭代器，真正的读取发生在上层拉取数据的时候
         let rows = txn.scan_table(self.table_name.clone(), self.filter)?;
-        Ok(ResultSet::Scan {
-            columns: table.columns.into_iter().map(|c| c.name.clone()).collect(),
+        Ok(ExecuteResult::Rows {
+            columns: column_labels(table, &self.alias),
             rows,
         })
     }
 }
 // 索引扫描的的结构体！
+// values 是索引各列的等值条件，可以只包含前面的几列
 pub struct IndexScan {
     table_name: String,
-    field: String,
-    value: Value,
+    alias: Option<String>,
+    index: Index,
+    values: Vec<Value>,
 }
 // 索引扫描的构造法！
 impl IndexScan {
-    pub fn new(table_name: String, field: String, value: Value) -> Box<Self> {
+    pub fn new(
+        table_name: String,
+        alias: Option<String>,
+        index: Index,
+        values: Vec<Value>,
+    ) -> Box<Self> {
         Box::new(Self {
             table_name,
-            field,
-            value,
+            alias,
+            index,
+            values,
         })
     }
 }
 
 impl<T: Transaction> Executor<T> for IndexScan {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         let table = txn.must_get_table(self.table_name.clone())?;
         pppy!("事务获取底层的表信息:",table);
 
-        // 加载索引
-        let index = txn.load_index(&self.table_name, &self.field, &self.value)?;
-        pppy!("加载到索引的数据主键为:",index);
-        let mut pks = index.iter().collect::<Vec<_>>();
-        // 对索引进行排序
+        // 等值查询就是上下界都是这些值的范围查询，只给出了前几列的时候就是前缀扫描
+        let range = (
+            Bound::Included(self.values.clone()),
+            Bound::Included(self.values),
+        );
+        let rows = read_index_rows(txn, &self.table_name, &self.index.name, range)?;
+        pppg!("索引扫描~~~~~~");
+        pppy!("根据索引得到的数据：", rows);
+        Ok(ExecuteResult::Rows {
+            columns: column_labels(table, &self.alias),
+            rows: Box::new(rows.into_iter().map(Ok)),
+        })
+    }
+}
+
+// 索引范围扫描，用于 > < BETWEEN 以及 ORDER BY 索引列
+pub struct IndexRangeScan {
+    table_name: String,
+    alias: Option<String>,
+    index: Index,
+    range: (Bound<Vec<Value>>, Bound<Vec<Value>>),
+}
+
+impl IndexRangeScan {
+    pub fn new(
+        table_name: String,
+        alias: Option<String>,
+        index: Index,
+        range: (Bound<Vec<Value>>, Bound<Vec<Value>>),
+    ) -> Box<Self> {
+        Box::new(Self {
+            table_name,
+            alias,
+            index,
+            range,
+        })
+    }
+}
+
+impl<T: Transaction> Executor<T> for IndexRangeScan {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        let table = txn.must_get_table(self.table_name.clone())?;
+        let rows = read_index_rows(txn, &self.table_name, &self.index.name, self.range)?;
+        Ok(ExecuteResult::Rows {
+            columns: column_labels(table, &self.alias),
+            rows: Box::new(rows.into_iter().map(Ok)),
+        })
+    }
+}
+
+// 扫描索引的一段范围，再根据主键读出对应的行
+fn read_index_rows<T: Transaction>(
+    txn: &mut T,
+    table_name: &str,
+    index_name: &str,
+    range: (Bound<Vec<Value>>, Bound<Vec<Value>>),
+) -> Result<Vec<Row>> {
+    // 索引 key 是按照列值有序编码的，扫描出来的主键集合也就是按照列值从小到大排列的
+    let index = txn.scan_index(table_name, index_name, range)?;
+    pppy!("加载到索引的数据主键为:",index);
+    let mut rows = Vec::new();
+    for pks in index {
+        // 同一组列值对应的多个主键，按照主键排序，保证输出稳定
+        let mut pks = pks.into_iter().collect::<Vec<_>>();
         pks.sort_by(|v1, v2| match v1.partial_cmp(v2) {
             Some(ord) => ord,
             None => Ordering::Equal,
         });
-
-        // pppy!("索引对应的数据列：",pks);
-        let mut rows = Vec::new();
-        // 拍完徐就能够通过id去拿到对应的数据！
         for pk in pks {
-            if let Some(row) = txn.read_by_id(&self.table_name, pk)? {
+            if let Some(row) = txn.read_by_id(table_name, &pk)? {
                 rows.push(row); //将获取到的数据行放在结果中
             }
         }
-        pppg!("索引扫描~~~~~~");
-        let res = ResultSet::Scan {
-            columns: table.columns.into_iter().map(|c| c.name.clone()).collect(),
-            rows,
-        };
-
-        pppy!("根据索引得到的数据：", res);
-        // Ok(ResultSet::Scan {
-        //     columns: table.columns.into_iter().map(|c| c.name.clone()).collect(),
-        //     rows,
-        // })
-        Ok(res)
     }
+    Ok(rows)
 }
 
+// 主键扫描，联合主键只给出了前面几列的值时，扫描主键以这几列为前缀的所有行
 pub struct PrimaryKeyScan {
     table_name: String,
-    value: Value,
+    alias: Option<String>,
+    values: Vec<Value>,
 }
 
 impl PrimaryKeyScan {
-    pub fn new(table_name: String, value: Value) -> Box<Self> {
-        Box::new(Self { table_name, value })
+    pub fn new(table_name: String, alias: Option<String>, values: Vec<Value>) -> Box<Self> {
+        Box::new(Self {
+            table_name,
+            alias,
+            values,
+        })
     }
 }
 
 impl<T: Transaction> Executor<T> for PrimaryKeyScan {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         let table = txn.must_get_table(self.table_name.clone())?;
-        let mut rows = Vec::new();
-        let mut id = self.value.clone();
-        if let Value::Float(v) = self.value {
-            if v.fract() == 0.0 {
-                id = Value::Integer(v as i64);
-            }
-        }
-        if let Some(row) = txn.read_by_id(&self.table_name, &id)? {
-            rows.push(row);
-        }
+        let rows: Rows = if self.values.len() == table.primary_key.len() {
+            let row = txn.read_by_id(&self.table_name, &self.values)?;
+            Box::new(row.into_iter().map(Ok))
+        } else {
+            txn.scan_by_id_prefix(&self.table_name, &self.values)?
+        };
 
-        Ok(ResultSet::Scan {
-            columns: table.columns.into_iter().map(|c| c.name.clone()).collect(),
+        Ok(ExecuteResult::Rows {
+            columns: column_labels(table, &self.alias),
             rows,
         })
     }
 }
 
+// FROM 中的子查询，把子查询输出的列名换成这一层查询中的列名
+pub struct SubqueryScan<T: Transaction> {
+    source: Box<dyn Executor<T>>,
+    alias: String,
+    qualified: bool,
+}
+
+impl<T: Transaction> SubqueryScan<T> {
+    pub fn new(source: Box<dyn Executor<T>>, alias: String, qualified: bool) -> Box<Self> {
+        Box::new(Self {
+            source,
+            alias,
+            qualified,
+        })
+    }
+}
+
+impl<T: Transaction> Executor<T> for SubqueryScan<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        match self.source.execute(txn)? {
+            ExecuteResult::Rows { columns, rows } => Ok(ExecuteResult::Rows {
+                // 子查询内部 Join 输出的 t.a 在外面只能通过 alias.a 或者 a 引用，和名字解析的规则一致
+                columns: columns
+                    .iter()
+                    .map(|c| {
+                        let name = column_name(c);
+                        if self.qualified {
+                            format!("{}.{}", self.alias, name)
+                        } else {
+                            name.to_string()
+                        }
+                    })
+                    .collect(),
+                rows,
+            }),
+            _ => Err(Error::Internal("Unexpected result set".into())),
+        }
+    }
+}
+
 pub struct Filter<T: Transaction> {
     source: Box<dyn Executor<T>>,
     predicate: Expression,
@@ -131,24 +249,43 @@ impl<T: Transaction> Filter<T> {
     }
 }
 
-impl<T: Transaction> Executor<T> for Filter<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+impl<T: Transaction + 'static> Executor<T> for Filter<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         match self.source.execute(txn)? {
-            ResultSet::Scan { columns, rows } => {
-                let mut new_rows = Vec::new();
-                for row in rows {
-                    match evaluate_expr(&self.predicate, &columns, &row, &columns, &row)? {
-                        Value::Null => {}
-                        Value::Boolean(false) => {}
-                        Value::Boolean(true) => {
+            ExecuteResult::Rows { columns, rows } => {
+                // 不相关子查询只需要计算一次
+                let predicate = resolve_subqueries(txn, &self.predicate, &columns, None)?;
+                if predicate.contains_subquery() {
+                    // 相关子查询需要对每一行重新计算，执行子查询要用到事务，所以先读出所有的行
+                    let mut new_rows = Vec::new();
+                    for row in rows.collect::<Result<Vec<_>>>()? {
+                        let predicate = resolve_subqueries(txn, &predicate, &columns, Some(&row))?;
+                        if filter_row(&predicate, &columns, &row)? {
                             new_rows.push(row);
                         }
-                        _ => return Err(Error::Internal("Unexpected expression".into())),
                     }
+                    return Ok(ExecuteResult::Rows {
+                        columns,
+                        rows: Box::new(new_rows.into_iter().map(Ok)),
+                    });
                 }
-                Ok(ResultSet::Scan {
+
+                let cols = columns.clone();
+                // 每拉取一行就判断一次，不满足条件的行直接跳过
+                let rows = rows.filter_map(move |row| {
+                    let row = match row {
+                        Ok(row) => row,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    match filter_row(&predicate, &cols, &row) {
+                        Ok(true) => Some(Ok(row)),
+                        Ok(false) => None,
+                        Err(err) => Some(Err(err)),
+                    }
+                });
+                Ok(ExecuteResult::Rows {
                     columns,
-                    rows: new_rows,
+                    rows: Box::new(rows),
                 })
             }
             _ => return Err(Error::Internal("Unexpected result set".into())),
@@ -156,6 +293,15 @@ impl<T: Transaction> Executor<T> for Filter<T> {
     }
 }
 
+// 判断一行是否满足过滤条件，结果是 NULL 的行也不满足
+fn filter_row(predicate: &Expression, cols: &Vec<String>, row: &Row) -> Result<bool> {
+    match evaluate_expr(predicate, cols, row, cols, row)? {
+        Value::Null | Value::Boolean(false) => Ok(false),
+        Value::Boolean(true) => Ok(true),
+        _ => Err(Error::Internal("Unexpected expression".into())),
+    }
+}
+
 pub struct Projection<T: Transaction> {
     //泛型 T 必须实现 Transaction trait，这是通过泛型约束（T: Transaction）来限制的。
     source: Box<dyn Executor<T>>, //source是一个指向了堆上的实现了executor的特征对象的指针！
@@ -171,52 +317,58 @@ impl<T: Transaction> Projection<T> {
     }
 }
 
-impl<T: Transaction> Executor<T> for Projection<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+impl<T: Transaction + 'static> Executor<T> for Projection<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         match self.source.execute(txn)? {
             //这里的返回结果是一个列和行的处理结果！和投影的定义一致~
-            // columns 是查询结果的列名，rows 是查询结果的行数据
-            ResultSet::Scan { columns, rows } => {
-                // 找到需要输出哪些列
-                let mut selected = Vec::new();
-                let mut new_columns = Vec::new();
-                for (expr, alias) in self.exprs {
-                    if let Expression::Field(col_name) = expr {
-                        // 找出列的索引位置
-                        let pos = match columns.iter().position(|c| *c == col_name) {
-                            Some(pos) => pos,
-                            None => {
-                                return Err(Error::Internal(format!(
-                                    "column {} not in table",
-                                    col_name
-                                )))
-                            }
-                        };
-                        //selected 存储了这些列的索引位置，new_columns 存储了新列名（可能是别名）。
-                        selected.push(pos);
-                        //对于找到的列，如果有别名，则使用别名，否则使用原始列名，将它们添加到 new_columns 中。
-                        new_columns.push(if alias.is_some() {
-                            alias.unwrap()
-                        } else {
-                            col_name
-                        });
+            // columns 是查询结果的列名，rows 是查询结果的行迭代器
+            ExecuteResult::Rows { columns, rows } => {
+                // 输出的列名，有别名则使用别名，否则使用表达式本身
+                let new_columns = self
+                    .exprs
+                    .iter()
+                    .map(|(expr, alias)| match alias {
+                        Some(alias) => alias.clone(),
+                        None => expr.to_string(),
+                    })
+                    .collect();
+
+                // 不相关子查询只需要计算一次
+                let exprs = self
+                    .exprs
+                    .iter()
+                    .map(|(expr, _)| resolve_subqueries(txn, expr, &columns, None))
+                    .collect::<Result<Vec<_>>>()?;
+                if exprs.iter().any(|expr| expr.contains_subquery()) {
+                    // 相关子查询需要对每一行重新计算，先读出所有的行
+                    let mut new_rows = Vec::new();
+                    for row in rows.collect::<Result<Vec<_>>>()? {
+                        let mut new_row = Vec::new();
+                        for expr in exprs.iter() {
+                            let expr = resolve_subqueries(txn, expr, &columns, Some(&row))?;
+                            new_row.push(evaluate_expr(&expr, &columns, &row, &columns, &row)?);
+                        }
+                        new_rows.push(new_row);
                     }
+                    return Ok(ExecuteResult::Rows {
+                        columns: new_columns,
+                        rows: Box::new(new_rows.into_iter().map(Ok)),
+                    });
                 }
 
                 // 建立新的行
-                let mut new_rows = Vec::new();
-                // 将列信息写入新的行中并返回~~
-                for row in rows.into_iter() {
-                    let mut new_row = Vec::new();
-                    for i in selected.iter() {
-                        new_row.push(row[*i].clone());
-                    }
-                    new_rows.push(new_row);
-                }
+                // 对每一行计算所有的投影表达式
+                let rows = rows.map(move |row| {
+                    let row = row?;
+                    exprs
+                        .iter()
+                        .map(|expr| evaluate_expr(expr, &columns, &row, &columns, &row))
+                        .collect()
+                });
 
-                Ok(ResultSet::Scan {
+                Ok(ExecuteResult::Rows {
                     columns: new_columns,
-                    rows: new_rows,
+                    rows: Box::new(rows),
                 })
             }
             _ => return Err(Error::Internal("Unexpected result set".into())),
@@ -227,56 +379,74 @@ impl<T: Transaction> Executor<T> for Projection<T> {
 // 排序语句的结构体~
 pub struct Order<T: Transaction> {
     source: Box<dyn Executor<T>>,
-    order_by: Vec<(String, OrderDirection)>,
+    order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
 }
 
 impl<T: Transaction> Order<T> {
-    pub fn new(source: Box<dyn Executor<T>>, order_by: Vec<(String, OrderDirection)>) -> Box<Self> {
+    pub fn new(
+        source: Box<dyn Executor<T>>,
+        order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
+    ) -> Box<Self> {
         Box::new(Self { source, order_by })
     }
 }
 
 impl<T: Transaction> Executor<T> for Order<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         match self.source.execute(txn)? {
-            ResultSet::Scan { columns, mut rows } => {
-                // 找到 order by 的列对应表中的列的位置
-                let mut order_col_index = HashMap::new();
-                for (i, (col_name, _)) in self.order_by.iter().enumerate() {
-                    match columns.iter().position(|c| *c == *col_name) {
-                        Some(pos) => order_col_index.insert(i, pos),
-                        None => {
-                            return Err(Error::Internal(format!(
-                                "order by column {} is not in table",
-                                col_name
-                            )))
-                        }
-                    };
-                }
-
-                rows.sort_by(|col1, col2| {
-                    for (i, (_, direction)) in self.order_by.iter().enumerate() {
-                        let col_index = order_col_index.get(&i).unwrap();
-                        let x = &col1[*col_index];
-                        let y = &col2[*col_index];
-                        match x.partial_cmp(y) {
-                            Some(Ordering::Equal) => {}
-                            Some(o) => {
-                                return if *direction == OrderDirection::Asc {
-                                    o
-                                } else {
-                                    o.reverse()
-                                }
-                            }
-                            None => {}
-                        }
-                    }
-                    Ordering::Equal
+            ExecuteResult::Rows { columns, rows } => {
+                // 排序需要拿到全部的数据，先算出每一行排序用的值，再按这些值排序
+                // 数据太多时排好序的部分先写到临时文件中，最后再归并
+                let order = sort_order(&self.order_by);
+                let entries = rows.map(|row| {
+                    let row = row?;
+                    Ok((sort_keys(&self.order_by, &columns, &row)?, row))
                 });
+                let rows = sort(entries, order, SORT_MEMORY_BUDGET)?;
+                Ok(ExecuteResult::Rows { columns, rows })
+            }
+            _ => Err(Error::Internal("Unexpected result set".into())),
+        }
+    }
+}
+
+// ORDER BY ... LIMIT 时只需要排在最前面的 limit 行（包括 OFFSET 跳过的行），不需要对全部的数据排序
+pub struct TopN<T: Transaction> {
+    source: Box<dyn Executor<T>>,
+    order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
+    limit: usize,
+}
 
-                Ok(ResultSet::Scan { columns, rows })
+impl<T: Transaction> TopN<T> {
+    pub fn new(
+        source: Box<dyn Executor<T>>,
+        order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
+        limit: usize,
+    ) -> Box<Self> {
+        Box::new(Self {
+            source,
+            order_by,
+            limit,
+        })
+    }
+}
+
+impl<T: Transaction> Executor<T> for TopN<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        match self.source.execute(txn)? {
+            ExecuteResult::Rows { columns, rows } => {
+                let order = sort_order(&self.order_by);
+                let entries = rows.map(|row| {
+                    let row = row?;
+                    Ok((sort_keys(&self.order_by, &columns, &row)?, row))
+                });
+                let rows = top_n(entries, order, self.limit)?;
+                Ok(ExecuteResult::Rows {
+                    columns,
+                    rows: Box::new(rows.into_iter().map(Ok)),
+                })
             }
-            _ => return Err(Error::Internal("Unexpected result set".into())),
+            _ => Err(Error::Internal("Unexpected result set".into())),
         }
     }
 }
@@ -294,12 +464,12 @@ impl<T: Transaction> Limit<T> {
 
 // 这里执行的真正的执行语句！(这不废话吗！也不看看这是什么文件executor！执行器！)
 impl<T: Transaction> Executor<T> for Limit<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
-        __function!("这里就是真正的Limit执行的语句了,通过集合截取的~");
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        __function!("这里就是真正的Limit执行的语句了,拉取够 limit 行之后就不再向下拉取了~");
         match self.source.execute(txn)? {
-            ResultSet::Scan { columns, rows } => Ok(ResultSet::Scan {
+            ExecuteResult::Rows { columns, rows } => Ok(ExecuteResult::Rows {
                 columns,
-                rows: rows.into_iter().take(self.limit).collect(),
+                rows: Box::new(rows.take(self.limit)),
             }),
             _ => return Err(Error::Internal("Unexpected result set".into())),
         }
@@ -318,11 +488,11 @@ impl<T: Transaction> Offset<T> {
 }
 
 impl<T: Transaction> Executor<T> for Offset<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         match self.source.execute(txn)? {
-            ResultSet::Scan { columns, rows } => Ok(ResultSet::Scan {
+            ExecuteResult::Rows { columns, rows } => Ok(ExecuteResult::Rows {
                 columns,
-                rows: rows.into_iter().skip(self.offset).collect(),
+                rows: Box::new(rows.skip(self.offset)),
             }),
             _ => return Err(Error::Internal("Unexpected result set".into())),
         }
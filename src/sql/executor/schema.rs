@@ -1,9 +1,12 @@
 use crate::{
     error::Result,
-    sql::{engine::Transaction, schema::Table},
+    sql::{
+        engine::Transaction,
+        schema::{Index, Table, TableChange},
+    },
 };
 
-use super::{Executor, ResultSet};
+use super::{ExecuteResult, Executor, ResultSet};
 
 // 创建表
 pub struct CreateTable {
@@ -17,10 +20,12 @@ impl CreateTable {
 }
 
 impl<T: Transaction> Executor<T> for CreateTable {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         let table_name = self.schema.name.clone();
         txn.create_table(self.schema)?;
-        Ok(ResultSet::CreateTable { table_name })
+        Ok(ExecuteResult::ResultSet(ResultSet::CreateTable {
+            table_name,
+        }))
     }
 }
 
@@ -37,10 +42,73 @@ impl DropTable {
 
 // 这里还再封装，真正与底层交互，写入数据的是mvcc文件里面的write_inner函数
 impl<T: Transaction> Executor<T> for DropTable {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
         txn.drop_table(self.name.clone())?;
-        Ok(ResultSet::DropTable {
+        Ok(ExecuteResult::ResultSet(ResultSet::DropTable {
             table_name: self.name,
-        })
+        }))
+    }
+}
+
+// 创建索引
+pub struct CreateIndex {
+    table_name: String,
+    index: Index,
+}
+
+impl CreateIndex {
+    pub fn new(table_name: String, index: Index) -> Box<Self> {
+        Box::new(Self { table_name, index })
+    }
+}
+
+impl<T: Transaction> Executor<T> for CreateIndex {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        let index_name = self.index.name.clone();
+        txn.create_index(self.table_name.clone(), self.index)?;
+        Ok(ExecuteResult::ResultSet(ResultSet::CreateIndex {
+            table_name: self.table_name,
+            index_name,
+        }))
+    }
+}
+
+// 删除索引
+pub struct DropIndex {
+    name: String,
+}
+
+impl DropIndex {
+    pub fn new(name: String) -> Box<Self> {
+        Box::new(Self { name })
+    }
+}
+
+impl<T: Transaction> Executor<T> for DropIndex {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        let table_name = txn.drop_index(self.name.clone())?;
+        Ok(ExecuteResult::ResultSet(ResultSet::DropIndex {
+            table_name,
+            index_name: self.name,
+        }))
+    }
+}
+
+// 修改表结构
+pub struct AlterTable {
+    table_name: String,
+    change: TableChange,
+}
+
+impl AlterTable {
+    pub fn new(table_name: String, change: TableChange) -> Box<Self> {
+        Box::new(Self { table_name, change })
+    }
+}
+
+impl<T: Transaction> Executor<T> for AlterTable {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ExecuteResult> {
+        let table_name = txn.alter_table(self.table_name, self.change)?;
+        Ok(ExecuteResult::ResultSet(ResultSet::AlterTable { table_name }))
     }
 }
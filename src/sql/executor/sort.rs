@@ -0,0 +1,275 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    rc::Rc,
+};
+
+use crate::{
+    error::Result,
+    sql::{
+        parser::ast::{evaluate_expr, Expression, NullsOrder, OrderDirection},
+        types::{Row, Rows, Value},
+    },
+};
+
+// 排序时最多在内存中保存这么多字节的数据（按照序列化之后的大小估算），超过之后排好序写到临时文件中
+pub const SORT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+// 每一行排序用的值和这一行的数据
+type Entry = (Vec<Value>, Row);
+type Entries = Box<dyn Iterator<Item = Result<Entry>>>;
+
+// 计算一行排序用的值
+pub fn sort_keys(
+    order_by: &[(Expression, OrderDirection, NullsOrder)],
+    columns: &Vec<String>,
+    row: &Row,
+) -> Result<Vec<Value>> {
+    order_by
+        .iter()
+        .map(|(expr, _, _)| evaluate_expr(expr, columns, row, columns, row))
+        .collect()
+}
+
+// 每一列的排序方向和 NULL 的位置
+pub fn sort_order(
+    order_by: &[(Expression, OrderDirection, NullsOrder)],
+) -> Vec<(OrderDirection, NullsOrder)> {
+    order_by
+        .iter()
+        .map(|(_, direction, nulls)| (direction.clone(), nulls.clone()))
+        .collect()
+}
+
+// 按照 ORDER BY 比较两行排序用的值
+// NULL 的位置由 NULLS FIRST/LAST 决定，和升序降序无关
+pub fn compare_keys(order: &[(OrderDirection, NullsOrder)], x: &[Value], y: &[Value]) -> Ordering {
+    for ((x, y), (direction, nulls)) in x.iter().zip(y).zip(order) {
+        let ord = match (x, y) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) if *nulls == NullsOrder::First => Ordering::Less,
+            (Value::Null, _) => Ordering::Greater,
+            (_, Value::Null) if *nulls == NullsOrder::First => Ordering::Greater,
+            (_, Value::Null) => Ordering::Less,
+            _ => match x.partial_cmp(y) {
+                Some(o) if *direction == OrderDirection::Asc => o,
+                Some(o) => o.reverse(),
+                None => Ordering::Equal,
+            },
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+// 堆中的一行，先按排序用的值比较，相等时按 seq 比较，保证结果和稳定排序一样
+struct HeapEntry {
+    keys: Vec<Value>,
+    seq: usize,
+    row: Row,
+    order: Rc<Vec<(OrderDirection, NullsOrder)>>,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.order, &self.keys, &other.keys).then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+// 只保留排在最前面的 limit 行：用一个大顶堆保存当前最小的 limit 行，新的一行比堆顶小时替换堆顶
+pub fn top_n(
+    entries: impl Iterator<Item = Result<Entry>>,
+    order: Vec<(OrderDirection, NullsOrder)>,
+    limit: usize,
+) -> Result<Vec<Row>> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let order = Rc::new(order);
+    // limit 可能很大，堆按照实际的行数增长
+    let mut heap = BinaryHeap::new();
+    for (seq, entry) in entries.enumerate() {
+        let (keys, row) = entry?;
+        let entry = HeapEntry {
+            keys,
+            seq,
+            row,
+            order: order.clone(),
+        };
+        if heap.len() < limit {
+            heap.push(entry);
+        } else if heap.peek().is_some_and(|top| entry < *top) {
+            heap.pop();
+            heap.push(entry);
+        }
+    }
+    Ok(heap.into_sorted_vec().into_iter().map(|e| e.row).collect())
+}
+
+// 外部排序：数据超过 budget 时，把内存中的数据排好序作为一段写到临时文件中，最后把所有的段多路归并
+// 数据没有超过 budget 时直接在内存中排序
+pub fn sort(
+    entries: impl Iterator<Item = Result<Entry>>,
+    order: Vec<(OrderDirection, NullsOrder)>,
+    budget: usize,
+) -> Result<Rows> {
+    let mut runs: Vec<Entries> = Vec::new();
+    let mut buffer = Vec::new();
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        size += bincode::serialized_size(&entry)? as usize;
+        buffer.push(entry);
+        if size > budget {
+            buffer.sort_by(|(x, _), (y, _)| compare_keys(&order, x, y));
+            runs.push(spill(std::mem::take(&mut buffer))?);
+            size = 0;
+        }
+    }
+    buffer.sort_by(|(x, _), (y, _)| compare_keys(&order, x, y));
+    if runs.is_empty() {
+        return Ok(Box::new(buffer.into_iter().map(|(_, row)| Ok(row))));
+    }
+    // 最后一段不超过 budget，不用写到文件中
+    runs.push(Box::new(buffer.into_iter().map(Ok)));
+    Ok(Box::new(Merge::new(runs, order)?))
+}
+
+// 把排好序的一段写到临时文件中，返回按顺序读取这一段的迭代器，临时文件关闭之后会自动删除
+fn spill(entries: Vec<Entry>) -> Result<Entries> {
+    let mut writer = BufWriter::new(tempfile::tempfile()?);
+    for entry in &entries {
+        bincode::serialize_into(&mut writer, entry)?;
+    }
+    writer.flush()?;
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    Ok(Box::new((0..entries.len()).map(
+        move |_| -> Result<Entry> { Ok(bincode::deserialize_from(&mut reader)?) },
+    )))
+}
+
+// 多路归并：每一段在堆中放一行，每次取出最小的一行，再从这一段中读出下一行放进堆中
+// 堆中的 seq 是段的编号，相等的行按段的顺序输出，也就是按原来的顺序输出
+struct Merge {
+    runs: Vec<Entries>,
+    heap: BinaryHeap<Reverse<HeapEntry>>,
+    order: Rc<Vec<(OrderDirection, NullsOrder)>>,
+}
+
+impl Merge {
+    fn new(runs: Vec<Entries>, order: Vec<(OrderDirection, NullsOrder)>) -> Result<Self> {
+        let mut merge = Self {
+            heap: BinaryHeap::with_capacity(runs.len()),
+            runs,
+            order: Rc::new(order),
+        };
+        for seq in 0..merge.runs.len() {
+            merge.advance(seq)?;
+        }
+        Ok(merge)
+    }
+
+    // 从第 seq 段中读出下一行放进堆中
+    fn advance(&mut self, seq: usize) -> Result<()> {
+        if let Some(entry) = self.runs[seq].next() {
+            let (keys, row) = entry?;
+            self.heap.push(Reverse(HeapEntry {
+                keys,
+                seq,
+                row,
+                order: self.order.clone(),
+            }));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse(entry) = self.heap.pop()?;
+        if let Err(e) = self.advance(entry.seq) {
+            return Some(Err(e));
+        }
+        Some(Ok(entry.row))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sort, top_n};
+    use crate::{
+        error::Result,
+        sql::{
+            parser::ast::{NullsOrder, OrderDirection},
+            types::Value,
+        },
+    };
+
+    // (b, a)：按 b 排序，a 用来检查相等的行是否保持原来的顺序
+    fn entries() -> Vec<(Vec<Value>, Vec<Value>)> {
+        (0..1000)
+            .map(|i| {
+                let b = match i % 7 {
+                    0 => Value::Null,
+                    _ => Value::Integer((i * 37) % 101),
+                };
+                (vec![b.clone()], vec![Value::Integer(i), b])
+            })
+            .collect()
+    }
+
+    fn expected(order: &[(OrderDirection, NullsOrder)]) -> Vec<Vec<Value>> {
+        let mut entries = entries();
+        entries.sort_by(|(x, _), (y, _)| super::compare_keys(order, x, y));
+        entries.into_iter().map(|(_, row)| row).collect()
+    }
+
+    #[test]
+    fn test_external_sort() -> Result<()> {
+        for order in [
+            vec![(OrderDirection::Asc, NullsOrder::First)],
+            vec![(OrderDirection::Desc, NullsOrder::First)],
+        ] {
+            let expected = expected(&order);
+            // 内存足够时不写临时文件
+            let rows = sort(entries().into_iter().map(Ok), order.clone(), usize::MAX)?;
+            assert_eq!(rows.collect::<Result<Vec<_>>>()?, expected);
+            // 每一段只有几十行，需要归并很多段
+            let rows = sort(entries().into_iter().map(Ok), order.clone(), 1024)?;
+            assert_eq!(rows.collect::<Result<Vec<_>>>()?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_n() -> Result<()> {
+        let order = vec![(OrderDirection::Desc, NullsOrder::Last)];
+        let expected = expected(&order);
+        for limit in [0, 1, 10, 999, 1000, 2000, usize::MAX] {
+            let rows = top_n(entries().into_iter().map(Ok), order.clone(), limit)?;
+            assert_eq!(rows, expected[..limit.min(expected.len())]);
+        }
+        Ok(())
+    }
+}
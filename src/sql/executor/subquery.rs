@@ -0,0 +1,175 @@
+use crate::{
+    error::{Error, Result},
+    sql::{
+        engine::Transaction,
+        parser::ast::{Expression, FromItem, Operation, Statement},
+        plan::Plan,
+        types::{Row, Value},
+    },
+};
+
+use super::{ExecuteResult, Executor};
+
+// 计算表达式中的子查询，把子查询的结果替换成常量
+// row 为 None 时只计算不相关子查询，引用了外层 columns 中的列的相关子查询原样保留，之后对每一行再计算；
+// row 不为 None 时先把子查询中引用的外层列替换成这一行的值，再执行子查询
+pub fn resolve_subqueries<T: Transaction + 'static>(
+    txn: &mut T,
+    expr: &Expression,
+    columns: &[String],
+    row: Option<&Row>,
+) -> Result<Expression> {
+    let mut expr = expr.clone();
+    expr.transform(&mut |e| {
+        let mut query = match e {
+            Expression::Subquery(query)
+            | Expression::Exists(query)
+            | Expression::Operation(Operation::InSubquery(_, query)) => (**query).clone(),
+            _ => return Ok(()),
+        };
+        if bind_outer(&mut query, 1, columns, row)? && row.is_none() {
+            return Ok(());
+        }
+        let (cols, rows) = execute_subquery(txn, query)?;
+        if !matches!(e, Expression::Exists(_)) && cols.len() != 1 {
+            return Err(Error::Internal("subquery must return only one column".into()));
+        }
+        *e = match e {
+            Expression::Exists(_) => Value::Boolean(!rows.is_empty()).into(),
+            Expression::Subquery(_) => {
+                if rows.len() > 1 {
+                    return Err(Error::Internal(
+                        "more than one row returned by a subquery used as an expression".into(),
+                    ));
+                }
+                // 没有结果的标量子查询的值是 NULL
+                match rows.into_iter().next() {
+                    Some(mut row) => row.remove(0).into(),
+                    None => Value::Null.into(),
+                }
+            }
+            // a IN (空集合) 总是 false，即使 a 是 NULL
+            Expression::Operation(Operation::InSubquery(_, _)) if rows.is_empty() => {
+                Value::Boolean(false).into()
+            }
+            Expression::Operation(Operation::InSubquery(l, _)) => {
+                Expression::Operation(Operation::In(
+                    l.clone(),
+                    rows.into_iter().map(|mut row| row.remove(0).into()).collect(),
+                ))
+            }
+            _ => return Err(Error::Internal("unexpected subquery".into())),
+        };
+        Ok(())
+    })?;
+    Ok(expr)
+}
+
+// 执行子查询，读出所有的结果
+fn execute_subquery<T: Transaction + 'static>(
+    txn: &mut T,
+    stmt: Statement,
+) -> Result<(Vec<String>, Vec<Row>)> {
+    let plan = Plan::build(stmt, txn)?;
+    match <dyn Executor<T>>::build(plan.0).execute(txn)? {
+        ExecuteResult::Rows { columns, rows } => Ok((columns, rows.collect::<Result<Vec<_>>>()?)),
+        _ => Err(Error::Internal("Unexpected result set".into())),
+    }
+}
+
+// 把子查询中引用的外层列替换成外层这一行的值（row 为 None 时不替换），返回子查询是否引用了外层的列
+// 名字解析时外层的列已经换成了 OuterField，depth 是这个子查询相对外层这一行所在查询的层数
+fn bind_outer(
+    stmt: &mut Statement,
+    depth: usize,
+    columns: &[String],
+    row: Option<&Row>,
+) -> Result<bool> {
+    let (select, from, exprs) = match stmt {
+        Statement::Select {
+            select,
+            from,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+            ..
+        } => {
+            let exprs = where_clause
+                .iter_mut()
+                .chain(group_by.iter_mut())
+                .chain(having.iter_mut())
+                .chain(order_by.iter_mut().map(|(expr, _, _)| expr))
+                .chain(limit.iter_mut())
+                .chain(offset.iter_mut())
+                .collect::<Vec<_>>();
+            (select, from, exprs)
+        }
+        _ => return Ok(false),
+    };
+
+    let mut correlated = bind_from(from, depth, columns, row)?;
+    for expr in select.iter_mut().map(|(expr, _)| expr).chain(exprs) {
+        correlated |= bind_expr(expr, depth, columns, row)?;
+    }
+    Ok(correlated)
+}
+
+fn bind_from(
+    item: &mut FromItem,
+    depth: usize,
+    columns: &[String],
+    row: Option<&Row>,
+) -> Result<bool> {
+    Ok(match item {
+        FromItem::Table { .. } => false,
+        FromItem::Join {
+            left,
+            right,
+            predicate,
+            ..
+        } => {
+            let mut correlated = bind_from(left, depth, columns, row)?;
+            correlated |= bind_from(right, depth, columns, row)?;
+            if let Some(predicate) = predicate {
+                correlated |= bind_expr(predicate, depth, columns, row)?;
+            }
+            correlated
+        }
+        // FROM 中的子查询比这一层又深了一层
+        FromItem::Subquery { query, .. } => bind_outer(query, depth + 1, columns, row)?,
+    })
+}
+
+fn bind_expr(
+    expr: &mut Expression,
+    depth: usize,
+    columns: &[String],
+    row: Option<&Row>,
+) -> Result<bool> {
+    let mut correlated = false;
+    expr.transform(&mut |e| {
+        match e {
+            Expression::OuterField(d, label) if *d == depth => {
+                let pos = columns.iter().position(|c| c == label).ok_or(Error::Internal(
+                    format!("column {} is not in table", label),
+                ))?;
+                correlated = true;
+                if let Some(row) = row {
+                    *e = row[pos].clone().into();
+                }
+            }
+            // 子查询中嵌套的子查询也可以引用最外层的列
+            Expression::Subquery(query)
+            | Expression::Exists(query)
+            | Expression::Operation(Operation::InSubquery(_, query)) => {
+                correlated |= bind_outer(query, depth + 1, columns, row)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(correlated)
+}
@@ -1,20 +1,38 @@
-use std::{collections::BTreeMap, fmt::Display};
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Display};
 
 use crate::{
     error::{Error, Result},
     sql::types::{DataType, Value},
+    storage::{
+        lock::LockMode,
+        mvcc::{IsolationLevel, Version},
+    },
 };
 
 // Abstract Syntax Tree 抽象语法树定义
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Statement {
     CreateTable {
         name: String,
         columns: Vec<Column>,
+        // 表级别的 PRIMARY KEY (a, b) 约束，没有的话为空
+        primary_key: Vec<String>,
     },
     DropTable {
         name: String,
     },
+    CreateIndex {
+        name: String,
+        table_name: String,
+        columns: Vec<String>,
+    },
+    DropIndex {
+        name: String,
+    },
+    AlterTable {
+        name: String,
+        action: AlterTableAction,
+    },
     Insert {
         table_name: String,
         columns: Option<Vec<String>>,
@@ -24,11 +42,16 @@ pub enum Statement {
         select: Vec<(Expression, Option<String>)>,
         from: FromItem,
         where_clause: Option<Expression>,
-        group_by: Option<Expression>,
+        group_by: Vec<Expression>,
         having: Option<Expression>,
-        order_by: Vec<(String, OrderDirection)>,
+        // 表达式（或者 SELECT 中的位置、别名）、方向、NULL 的位置
+        order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
         limit: Option<Expression>,
         offset: Option<Expression>,
+        // FOR UPDATE / FOR SHARE，给查询到的行加锁
+        lock: Option<LockMode>,
+        // AS OF <version>，读取指定版本的历史数据
+        as_of: Option<Version>,
     },
     // 更新操作的抽象语法树~~！！
     Update {
@@ -41,22 +64,71 @@ pub enum Statement {
         where_clause: Option<Expression>,
     },
     // 构建事务相关的抽象语法树
-    Begin,
+    // BEGIN [TRANSACTION] [ISOLATION LEVEL {SNAPSHOT | REPEATABLE READ | SERIALIZABLE}]
+    //       [READ ONLY [AS OF SYSTEM TIME <version>]]
+    Begin {
+        isolation: IsolationLevel,
+        read_only: bool,
+        as_of: Option<Version>,
+    },
     Commit,
     Rollback,
+    // SAVEPOINT <name>
+    Savepoint {
+        name: String,
+    },
+    // ROLLBACK [TRANSACTION] TO [SAVEPOINT] <name>
+    RollbackToSavepoint {
+        name: String,
+    },
+    // RELEASE [SAVEPOINT] <name>
+    ReleaseSavepoint {
+        name: String,
+    },
     Explain {
         stmt: Box<Statement>,
     },
+    // 清理所有事务都不再需要的旧版本数据
+    Vacuum,
 }
+// ALTER TABLE 支持的操作
+#[derive(Debug, PartialEq, Clone)]
+pub enum AlterTableAction {
+    // ADD [COLUMN] 列定义
+    AddColumn(Column),
+    // DROP [COLUMN] 列名
+    DropColumn(String),
+    // RENAME [COLUMN] 旧列名 TO 新列名
+    RenameColumn { from: String, to: String },
+    // RENAME TO 新表名
+    RenameTable(String),
+}
+
 // 增长方向
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum OrderDirection {
     Asc,
     Desc,
 }
 
+// 排序时 NULL 的位置，没有指定时 NULL 当作最小的值，升序时在最前面，降序时在最后面
+#[derive(Debug, PartialEq, Clone)]
+pub enum NullsOrder {
+    First,
+    Last,
+}
+
+impl NullsOrder {
+    pub fn default_for(direction: &OrderDirection) -> Self {
+        match direction {
+            OrderDirection::Asc => NullsOrder::First,
+            OrderDirection::Desc => NullsOrder::Last,
+        }
+    }
+}
+
 // 列定义
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Column {
     pub name: String,
     pub datatype: DataType,
@@ -67,11 +139,13 @@ pub struct Column {
     pub index: bool,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 // FromItem枚举 表示 FROM 子句可能包含的不同类型的元素
 pub enum FromItem {
+    // table_name [[AS] alias]
     Table {
         name: String,
+        alias: Option<String>,
     },
 
     Join {
@@ -80,9 +154,15 @@ pub enum FromItem {
         join_type: JoinType,
         predicate: Option<Expression>,
     },
+
+    // FROM (SELECT ...) [AS] alias，子查询的结果当作一张表
+    Subquery {
+        query: Box<Statement>,
+        alias: String,
+    },
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum JoinType {
     Cross,
     Inner,
@@ -93,10 +173,16 @@ pub enum JoinType {
 // 表达式定义，凡是1+1,a>1,等都是表达式
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expression {
-    Field(String),//字段
+    Field(String),//字段，可以是 列名 或者 表名.列名
     Consts(Consts),//常量
     Operation(Operation),//=、>、<
-    Function(String, String),//新增的聚集函数类型，表示聚集函数的相关操作！
+    Function(String, Option<Box<Expression>>, bool),//新增的聚集函数类型：函数名、参数（COUNT(*) 时为 None）、是否 DISTINCT
+    // 标量子查询 (SELECT ...)，结果最多只能有一行一列
+    Subquery(Box<Statement>),
+    // EXISTS (SELECT ...)
+    Exists(Box<Statement>),
+    // 相关子查询中引用的外层查询的列，由名字解析生成，第一个值是向外的层数
+    OuterField(usize, String),
 }
 
 impl From<Consts> for Expression {
@@ -105,6 +191,64 @@ impl From<Consts> for Expression {
     }
 }
 
+// 子查询计算出来的值替换回表达式中
+impl From<Value> for Expression {
+    fn from(value: Value) -> Self {
+        Self::Consts(match value {
+            Value::Null => Consts::Null,
+            Value::Boolean(b) => Consts::Boolean(b),
+            Value::Integer(i) => Consts::Integer(i),
+            Value::Float(f) => Consts::Float(f),
+            Value::String(s) => Consts::String(s),
+        })
+    }
+}
+
+impl Expression {
+    // 表达式中是否包含子查询
+    pub fn contains_subquery(&self) -> bool {
+        match self {
+            Expression::Subquery(_) | Expression::Exists(_) => true,
+            Expression::Operation(Operation::InSubquery(_, _)) => true,
+            Expression::Operation(operation) => {
+                operation.operands().iter().any(|e| e.contains_subquery())
+            }
+            Expression::Function(_, Some(arg), _) => arg.contains_subquery(),
+            _ => false,
+        }
+    }
+
+    // 表达式中是否包含聚集函数，子查询中的不算
+    pub fn contains_aggregate(&self) -> bool {
+        match self {
+            Expression::Function(_, _, _) => true,
+            Expression::Operation(operation) => {
+                operation.operands().iter().any(|e| e.contains_aggregate())
+            }
+            _ => false,
+        }
+    }
+
+    // 先对当前节点调用 f，再递归处理运算的各个操作数以及聚集函数的参数
+    // f 可以直接替换掉当前的节点，子查询内部的语句不会被遍历
+    pub fn transform<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&mut Expression) -> Result<()>,
+    {
+        f(self)?;
+        match self {
+            Expression::Operation(operation) => {
+                for operand in operation.operands_mut() {
+                    operand.transform(f)?;
+                }
+            }
+            Expression::Function(_, Some(arg), _) => arg.transform(f)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Consts {
     Null,
@@ -119,6 +263,84 @@ pub enum Operation {
     Equal(Box<Expression>, Box<Expression>),//这里就是索引的判断，如果查询的某一列上面有索引，那就直接走索引！这里的Equal左=字段名，右=索引名
     GreaterThan(Box<Expression>, Box<Expression>),
     LessThan(Box<Expression>, Box<Expression>),
+    NotEqual(Box<Expression>, Box<Expression>),
+    GreaterThanOrEqual(Box<Expression>, Box<Expression>),
+    LessThanOrEqual(Box<Expression>, Box<Expression>),
+    // 逻辑运算 AND OR NOT
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    // a IS NULL，IS NOT NULL 解析为 Not(IsNull)
+    IsNull(Box<Expression>),
+    // a IN (x, y, z)
+    In(Box<Expression>, Vec<Expression>),
+    // a IN (SELECT ...)，执行时先计算出子查询的结果，再转换成 In
+    InSubquery(Box<Expression>, Box<Statement>),
+    // a LIKE 'abc%'
+    Like(Box<Expression>, Box<Expression>),
+    // 算术运算 + - * / %
+    Add(Box<Expression>, Box<Expression>),
+    Subtract(Box<Expression>, Box<Expression>),
+    Multiply(Box<Expression>, Box<Expression>),
+    Divide(Box<Expression>, Box<Expression>),
+    Modulo(Box<Expression>, Box<Expression>),
+    // 一元负号 -a
+    Negate(Box<Expression>),
+    // 字符串拼接 a || b
+    Concat(Box<Expression>, Box<Expression>),
+}
+
+impl Operation {
+    // 运算的所有操作数
+    pub fn operands(&self) -> Vec<&Expression> {
+        match self {
+            Operation::Equal(l, r)
+            | Operation::GreaterThan(l, r)
+            | Operation::LessThan(l, r)
+            | Operation::NotEqual(l, r)
+            | Operation::GreaterThanOrEqual(l, r)
+            | Operation::LessThanOrEqual(l, r)
+            | Operation::And(l, r)
+            | Operation::Or(l, r)
+            | Operation::Like(l, r)
+            | Operation::Add(l, r)
+            | Operation::Subtract(l, r)
+            | Operation::Multiply(l, r)
+            | Operation::Divide(l, r)
+            | Operation::Modulo(l, r)
+            | Operation::Concat(l, r) => vec![l, r],
+            Operation::Not(e)
+            | Operation::IsNull(e)
+            | Operation::Negate(e)
+            | Operation::InSubquery(e, _) => vec![e],
+            Operation::In(e, list) => std::iter::once(&**e).chain(list.iter()).collect(),
+        }
+    }
+
+    fn operands_mut(&mut self) -> Vec<&mut Expression> {
+        match self {
+            Operation::Equal(l, r)
+            | Operation::GreaterThan(l, r)
+            | Operation::LessThan(l, r)
+            | Operation::NotEqual(l, r)
+            | Operation::GreaterThanOrEqual(l, r)
+            | Operation::LessThanOrEqual(l, r)
+            | Operation::And(l, r)
+            | Operation::Or(l, r)
+            | Operation::Like(l, r)
+            | Operation::Add(l, r)
+            | Operation::Subtract(l, r)
+            | Operation::Multiply(l, r)
+            | Operation::Divide(l, r)
+            | Operation::Modulo(l, r)
+            | Operation::Concat(l, r) => vec![l, r],
+            Operation::Not(e)
+            | Operation::IsNull(e)
+            | Operation::Negate(e)
+            | Operation::InSubquery(e, _) => vec![e],
+            Operation::In(e, list) => std::iter::once(&mut **e).chain(list.iter_mut()).collect(),
+        }
+    }
 }
 
 impl Display for Expression {
@@ -134,8 +356,37 @@ impl Display for Expression {
                 Operation::Equal(l, r) => write!(f, "{} = {}", l, r),
                 Operation::GreaterThan(l, r) => write!(f, "{} > {}", l, r),
                 Operation::LessThan(l, r) => write!(f, "{} < {}", l, r),
+                Operation::NotEqual(l, r) => write!(f, "{} != {}", l, r),
+                Operation::GreaterThanOrEqual(l, r) => write!(f, "{} >= {}", l, r),
+                Operation::LessThanOrEqual(l, r) => write!(f, "{} <= {}", l, r),
+                Operation::And(l, r) => write!(f, "({} AND {})", l, r),
+                Operation::Or(l, r) => write!(f, "({} OR {})", l, r),
+                Operation::Not(e) => write!(f, "NOT {}", e),
+                Operation::IsNull(e) => write!(f, "{} IS NULL", e),
+                Operation::In(e, list) => write!(
+                    f,
+                    "{} IN ({})",
+                    e,
+                    list.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+                ),
+                Operation::InSubquery(e, _) => write!(f, "{} IN (subquery)", e),
+                Operation::Like(l, r) => write!(f, "{} LIKE {}", l, r),
+                Operation::Add(l, r) => write!(f, "({} + {})", l, r),
+                Operation::Subtract(l, r) => write!(f, "({} - {})", l, r),
+                Operation::Multiply(l, r) => write!(f, "({} * {})", l, r),
+                Operation::Divide(l, r) => write!(f, "({} / {})", l, r),
+                Operation::Modulo(l, r) => write!(f, "({} % {})", l, r),
+                Operation::Negate(e) => write!(f, "-{}", e),
+                Operation::Concat(l, r) => write!(f, "({} || {})", l, r),
             },
-            Expression::Function(name, field) => write!(f, "{}({})", name, field),
+            Expression::Function(name, arg, distinct) => match arg {
+                None => write!(f, "{}(*)", name),
+                Some(arg) if *distinct => write!(f, "{}(DISTINCT {})", name, arg),
+                Some(arg) => write!(f, "{}({})", name, arg),
+            },
+            Expression::Subquery(_) => write!(f, "(subquery)"),
+            Expression::Exists(_) => write!(f, "EXISTS (subquery)"),
+            Expression::OuterField(_, name) => write!(f, "{}", name),
         }
     }
 }
@@ -156,17 +407,18 @@ pub fn evaluate_expr(
     // 匹配表达式，返回结果集~
     match expr {
         // 如果表达式是字段（Field），我们在左表 (lcols) 中查找字段名 col_name，并返回对应位置的行数据 lrows[pos]。
+        // 左表中找不到的话再去右表中找，这样 Join 条件中的字段不论写在哪一边都可以找到
         Expression::Field(col_name) => {
-            let pos = match lcols.iter().position(|c| *c == *col_name) {
-                Some(pos) => pos,
-                None => {
-                    return Err(Error::Internal(format!(
-                        "column {} is not in table",
-                        col_name
-                    )))
-                }
-            };
-            Ok(lrows[pos].clone())
+            if let Some(pos) = lcols.iter().position(|c| *c == *col_name) {
+                return Ok(lrows[pos].clone());
+            }
+            match rcols.iter().position(|c| *c == *col_name) {
+                Some(pos) => Ok(rrows[pos].clone()),
+                None => Err(Error::Internal(format!(
+                    "column {} is not in table",
+                    col_name
+                ))),
+            }
         }
 
         // 如果表达式是常量（Consts），根据不同的常量类型（如 Null、Boolean、Integer、Float、String），返回对应的 Value 类型。
@@ -177,71 +429,265 @@ pub fn evaluate_expr(
             Consts::Float(f) => Value::Float(*f),
             Consts::String(s) => Value::String(s.clone()),
         }),
-        // 如果表达式是 = 运算符。首先`递归`地评估左右表达式 lexpr 和 rexpr，并得到它们的值 lv 和 rv。
+        // 如果表达式是比较运算符。首先`递归`地评估左右表达式 lexpr 和 rexpr，并得到它们的值 lv 和 rv。
         Expression::Operation(operation) => match operation {
-            Operation::Equal(lexpr, rexpr) => {
-                let lv = evaluate_expr(&lexpr, lcols, lrows, rcols, rrows)?;
-                let rv = evaluate_expr(&rexpr, rcols, rrows, lcols, lrows)?;
-                Ok(match (lv, rv) {
-                    (Value::Boolean(l), Value::Boolean(r)) => Value::Boolean(l == r),
-                    (Value::Integer(l), Value::Integer(r)) => Value::Boolean(l == r),
-                    (Value::Integer(l), Value::Float(r)) => Value::Boolean(l as f64 == r),
-                    (Value::Float(l), Value::Integer(r)) => Value::Boolean(l == r as f64),
-                    (Value::Float(l), Value::Float(r)) => Value::Boolean(l == r),
-                    (Value::String(l), Value::String(r)) => Value::Boolean(l == r),
-                    (Value::Null, _) => Value::Null,
-                    (_, Value::Null) => Value::Null,
-                    (l, r) => {
+            Operation::Equal(lexpr, rexpr)
+            | Operation::NotEqual(lexpr, rexpr)
+            | Operation::GreaterThan(lexpr, rexpr)
+            | Operation::GreaterThanOrEqual(lexpr, rexpr)
+            | Operation::LessThan(lexpr, rexpr)
+            | Operation::LessThanOrEqual(lexpr, rexpr) => {
+                let lv = evaluate_expr(lexpr, lcols, lrows, rcols, rrows)?;
+                let rv = evaluate_expr(rexpr, rcols, rrows, lcols, lrows)?;
+                // 有一边是 NULL 时比较的结果是 NULL（未知）
+                if lv == Value::Null || rv == Value::Null {
+                    return Ok(Value::Null);
+                }
+                let ord = match lv.partial_cmp(&rv) {
+                    Some(ord) => ord,
+                    None => {
                         return Err(Error::Internal(format!(
                             "can not compare exression {} and {}",
-                            l, r
+                            lv, rv
                         )))
                     }
+                };
+                Ok(Value::Boolean(match operation {
+                    Operation::Equal(_, _) => ord == Ordering::Equal,
+                    Operation::NotEqual(_, _) => ord != Ordering::Equal,
+                    Operation::GreaterThan(_, _) => ord == Ordering::Greater,
+                    Operation::GreaterThanOrEqual(_, _) => ord != Ordering::Less,
+                    Operation::LessThan(_, _) => ord == Ordering::Less,
+                    _ => ord != Ordering::Greater,
+                }))
+            }
+            // 逻辑运算遵循 SQL 的三值逻辑
+            // AND：有一边是 false 结果就是 false，否则有一边是 NULL 结果就是 NULL
+            // OR：有一边是 true 结果就是 true，否则有一边是 NULL 结果就是 NULL
+            Operation::And(lexpr, rexpr) | Operation::Or(lexpr, rexpr) => {
+                let lv = evaluate_bool(lexpr, lcols, lrows, rcols, rrows)?;
+                let rv = evaluate_bool(rexpr, lcols, lrows, rcols, rrows)?;
+                let is_and = matches!(operation, Operation::And(_, _));
+                Ok(match (lv, rv) {
+                    (Some(l), _) if l != is_and => Value::Boolean(l),
+                    (_, Some(r)) if r != is_and => Value::Boolean(r),
+                    (Some(_), Some(_)) => Value::Boolean(is_and),
+                    _ => Value::Null,
                 })
             }
-        // 如果表达式是>
-            Operation::GreaterThan(lexpr, rexpr) => {
-                let lv = evaluate_expr(&lexpr, lcols, lrows, rcols, rrows)?;
-                let rv = evaluate_expr(&rexpr, rcols, rrows, lcols, lrows)?;
+            Operation::Not(expr) => Ok(match evaluate_bool(expr, lcols, lrows, rcols, rrows)? {
+                Some(b) => Value::Boolean(!b),
+                None => Value::Null,
+            }),
+            Operation::IsNull(expr) => Ok(Value::Boolean(
+                evaluate_expr(expr, lcols, lrows, rcols, rrows)? == Value::Null,
+            )),
+            // a IN (x, y, z) 等价于 a = x OR a = y OR a = z
+            Operation::In(expr, list) => {
+                let v = evaluate_expr(expr, lcols, lrows, rcols, rrows)?;
+                if v == Value::Null {
+                    return Ok(Value::Null);
+                }
+                let mut has_null = false;
+                for item in list {
+                    match evaluate_expr(item, lcols, lrows, rcols, rrows)? {
+                        Value::Null => has_null = true,
+                        item => match v.partial_cmp(&item) {
+                            Some(Ordering::Equal) => return Ok(Value::Boolean(true)),
+                            Some(_) => {}
+                            None => {
+                                return Err(Error::Internal(format!(
+                                    "can not compare exression {} and {}",
+                                    v, item
+                                )))
+                            }
+                        },
+                    }
+                }
+                Ok(if has_null { Value::Null } else { Value::Boolean(false) })
+            }
+            // LIKE 匹配，% 匹配任意多个字符，_ 匹配单个字符
+            Operation::Like(lexpr, rexpr) => {
+                let lv = evaluate_expr(lexpr, lcols, lrows, rcols, rrows)?;
+                let rv = evaluate_expr(rexpr, lcols, lrows, rcols, rrows)?;
                 Ok(match (lv, rv) {
-                    (Value::Boolean(l), Value::Boolean(r)) => Value::Boolean(l > r),
-                    (Value::Integer(l), Value::Integer(r)) => Value::Boolean(l > r),
-                    (Value::Integer(l), Value::Float(r)) => Value::Boolean(l as f64 > r),
-                    (Value::Float(l), Value::Integer(r)) => Value::Boolean(l > r as f64),
-                    (Value::Float(l), Value::Float(r)) => Value::Boolean(l > r),
-                    (Value::String(l), Value::String(r)) => Value::Boolean(l > r),
-                    (Value::Null, _) => Value::Null,
-                    (_, Value::Null) => Value::Null,
+                    (Value::Null, _) | (_, Value::Null) => Value::Null,
+                    (Value::String(l), Value::String(r)) => {
+                        let l = l.chars().collect::<Vec<_>>();
+                        let r = r.chars().collect::<Vec<_>>();
+                        Value::Boolean(like_match(&l, &r))
+                    }
                     (l, r) => {
                         return Err(Error::Internal(format!(
-                            "can not compare exression {} and {}",
+                            "can not match exression {} LIKE {}",
                             l, r
                         )))
                     }
                 })
             }
-            // 如果表达式是<
-            Operation::LessThan(lexpr, rexpr) => {
-                let lv = evaluate_expr(&lexpr, lcols, lrows, rcols, rrows)?;
-                let rv = evaluate_expr(&rexpr, rcols, rrows, lcols, lrows)?;
+            // 算术运算，左右两边使用同一组行数据求值
+            Operation::Add(lexpr, rexpr)
+            | Operation::Subtract(lexpr, rexpr)
+            | Operation::Multiply(lexpr, rexpr)
+            | Operation::Divide(lexpr, rexpr)
+            | Operation::Modulo(lexpr, rexpr) => {
+                let lv = evaluate_expr(lexpr, lcols, lrows, rcols, rrows)?;
+                let rv = evaluate_expr(rexpr, lcols, lrows, rcols, rrows)?;
+                evaluate_arithmetic(operation, lv, rv)
+            }
+            Operation::Negate(expr) => Ok(match evaluate_expr(expr, lcols, lrows, rcols, rrows)? {
+                Value::Null => Value::Null,
+                Value::Integer(i) => Value::Integer(
+                    i.checked_neg()
+                        .ok_or(Error::Internal(format!("integer overflow: -{}", i)))?,
+                ),
+                Value::Float(f) => Value::Float(-f),
+                v => return Err(Error::Internal(format!("can not negate {}", v))),
+            }),
+            // 字符串拼接，只要有一边是字符串，另一边就按照字符串的形式拼接上去
+            Operation::Concat(lexpr, rexpr) => {
+                let lv = evaluate_expr(lexpr, lcols, lrows, rcols, rrows)?;
+                let rv = evaluate_expr(rexpr, lcols, lrows, rcols, rrows)?;
                 Ok(match (lv, rv) {
-                    (Value::Boolean(l), Value::Boolean(r)) => Value::Boolean(l < r),
-                    (Value::Integer(l), Value::Integer(r)) => Value::Boolean(l < r),
-                    (Value::Integer(l), Value::Float(r)) => Value::Boolean((l as f64) < r),
-                    (Value::Float(l), Value::Integer(r)) => Value::Boolean(l < r as f64),
-                    (Value::Float(l), Value::Float(r)) => Value::Boolean(l < r),
-                    (Value::String(l), Value::String(r)) => Value::Boolean(l < r),
-                    (Value::Null, _) => Value::Null,
-                    (_, Value::Null) => Value::Null,
+                    (Value::Null, _) | (_, Value::Null) => Value::Null,
+                    (l @ Value::String(_), r) | (l, r @ Value::String(_)) => {
+                        Value::String(format!("{}{}", l, r))
+                    }
                     (l, r) => {
                         return Err(Error::Internal(format!(
-                            "can not compare exression {} and {}",
+                            "can not concat exression {} and {}",
                             l, r
                         )))
                     }
                 })
             }
+            // 子查询在执行器中已经被替换成了常量，这里不应该再出现
+            Operation::InSubquery(_, _) => {
+                Err(Error::Internal("unexpected subquery in expression".into()))
+            }
         },
-        _ => return Err(Error::Internal("unexpected expression".into())),
+        Expression::Subquery(_) | Expression::Exists(_) | Expression::OuterField(_, _) => {
+            Err(Error::Internal("unexpected subquery in expression".into()))
+        }
+        // 聚集函数在 Aggregate 执行器中已经被替换成了常量，其他地方不能使用
+        Expression::Function(_, _, _) => Err(Error::Internal(format!(
+            "aggregate function {} is not allowed here",
+            expr
+        ))),
+    }
+}
+
+// 计算二元算术运算
+// 两边都是整数时结果还是整数，有一边是浮点数时结果是浮点数，有一边是 NULL 时结果是 NULL
+fn evaluate_arithmetic(operation: &Operation, lv: Value, rv: Value) -> Result<Value> {
+    Ok(match (lv, rv) {
+        (Value::Null, _) | (_, Value::Null) => Value::Null,
+        (Value::Integer(l), Value::Integer(r)) => {
+            let res = match operation {
+                Operation::Add(_, _) => l.checked_add(r),
+                Operation::Subtract(_, _) => l.checked_sub(r),
+                Operation::Multiply(_, _) => l.checked_mul(r),
+                Operation::Divide(_, _) | Operation::Modulo(_, _) if r == 0 => {
+                    return Err(Error::Internal("division by zero".into()))
+                }
+                Operation::Divide(_, _) => l.checked_div(r),
+                Operation::Modulo(_, _) => l.checked_rem(r),
+                _ => return Err(Error::Internal("unexpected arithmetic operation".into())),
+            };
+            match res {
+                Some(v) => Value::Integer(v),
+                None => {
+                    return Err(Error::Internal(format!(
+                        "integer overflow: {} {} {}",
+                        l,
+                        arithmetic_symbol(operation),
+                        r
+                    )))
+                }
+            }
+        }
+        (Value::Integer(l), Value::Float(r)) => evaluate_float(operation, l as f64, r)?,
+        (Value::Float(l), Value::Integer(r)) => evaluate_float(operation, l, r as f64)?,
+        (Value::Float(l), Value::Float(r)) => evaluate_float(operation, l, r)?,
+        (l, r) => {
+            return Err(Error::Internal(format!(
+                "can not calculate exression {} {} {}",
+                l,
+                arithmetic_symbol(operation),
+                r
+            )))
+        }
+    })
+}
+
+fn evaluate_float(operation: &Operation, l: f64, r: f64) -> Result<Value> {
+    Ok(Value::Float(match operation {
+        Operation::Add(_, _) => l + r,
+        Operation::Subtract(_, _) => l - r,
+        Operation::Multiply(_, _) => l * r,
+        Operation::Divide(_, _) | Operation::Modulo(_, _) if r == 0.0 => {
+            return Err(Error::Internal("division by zero".into()))
+        }
+        Operation::Divide(_, _) => l / r,
+        Operation::Modulo(_, _) => l % r,
+        _ => return Err(Error::Internal("unexpected arithmetic operation".into())),
+    }))
+}
+
+fn arithmetic_symbol(operation: &Operation) -> &'static str {
+    match operation {
+        Operation::Add(_, _) => "+",
+        Operation::Subtract(_, _) => "-",
+        Operation::Multiply(_, _) => "*",
+        Operation::Divide(_, _) => "/",
+        _ => "%",
+    }
+}
+
+// 计算不依赖任何行数据的常量表达式，例如 insert 的 values、列的默认值、limit/offset 等
+pub fn evaluate_const_expr(expr: &Expression) -> Result<Value> {
+    evaluate_expr(expr, &Vec::new(), &Vec::new(), &Vec::new(), &Vec::new())
+}
+
+// 计算结果必须是布尔值或者 NULL，NULL 使用 None 表示
+fn evaluate_bool(
+    expr: &Expression,
+    lcols: &Vec<String>,
+    lrows: &Vec<Value>,
+    rcols: &Vec<String>,
+    rrows: &Vec<Value>,
+) -> Result<Option<bool>> {
+    match evaluate_expr(expr, lcols, lrows, rcols, rrows)? {
+        Value::Null => Ok(None),
+        Value::Boolean(b) => Ok(Some(b)),
+        v => Err(Error::Internal(format!("expression {} is not a boolean", v))),
+    }
+}
+
+// LIKE 的模式匹配，% 匹配 0 个或者多个字符，_ 匹配单个字符
+// 遇到 % 时记录下位置，后面匹配失败就回到这里，让 % 多吃掉一个字符再重新匹配
+fn like_match(s: &[char], pattern: &[char]) -> bool {
+    let (mut i, mut j) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        match pattern.get(j) {
+            Some('%') => {
+                star = Some((j, i));
+                j += 1;
+            }
+            Some(c) if *c == '_' || *c == s[i] => {
+                i += 1;
+                j += 1;
+            }
+            _ => match star {
+                Some((sj, si)) => {
+                    star = Some((sj, si + 1));
+                    i = si + 1;
+                    j = sj + 1;
+                }
+                None => return false,
+            },
+        }
     }
+    pattern[j..].iter().all(|c| *c == '%')
 }
@@ -2,7 +2,7 @@ use std::{fmt::Display, iter::Peekable, result, str::Chars};
 
 use crate::{error::{Error, Result}, pppg};
 
-use super::ast::{Consts, Expression};
+use super::ast::{Expression, Operation};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -20,6 +20,8 @@ pub enum Token {
     CloseParen,
     // 逗号 ,
     Comma,
+    // 点号 .，用于 表名.列名
+    Period,
     // 分号 ;
     Semicolon,
     // 星号 & 乘号 *
@@ -30,63 +32,80 @@ pub enum Token {
     Minus,
     // 斜杠 & 除号 /
     Slash,
+    // 取模 %
+    Percent,
+    // 字符串拼接 ||
+    Concat,
     // 等号 =
     Equal,
     // 大于
     GreaterThan,
+    // 大于等于 >=
+    GreaterThanOrEqual,
     // 小于
     LessThan,
+    // 小于等于 <=
+    LessThanOrEqual,
+    // 不等于 != 或者 <>
+    NotEqual,
 }
 
+// 运算符的优先级，数字越大优先级越高
+// NOT 是前缀运算符，IS/IN/BETWEEN/LIKE 与比较运算符同一优先级
+pub const PREC_OR: i32 = 1;
+pub const PREC_AND: i32 = 2;
+pub const PREC_NOT: i32 = 3;
+pub const PREC_COMPARE: i32 = 4;
+
 impl Token {
     // 判断是不是运算符
     pub fn is_operator(&self) -> bool {
-        match self {
-            Token::Plus | Token::Minus | Token::Asterisk | Token::Slash => true,
-            _ => false,
-        }
+        self.precedence() > 0
     }
 
-    // 获取运算符的优先级
+    // 获取运算符的优先级，数字越大优先级越高
     pub fn precedence(&self) -> i32 {
         match self {
-            Token::Plus | Token::Minus => 1,
-            Token::Asterisk | Token::Slash => 2,
+            Token::Keyword(Keyword::Or) => PREC_OR,
+            Token::Keyword(Keyword::And) => PREC_AND,
+            Token::Equal
+            | Token::NotEqual
+            | Token::GreaterThan
+            | Token::GreaterThanOrEqual
+            | Token::LessThan
+            | Token::LessThanOrEqual
+            | Token::Keyword(Keyword::Is)
+            | Token::Keyword(Keyword::In)
+            | Token::Keyword(Keyword::Between)
+            | Token::Keyword(Keyword::Like)
+            | Token::Keyword(Keyword::Not) => PREC_COMPARE,
+            Token::Concat => 5,
+            Token::Plus | Token::Minus => 6,
+            Token::Asterisk | Token::Slash | Token::Percent => 7,
             _ => 0,
         }
     }
 
-    // 根据运算符进行计算
-    pub fn compute_expr(&self, l: Expression, r: Expression) -> Result<Expression> {
-        let val = match (l, r) {
-            (Expression::Consts(c1), Expression::Consts(c2)) => match (c1, c2) {
-                (super::ast::Consts::Integer(l), super::ast::Consts::Integer(r)) => {
-                    self.compute(l as f64, r as f64)?
-                }
-                (super::ast::Consts::Integer(l), super::ast::Consts::Float(r)) => {
-                    self.compute(l as f64, r)?
-                }
-                (super::ast::Consts::Float(l), super::ast::Consts::Integer(r)) => {
-                    self.compute(l, r as f64)?
-                }
-                (super::ast::Consts::Float(l), super::ast::Consts::Float(r)) => {
-                    self.compute(l, r)?
-                }
-                _ => return Err(Error::Parse("cannot compute the expresssion".into())),
-            },
-            _ => return Err(Error::Parse("cannot compute the expresssion".into())),
-        };
-        Ok(Expression::Consts(Consts::Float(val)))
-    }
-
-    fn compute(&self, l: f64, r: f64) -> Result<f64> {
-        Ok(match self {
-            Token::Asterisk => l * r,
-            Token::Plus => l + r,
-            Token::Minus => l - r,
-            Token::Slash => l / r,
-            _ => return Err(Error::Parse("cannot compute the expresssion".into())),
-        })
+    // 根据运算符构建表达式，具体的值在执行的时候才会计算
+    pub fn build_expr(&self, l: Expression, r: Expression) -> Result<Expression> {
+        let (l, r) = (Box::new(l), Box::new(r));
+        Ok(Expression::Operation(match self {
+            Token::Plus => Operation::Add(l, r),
+            Token::Minus => Operation::Subtract(l, r),
+            Token::Asterisk => Operation::Multiply(l, r),
+            Token::Slash => Operation::Divide(l, r),
+            Token::Percent => Operation::Modulo(l, r),
+            Token::Concat => Operation::Concat(l, r),
+            Token::Equal => Operation::Equal(l, r),
+            Token::NotEqual => Operation::NotEqual(l, r),
+            Token::GreaterThan => Operation::GreaterThan(l, r),
+            Token::GreaterThanOrEqual => Operation::GreaterThanOrEqual(l, r),
+            Token::LessThan => Operation::LessThan(l, r),
+            Token::LessThanOrEqual => Operation::LessThanOrEqual(l, r),
+            Token::Keyword(Keyword::And) => Operation::And(l, r),
+            Token::Keyword(Keyword::Or) => Operation::Or(l, r),
+            _ => return Err(Error::Parse(format!("[Parser] Unexpected operator {}", self))),
+        }))
     }
 }
 
@@ -102,14 +121,20 @@ impl Display for Token {
             Token::OpenParen => "(",
             Token::CloseParen => ")",
             Token::Comma => ",",
+            Token::Period => ".",
             Token::Semicolon => ";",
             Token::Asterisk => "*",
             Token::Plus => "+",
             Token::Minus => "-",
             Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Concat => "||",
             Token::Equal => "=",
             Token::GreaterThan => ">",
+            Token::GreaterThanOrEqual => ">=",
             Token::LessThan => "<",
+            Token::LessThanOrEqual => "<=",
+            Token::NotEqual => "!=",
         })
     }
 }
@@ -166,6 +191,46 @@ pub enum Keyword {
     Explain,
     // 删除表
     Drop,
+    // 逻辑运算以及谓词
+    And,
+    Or,
+    Is,
+    In,
+    Between,
+    Like,
+    // 修改表结构
+    Alter,
+    Add,
+    Column,
+    Rename,
+    To,
+    // 清理旧版本数据
+    Vacuum,
+    // 事务的隔离级别
+    Transaction,
+    Isolation,
+    Level,
+    Serializable,
+    Snapshot,
+    Repeatable,
+    Read,
+    // 只读事务和历史版本查询
+    Only,
+    Of,
+    System,
+    Time,
+    // 保存点
+    Savepoint,
+    Release,
+    // 行锁
+    For,
+    Share,
+    // 子查询
+    Exists,
+    Distinct,
+    Nulls,
+    First,
+    Last,
 }
 
 impl Keyword {
@@ -220,6 +285,38 @@ impl Keyword {
             "EXPLAIN" => Keyword::Explain,
             // 删除表实现
             "DROP" => Keyword::Drop,
+            "AND" => Keyword::And,
+            "OR" => Keyword::Or,
+            "IS" => Keyword::Is,
+            "IN" => Keyword::In,
+            "BETWEEN" => Keyword::Between,
+            "LIKE" => Keyword::Like,
+            "ALTER" => Keyword::Alter,
+            "ADD" => Keyword::Add,
+            "COLUMN" => Keyword::Column,
+            "RENAME" => Keyword::Rename,
+            "TO" => Keyword::To,
+            "VACUUM" => Keyword::Vacuum,
+            "TRANSACTION" => Keyword::Transaction,
+            "ISOLATION" => Keyword::Isolation,
+            "LEVEL" => Keyword::Level,
+            "SERIALIZABLE" => Keyword::Serializable,
+            "SNAPSHOT" => Keyword::Snapshot,
+            "REPEATABLE" => Keyword::Repeatable,
+            "READ" => Keyword::Read,
+            "ONLY" => Keyword::Only,
+            "OF" => Keyword::Of,
+            "SYSTEM" => Keyword::System,
+            "TIME" => Keyword::Time,
+            "SAVEPOINT" => Keyword::Savepoint,
+            "RELEASE" => Keyword::Release,
+            "FOR" => Keyword::For,
+            "SHARE" => Keyword::Share,
+            "EXISTS" => Keyword::Exists,
+            "DISTINCT" => Keyword::Distinct,
+            "NULLS" => Keyword::Nulls,
+            "FIRST" => Keyword::First,
+            "LAST" => Keyword::Last,
             _ => return None,
         })
     }
@@ -273,6 +370,38 @@ impl Keyword {
             Keyword::Index => "INDEX",
             Keyword::Explain => "EXPLAIN",
             Keyword::Drop => "DROP",
+            Keyword::And => "AND",
+            Keyword::Or => "OR",
+            Keyword::Is => "IS",
+            Keyword::In => "IN",
+            Keyword::Between => "BETWEEN",
+            Keyword::Like => "LIKE",
+            Keyword::Alter => "ALTER",
+            Keyword::Add => "ADD",
+            Keyword::Column => "COLUMN",
+            Keyword::Rename => "RENAME",
+            Keyword::To => "TO",
+            Keyword::Vacuum => "VACUUM",
+            Keyword::Transaction => "TRANSACTION",
+            Keyword::Isolation => "ISOLATION",
+            Keyword::Level => "LEVEL",
+            Keyword::Serializable => "SERIALIZABLE",
+            Keyword::Snapshot => "SNAPSHOT",
+            Keyword::Repeatable => "REPEATABLE",
+            Keyword::Read => "READ",
+            Keyword::Only => "ONLY",
+            Keyword::Of => "OF",
+            Keyword::System => "SYSTEM",
+            Keyword::Time => "TIME",
+            Keyword::Savepoint => "SAVEPOINT",
+            Keyword::Release => "RELEASE",
+            Keyword::For => "FOR",
+            Keyword::Share => "SHARE",
+            Keyword::Exists => "EXISTS",
+            Keyword::Distinct => "DISTINCT",
+            Keyword::Nulls => "NULLS",
+            Keyword::First => "FIRST",
+            Keyword::Last => "LAST",
         }
     }
 }
@@ -287,7 +416,7 @@ impl Display for Keyword {
 // 词法分析 Lexer 定义
 // 目前支持的 SQL 语法
 // see README.md
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Lexer<'a> {
     // 就是一个迭代器，用于遍历 SQL 文本中的字符
     iter: Peekable<Chars<'a>>,
@@ -423,19 +552,46 @@ impl<'a> Lexer<'a> {
 
     // 扫描符号
     fn scan_symbol(&mut self) -> Option<Token> {
+        // 由两个字符组成的符号需要单独处理
+        match self.iter.peek() {
+            Some('|') => {
+                self.iter.next();
+                return self.next_if(|c| c == '|').map(|_| Token::Concat);
+            }
+            Some('!') => {
+                self.iter.next();
+                return self.next_if(|c| c == '=').map(|_| Token::NotEqual);
+            }
+            Some('>') => {
+                self.iter.next();
+                return Some(match self.next_if(|c| c == '=') {
+                    Some(_) => Token::GreaterThanOrEqual,
+                    None => Token::GreaterThan,
+                });
+            }
+            Some('<') => {
+                self.iter.next();
+                return Some(match self.next_if(|c| c == '=' || c == '>') {
+                    Some('=') => Token::LessThanOrEqual,
+                    Some(_) => Token::NotEqual,
+                    None => Token::LessThan,
+                });
+            }
+            _ => {}
+        }
         self.next_if_token(|c| match c {
             //只有是Token的元词，才会立即返回！
             '*' => Some(Token::Asterisk),
             '(' => Some(Token::OpenParen),
             ')' => Some(Token::CloseParen),
             ',' => Some(Token::Comma),
+            '.' => Some(Token::Period),
             ';' => Some(Token::Semicolon),
             '+' => Some(Token::Plus),
             '-' => Some(Token::Minus),
             '/' => Some(Token::Slash),
+            '%' => Some(Token::Percent),
             '=' => Some(Token::Equal),
-            '>' => Some(Token::GreaterThan),
-            '<' => Some(Token::LessThan),
             _ => None,
         })
     }
@@ -1,11 +1,15 @@
 use std::{collections::BTreeMap, iter::Peekable};
 
-use ast::{Column, Expression, Operation, OrderDirection};
-use lexer::{Keyword, Lexer, Token};
+use ast::{Column, Expression, NullsOrder, Operation, OrderDirection};
+use lexer::{Keyword, Lexer, Token, PREC_NOT, PREC_OR};
 
 use crate::{
     error::{Error, Result},
     ppb, pppb, pppg, pppy,
+    storage::{
+        lock::LockMode,
+        mvcc::{IsolationLevel, Version},
+    },
 };
 
 use super::types::DataType;
@@ -47,6 +51,7 @@ impl<'a> Parser<'a> {
         match self.peek()? {
             Some(Token::Keyword(Keyword::Create)) => self.parse_ddl(),
             Some(Token::Keyword(Keyword::Drop)) => self.parse_ddl(),
+            Some(Token::Keyword(Keyword::Alter)) => self.parse_ddl(),
             Some(Token::Keyword(Keyword::Select)) => self.parse_select(),
             Some(Token::Keyword(Keyword::Insert)) => self.parse_insert(),
             Some(Token::Keyword(Keyword::Update)) => self.parse_update(),
@@ -54,7 +59,13 @@ impl<'a> Parser<'a> {
             Some(Token::Keyword(Keyword::Begin)) => self.parse_transaction(),
             Some(Token::Keyword(Keyword::Commit)) => self.parse_transaction(),
             Some(Token::Keyword(Keyword::Rollback)) => self.parse_transaction(),
+            Some(Token::Keyword(Keyword::Savepoint)) => self.parse_transaction(),
+            Some(Token::Keyword(Keyword::Release)) => self.parse_transaction(),
             Some(Token::Keyword(Keyword::Explain)) => self.parse_explain(),
+            Some(Token::Keyword(Keyword::Vacuum)) => {
+                self.next()?;
+                Ok(ast::Statement::Vacuum)
+            }
             Some(t) => Err(Error::Parse(format!("[Parser] Unexpected token {}", t))),
             None => Err(Error::Parse(format!("[Parser] Unexpected end of input"))),
         }
@@ -64,8 +75,15 @@ impl<'a> Parser<'a> {
     // ddl统一指的是建表删表的过程~
     fn parse_ddl(&mut self) -> Result<ast::Statement> {
         match self.next()? {
-            Token::Keyword(Keyword::Create) => self.parse_ddl_create_table(),
-            Token::Keyword(Keyword::Drop) => self.parse_ddl_drop_table(),
+            Token::Keyword(Keyword::Create) => match self.peek()? {
+                Some(Token::Keyword(Keyword::Index)) => self.parse_ddl_create_index(),
+                _ => self.parse_ddl_create_table(),
+            },
+            Token::Keyword(Keyword::Drop) => match self.peek()? {
+                Some(Token::Keyword(Keyword::Index)) => self.parse_ddl_drop_index(),
+                _ => self.parse_ddl_drop_table(),
+            },
+            Token::Keyword(Keyword::Alter) => self.parse_ddl_alter_table(),
             token => Err(Error::Parse(format!("[Parser] Unexpected token {}", token))),
         }
     }
@@ -106,6 +124,8 @@ impl<'a> Parser<'a> {
                     None
                 }
             },
+            lock: self.parse_lock_clause()?,
+            as_of: self.parse_as_of()?,
         });
         pppy!(format!("解析得到的select总语法树为:{:?}", res));
         res
@@ -175,10 +195,21 @@ impl<'a> Parser<'a> {
         // 表名之后应该是括号
         self.next_expect(Token::OpenParen)?;
 
-        // 解析列信息
+        // 解析列信息，以及表级别的主键约束 PRIMARY KEY (a, b)
         let mut columns = Vec::new();
+        let mut primary_key = Vec::new();
         loop {
-            columns.push(self.parse_ddl_column()?);
+            if self.next_if_token(Token::Keyword(Keyword::Primary)).is_some() {
+                self.next_expect(Token::Keyword(Keyword::Key))?;
+                if !primary_key.is_empty() {
+                    return Err(Error::Parse(
+                        "[Parser] Multiple PRIMARY KEY constraints".into(),
+                    ));
+                }
+                primary_key = self.parse_column_list()?;
+            } else {
+                columns.push(self.parse_ddl_column()?);
+            }
             // 如果没有逗号，列解析完成，跳出
             if self.next_if_token(Token::Comma).is_none() {
                 break;
@@ -189,9 +220,27 @@ impl<'a> Parser<'a> {
         Ok(ast::Statement::CreateTable {
             name: table_name,
             columns,
+            primary_key,
         })
     }
 
+    // 解析括号中的列名列表 (a, b, c)
+    fn parse_column_list(&mut self) -> Result<Vec<String>> {
+        self.next_expect(Token::OpenParen)?;
+        let mut cols = Vec::new();
+        loop {
+            cols.push(self.next_ident()?);
+            match self.next()? {
+                Token::CloseParen => break,
+                Token::Comma => {}
+                token => {
+                    return Err(Error::Parse(format!("[Parser] Unexpected token {}", token)));
+                }
+            }
+        }
+        Ok(cols)
+    }
+
     // 解析列信息
     fn parse_ddl_column(&mut self) -> Result<ast::Column> {
         let mut column = Column {
@@ -245,6 +294,71 @@ impl<'a> Parser<'a> {
         })
     }
 
+    // 解析 Create Index 语句
+    // CREATE INDEX name ON table_name (column [, ...])
+    fn parse_ddl_create_index(&mut self) -> Result<ast::Statement> {
+        self.next_expect(Token::Keyword(Keyword::Index))?;
+        let name = self.next_ident()?;
+        self.next_expect(Token::Keyword(Keyword::On))?;
+        let table_name = self.next_ident()?;
+        Ok(ast::Statement::CreateIndex {
+            name,
+            table_name,
+            columns: self.parse_column_list()?,
+        })
+    }
+
+    // 解析 Drop Index 语句
+    fn parse_ddl_drop_index(&mut self) -> Result<ast::Statement> {
+        self.next_expect(Token::Keyword(Keyword::Index))?;
+        Ok(ast::Statement::DropIndex {
+            name: self.next_ident()?,
+        })
+    }
+
+    // 解析 Alter Table 语句
+    // ALTER TABLE table_name ADD [COLUMN] column_def
+    // ALTER TABLE table_name DROP [COLUMN] column_name
+    // ALTER TABLE table_name RENAME [COLUMN] column_name TO new_column_name
+    // ALTER TABLE table_name RENAME TO new_table_name
+    fn parse_ddl_alter_table(&mut self) -> Result<ast::Statement> {
+        self.next_expect(Token::Keyword(Keyword::Table))?;
+        let name = self.next_ident()?;
+        let action = match self.next()? {
+            Token::Keyword(Keyword::Add) => {
+                self.next_if_token(Token::Keyword(Keyword::Column));
+                let column = self.parse_ddl_column()?;
+                // 已有的行无法满足新主键的唯一性，不允许新增主键列
+                if column.primary_key {
+                    return Err(Error::Parse(format!(
+                        "[Parser] Cannot add primary key column {}",
+                        column.name
+                    )));
+                }
+                ast::AlterTableAction::AddColumn(column)
+            }
+            Token::Keyword(Keyword::Drop) => {
+                self.next_if_token(Token::Keyword(Keyword::Column));
+                ast::AlterTableAction::DropColumn(self.next_ident()?)
+            }
+            Token::Keyword(Keyword::Rename) => {
+                if self.next_if_token(Token::Keyword(Keyword::To)).is_some() {
+                    ast::AlterTableAction::RenameTable(self.next_ident()?)
+                } else {
+                    self.next_if_token(Token::Keyword(Keyword::Column));
+                    let from = self.next_ident()?;
+                    self.next_expect(Token::Keyword(Keyword::To))?;
+                    ast::AlterTableAction::RenameColumn {
+                        from,
+                        to: self.next_ident()?,
+                    }
+                }
+            }
+            token => return Err(Error::Parse(format!("[Parser] Unexpected token {}", token))),
+        };
+        Ok(ast::Statement::AlterTable { name, action })
+    }
+
     // 解析 Update 语句
     fn parse_update(&mut self) -> Result<ast::Statement> {
         self.next_expect(Token::Keyword(Keyword::Update))?; //期望第一个关键字是update！
@@ -294,12 +408,105 @@ impl<'a> Parser<'a> {
     fn parse_transaction(&mut self) -> Result<ast::Statement> {
         Ok(match self.next()? {
             // 匹配接下来的事务的类型~
-            Token::Keyword(Keyword::Begin) => ast::Statement::Begin,
+            Token::Keyword(Keyword::Begin) => {
+                self.next_if_token(Token::Keyword(Keyword::Transaction));
+                let mut isolation = IsolationLevel::Snapshot;
+                if self.next_if_token(Token::Keyword(Keyword::Isolation)).is_some() {
+                    self.next_expect(Token::Keyword(Keyword::Level))?;
+                    isolation = match self.next()? {
+                        Token::Keyword(Keyword::Serializable) => IsolationLevel::Serializable,
+                        Token::Keyword(Keyword::Snapshot) => IsolationLevel::Snapshot,
+                        // 快照隔离本身就满足可重复读
+                        Token::Keyword(Keyword::Repeatable) => {
+                            self.next_expect(Token::Keyword(Keyword::Read))?;
+                            IsolationLevel::Snapshot
+                        }
+                        token => {
+                            return Err(Error::Parse(format!(
+                                "[Parser] Unexpected isolation level {}",
+                                token
+                            )))
+                        }
+                    };
+                }
+                let mut read_only = false;
+                let mut as_of = None;
+                if self.next_if_token(Token::Keyword(Keyword::Read)).is_some() {
+                    self.next_expect(Token::Keyword(Keyword::Only))?;
+                    read_only = true;
+                    if self.next_if_token(Token::Keyword(Keyword::As)).is_some() {
+                        self.next_expect(Token::Keyword(Keyword::Of))?;
+                        self.next_expect(Token::Keyword(Keyword::System))?;
+                        self.next_expect(Token::Keyword(Keyword::Time))?;
+                        as_of = Some(self.next_version()?);
+                    }
+                }
+                ast::Statement::Begin {
+                    isolation,
+                    read_only,
+                    as_of,
+                }
+            }
             Token::Keyword(Keyword::Commit) => ast::Statement::Commit,
-            Token::Keyword(Keyword::Rollback) => ast::Statement::Rollback,
+            Token::Keyword(Keyword::Rollback) => {
+                self.next_if_token(Token::Keyword(Keyword::Transaction));
+                if self.next_if_token(Token::Keyword(Keyword::To)).is_some() {
+                    self.next_if_token(Token::Keyword(Keyword::Savepoint));
+                    ast::Statement::RollbackToSavepoint {
+                        name: self.next_ident()?,
+                    }
+                } else {
+                    ast::Statement::Rollback
+                }
+            }
+            Token::Keyword(Keyword::Savepoint) => ast::Statement::Savepoint {
+                name: self.next_ident()?,
+            },
+            Token::Keyword(Keyword::Release) => {
+                self.next_if_token(Token::Keyword(Keyword::Savepoint));
+                ast::Statement::ReleaseSavepoint {
+                    name: self.next_ident()?,
+                }
+            }
             _ => return Err(Error::Parse("unknown transaction command".into())),
         })
     }
+    // 解析 FOR UPDATE / FOR SHARE
+    fn parse_lock_clause(&mut self) -> Result<Option<LockMode>> {
+        if self.next_if_token(Token::Keyword(Keyword::For)).is_none() {
+            return Ok(None);
+        }
+        Ok(Some(match self.next()? {
+            Token::Keyword(Keyword::Update) => LockMode::Exclusive,
+            Token::Keyword(Keyword::Share) => LockMode::Shared,
+            token => {
+                return Err(Error::Parse(format!(
+                    "[Parser] Unexpected token {}",
+                    token
+                )))
+            }
+        }))
+    }
+
+    // 解析 AS OF <version>
+    fn parse_as_of(&mut self) -> Result<Option<Version>> {
+        if self.next_if_token(Token::Keyword(Keyword::As)).is_none() {
+            return Ok(None);
+        }
+        self.next_expect(Token::Keyword(Keyword::Of))?;
+        Ok(Some(self.next_version()?))
+    }
+
+    // 解析版本号，必须是非负的整数
+    fn next_version(&mut self) -> Result<Version> {
+        match self.next()? {
+            Token::Number(n) => Ok(n.parse::<Version>()?),
+            token => Err(Error::Parse(format!(
+                "[Parser] Expected version number, got token {}",
+                token
+            ))),
+        }
+    }
     // 因为explain语句本身不需要去执行器中执行，直接在Engine里面处理就行了，就和前面的begin、commit类似，不需要在事务中处理！
     // 解析 explain 语句
     fn parse_explain(&mut self) -> Result<ast::Statement> {
@@ -321,12 +528,10 @@ impl<'a> Parser<'a> {
         if self.next_if_token(Token::Keyword(Keyword::Where)).is_none() {
             return Ok(None);
         }
-        pppy!(format!(
-            "解析出来的where子句:{:?}",
-            self.parse_opreation_expr()
-        ));
+        let expr = self.parse_expression()?;
+        pppy!(format!("解析出来的where子句:{:?}", expr));
 
-        Ok(Some(self.parse_opreation_expr()?))
+        Ok(Some(expr))
     }
 
     fn parse_having_clause(&mut self) -> Result<Option<Expression>> {
@@ -336,14 +541,13 @@ impl<'a> Parser<'a> {
         {
             return Ok(None);
         }
-        pppy!(format!(
-            "解析出来的having子句:{:?}",
-            self.parse_opreation_expr()
-        ));
-        Ok(Some(self.parse_opreation_expr()?))
+        let expr = self.parse_expression()?;
+        pppy!(format!("解析出来的having子句:{:?}", expr));
+        Ok(Some(expr))
     }
     // 解析 SQL 语句中的 ORDER BY 子句
-    fn parse_order_clause(&mut self) -> Result<Vec<(String, OrderDirection)>> {
+    // ORDER BY expr [ASC | DESC] [NULLS FIRST | NULLS LAST] [, ...]
+    fn parse_order_clause(&mut self) -> Result<Vec<(Expression, OrderDirection, NullsOrder)>> {
         pppy!("开始解析OrderBy子句......");
         let mut orders = Vec::new();
         if self.next_if_token(Token::Keyword(Keyword::Order)).is_none() {
@@ -352,7 +556,7 @@ impl<'a> Parser<'a> {
         self.next_expect(Token::Keyword(Keyword::By))?;
 
         loop {
-            let col = self.next_ident()?;
+            let expr = self.parse_expression()?;
             let ord = match self.next_if(|t| {
                 matches!(
                     t,
@@ -363,7 +567,20 @@ impl<'a> Parser<'a> {
                 Some(Token::Keyword(Keyword::Desc)) => OrderDirection::Desc,
                 _ => OrderDirection::Asc,
             };
-            orders.push((col, ord));
+            let nulls = match self.next_if_token(Token::Keyword(Keyword::Nulls)) {
+                Some(_) => match self.next()? {
+                    Token::Keyword(Keyword::First) => NullsOrder::First,
+                    Token::Keyword(Keyword::Last) => NullsOrder::Last,
+                    token => {
+                        return Err(Error::Parse(format!(
+                            "[Parser] Expected FIRST or LAST after NULLS, got token {}",
+                            token
+                        )))
+                    }
+                },
+                None => NullsOrder::default_for(&ord),
+            };
+            orders.push((expr, ord, nulls));
 
             if self.next_if_token(Token::Comma).is_none() {
                 break;
@@ -426,19 +643,9 @@ impl<'a> Parser<'a> {
                 ast::JoinType::Cross => None,
                 _ => {
                     self.next_expect(Token::Keyword(Keyword::On))?;
-                    let l = self.parse_expression()?;
-                    pppy!(format!("解析出来的左连接:{:?}", l));
-                    self.next_expect(Token::Equal)?;
-                    let r = self.parse_expression()?;
-                    pppy!(format!("解析出来的右连接:{:?}", r));
-                    let (l, r) = match join_type {
-                        ast::JoinType::Right => (r, l),
-                        _ => (l, r),
-                    };
-
-                    let cond = Operation::Equal(Box::new(l), Box::new(r));
+                    let cond = self.parse_expression()?;
                     pppy!(format!("返回的连接条件:{:?}", cond));
-                    Some(ast::Expression::Operation(cond))
+                    Some(cond)
                 }
             };
 
@@ -454,30 +661,61 @@ impl<'a> Parser<'a> {
         Ok(item)
     }
 
-    fn parse_group_clause(&mut self) -> Result<Option<Expression>> {
+    // GROUP BY expr [, ...]
+    fn parse_group_clause(&mut self) -> Result<Vec<Expression>> {
         pppy!("开始解析Group子句......");
 
+        let mut group_by = Vec::new();
         if self.next_if_token(Token::Keyword(Keyword::Group)).is_none() {
-            return Ok(None);
+            return Ok(group_by);
         }
 
         self.next_expect(Token::Keyword(Keyword::By))?;
-        pppy!(format!("解析出来的Group子句{:?}", self.parse_expression()?));
+        loop {
+            group_by.push(self.parse_expression()?);
+            if self.next_if_token(Token::Comma).is_none() {
+                break;
+            }
+        }
+        pppy!(format!("解析出来的Group子句{:?}", group_by));
 
-        Ok(Some(self.parse_expression()?))
+        Ok(group_by)
     }
     // 得到from后面的表名
     fn parse_from_table_clause(&mut self) -> Result<ast::FromItem> {
         pppy!("开始解析表名......");
-        pppy!(format!(
-            "解析出来的表名{:?}",
-            ast::FromItem::Table {
-                name: self.next_ident()?,
+        // 子查询 (SELECT ...) [AS] alias，必须有别名
+        if self.next_if_token(Token::OpenParen).is_some() {
+            let query = self.parse_subquery()?;
+            self.next_if_token(Token::Keyword(Keyword::As));
+            let alias = match self.next()? {
+                Token::Ident(alias) => alias,
+                token => {
+                    return Err(Error::Parse(format!(
+                        "[Parser] Subquery in FROM must have an alias, got token {}",
+                        token
+                    )))
+                }
+            };
+            return Ok(ast::FromItem::Subquery { query, alias });
+        }
+        let name = self.next_ident()?;
+        // 表的别名，AS 可以省略，AS OF 是历史版本查询
+        let alias = match (self.peek()?, self.peek_second()?) {
+            (Some(Token::Keyword(Keyword::As)), Some(Token::Keyword(Keyword::Of))) => None,
+            (Some(Token::Keyword(Keyword::As)), _) => {
+                self.next()?;
+                Some(self.next_ident()?)
             }
-        ));
-        Ok(ast::FromItem::Table {
-            name: self.next_ident()?,
-        })
+            (Some(Token::Ident(alias)), _) => {
+                self.next()?;
+                Some(alias)
+            }
+            _ => None,
+        };
+        let item = ast::FromItem::Table { name, alias };
+        pppy!(format!("解析出来的表名{:?}", item));
+        Ok(item)
     }
 
     fn parse_from_clause_join(&mut self) -> Result<Option<ast::JoinType>> {
@@ -516,57 +754,40 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_opreation_expr(&mut self) -> Result<ast::Expression> {
-        pppy!("开始解析运算符表达式......");
-        // 类似于 a>1
-        // 这里的left = a
-        let left = self.parse_expression()?;
-        // 下面继续解析right
-        Ok(match self.next()? {
-            // 优先级
-            Token::Equal => {
-                let res = ast::Expression::Operation(Operation::Equal(
-                    Box::new(left),
-                    Box::new(self.compute_math_operator(1)?),
-                ));
-                pppy!("解析出来的运算符表达式:{:?}", res);
-                res
-            }
-            Token::GreaterThan => {
-                let res = ast::Expression::Operation(Operation::GreaterThan(
-                    Box::new(left),
-                    Box::new(self.compute_math_operator(1)?),
-                ));
-                pppy!("解析出来的运算符表达式:{:?}", res);
-                res
-            }
-            Token::LessThan => {
-                let res = ast::Expression::Operation(Operation::LessThan(
-                    Box::new(left),
-                    Box::new(self.compute_math_operator(1)?),
-                ));
-                pppy!("解析出来的运算符表达式:{:?}", res);
-                res
-            }
-            _ => return Err(Error::Internal("Unexpected token".into())),
-        })
+    // 解析表达式，包含算术运算、比较运算以及逻辑运算
+    fn parse_expression(&mut self) -> Result<ast::Expression> {
+        self.compute_math_operator(PREC_OR)
     }
 
-    // 解析表达式
-    fn parse_expression(&mut self) -> Result<ast::Expression> {
+    // 解析单个的表达式，字段、函数、常量、括号以及一元负号
+    fn parse_expression_atom(&mut self) -> Result<ast::Expression> {
         pppb!("开始进行表达式解析......");
         Ok(match self.next()? {
             Token::Ident(ident) => {
                 // 函数，检测(、)
-                // count(col_name)
+                // count(*)、count(DISTINCT expr)、sum(expr)
                 if self.next_if_token(Token::OpenParen).is_some() {
-                    let col_name = self.next_ident()?;
+                    let func = if self.next_if_token(Token::Asterisk).is_some() {
+                        if !ident.eq_ignore_ascii_case("count") {
+                            return Err(Error::Parse(format!(
+                                "[Parser] {}(*) is not supported",
+                                ident
+                            )));
+                        }
+                        ast::Expression::Function(ident, None, false)
+                    } else {
+                        let distinct = self
+                            .next_if_token(Token::Keyword(Keyword::Distinct))
+                            .is_some();
+                        let arg = self.parse_expression()?;
+                        ast::Expression::Function(ident, Some(Box::new(arg)), distinct)
+                    };
                     self.next_expect(Token::CloseParen)?;
-                    pppy!(format!(
-                        "解析得到的表达式,函数{:?}",
-                        ast::Expression::Function(ident.clone(), col_name.clone())
-                    ));
-                    ast::Expression::Function(ident, col_name)
+                    pppy!(format!("解析得到的表达式,函数{:?}", func));
+                    func
+                } else if self.next_if_token(Token::Period).is_some() {
+                    // 表名.列名
+                    ast::Expression::Field(format!("{}.{}", ident, self.next_ident()?))
                 } else {
                     // 列名
                     pppy!(format!(
@@ -590,8 +811,16 @@ impl<'a> Parser<'a> {
                     res
                 }
             }
+            // 标量子查询 (SELECT ...)
+            Token::OpenParen if self.peek()? == Some(Token::Keyword(Keyword::Select)) => {
+                ast::Expression::Subquery(self.parse_subquery()?)
+            }
+            Token::Keyword(Keyword::Exists) => {
+                self.next_expect(Token::OpenParen)?;
+                ast::Expression::Exists(self.parse_subquery()?)
+            }
             Token::OpenParen => {
-                let expr = self.compute_math_operator(1)?;
+                let expr = self.parse_expression()?;
                 self.next_expect(Token::CloseParen)?;
                 let res = expr;
                 pppy!(format!("解析得到的表达式,附点数{:?}", res));
@@ -621,6 +850,14 @@ impl<'a> Parser<'a> {
 
                 res
             }
+            // 一元负号，数字常量直接取反，其他的表达式在执行时再计算
+            Token::Minus => match self.parse_expression_atom()? {
+                ast::Expression::Consts(ast::Consts::Integer(i)) => {
+                    ast::Consts::Integer(-i).into()
+                }
+                ast::Expression::Consts(ast::Consts::Float(f)) => ast::Consts::Float(-f).into(),
+                expr => ast::Expression::Operation(Operation::Negate(Box::new(expr))),
+            },
             t => {
                 return Err(Error::Parse(format!(
                     "[Parser] Unexpected expression token {}",
@@ -630,12 +867,21 @@ impl<'a> Parser<'a> {
         })
     }
 
-    // 计算数学表达式
+    // 解析运算符表达式
     // 5 + 2 + 1
     // 5 + 2 * 1
+    // a > 1 AND NOT b = 2 OR c IS NULL
     fn compute_math_operator(&mut self, min_prec: i32) -> Result<Expression> {
-        pppb!("开始进行数学表达式运算解析,这里是Precedence Climbing算法......");
-        let mut left = self.parse_expression()?;
+        pppb!("开始进行运算符表达式解析,这里是Precedence Climbing算法......");
+        // 前缀的 NOT，优先级比比较运算符低，NOT a = 1 等价于 NOT (a = 1)
+        let mut left = if min_prec <= PREC_NOT
+            && self.next_if_token(Token::Keyword(Keyword::Not)).is_some()
+        {
+            let expr = self.compute_math_operator(PREC_NOT)?;
+            ast::Expression::Operation(Operation::Not(Box::new(expr)))
+        } else {
+            self.parse_expression_atom()?
+        };
         loop {
             // 当前 Token
             //查看下一个Token，因为可能是只有一个数字的情况
@@ -651,20 +897,126 @@ impl<'a> Parser<'a> {
             }
             // 这里很重要的优先级递增，这是 Precedence Climbing 的基本实现
             let next_prec = token.precedence() + 1;
-            self.next()?;
 
-            // 递归计算右边的表达式
-            let right = self.compute_math_operator(next_prec)?;
-            // 计算左右两边的值
-            left = token.compute_expr(left, right)?;
+            left = match token {
+                // a IS [NOT] NULL
+                Token::Keyword(Keyword::Is) => {
+                    self.next()?;
+                    let not = self.next_if_token(Token::Keyword(Keyword::Not)).is_some();
+                    self.next_expect(Token::Keyword(Keyword::Null))?;
+                    let expr = ast::Expression::Operation(Operation::IsNull(Box::new(left)));
+                    Self::negate_if(not, expr)
+                }
+                // a [NOT] IN (...) / a [NOT] BETWEEN x AND y / a [NOT] LIKE 'x'
+                Token::Keyword(Keyword::Not) => {
+                    // 只有后面跟着 IN/BETWEEN/LIKE 的 NOT 才是这里的运算符，否则交给上层处理
+                    match self.peek_second()? {
+                        Some(Token::Keyword(Keyword::In | Keyword::Between | Keyword::Like)) => {}
+                        _ => break,
+                    }
+                    self.next()?;
+                    let expr = self.parse_predicate(left, next_prec)?;
+                    Self::negate_if(true, expr)
+                }
+                Token::Keyword(Keyword::In | Keyword::Between | Keyword::Like) => {
+                    self.parse_predicate(left, next_prec)?
+                }
+                token => {
+                    self.next()?;
+                    // 递归解析右边的表达式
+                    let right = self.compute_math_operator(next_prec)?;
+                    // 组合成新的表达式，作为下一轮的左边
+                    token.build_expr(left, right)?
+                }
+            };
         }
         Ok(left)
     }
 
+    // 解析 IN/BETWEEN/LIKE 谓词，此时下一个 Token 就是对应的关键字
+    fn parse_predicate(&mut self, left: Expression, next_prec: i32) -> Result<Expression> {
+        let left = Box::new(left);
+        Ok(ast::Expression::Operation(match self.next()? {
+            Token::Keyword(Keyword::In) => {
+                self.next_expect(Token::OpenParen)?;
+                // a IN (SELECT ...)
+                if self.peek()? == Some(Token::Keyword(Keyword::Select)) {
+                    return Ok(ast::Expression::Operation(Operation::InSubquery(
+                        left,
+                        self.parse_subquery()?,
+                    )));
+                }
+                let mut list = Vec::new();
+                loop {
+                    list.push(self.parse_expression()?);
+                    if self.next_if_token(Token::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.next_expect(Token::CloseParen)?;
+                Operation::In(left, list)
+            }
+            // a BETWEEN x AND y 等价于 a >= x AND a <= y
+            // 这里的 x 不能把后面的 AND 当作逻辑运算解析进去
+            Token::Keyword(Keyword::Between) => {
+                let low = self.compute_math_operator(next_prec)?;
+                self.next_expect(Token::Keyword(Keyword::And))?;
+                let high = self.compute_math_operator(next_prec)?;
+                Operation::And(
+                    Box::new(ast::Expression::Operation(Operation::GreaterThanOrEqual(
+                        left.clone(),
+                        Box::new(low),
+                    ))),
+                    Box::new(ast::Expression::Operation(Operation::LessThanOrEqual(
+                        left,
+                        Box::new(high),
+                    ))),
+                )
+            }
+            Token::Keyword(Keyword::Like) => {
+                Operation::Like(left, Box::new(self.compute_math_operator(next_prec)?))
+            }
+            token => {
+                return Err(Error::Parse(format!(
+                    "[Parser] Unexpected token {}",
+                    token
+                )))
+            }
+        }))
+    }
+
+    // 解析括号中的子查询，左括号已经被读取了
+    fn parse_subquery(&mut self) -> Result<Box<ast::Statement>> {
+        let stmt = self.parse_select()?;
+        self.next_expect(Token::CloseParen)?;
+        match &stmt {
+            ast::Statement::Select { lock: Some(_), .. } => Err(Error::Parse(
+                "[Parser] FOR UPDATE/FOR SHARE is not allowed in a subquery".into(),
+            )),
+            ast::Statement::Select { as_of: Some(_), .. } => Err(Error::Parse(
+                "[Parser] AS OF is only allowed in the outermost query".into(),
+            )),
+            _ => Ok(Box::new(stmt)),
+        }
+    }
+
+    fn negate_if(not: bool, expr: Expression) -> Expression {
+        if not {
+            ast::Expression::Operation(Operation::Not(Box::new(expr)))
+        } else {
+            expr
+        }
+    }
+
     fn peek(&mut self) -> Result<Option<Token>> {
         self.lexer.peek().cloned().transpose()
     }
 
+    // 查看下下个 Token，不会移动当前的位置
+    fn peek_second(&mut self) -> Result<Option<Token>> {
+        self.lexer.clone().nth(1).transpose()
+    }
+
     // 获取下一个 Token（词法单元）
     fn next(&mut self) -> Result<Token> {
         // self.lexer
@@ -726,7 +1078,9 @@ mod tests {
     use crate::{
         error::Result,
         pppr,
-        sql::parser::ast::{self, Consts, Expression, OrderDirection},
+        sql::parser::ast::{self, Consts, Expression, NullsOrder, Operation, OrderDirection},
+        sql::types::DataType,
+        storage::mvcc::IsolationLevel,
     };
 
     use super::Parser;
@@ -775,6 +1129,113 @@ mod tests {
 
         let stmt3 = Parser::new(sql3).parse();
         assert!(stmt3.is_err());
+
+        // 表级别的联合主键约束
+        let stmt4 = Parser::new("create table tbl2 (a int, b text, primary key (a, b));").parse()?;
+        match stmt4 {
+            ast::Statement::CreateTable {
+                columns,
+                primary_key,
+                ..
+            } => {
+                assert_eq!(columns.len(), 2);
+                assert_eq!(primary_key, vec!["a".to_string(), "b".to_string()]);
+            }
+            _ => unreachable!(),
+        }
+        assert!(Parser::new("create table tbl2 (a int, primary key (a), primary key (a));")
+            .parse()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_index() -> Result<()> {
+        let stmt = Parser::new("create index idx_b on tbl1 (b);").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::CreateIndex {
+                name: "idx_b".into(),
+                table_name: "tbl1".into(),
+                columns: vec!["b".into()],
+            }
+        );
+
+        let stmt = Parser::new("drop index idx_b;").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::DropIndex {
+                name: "idx_b".into()
+            }
+        );
+
+        let stmt = Parser::new("create index idx_bc on tbl1 (b, c);").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::CreateIndex {
+                name: "idx_bc".into(),
+                table_name: "tbl1".into(),
+                columns: vec!["b".into(), "c".into()],
+            }
+        );
+
+        assert!(Parser::new("create index idx_b on tbl1 b;").parse().is_err());
+        assert!(Parser::new("create index on tbl1 (b);").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_alter_table() -> Result<()> {
+        let stmt = Parser::new("alter table tbl1 add column d integer default 0;").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::AlterTable {
+                name: "tbl1".into(),
+                action: ast::AlterTableAction::AddColumn(ast::Column {
+                    name: "d".into(),
+                    datatype: DataType::Integer,
+                    nullable: None,
+                    default: Some(ast::Consts::Integer(0).into()),
+                    primary_key: false,
+                    index: false,
+                }),
+            }
+        );
+
+        let stmt = Parser::new("alter table tbl1 drop c;").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::AlterTable {
+                name: "tbl1".into(),
+                action: ast::AlterTableAction::DropColumn("c".into()),
+            }
+        );
+
+        let stmt = Parser::new("alter table tbl1 rename column b to e;").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::AlterTable {
+                name: "tbl1".into(),
+                action: ast::AlterTableAction::RenameColumn {
+                    from: "b".into(),
+                    to: "e".into()
+                },
+            }
+        );
+
+        let stmt = Parser::new("alter table tbl1 rename to tbl2;").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::AlterTable {
+                name: "tbl1".into(),
+                action: ast::AlterTableAction::RenameTable("tbl2".into()),
+            }
+        );
+
+        assert!(Parser::new("alter table tbl1 add column d integer primary key;")
+            .parse()
+            .is_err());
+        assert!(Parser::new("alter table tbl1 rename b;").parse().is_err());
         Ok(())
     }
 
@@ -833,17 +1294,20 @@ mod tests {
             ast::Statement::Select {
                 select: vec![],
                 from: ast::FromItem::Table {
-                    name: "tbl1".into()
+                    name: "tbl1".into(),
+                    alias: None
                 },
                 where_clause: Some(ast::Expression::Operation(ast::Operation::Equal(
                     Box::new(ast::Expression::Field("a".into())),
                     Box::new(ast::Expression::Consts(Consts::Integer(100)))
                 ))),
-                group_by: None,
+                group_by: vec![],
                 having: None,
                 order_by: vec![],
                 limit: Some(Expression::Consts(Consts::Integer(10))),
                 offset: Some(Expression::Consts(Consts::Integer(20))),
+                lock: None,
+                as_of: None,
             }
         );
 
@@ -854,18 +1318,21 @@ mod tests {
             ast::Statement::Select {
                 select: vec![],
                 from: ast::FromItem::Table {
-                    name: "tbl1".into()
+                    name: "tbl1".into(),
+                    alias: None
                 },
                 where_clause: None,
-                group_by: None,
+                group_by: vec![],
                 order_by: vec![
-                    ("a".to_string(), OrderDirection::Asc),
-                    ("b".to_string(), OrderDirection::Asc),
-                    ("c".to_string(), OrderDirection::Desc),
+                    (ast::Expression::Field("a".into()), OrderDirection::Asc, NullsOrder::First),
+                    (ast::Expression::Field("b".into()), OrderDirection::Asc, NullsOrder::First),
+                    (ast::Expression::Field("c".into()), OrderDirection::Desc, NullsOrder::Last),
                 ],
                 having: None,
                 limit: None,
                 offset: None,
+                lock: None,
+                as_of: None,
             }
         );
 
@@ -880,18 +1347,21 @@ mod tests {
                     (Expression::Field("c".into()), None),
                 ],
                 from: ast::FromItem::Table {
-                    name: "tbl1".into()
+                    name: "tbl1".into(),
+                    alias: None
                 },
                 where_clause: None,
-                group_by: None,
+                group_by: vec![],
                 having: None,
                 order_by: vec![
-                    ("a".to_string(), OrderDirection::Asc),
-                    ("b".to_string(), OrderDirection::Asc),
-                    ("c".to_string(), OrderDirection::Desc),
+                    (ast::Expression::Field("a".into()), OrderDirection::Asc, NullsOrder::First),
+                    (ast::Expression::Field("b".into()), OrderDirection::Asc, NullsOrder::First),
+                    (ast::Expression::Field("c".into()), OrderDirection::Desc, NullsOrder::Last),
                 ],
                 limit: None,
                 offset: None,
+                lock: None,
+                as_of: None,
             }
         );
 
@@ -904,26 +1374,31 @@ mod tests {
                 from: ast::FromItem::Join {
                     left: Box::new(ast::FromItem::Join {
                         left: Box::new(ast::FromItem::Table {
-                            name: "tbl1".into()
+                            name: "tbl1".into(),
+                            alias: None
                         }),
                         right: Box::new(ast::FromItem::Table {
-                            name: "tbl2".into()
+                            name: "tbl2".into(),
+                            alias: None
                         }),
                         join_type: ast::JoinType::Cross,
                         predicate: None
                     }),
                     right: Box::new(ast::FromItem::Table {
-                        name: "tbl3".into()
+                        name: "tbl3".into(),
+                        alias: None
                     }),
                     join_type: ast::JoinType::Cross,
                     predicate: None
                 },
                 where_clause: None,
-                group_by: None,
+                group_by: vec![],
                 having: None,
                 order_by: vec![],
                 limit: None,
                 offset: None,
+                lock: None,
+                as_of: None,
             }
         );
 
@@ -933,15 +1408,37 @@ mod tests {
             stmt,
             ast::Statement::Select {
                 select: vec![
-                    (ast::Expression::Function("count".into(), "a".into()), None),
-                    (ast::Expression::Function("min".into(), "b".into()), None),
-                    (ast::Expression::Function("max".into(), "c".into()), None),
+                    (
+                        ast::Expression::Function(
+                            "count".into(),
+                            Some(Box::new(ast::Expression::Field("a".into()))),
+                            false
+                        ),
+                        None
+                    ),
+                    (
+                        ast::Expression::Function(
+                            "min".into(),
+                            Some(Box::new(ast::Expression::Field("b".into()))),
+                            false
+                        ),
+                        None
+                    ),
+                    (
+                        ast::Expression::Function(
+                            "max".into(),
+                            Some(Box::new(ast::Expression::Field("c".into()))),
+                            false
+                        ),
+                        None
+                    ),
                 ],
                 from: ast::FromItem::Table {
-                    name: "tbl1".into()
+                    name: "tbl1".into(),
+                    alias: None
                 },
                 where_clause: None,
-                group_by: Some(ast::Expression::Field("a".into())),
+                group_by: vec![ast::Expression::Field("a".into())],
                 having: Some(ast::Expression::Operation(ast::Operation::Equal(
                     Box::new(ast::Expression::Field("min".into())),
                     Box::new(ast::Expression::Consts(Consts::Integer(10)))
@@ -949,12 +1446,397 @@ mod tests {
                 order_by: vec![],
                 limit: None,
                 offset: None,
+                lock: None,
+                as_of: None,
             }
         );
 
         Ok(())
     }
 
+    #[test]
+    fn test_parser_arithmetic() -> Result<()> {
+        let field = |name: &str| Box::new(Expression::Field(name.into()));
+        let int = |i: i64| Box::new(Expression::Consts(Consts::Integer(i)));
+
+        let sql = "select a + b * 2, -a, (a - 1) % 3, 'x' || b from tbl1 where a - b > -3;";
+        let stmt = Parser::new(sql).parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::Select {
+                select: vec![
+                    (
+                        Expression::Operation(Operation::Add(
+                            field("a"),
+                            Box::new(Expression::Operation(Operation::Multiply(
+                                field("b"),
+                                int(2)
+                            ))),
+                        )),
+                        None
+                    ),
+                    (Expression::Operation(Operation::Negate(field("a"))), None),
+                    (
+                        Expression::Operation(Operation::Modulo(
+                            Box::new(Expression::Operation(Operation::Subtract(
+                                field("a"),
+                                int(1)
+                            ))),
+                            int(3),
+                        )),
+                        None
+                    ),
+                    (
+                        Expression::Operation(Operation::Concat(
+                            Box::new(Expression::Consts(Consts::String("x".into()))),
+                            field("b"),
+                        )),
+                        None
+                    ),
+                ],
+                from: ast::FromItem::Table {
+                    name: "tbl1".into(),
+                    alias: None
+                },
+                where_clause: Some(Expression::Operation(Operation::GreaterThan(
+                    Box::new(Expression::Operation(Operation::Subtract(
+                        field("a"),
+                        field("b")
+                    ))),
+                    int(-3),
+                ))),
+                group_by: vec![],
+                having: None,
+                order_by: vec![],
+                limit: None,
+                offset: None,
+                lock: None,
+                as_of: None,
+            }
+        );
+
+        // 同一优先级的运算符从左往右结合
+        let stmt = Parser::new("update tbl1 set a = a - 1 - 2;").parse()?;
+        assert_eq!(
+            stmt,
+            ast::Statement::Update {
+                table_name: "tbl1".into(),
+                columns: vec![(
+                    "a".into(),
+                    Expression::Operation(Operation::Subtract(
+                        Box::new(Expression::Operation(Operation::Subtract(
+                            field("a"),
+                            int(1)
+                        ))),
+                        int(2),
+                    ))
+                )]
+                .into_iter()
+                .collect(),
+                where_clause: None,
+            }
+        );
+
+        assert!(Parser::new("select a | b from tbl1;").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_predicate() -> Result<()> {
+        let field = |name: &str| Box::new(Expression::Field(name.into()));
+        let int = |i: i64| Box::new(Expression::Consts(Consts::Integer(i)));
+        let op = |o: Operation| Box::new(Expression::Operation(o));
+
+        // AND 的优先级比 OR 高，NOT 的优先级比比较运算低
+        let sql = "select * from tbl1 where a >= 1 and not b != 2 or c <> 3 and d <= 4;";
+        let stmt = Parser::new(sql).parse()?;
+        let expect = Operation::Or(
+            op(Operation::And(
+                op(Operation::GreaterThanOrEqual(field("a"), int(1))),
+                op(Operation::Not(op(Operation::NotEqual(field("b"), int(2))))),
+            )),
+            op(Operation::And(
+                op(Operation::NotEqual(field("c"), int(3))),
+                op(Operation::LessThanOrEqual(field("d"), int(4))),
+            )),
+        );
+        match stmt {
+            ast::Statement::Select { where_clause, .. } => {
+                assert_eq!(where_clause, Some(Expression::Operation(expect)))
+            }
+            _ => unreachable!(),
+        }
+
+        let sql = "select * from tbl1 where a is null and b is not null and c not in (1, 2) and d between 1 + 1 and 5 and e not like 'a%';";
+        let stmt = Parser::new(sql).parse()?;
+        let expect = Operation::And(
+            op(Operation::And(
+                op(Operation::And(
+                    op(Operation::And(
+                        op(Operation::IsNull(field("a"))),
+                        op(Operation::Not(op(Operation::IsNull(field("b"))))),
+                    )),
+                    op(Operation::Not(op(Operation::In(
+                        field("c"),
+                        vec![*int(1), *int(2)],
+                    )))),
+                )),
+                op(Operation::And(
+                    op(Operation::GreaterThanOrEqual(
+                        field("d"),
+                        op(Operation::Add(int(1), int(1))),
+                    )),
+                    op(Operation::LessThanOrEqual(field("d"), int(5))),
+                )),
+            )),
+            op(Operation::Not(op(Operation::Like(
+                field("e"),
+                Box::new(Expression::Consts(Consts::String("a%".into()))),
+            )))),
+        );
+        match stmt {
+            ast::Statement::Select { where_clause, .. } => {
+                assert_eq!(where_clause, Some(Expression::Operation(expect)))
+            }
+            _ => unreachable!(),
+        }
+
+        // 列定义中 default 后面的 not null 不是运算符
+        assert!(Parser::new("create table t (a int default 1 not null);").parse().is_ok());
+        assert!(Parser::new("select * from tbl1 where a not 1;").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_subquery() -> Result<()> {
+        let field = |name: &str| Box::new(Expression::Field(name.into()));
+        let subquery = |sql: &str| match Parser::new(sql).parse() {
+            Ok(stmt) => Box::new(stmt),
+            Err(err) => panic!("{}", err),
+        };
+
+        let sql = "select a, (select max(b) from t2) as m from t1 where a in (select b from t2) and not exists (select * from t3 where c = a);";
+        match Parser::new(sql).parse()? {
+            ast::Statement::Select {
+                select,
+                where_clause,
+                ..
+            } => {
+                assert_eq!(
+                    select[1],
+                    (
+                        Expression::Subquery(subquery("select max(b) from t2;")),
+                        Some("m".into())
+                    )
+                );
+                assert_eq!(
+                    where_clause,
+                    Some(Expression::Operation(Operation::And(
+                        Box::new(Expression::Operation(Operation::InSubquery(
+                            field("a"),
+                            subquery("select b from t2;"),
+                        ))),
+                        Box::new(Expression::Operation(Operation::Not(Box::new(
+                            Expression::Exists(subquery("select * from t3 where c = a;"))
+                        )))),
+                    )))
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        // FROM 中的子查询，AS 可以省略
+        let sql = "select * from (select a from t1) as x join (select b from t2) y on a = b;";
+        match Parser::new(sql).parse()? {
+            ast::Statement::Select { from, .. } => assert_eq!(
+                from,
+                ast::FromItem::Join {
+                    left: Box::new(ast::FromItem::Subquery {
+                        query: subquery("select a from t1;"),
+                        alias: "x".into(),
+                    }),
+                    right: Box::new(ast::FromItem::Subquery {
+                        query: subquery("select b from t2;"),
+                        alias: "y".into(),
+                    }),
+                    join_type: ast::JoinType::Inner,
+                    predicate: Some(Expression::Operation(Operation::Equal(
+                        field("a"),
+                        field("b")
+                    ))),
+                }
+            ),
+            _ => unreachable!(),
+        }
+
+        assert!(Parser::new("select * from (select a from t1);").parse().is_err());
+        assert!(Parser::new("select * from t1 where a in (select a from t2 for update);")
+            .parse()
+            .is_err());
+        assert!(Parser::new("select * from t1 where exists (select a from t2 as of 1);")
+            .parse()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_alias() -> Result<()> {
+        let field = |name: &str| Box::new(Expression::Field(name.into()));
+        let table = |name: &str, alias: Option<&str>| {
+            Box::new(ast::FromItem::Table {
+                name: name.into(),
+                alias: alias.map(|a| a.into()),
+            })
+        };
+
+        let sql = "select u.id, o.amount, count(o.id) from users as u join orders o on u.id = o.user_id order by u.id;";
+        match Parser::new(sql).parse()? {
+            ast::Statement::Select {
+                select,
+                from,
+                order_by,
+                ..
+            } => {
+                assert_eq!(
+                    select,
+                    vec![
+                        (Expression::Field("u.id".into()), None),
+                        (Expression::Field("o.amount".into()), None),
+                        (
+                            Expression::Function(
+                                "count".into(),
+                                Some(Box::new(Expression::Field("o.id".into()))),
+                                false
+                            ),
+                            None
+                        ),
+                    ]
+                );
+                assert_eq!(
+                    from,
+                    ast::FromItem::Join {
+                        left: table("users", Some("u")),
+                        right: table("orders", Some("o")),
+                        join_type: ast::JoinType::Inner,
+                        predicate: Some(Expression::Operation(Operation::Equal(
+                            field("u.id"),
+                            field("o.user_id")
+                        ))),
+                    }
+                );
+                assert_eq!(
+                    order_by,
+                    vec![(
+                        Expression::Field("u.id".into()),
+                        OrderDirection::Asc,
+                        NullsOrder::First
+                    )]
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        // AS OF 不是别名
+        match Parser::new("select * from t as of 1;").parse()? {
+            ast::Statement::Select { from, as_of, .. } => {
+                assert_eq!(from, *table("t", None));
+                assert!(as_of.is_some());
+            }
+            _ => unreachable!(),
+        }
+
+        assert!(Parser::new("select a. from t;").parse().is_err());
+        assert!(Parser::new("select * from t as;").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_aggregate() -> Result<()> {
+        let field = |name: &str| Box::new(Expression::Field(name.into()));
+        let sql = "select count(*), count(distinct a), sum(b * c) from t group by a, b % 2 having max(c) > 1 order by count(*) desc;";
+        match Parser::new(sql).parse()? {
+            ast::Statement::Select {
+                select,
+                group_by,
+                having,
+                order_by,
+                ..
+            } => {
+                let count_all = Expression::Function("count".into(), None, false);
+                assert_eq!(
+                    select,
+                    vec![
+                        (count_all.clone(), None),
+                        (Expression::Function("count".into(), Some(field("a")), true), None),
+                        (
+                            Expression::Function(
+                                "sum".into(),
+                                Some(Box::new(Expression::Operation(Operation::Multiply(
+                                    field("b"),
+                                    field("c")
+                                )))),
+                                false
+                            ),
+                            None
+                        ),
+                    ]
+                );
+                assert_eq!(
+                    group_by,
+                    vec![
+                        Expression::Field("a".into()),
+                        Expression::Operation(Operation::Modulo(
+                            field("b"),
+                            Box::new(Expression::Consts(Consts::Integer(2)))
+                        )),
+                    ]
+                );
+                assert_eq!(
+                    having,
+                    Some(Expression::Operation(Operation::GreaterThan(
+                        Box::new(Expression::Function("max".into(), Some(field("c")), false)),
+                        Box::new(Expression::Consts(Consts::Integer(1)))
+                    )))
+                );
+                assert_eq!(order_by, vec![(count_all, OrderDirection::Desc, NullsOrder::Last)]);
+            }
+            _ => unreachable!(),
+        }
+
+        assert!(Parser::new("select sum(*) from t;").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_order_by() -> Result<()> {
+        let field = |name: &str| Box::new(Expression::Field(name.into()));
+        let sql = "select a, b from t order by a * b desc nulls first, 2, c nulls last;";
+        match Parser::new(sql).parse()? {
+            ast::Statement::Select { order_by, .. } => {
+                assert_eq!(
+                    order_by,
+                    vec![
+                        (
+                            Expression::Operation(Operation::Multiply(field("a"), field("b"))),
+                            OrderDirection::Desc,
+                            NullsOrder::First
+                        ),
+                        (
+                            Expression::Consts(Consts::Integer(2)),
+                            OrderDirection::Asc,
+                            NullsOrder::First
+                        ),
+                        (Expression::Field("c".into()), OrderDirection::Asc, NullsOrder::Last),
+                    ]
+                );
+            }
+            _ => unreachable!(),
+        }
+
+        assert!(Parser::new("select a from t order by a nulls;").parse().is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_parser_update() -> Result<()> {
         let sql = "update tabl set a = 1, b = 2.0 where c = 'a';";
@@ -978,4 +1860,81 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parser_begin() -> Result<()> {
+        let begin = |sql| Parser::new(sql).parse();
+        let snapshot = ast::Statement::Begin {
+            isolation: IsolationLevel::Snapshot,
+            read_only: false,
+            as_of: None,
+        };
+        let serializable = ast::Statement::Begin {
+            isolation: IsolationLevel::Serializable,
+            read_only: false,
+            as_of: None,
+        };
+        assert_eq!(begin("begin;")?, snapshot);
+        assert_eq!(begin("begin transaction;")?, snapshot);
+        assert_eq!(begin("begin isolation level snapshot;")?, snapshot);
+        assert_eq!(begin("begin transaction isolation level repeatable read;")?, snapshot);
+        assert_eq!(begin("BEGIN ISOLATION LEVEL SERIALIZABLE;")?, serializable);
+        assert!(begin("begin isolation level read committed;").is_err());
+        assert!(begin("begin isolation serializable;").is_err());
+
+        // 只读事务和历史快照
+        assert_eq!(
+            begin("begin read only;")?,
+            ast::Statement::Begin {
+                isolation: IsolationLevel::Snapshot,
+                read_only: true,
+                as_of: None,
+            }
+        );
+        assert_eq!(
+            begin("BEGIN TRANSACTION READ ONLY AS OF SYSTEM TIME 5;")?,
+            ast::Statement::Begin {
+                isolation: IsolationLevel::Snapshot,
+                read_only: true,
+                as_of: Some(5),
+            }
+        );
+        assert!(begin("begin read only as of system time -1;").is_err());
+        assert!(begin("begin read only as of 5;").is_err());
+        assert!(begin("begin read;").is_err());
+
+        match Parser::new("select * from t where a = 1 limit 1 as of 3;").parse()? {
+            ast::Statement::Select { as_of, limit, .. } => {
+                assert_eq!(as_of, Some(3));
+                assert!(limit.is_some());
+            }
+            _ => unreachable!(),
+        }
+        assert!(Parser::new("select * from t as of 1.5;").parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parser_savepoint() -> Result<()> {
+        let parse = |sql| Parser::new(sql).parse();
+        let name = || "sp1".to_string();
+        assert_eq!(parse("savepoint sp1;")?, ast::Statement::Savepoint { name: name() });
+        assert_eq!(
+            parse("rollback to sp1;")?,
+            ast::Statement::RollbackToSavepoint { name: name() }
+        );
+        assert_eq!(
+            parse("ROLLBACK TRANSACTION TO SAVEPOINT sp1;")?,
+            ast::Statement::RollbackToSavepoint { name: name() }
+        );
+        assert_eq!(parse("rollback transaction;")?, ast::Statement::Rollback);
+        assert_eq!(
+            parse("release savepoint sp1;")?,
+            ast::Statement::ReleaseSavepoint { name: name() }
+        );
+        assert_eq!(parse("release sp1;")?, ast::Statement::ReleaseSavepoint { name: name() });
+        assert!(parse("savepoint;").is_err());
+        assert!(parse("rollback to;").is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,343 @@
+use std::collections::HashSet;
+
+use crate::{
+    error::{Error, Result},
+    sql::{
+        engine::Transaction,
+        parser::ast::{Consts, Expression, FromItem, JoinType, Operation, Statement},
+    },
+};
+
+// 名字解析：检查语句中引用的列是否存在、有没有歧义，并换成执行器输出的列名
+// FROM 中只有一张表（或者一个子查询）时，执行器输出的就是列名；有 Join 时输出 表名.列名（有别名时用别名）
+// 子查询中引用的外层查询的列换成 OuterField，执行的时候再替换成外层当前行的值
+pub struct Binder<'a, T: Transaction> {
+    txn: &'a T,
+    // 从外到内每一层查询能看到的列
+    scopes: Vec<Scope>,
+}
+
+// 一层查询能看到的列：(表名或者别名, 列名, 执行器输出的列名)
+// 表不存在时不知道有哪些列，unknown 为 true，找不到的列不报错，执行的时候再报表不存在
+#[derive(Default)]
+struct Scope {
+    columns: Vec<(String, String, String)>,
+    unknown: bool,
+}
+
+impl Scope {
+    // name 可以是 列名，也可以是 表名.列名，这一层中找不到时返回 None
+    fn lookup(&self, name: &str) -> Result<Option<String>> {
+        let (table, column) = match name.split_once('.') {
+            Some((table, column)) => (Some(table), column),
+            None => (None, name),
+        };
+        let mut found = self
+            .columns
+            .iter()
+            .filter(|(t, c, _)| c == column && table.is_none_or(|table| table == t));
+        match (found.next(), found.next()) {
+            (Some((_, _, label)), None) => Ok(Some(label.clone())),
+            (Some(_), Some(_)) => Err(Error::Internal(format!(
+                "column reference {} is ambiguous",
+                name
+            ))),
+            (None, _) => Ok(None),
+        }
+    }
+
+    fn tables(&self) -> HashSet<&String> {
+        self.columns.iter().map(|(t, _, _)| t).collect()
+    }
+}
+
+impl<'a, T: Transaction> Binder<'a, T> {
+    pub fn new(txn: &'a T) -> Self {
+        Self {
+            txn,
+            scopes: Vec::new(),
+        }
+    }
+
+    pub fn bind(&mut self, stmt: &mut Statement) -> Result<()> {
+        match stmt {
+            Statement::Select { .. } => {
+                self.bind_select(stmt)?;
+            }
+            // 更新和删除只有一张表，列名不加表名
+            Statement::Update {
+                table_name,
+                columns,
+                where_clause,
+            } => {
+                self.scopes.push(self.table_scope(table_name, None, false)?);
+                for expr in columns.values_mut().chain(where_clause.iter_mut()) {
+                    self.bind_expr(expr, true)?;
+                }
+                self.scopes.pop();
+            }
+            Statement::Delete {
+                table_name,
+                where_clause,
+            } => {
+                self.scopes.push(self.table_scope(table_name, None, false)?);
+                if let Some(expr) = where_clause {
+                    self.bind_expr(expr, true)?;
+                }
+                self.scopes.pop();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // 解析一个查询，返回查询输出的列名（去掉了表名），用作 FROM 中子查询的列名，不知道有哪些列时返回 None
+    fn bind_select(&mut self, stmt: &mut Statement) -> Result<Option<Vec<String>>> {
+        let (select, from, where_clause, group_by, having, order_by, limit, offset) = match stmt {
+            Statement::Select {
+                select,
+                from,
+                where_clause,
+                group_by,
+                having,
+                order_by,
+                limit,
+                offset,
+                ..
+            } => (
+                select,
+                from,
+                where_clause,
+                group_by,
+                having,
+                order_by,
+                limit,
+                offset,
+            ),
+            _ => return Err(Error::Internal("unexpected statement in subquery".into())),
+        };
+
+        let qualified = matches!(from, FromItem::Join { .. });
+        let scope = self.bind_from(from, qualified)?;
+        let mut output = Vec::new();
+        let unknown = select.is_empty() && scope.unknown;
+        if select.is_empty() {
+            output.extend(scope.columns.iter().map(|(_, c, _)| c.clone()));
+        }
+        self.scopes.push(scope);
+
+        for (expr, alias) in select.iter_mut() {
+            self.bind_expr(expr, true)?;
+            output.push(match (alias, &*expr) {
+                (Some(alias), _) => alias.clone(),
+                (None, Expression::Function(name, _, _)) => name.clone(),
+                (None, Expression::Field(label)) => column_name(label).to_string(),
+                (None, expr) => expr.to_string(),
+            });
+        }
+        for expr in where_clause
+            .iter_mut()
+            .chain(group_by.iter_mut())
+            .chain(limit.iter_mut())
+            .chain(offset.iter_mut())
+        {
+            self.bind_expr(expr, true)?;
+        }
+        // HAVING 和 ORDER BY 还可以引用聚集的结果和 SELECT 中的别名，这一层中找不到的列原样保留
+        if let Some(expr) = having {
+            self.bind_expr(expr, false)?;
+        }
+        for (expr, _, _) in order_by.iter_mut() {
+            match self.order_target(expr, select)? {
+                Some(target) => *expr = target,
+                None => self.bind_expr(expr, false)?,
+            }
+        }
+
+        self.scopes.pop();
+        Ok((!unknown).then_some(output))
+    }
+
+    // ORDER BY 中的整数表示 SELECT 中的第几列（从 1 开始），单独的名字可以是 SELECT 中的别名或者聚集函数的名字
+    // 返回对应的 SELECT 中已经解析过的表达式，都不是的时候返回 None
+    fn order_target(
+        &self,
+        expr: &Expression,
+        select: &[(Expression, Option<String>)],
+    ) -> Result<Option<Expression>> {
+        Ok(match expr {
+            Expression::Consts(Consts::Integer(n)) => {
+                let scope = self.scopes.last();
+                // SELECT * 时按照 FROM 中所有的列计算位置，不知道有哪些列时原样保留
+                if select.is_empty() && scope.is_some_and(|s| s.unknown) {
+                    return Ok(None);
+                }
+                let target = match usize::try_from(*n - 1) {
+                    Ok(i) if select.is_empty() => scope
+                        .and_then(|s| s.columns.get(i))
+                        .map(|(_, _, label)| Expression::Field(label.clone())),
+                    Ok(i) => select.get(i).map(|(expr, _)| expr.clone()),
+                    Err(_) => None,
+                };
+                match target {
+                    Some(target) => Some(target),
+                    None => {
+                        return Err(Error::Internal(format!(
+                            "ORDER BY position {} is not in select list",
+                            n
+                        )))
+                    }
+                }
+            }
+            Expression::Field(name) => select
+                .iter()
+                .find(|(expr, alias)| match (alias, expr) {
+                    (Some(alias), _) => alias == name,
+                    (None, Expression::Function(func, _, _)) => func == name,
+                    _ => false,
+                })
+                .map(|(expr, _)| expr.clone()),
+            _ => None,
+        })
+    }
+
+    // 解析 FROM，返回 FROM 中能看到的所有列
+    fn bind_from(&mut self, item: &mut FromItem, qualified: bool) -> Result<Scope> {
+        Ok(match item {
+            FromItem::Table { name, alias } => {
+                self.table_scope(name, alias.as_deref(), qualified)?
+            }
+            FromItem::Join {
+                left,
+                right,
+                join_type,
+                predicate,
+            } => {
+                let left = self.bind_from(left, qualified)?;
+                let right = self.bind_from(right, qualified)?;
+                if let Some(table) = left.tables().intersection(&right.tables()).next() {
+                    return Err(Error::Internal(format!(
+                        "table name {} specified more than once",
+                        table
+                    )));
+                }
+                // right join 在执行的时候交换了左右两边，输出的列也是右边的在前
+                let (first, second) = match join_type {
+                    JoinType::Right => (right, left),
+                    _ => (left, right),
+                };
+                let mut scope = first;
+                scope.columns.extend(second.columns);
+                scope.unknown |= second.unknown;
+                if let Some(predicate) = predicate {
+                    self.scopes.push(scope);
+                    self.bind_expr(predicate, true)?;
+                    scope = self.scopes.pop().unwrap_or_default();
+                }
+                scope
+            }
+            // FROM 中的子查询看不到同一层的其他表，用一个空的作用域占住这一层
+            FromItem::Subquery { query, alias } => {
+                self.scopes.push(Scope::default());
+                let output = self.bind_select(query)?;
+                self.scopes.pop();
+                Scope {
+                    unknown: output.is_none(),
+                    columns: output
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|c| {
+                            let label = if qualified {
+                                format!("{}.{}", alias, c)
+                            } else {
+                                c.clone()
+                            };
+                            (alias.clone(), c, label)
+                        })
+                        .collect(),
+                }
+            }
+        })
+    }
+
+    fn table_scope(&self, name: &str, alias: Option<&str>, qualified: bool) -> Result<Scope> {
+        let qualifier = alias.unwrap_or(name).to_string();
+        let table = match self.txn.get_table(name.to_string())? {
+            Some(table) => table,
+            None => {
+                return Ok(Scope {
+                    columns: Vec::new(),
+                    unknown: true,
+                })
+            }
+        };
+        Ok(Scope {
+            unknown: false,
+            columns: table
+                .columns
+                .into_iter()
+                .map(|c| {
+                    let label = if qualified {
+                        format!("{}.{}", qualifier, c.name)
+                    } else {
+                        c.name.clone()
+                    };
+                    (qualifier.clone(), c.name, label)
+                })
+                .collect(),
+        })
+    }
+
+    // strict 为 false 时，找不到的列原样保留，交给执行器处理
+    fn bind_expr(&mut self, expr: &mut Expression, strict: bool) -> Result<()> {
+        expr.transform(&mut |e| {
+            match e {
+                Expression::Field(name) => {
+                    if let Some(bound) = self.resolve(name, strict)? {
+                        *e = bound;
+                    }
+                }
+                Expression::Subquery(query)
+                | Expression::Exists(query)
+                | Expression::Operation(Operation::InSubquery(_, query)) => {
+                    self.bind_select(query)?;
+                }
+                _ => {}
+            }
+            Ok(())
+        })
+    }
+
+    // 从内到外逐层查找列，在外层找到时换成 OuterField，depth 为往外的层数
+    fn resolve(&self, name: &str, strict: bool) -> Result<Option<Expression>> {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(label) = scope.lookup(name)? {
+                return Ok(Some(match depth {
+                    0 => Expression::Field(label),
+                    _ => Expression::OuterField(depth, label),
+                }));
+            }
+            if scope.unknown {
+                return Ok(None);
+            }
+        }
+        if strict {
+            return Err(Error::Internal(format!("column {} is not in table", name)));
+        }
+        Ok(None)
+    }
+}
+
+// 执行器输出的 表名.列名 中的列名，不是这种形式的（比如表达式）原样返回
+pub fn column_name(label: &str) -> &str {
+    let is_ident = |s: &str| {
+        s.chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+    };
+    match label.split_once('.') {
+        Some((table, column)) if is_ident(table) && is_ident(column) => column,
+        _ => label,
+    }
+}
@@ -1,17 +1,19 @@
-use std::{collections::BTreeMap, fmt::Display};
+use std::{collections::BTreeMap, fmt::Display, ops::Bound};
 
+pub use binder::column_name;
 use planner::Planner;
 
-use crate::error::Result;
+use crate::{error::Result, storage::lock::LockMode};
 
 use super::{
     engine::Transaction,
     executor::{Executor, ResultSet},
-    parser::ast::{self, Expression, OrderDirection},
-    schema::Table,
+    parser::ast::{self, Expression, NullsOrder, OrderDirection},
+    schema::{Index, Table, TableChange},
     types::Value,
 };
 
+mod binder;
 mod planner;
 
 // 执行节点
@@ -27,6 +29,23 @@ pub enum Node {
         name: String,
     },
 
+    // 创建索引
+    CreateIndex {
+        table_name: String,
+        index: Index,
+    },
+
+    // 删除索引
+    DropIndex {
+        name: String,
+    },
+
+    // 修改表结构
+    AlterTable {
+        table_name: String,
+        change: TableChange,
+    },
+
     // 插入数据
     Insert {
         table_name: String,
@@ -35,8 +54,10 @@ pub enum Node {
     },
 
     // 扫描节点
+    // alias 不为空时输出的列名为 alias.列名，用来区分 Join 中不同表的同名列，下面几种扫描节点也一样
     Scan {
         table_name: String,
+        alias: Option<String>,
         filter: Option<Expression>,
     },
 
@@ -56,7 +77,14 @@ pub enum Node {
     // 排序节点
     Order {
         source: Box<Node>,
-        order_by: Vec<(String, OrderDirection)>,
+        order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
+    },
+
+    // 只保留排序之后的前 limit 行，用于 ORDER BY ... LIMIT
+    TopN {
+        source: Box<Node>,
+        order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
+        limit: usize,
     },
 
     // Limit 节点
@@ -71,12 +99,26 @@ pub enum Node {
         offset: usize,
     },
 
+    // 行锁节点，给输出的每一行加锁
+    Lock {
+        source: Box<Node>,
+        table_name: String,
+        mode: LockMode,
+    },
+
     // 投影节点
     Projection {
         source: Box<Node>,
         exprs: Vec<(Expression, Option<String>)>,
     },
 
+    // FROM 中的子查询，输出的列名去掉子查询内部的表名，qualified 时加上子查询的别名
+    SubqueryScan {
+        source: Box<Node>,
+        alias: String,
+        qualified: bool,
+    },
+
     // 嵌套循环 Join 节点
     NestedLoopJoin {
         left: Box<Node>,
@@ -93,11 +135,11 @@ pub enum Node {
         outer: bool,
     },
 
-    // Agg 聚集节点——实现各大聚集函数
+    // Agg 聚集节点——实现各大聚集函数，按照 group_by 中各个表达式的值分组
     Aggregate {
         source: Box<Node>,
         exprs: Vec<(Expression, Option<String>)>,
-        group_by: Option<Expression>,
+        group_by: Vec<Expression>,
     },
 
     // 过滤节点
@@ -106,17 +148,27 @@ pub enum Node {
         predicate: Expression,
     },
 
-    // 索引查询节点
+    // 索引查询节点，values 可以只包含索引前面几列的值
     IndexScan {
         table_name: String,
-        field: String,
-        value: Value,
+        alias: Option<String>,
+        index: Index,
+        values: Vec<Value>,
     },
 
-    // 主键查询节点
+    // 索引范围查询节点，按照索引列的值从小到大输出
+    IndexRangeScan {
+        table_name: String,
+        alias: Option<String>,
+        index: Index,
+        range: (Bound<Vec<Value>>, Bound<Vec<Value>>),
+    },
+
+    // 主键查询节点，values 可以只包含联合主键前面几列的值
     PrimaryKeyScan {
         table_name: String,
-        value: Value,
+        alias: Option<String>,
+        values: Vec<Value>,
     },
 }
 
@@ -156,6 +208,26 @@ impl Node {
             Node::DropTable { name } => {
                 write!(f, "Drop Table {}", name)
             }
+            Node::CreateIndex { table_name, index } => {
+                write!(f, "Create Index {} On {}({})", index.name, table_name, index.columns.join(", "))
+            }
+            Node::DropIndex { name } => {
+                write!(f, "Drop Index {}", name)
+            }
+            Node::AlterTable { table_name, change } => match change {
+                TableChange::AddColumn { column, index: _ } => {
+                    write!(f, "Alter Table {} Add Column {}", table_name, column.name)
+                }
+                TableChange::DropColumn(name) => {
+                    write!(f, "Alter Table {} Drop Column {}", table_name, name)
+                }
+                TableChange::RenameColumn { from, to } => {
+                    write!(f, "Alter Table {} Rename Column {} To {}", table_name, from, to)
+                }
+                TableChange::RenameTable(name) => {
+                    write!(f, "Alter Table {} Rename To {}", table_name, name)
+                }
+            },
             Node::Insert {
                 table_name,
                 columns: _,
@@ -163,8 +235,13 @@ impl Node {
             } => {
                 write!(f, "Insert Into {}", table_name)
             }
-            Node::Scan { table_name, filter } => {
+            Node::Scan {
+                table_name,
+                alias,
+                filter,
+            } => {
                 write!(f, "Seq Scan On {}", table_name)?;
+                write_alias(f, table_name, alias)?;
                 if let Some(filter) = filter {
                     write!(f, " ({})", filter)?;
                 }
@@ -183,22 +260,15 @@ impl Node {
                 (*source).format(f, &prefix, false)
             }
             Node::Order { source, order_by } => {
-                let desc = order_by
-                    .iter()
-                    .map(|c| {
-                        format!(
-                            "{} {}",
-                            c.0,
-                            if c.1 == OrderDirection::Asc {
-                                "asc"
-                            } else {
-                                "desc"
-                            }
-                        )
-                    })
-                    .collect::<Vec<_>>()
-                    .join(",");
-                write!(f, "Order By ({})", desc)?;
+                write!(f, "Order By ({})", format_order_by(order_by))?;
+                (*source).format(f, &prefix, false)
+            }
+            Node::TopN {
+                source,
+                order_by,
+                limit,
+            } => {
+                write!(f, "Top {} Order By ({})", limit, format_order_by(order_by))?;
                 (*source).format(f, &prefix, false)
             }
             Node::Limit { source, limit } => {
@@ -209,6 +279,17 @@ impl Node {
                 write!(f, "Offset {}", offset)?;
                 (*source).format(f, &prefix, false)
             }
+            Node::Lock {
+                source,
+                table_name,
+                mode,
+            } => {
+                match mode {
+                    LockMode::Exclusive => write!(f, "Lock Rows On {} For Update", table_name)?,
+                    LockMode::Shared => write!(f, "Lock Rows On {} For Share", table_name)?,
+                }
+                (*source).format(f, &prefix, false)
+            }
             Node::Projection { source, exprs } => {
                 let desc = exprs
                     .iter()
@@ -228,6 +309,14 @@ impl Node {
                 write!(f, "Projection ({})", desc)?;
                 (*source).format(f, &prefix, false)
             }
+            Node::SubqueryScan {
+                source,
+                alias,
+                qualified: _,
+            } => {
+                write!(f, "Subquery Scan On {}", alias)?;
+                (*source).format(f, &prefix, false)
+            }
             Node::NestedLoopJoin {
                 left,
                 right,
@@ -257,7 +346,7 @@ impl Node {
             Node::Aggregate {
                 source,
                 exprs,
-                group_by: _,
+                group_by,
             } => {
                 let desc = exprs
                     .iter()
@@ -275,6 +364,14 @@ impl Node {
                     .collect::<Vec<_>>()
                     .join(", ");
                 write!(f, "Aggregate ({})", desc)?;
+                if !group_by.is_empty() {
+                    let group_by = group_by
+                        .iter()
+                        .map(|e| e.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    write!(f, " Group By ({})", group_by)?;
+                }
                 (*source).format(f, &prefix, false)
             }
             Node::Filter { source, predicate } => {
@@ -283,18 +380,85 @@ impl Node {
             }
             Node::IndexScan {
                 table_name,
-                field,
-                value: _,
+                alias,
+                index,
+                values: _,
+            } => {
+                write!(f, "Index Scan On {}.{}", table_name, index_columns(index))?;
+                write_alias(f, table_name, alias)
+            }
+            Node::IndexRangeScan {
+                table_name,
+                alias,
+                index,
+                range: _,
             } => {
-                write!(f, "Index Scan On {}.{}", table_name, field)
+                write!(f, "Index Range Scan On {}.{}", table_name, index_columns(index))?;
+                write_alias(f, table_name, alias)
             }
-            Node::PrimaryKeyScan { table_name, value } => {
-                write!(f, "Primary Key Scan On {}({})", table_name, value)
+            Node::PrimaryKeyScan {
+                table_name,
+                alias,
+                values,
+            } => {
+                let desc = values
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "Primary Key Scan On {}({})", table_name, desc)?;
+                write_alias(f, table_name, alias)
             }
         }
     }
 }
 
+// 表的别名和表名不一样时输出别名
+fn write_alias(
+    f: &mut std::fmt::Formatter<'_>,
+    table_name: &str,
+    alias: &Option<String>,
+) -> std::fmt::Result {
+    match alias {
+        Some(alias) if alias != table_name => write!(f, " As {}", alias),
+        _ => Ok(()),
+    }
+}
+
+// 排序的描述，例如 a asc,(b * c) desc，只有和默认不一样的时候才显示 NULL 的位置
+fn format_order_by(order_by: &[(Expression, OrderDirection, NullsOrder)]) -> String {
+    order_by
+        .iter()
+        .map(|(expr, direction, nulls)| {
+            let mut s = format!(
+                "{} {}",
+                expr,
+                if *direction == OrderDirection::Asc {
+                    "asc"
+                } else {
+                    "desc"
+                }
+            );
+            if *nulls != NullsOrder::default_for(direction) {
+                s += match nulls {
+                    NullsOrder::First => " nulls first",
+                    NullsOrder::Last => " nulls last",
+                };
+            }
+            s
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// 索引列的描述，单列索引为 b，多列索引为 (a, b)
+fn index_columns(index: &Index) -> String {
+    match index.columns.as_slice() {
+        [col] => col.clone(),
+        cols => format!("({})", cols.join(", ")),
+    }
+}
+
 #[derive(Debug, PartialEq)]
 // 执行计划定义，底层是不同类型执行节点
 pub struct Plan(pub Node);
@@ -305,7 +469,9 @@ impl Plan {
     }
 
     pub fn execute<T: Transaction + 'static>(self, txn: &mut T) -> Result<ResultSet> {
-        <dyn Executor<T>>::build(self.0).execute(txn)
+        <dyn Executor<T>>::build(self.0)
+            .execute(txn)?
+            .into_result_set()
     }
 }
 
@@ -327,7 +493,7 @@ mod tests {
     #[test]
     fn test_plan_select_all() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut txn = kvengine.begin()?;
         let sql = "select * from tbl1;";
         pppp!(40);
@@ -341,7 +507,7 @@ mod tests {
     #[test]
     fn test_plan_create_table() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut txn = kvengine.begin()?;
 
         let sql1 = "
@@ -377,7 +543,7 @@ mod tests {
     #[test]
     fn test_plan_insert() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut txn = kvengine.begin()?;
 
         let sql1 = "insert into tbl1 values (1, 2, 3, 'a', true);";
@@ -428,7 +594,7 @@ mod tests {
     #[test]
     fn test_plan_select() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut txn = kvengine.begin()?;
 
         let sql = "select * from tbl1;";
@@ -438,6 +604,7 @@ mod tests {
             plan,
             Plan(Node::Scan {
                 table_name: "tbl1".to_string(),
+                alias: None,
                 filter: None,
             })
         );
@@ -449,7 +616,7 @@ mod tests {
     #[test]
     fn test_plan_explain() -> Result<()> {
         let p = tempfile::tempdir()?.into_path().join("sqldb-log");
-        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?);
+        let kvengine = KVEngine::new(DiskEngine::new(p.clone())?)?;
         let mut txn = kvengine.begin()?;
 
         let sql = "explain select * from ta1;";
@@ -1,13 +1,20 @@
+use std::{cmp::Ordering, ops::Bound};
+
 use crate::{
     error::{Error, Result}, pppg, sql::{
         engine::Transaction,
-        parser::ast::{self, Expression},
+        parser::ast::{self, evaluate_const_expr, Expression, NullsOrder, OrderDirection},
         schema::{self, Table},
-        types::Value,
+        types::{DataType, Value},
     }
 };
 
-use super::{Node, Plan};
+use crate::sql::executor::{aggregate_output_name, parse_join_filter};
+
+use super::{binder::Binder, Node, Plan};
+
+// ORDER BY ... LIMIT 使用 Top-N 时最多保留的行数（offset + limit）
+const TOP_N_MAX_ROWS: usize = 10000;
 
 // 需要手动标注生命周期！
 pub struct Planner<'a, T: Transaction> {
@@ -23,36 +30,26 @@ impl<'a, T: Transaction> Planner<'a, T> {
         Ok(Plan(self.build_statment(stmt)?))
     }
 
-    fn build_statment(&self, stmt: ast::Statement) -> Result<Node> {
+    fn build_statment(&self, mut stmt: ast::Statement) -> Result<Node> {
+        // 先做名字解析，之后的列名都是执行器输出的列名
+        Binder::new(&*self.txn).bind(&mut stmt)?;
         Ok(match stmt {
-            ast::Statement::CreateTable { name, columns } => Node::CreateTable {
-                schema: Table {
-                    name,
-                    columns: columns
-                        .into_iter()
-                        .map(|c| {
-                            let nullable = c.nullable.unwrap_or(!c.primary_key);
-                            let default = match c.default {
-                                Some(expr) => Some(Value::from_expression(expr)),
-                                None if nullable => Some(Value::Null),
-                                None => None,
-                            };
-
-                            schema::Column {
-                                name: c.name,
-                                datatype: c.datatype,
-                                nullable,
-                                default,
-                                // 这里将抽象语法树里面的信息转化为具体的Node，然后送进去执行！
-                                primary_key: c.primary_key,
-                                //这里不是主键列，因为主键列不需要去建立索引
-                                index: c.index && !c.primary_key,
-                            }
-                        })
-                        .collect(),
-                },
-            },
+            ast::Statement::CreateTable {
+                name,
+                columns,
+                primary_key,
+            } => self.build_create_table(name, columns, primary_key)?,
             ast::Statement::DropTable { name } => Node::DropTable { name },
+            ast::Statement::CreateIndex {
+                name,
+                table_name,
+                columns,
+            } => Node::CreateIndex {
+                table_name,
+                index: schema::Index { name, columns },
+            },
+            ast::Statement::DropIndex { name } => Node::DropIndex { name },
+            ast::Statement::AlterTable { name, action } => self.build_alter_table(name, action)?,
             ast::Statement::Insert {
                 table_name,
                 columns,
@@ -73,33 +70,86 @@ impl<'a, T: Transaction> Planner<'a, T> {
                 order_by,
                 limit,
                 offset,
+                lock,
+                // 历史版本在开启事务时处理
+                as_of: _,
             } => {
+                // 加锁需要知道每一行的主键，所以只支持单表，并且不能有聚集
+                let lock_table = match (&from, lock) {
+                    (_, None) => None,
+                    (ast::FromItem::Table { name, .. }, Some(mode)) => Some((name.clone(), mode)),
+                    _ => {
+                        return Err(Error::Internal(
+                            "FOR UPDATE/FOR SHARE is only supported on a single table".into(),
+                        ))
+                    }
+                };
+
+                if where_clause.as_ref().is_some_and(|e| e.contains_aggregate()) {
+                    return Err(Error::Internal(
+                        "aggregate functions are not allowed in WHERE".into(),
+                    ));
+                }
+
                 // from
                 // 这里就会选择走索引还是走普通全表扫描
-                let mut node = self.build_from_item(from, &where_clause)?;
+                let mut node = match from {
+                    join @ ast::FromItem::Join { .. } => self.build_join(join, where_clause)?,
+                    item => self.build_from_item(item, where_clause, false)?,
+                };
 
-                let mut has_agg = false;
                 // aggregate、group by
-                if !select.is_empty() {
-                    for (expr, _) in select.iter() {
-                        // 如果是 Function，说明是 agg
-                        if let ast::Expression::Function(_, _) = expr {
-                            has_agg = true;
-                            break;
+                // 有 GROUP BY，或者 SELECT、HAVING、ORDER BY 中有聚集函数时需要聚集
+                let has_agg = !group_by.is_empty()
+                    || select.iter().any(|(expr, _)| expr.contains_aggregate())
+                    || having
+                        .iter()
+                        .chain(order_by.iter().map(|(expr, _, _)| expr))
+                        .any(|expr| expr.contains_aggregate());
+                let (mut having, mut order_by) = (having, order_by);
+                // Aggregate 输出的列是否和 SELECT 不一样（有额外的列，或者改了列名），需要最后再投影一次
+                let mut reshaped = false;
+                let mut aggregate_names = Vec::new();
+                if has_agg {
+                    if select.is_empty() {
+                        return Err(Error::Internal(
+                            "SELECT * is not supported with aggregation".into(),
+                        ));
+                    }
+                    // HAVING 和 ORDER BY 在聚集的结果上计算，其中用到的聚集函数和分组表达式换成 Aggregate 输出的列
+                    // 按列名引用 Aggregate 输出的列，SELECT 中输出的列名重复时（比如两个 count），
+                    // 给这些列换一个不重复的名字，最后再换回来
+                    let mut exprs = select.clone();
+                    if having.is_some() || !order_by.is_empty() {
+                        let names = select
+                            .iter()
+                            .map(|(expr, alias)| aggregate_output_name(expr, alias))
+                            .collect::<Vec<_>>();
+                        for (i, (_, alias)) in exprs.iter_mut().enumerate() {
+                            if names.iter().filter(|n| **n == names[i]).count() > 1 {
+                                *alias = Some(format!("{}#{}", names[i], i + 1));
+                                reshaped = true;
+                            }
                         }
                     }
-                    if group_by.is_some() {
-                        has_agg = true;
+                    for expr in having
+                        .iter_mut()
+                        .chain(order_by.iter_mut().map(|(expr, _, _)| expr))
+                    {
+                        Self::rewrite_aggregate(expr, &group_by, &mut exprs)?;
                     }
-                    if has_agg {
-                        node = Node::Aggregate {
-                            source: Box::new(node),
-                            exprs: select.clone(),
-                            group_by,
-                        }
+                    reshaped |= exprs.len() > select.len();
+                    aggregate_names = exprs
+                        .iter()
+                        .take(select.len())
+                        .map(|(expr, alias)| aggregate_output_name(expr, alias))
+                        .collect();
+                    node = Node::Aggregate {
+                        source: Box::new(node),
+                        exprs,
+                        group_by,
                     }
                 }
-
                 // having
                 if let Some(expr) = having {
                     node = Node::Filter {
@@ -108,33 +158,74 @@ impl<'a, T: Transaction> Planner<'a, T> {
                     }
                 }
 
+                let offset = match offset {
+                    Some(expr) => match evaluate_const_expr(&expr)? {
+                        Value::Integer(i) if i >= 0 => Some(i as usize),
+                        _ => return Err(Error::Internal("invalid offset".into())),
+                    },
+                    None => None,
+                };
+                let limit = match limit {
+                    Some(expr) => match evaluate_const_expr(&expr)? {
+                        Value::Integer(i) if i >= 0 => Some(i as usize),
+                        _ => return Err(Error::Internal("invalid limit".into())),
+                    },
+                    None => None,
+                };
+
                 // order by
+                // 没有聚集的时候，行还是表中原始的行，可以考虑直接利用索引的顺序
                 if !order_by.is_empty() {
-                    node = Node::Order {
-                        source: Box::new(node),
-                        order_by,
-                    }
+                    node = if has_agg {
+                        Node::Order {
+                            source: Box::new(node),
+                            order_by,
+                        }
+                    } else {
+                        self.build_order(node, order_by)?
+                    };
+                    // 有 LIMIT 时只需要保留排在前面的 offset + limit 行，不需要对全部的数据排序
+                    // 这些行都在内存的堆中，行数太多时还是用可以写临时文件的外部排序
+                    let top = limit.and_then(|limit| limit.checked_add(offset.unwrap_or(0)));
+                    node = match (node, top) {
+                        (Node::Order { source, order_by }, Some(limit)) if limit <= TOP_N_MAX_ROWS => {
+                            Node::TopN {
+                                source,
+                                order_by,
+                                limit,
+                            }
+                        }
+                        (node, _) => node,
+                    };
                 }
 
                 // offset
-                if let Some(expr) = offset {
+                if let Some(offset) = offset {
                     node = Node::Offset {
                         source: Box::new(node),
-                        offset: match Value::from_expression(expr) {
-                            Value::Integer(i) => i as usize,
-                            _ => return Err(Error::Internal("invalid offset".into())),
-                        },
+                        offset,
                     }
                 }
 
                 // limit
-                if let Some(expr) = limit {
+                if let Some(limit) = limit {
                     node = Node::Limit {
                         source: Box::new(node),
-                        limit: match Value::from_expression(expr) {
-                            Value::Integer(i) => i as usize,
-                            _ => return Err(Error::Internal("invalid limit".into())),
-                        },
+                        limit,
+                    }
+                }
+
+                // 给最终输出的行加锁
+                if let Some((table_name, mode)) = lock_table {
+                    if has_agg {
+                        return Err(Error::Internal(
+                            "FOR UPDATE/FOR SHARE is not allowed with aggregation".into(),
+                        ));
+                    }
+                    node = Node::Lock {
+                        source: Box::new(node),
+                        table_name,
+                        mode,
                     }
                 }
 
@@ -144,6 +235,18 @@ impl<'a, T: Transaction> Planner<'a, T> {
                         source: Box::new(node),
                         exprs: select,
                     }
+                } else if reshaped {
+                    // 只保留 Aggregate 输出的前 select.len() 列，去掉为 HAVING 和 ORDER BY 额外计算的列，列名换回 SELECT 中的
+                    node = Node::Projection {
+                        source: Box::new(node),
+                        exprs: aggregate_names
+                            .into_iter()
+                            .zip(select.iter())
+                            .map(|(name, (expr, alias))| {
+                                (Expression::Field(name), Some(aggregate_output_name(expr, alias)))
+                            })
+                            .collect(),
+                    }
                 }
 
                 node
@@ -154,7 +257,7 @@ impl<'a, T: Transaction> Planner<'a, T> {
                 where_clause,
             } => Node::Update {
                 table_name: table_name.clone(),
-                source: Box::new(self.build_scan(table_name, where_clause)?),
+                source: Box::new(self.build_scan(table_name, None, where_clause)?),
                 columns,
             },
             ast::Statement::Delete {
@@ -162,118 +265,657 @@ impl<'a, T: Transaction> Planner<'a, T> {
                 where_clause,
             } => Node::Delete {
                 table_name: table_name.clone(),
-                source: Box::new(self.build_scan(table_name, where_clause)?),
+                source: Box::new(self.build_scan(table_name, None, where_clause)?),
             },
             // 处理事务的命令执行
-            ast::Statement::Begin | ast::Statement::Commit | ast::Statement::Rollback => {
+            ast::Statement::Begin { .. }
+            | ast::Statement::Commit
+            | ast::Statement::Rollback
+            | ast::Statement::Savepoint { .. }
+            | ast::Statement::RollbackToSavepoint { .. }
+            | ast::Statement::ReleaseSavepoint { .. } => {
                 return Err(Error::Internal("unexpected transaction command".into()));
             }
             ast::Statement::Explain { stmt: _ } => {
                 return Err(Error::Internal("unexpected explain command".into()));
             }
+            ast::Statement::Vacuum => {
+                return Err(Error::Internal("unexpected vacuum command".into()));
+            }
         })
     }
 
-    fn build_from_item(&self, item: ast::FromItem, filter: &Option<Expression>) -> Result<Node> {
+    fn build_create_table(
+        &self,
+        name: String,
+        columns: Vec<ast::Column>,
+        primary_key: Vec<String>,
+    ) -> Result<Node> {
+        // 主键可以声明在列上，也可以使用 PRIMARY KEY (a, b) 声明联合主键，但是只能有一个主键
+        let inline_pk = columns
+            .iter()
+            .filter(|c| c.primary_key)
+            .map(|c| c.name.clone())
+            .collect::<Vec<_>>();
+        let primary_key = match (inline_pk.len(), primary_key.is_empty()) {
+            (0, _) => primary_key,
+            (1, true) => inline_pk,
+            _ => {
+                return Err(Error::Internal(format!(
+                    "Multiple primary keys for table {}",
+                    name
+                )))
+            }
+        };
+
+        Ok(Node::CreateTable {
+            schema: Table {
+                // 列上声明的 index 转换成表上的索引，索引名默认为 表名_列名_idx
+                //这里不是主键列，因为主键列不需要去建立索引
+                indexes: columns
+                    .iter()
+                    .filter(|c| c.index && primary_key != [c.name.clone()])
+                    .map(|c| schema::Index {
+                        name: format!("{}_{}_idx", name, c.name),
+                        columns: vec![c.name.clone()],
+                    })
+                    .collect(),
+                columns: columns
+                    .into_iter()
+                    .map(|c| {
+                        let is_pk = primary_key.contains(&c.name);
+                        Self::build_column(c, is_pk)
+                    })
+                    .collect::<Result<_>>()?,
+                name,
+                primary_key,
+            },
+        })
+    }
+
+    // 将抽象语法树中的列定义转换为表结构中的列
+    fn build_column(c: ast::Column, is_pk: bool) -> Result<schema::Column> {
+        // 主键列默认不能为空
+        let nullable = c.nullable.unwrap_or(!is_pk);
+        let default = match c.default {
+            Some(expr) => Some(evaluate_const_expr(&expr)?),
+            None if nullable => Some(Value::Null),
+            None => None,
+        };
+
+        // 这里将抽象语法树里面的信息转化为具体的Node，然后送进去执行！
+        Ok(schema::Column {
+            name: c.name,
+            datatype: c.datatype,
+            nullable,
+            default,
+        })
+    }
+
+    fn build_alter_table(&self, table_name: String, action: ast::AlterTableAction) -> Result<Node> {
+        let change = match action {
+            ast::AlterTableAction::AddColumn(c) => {
+                if c.primary_key {
+                    return Err(Error::Internal(format!(
+                        "Cannot add primary key column {} to table {}",
+                        c.name, table_name
+                    )));
+                }
+                let index = c.index;
+                schema::TableChange::AddColumn {
+                    column: Self::build_column(c, false)?,
+                    index,
+                }
+            }
+            ast::AlterTableAction::DropColumn(name) => schema::TableChange::DropColumn(name),
+            ast::AlterTableAction::RenameColumn { from, to } => {
+                schema::TableChange::RenameColumn { from, to }
+            }
+            ast::AlterTableAction::RenameTable(name) => schema::TableChange::RenameTable(name),
+        };
+        Ok(Node::AlterTable { table_name, change })
+    }
+
+    // FROM 中只有一张表或者一个子查询，WHERE 条件可以直接下推
+    // qualified 表示 FROM 中有 Join，这时输出的列名需要加上表名（或者别名）
+    fn build_from_item(
+        &self,
+        item: ast::FromItem,
+        filter: Option<Expression>,
+        qualified: bool,
+    ) -> Result<Node> {
         Ok(match item {
             // 这里总的查询表的逻辑
             // 这一句查表，回判断走索引还是走普通查询！！
-            ast::FromItem::Table { name } => {
-               let res= self.build_scan(name, filter.clone())?;
-               pppg!("查表的节点：",res);
-               res
+            ast::FromItem::Table { name, alias } => {
+                let alias = qualified.then(|| alias.unwrap_or(name.clone()));
+                let res = self.build_scan(name, alias, filter)?;
+                pppg!("查表的节点：", res);
+                res
             }
+            // 子查询的结果不是表，WHERE 条件没有办法下推，只能在子查询的结果上过滤
+            ast::FromItem::Subquery { query, alias } => {
+                let node = Node::SubqueryScan {
+                    source: Box::new(self.build_statment(*query)?),
+                    alias,
+                    qualified,
+                };
+                match filter {
+                    Some(predicate) => Node::Filter {
+                        source: Box::new(node),
+                        predicate,
+                    },
+                    None => node,
+                }
+            }
+            join => self.build_join(join, filter)?,
+        })
+    }
+
+    // 构造 Join，WHERE 中只涉及一张表的条件下推到这张表的扫描中，其他的条件在 Join 之后过滤
+    fn build_join(&self, item: ast::FromItem, filter: Option<Expression>) -> Result<Node> {
+        let mut conditions = Vec::new();
+        if let Some(filter) = filter {
+            Self::split_and(filter, &mut conditions);
+        }
+        let node = self.build_join_item(item, &mut conditions, true)?;
+        Ok(match conditions.into_iter().reduce(Self::and) {
+            Some(predicate) => Node::Filter {
+                source: Box::new(node),
+                predicate,
+            },
+            None => node,
+        })
+    }
+
+    // pushdown 为 false 时不下推条件，外连接中补 NULL 的一边先过滤会改变结果
+    fn build_join_item(
+        &self,
+        item: ast::FromItem,
+        conditions: &mut Vec<Expression>,
+        pushdown: bool,
+    ) -> Result<Node> {
+        let (left, right, join_type, predicate) = match item {
             ast::FromItem::Join {
                 left,
                 right,
                 join_type,
                 predicate,
-            } => {
-                // 如果是 right join，则交换位置
-                let (left, right) = match join_type {
-                    ast::JoinType::Right => (right, left),
-                    _ => (left, right),
-                };
+            } => (left, right, join_type, predicate),
+            ast::FromItem::Table { ref name, ref alias } => {
+                let qualifier = alias.as_ref().unwrap_or(name).clone();
+                let filter = pushdown
+                    .then(|| Self::take_conditions(conditions, &qualifier, true))
+                    .flatten();
+                return self.build_from_item(item, filter, true);
+            }
+            ast::FromItem::Subquery { ref alias, .. } => {
+                let qualifier = alias.clone();
+                let filter = pushdown
+                    .then(|| Self::take_conditions(conditions, &qualifier, false))
+                    .flatten();
+                return self.build_from_item(item, filter, true);
+            }
+        };
+
+        // 如果是 right join，则交换位置
+        let (left, right) = match join_type {
+            ast::JoinType::Right => (right, left),
+            _ => (left, right),
+        };
+
+        // Join 条件是在 Join 执行器中计算的，没有执行子查询的时机
+        if predicate.as_ref().is_some_and(|p| p.contains_subquery()) {
+            return Err(Error::Internal(
+                "subquery is not supported in JOIN condition".into(),
+            ));
+        }
+
+        let outer = !matches!(join_type, ast::JoinType::Cross | ast::JoinType::Inner);
+        let left = Box::new(self.build_join_item(*left, conditions, pushdown)?);
+        let right = Box::new(self.build_join_item(*right, conditions, pushdown && !outer)?);
+        // 实现两种合并，左合并还是右合并！
+        // 只有 列 = 列 的等值条件才能使用 HashJoin，其他的条件使用 NestedLoopJoin 逐行判断
+        Ok(
+            if join_type == ast::JoinType::Cross || parse_join_filter(predicate.clone()).is_none() {
+                Node::NestedLoopJoin {
+                    left,
+                    right,
+                    predicate,
+                    outer,
+                }
+            } else {
+                Node::HashJoin {
+                    left,
+                    right,
+                    predicate,
+                    outer,
+                }
+            },
+        )
+    }
 
-                let outer = match join_type {
-                    ast::JoinType::Cross | ast::JoinType::Inner => false,
-                    _ => true,
+    // 取出只引用了 qualifier 这张表的列的条件，strip 时去掉列名中的表名，用于下推到表的扫描中
+    fn take_conditions(
+        conditions: &mut Vec<Expression>,
+        qualifier: &str,
+        strip: bool,
+    ) -> Option<Expression> {
+        let prefix = format!("{}.", qualifier);
+        let mut taken = Vec::new();
+        conditions.retain(|cond| {
+            let mut cond = cond.clone();
+            let mut only_this = true;
+            let mut has_field = false;
+            let _ = cond.transform(&mut |e| {
+                match e {
+                    Expression::Field(name) => match name.strip_prefix(&prefix) {
+                        Some(column) => {
+                            has_field = true;
+                            if strip {
+                                *name = column.to_string();
+                            }
+                        }
+                        None => only_this = false,
+                    },
+                    Expression::Consts(_) | Expression::Operation(_) => {}
+                    _ => only_this = false,
+                }
+                Ok(())
+            });
+            if only_this && has_field {
+                taken.push(cond);
+            }
+            !(only_this && has_field)
+        });
+        taken.into_iter().reduce(Self::and)
+    }
+
+    // 把 AND 连接的条件拆开
+    fn split_and(expr: Expression, conditions: &mut Vec<Expression>) {
+        match expr {
+            Expression::Operation(ast::Operation::And(l, r)) => {
+                Self::split_and(*l, conditions);
+                Self::split_and(*r, conditions);
+            }
+            expr => conditions.push(expr),
+        }
+    }
+
+    fn and(l: Expression, r: Expression) -> Expression {
+        Expression::Operation(ast::Operation::And(Box::new(l), Box::new(r)))
+    }
+
+    fn build_scan(
+        &self,
+        table_name: String,
+        alias: Option<String>,
+        filter: Option<Expression>,
+    ) -> Result<Node> {
+        let predicate = match filter {
+            Some(predicate) => predicate,
+            None => {
+                return Ok(Node::Scan {
+                    table_name,
+                    alias,
+                    filter,
+                })
+            }
+        };
+        // 下推到存储层的条件使用表中的列名，扫描之上的 Filter 使用扫描输出的列名
+        let residual = match &alias {
+            Some(alias) => Self::qualify(predicate.clone(), alias),
+            None => predicate.clone(),
+        };
+        let table = self.txn.must_get_table(table_name.clone())?; //拿到txn里面的表结构的信息
+
+        // 只有 字段 比较 常量 形式的条件才能走主键或者索引
+        let mut conditions = Vec::new();
+        Self::collect_conditions(&predicate, &mut conditions);
+
+        // 判断是否是主键，主键自动走索引！联合主键只要前面几列有等值条件，也可以按照前缀扫描
+        let values = Self::equal_prefix(&table, &table.primary_key, &conditions);
+        if !values.is_empty() {
+            let used = values.len();
+            let node = Node::PrimaryKeyScan {
+                table_name,
+                alias,
+                values,
+            }; //主键索引
+            return Ok(Self::wrap_filter(node, residual, used == conditions.len()));
+        }
+
+        //找到等值条件覆盖的列最多的索引，那就直接走索引！
+        let best = table
+            .indexes
+            .iter()
+            .map(|index| (index, Self::equal_prefix(&table, &index.columns, &conditions)))
+            .filter(|(_, values)| !values.is_empty())
+            .max_by_key(|(_, values)| values.len());
+        if let Some((index, values)) = best {
+            let used = values.len();
+            //找到索引列，构造节点
+            let node = Node::IndexScan {
+                table_name,
+                alias,
+                index: index.clone(),
+                values,
+            };
+            return Ok(Self::wrap_filter(node, residual, used == conditions.len()));
+        }
+
+        // 索引的第一列上有 > < >= <= 常量（或者 BETWEEN）的条件时，走索引范围扫描
+        // 索引只负责缩小扫描的范围，原始的条件仍然放在 Filter 节点里面逐行判断
+        for index in table.indexes.iter() {
+            let field = &index.columns[0];
+            let datatype = &table.columns[table.get_col_index(field)?].datatype;
+            // 同一列上的多个条件，取交集
+            let mut range = (Bound::Unbounded, Bound::Unbounded);
+            for (_, op, value) in conditions.iter().flatten().filter(|(f, _, _)| f == field) {
+                // 常量的类型和列的类型一致才能换算成索引 key 的范围
+                let value = match Self::coerce_value(datatype, value) {
+                    Some(value) => vec![value],
+                    None => continue,
                 };
-                // 实现两种合并，左合并还是右合并！
-                if join_type == ast::JoinType::Cross {
-                    Node::NestedLoopJoin {
-                        left: Box::new(self.build_from_item(*left, filter)?),
-                        right: Box::new(self.build_from_item(*right, filter)?),
-                        predicate,
-                        outer,
-                    }
-                } else {
-                    Node::HashJoin {
-                        left: Box::new(self.build_from_item(*left, filter)?),
-                        right: Box::new(self.build_from_item(*right, filter)?),
-                        predicate,
-                        outer,
-                    }
+                match op {
+                    CompareOp::Gt => range.0 = Self::max_bound(range.0, Bound::Excluded(value)),
+                    CompareOp::Gte => range.0 = Self::max_bound(range.0, Bound::Included(value)),
+                    CompareOp::Lt => range.1 = Self::min_bound(range.1, Bound::Excluded(value)),
+                    CompareOp::Lte => range.1 = Self::min_bound(range.1, Bound::Included(value)),
+                    CompareOp::Eq => {}
                 }
             }
+            if range == (Bound::Unbounded, Bound::Unbounded) {
+                continue;
+            }
+            // 只有上界时，NULL 值不满足任何比较条件，跳过索引中的 NULL
+            if range.0 == Bound::Unbounded {
+                range.0 = Bound::Excluded(vec![Value::Null]);
+            }
+            let node = Node::IndexRangeScan {
+                table_name,
+                alias,
+                index: index.clone(),
+                range,
+            };
+            return Ok(Self::wrap_filter(node, residual, false));
+        }
+
+        // 子查询需要在执行器中计算，不能下推到存储层的扫描中
+        if predicate.contains_subquery() {
+            return Ok(Node::Filter {
+                source: Box::new(Node::Scan {
+                    table_name,
+                    alias,
+                    filter: None,
+                }),
+                predicate: residual,
+            });
+        }
+
+        //表示没有匹配到我们想要的索引列的情况！进行普通扫描！
+        Ok(Node::Scan {
+            table_name,
+            alias,
+            filter: Some(predicate),
         })
     }
 
-    fn build_scan(&self, table_name: String, filter: Option<Expression>) -> Result<Node> {
-        Ok(match Self::parse_scan_filter(filter.clone()) {
-            //匹配列的情况和他的值，但是这里不一定就是索引，所以这里需要做对比工作
-            Some((field, value)) => {
-                let table = self.txn.must_get_table(table_name.clone())?; //拿到txn里面的表结构的信息
+    // 列名加上表的别名
+    fn qualify(mut expr: Expression, alias: &str) -> Expression {
+        let _ = expr.transform(&mut |e| {
+            if let Expression::Field(name) = e {
+                *name = format!("{}.{}", alias, name);
+            }
+            Ok(())
+        });
+        expr
+    }
 
-                // 判断是否是主键，主键自动走索引！
-                if table
-                    .columns
-                    .iter()
-                    .position(|c| c.name == field && c.primary_key)
-                    .is_some()
-                {
-                    return Ok(Node::PrimaryKeyScan { table_name, value });//主键索引
+    // 把 HAVING、ORDER BY 中的聚集函数、分组表达式和 SELECT 中的表达式换成 Aggregate 输出的列名
+    // SELECT 中有相同的表达式（并且输出的列名没有重复）时直接使用，否则作为额外的列加到 Aggregate 中
+    fn rewrite_aggregate(
+        expr: &mut Expression,
+        group_by: &[Expression],
+        exprs: &mut Vec<(Expression, Option<String>)>,
+    ) -> Result<()> {
+        expr.transform(&mut |e| {
+            let selected =
+                !matches!(e, Expression::Consts(_)) && exprs.iter().any(|(expr, _)| expr == e);
+            if !matches!(e, Expression::Function(_, _, _)) && !group_by.contains(e) && !selected {
+                return Ok(());
+            }
+            let names = exprs
+                .iter()
+                .map(|(expr, alias)| aggregate_output_name(expr, alias))
+                .collect::<Vec<_>>();
+            let name = match exprs.iter().position(|(expr, _)| expr == e) {
+                Some(i) => names[i].clone(),
+                None => {
+                    let name = e.to_string();
+                    exprs.push((e.clone(), Some(name.clone())));
+                    name
                 }
-                //找到列名和查询字段相同并且该列存在索引，那就直接走索引！返回所以信息
-                match table
-                    .columns
-                    .iter()
-                    .position(|c| c.name == field && c.index)
-                {
-                    Some(_) => Node::IndexScan {
-                        //找到索引列，构造节点
-                        table_name,
-                        field,
-                        value,
-                    },
-                    //表示没有匹配到我们想要的索引列的情况！进行普通扫描！
-                    None => Node::Scan { table_name, filter },
+            };
+            *e = Expression::Field(name);
+            Ok(())
+        })
+    }
+
+    // 所有的条件都已经被主键或者索引的查找满足了，就不需要再过滤了
+    fn wrap_filter(node: Node, predicate: Expression, satisfied: bool) -> Node {
+        if satisfied {
+            node
+        } else {
+            Node::Filter {
+                source: Box::new(node),
+                predicate,
+            }
+        }
+    }
+
+    // 按照列的顺序找到连续的等值条件，返回这些条件的值，遇到没有等值条件的列就停止
+    fn equal_prefix(
+        table: &Table,
+        columns: &[String],
+        conditions: &[Option<(String, CompareOp, Value)>],
+    ) -> Vec<Value> {
+        let mut values = Vec::new();
+        for col in columns {
+            let datatype = match table.get_col_index(col) {
+                Ok(pos) => &table.columns[pos].datatype,
+                Err(_) => break,
+            };
+            let value = conditions.iter().flatten().find_map(|(f, op, v)| match op {
+                CompareOp::Eq if f == col => Self::coerce_value(datatype, v),
+                _ => None,
+            });
+            match value {
+                Some(value) => values.push(value),
+                None => break,
+            }
+        }
+        values
+    }
+
+    // 把常量转换成列的类型，整数和浮点数之间可以互相转换，其他类型不一致的常量无法用来查找
+    fn coerce_value(datatype: &DataType, value: &Value) -> Option<Value> {
+        match (datatype, value) {
+            (DataType::Float, Value::Integer(i)) => Some(Value::Float(*i as f64)),
+            (DataType::Integer, Value::Float(f)) if f.fract() == 0.0 => {
+                Some(Value::Integer(*f as i64))
+            }
+            (datatype, value) if value.datatype().as_ref() == Some(datatype) => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // 把 AND 连接的条件拆开，找到所有 字段 比较 常量 形式的条件，常量在左边时把比较符号反过来
+    // 其他形式的条件记为 None，用来判断是否所有条件都被主键或者索引满足了
+    fn collect_conditions(
+        expr: &Expression,
+        conditions: &mut Vec<Option<(String, CompareOp, Value)>>,
+    ) {
+        let (op, l, r) = match expr {
+            Expression::Operation(ast::Operation::And(l, r)) => {
+                Self::collect_conditions(l, conditions);
+                Self::collect_conditions(r, conditions);
+                return;
+            }
+            Expression::Operation(ast::Operation::Equal(l, r)) => (CompareOp::Eq, l, r),
+            Expression::Operation(ast::Operation::GreaterThan(l, r)) => (CompareOp::Gt, l, r),
+            Expression::Operation(ast::Operation::GreaterThanOrEqual(l, r)) => (CompareOp::Gte, l, r),
+            Expression::Operation(ast::Operation::LessThan(l, r)) => (CompareOp::Lt, l, r),
+            Expression::Operation(ast::Operation::LessThanOrEqual(l, r)) => (CompareOp::Lte, l, r),
+            _ => return conditions.push(None),
+        };
+        conditions.push(match (&**l, &**r) {
+            (Expression::Field(f), Expression::Consts(c)) => Some((
+                f.clone(),
+                op,
+                Value::from_expression(Expression::Consts(c.clone())),
+            )),
+            (Expression::Consts(c), Expression::Field(f)) => Some((
+                f.clone(),
+                op.flip(),
+                Value::from_expression(Expression::Consts(c.clone())),
+            )),
+            _ => None,
+        });
+    }
+
+    // 两个下界中更严格（更大）的那个
+    fn max_bound(a: Bound<Vec<Value>>, b: Bound<Vec<Value>>) -> Bound<Vec<Value>> {
+        match (&a, &b) {
+            (Bound::Unbounded, _) => b,
+            (_, Bound::Unbounded) => a,
+            (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+                match x.partial_cmp(y) {
+                    Some(Ordering::Less) => b,
+                    Some(Ordering::Equal) if matches!(b, Bound::Excluded(_)) => b,
+                    _ => a,
                 }
             }
-            None => Node::Scan { table_name, filter },
-        })
+        }
     }
 
-    fn parse_scan_filter(filter: Option<Expression>) -> Option<(String, Value)> {
-        match filter {
-            Some(expr) => match expr {
-                Expression::Field(f) => Some((f, Value::Null)),
-                Expression::Consts(c) => {
-                    Some(("".into(), Value::from_expression(Expression::Consts(c))))
+    // 两个上界中更严格（更小）的那个
+    fn min_bound(a: Bound<Vec<Value>>, b: Bound<Vec<Value>>) -> Bound<Vec<Value>> {
+        match (&a, &b) {
+            (Bound::Unbounded, _) => b,
+            (_, Bound::Unbounded) => a,
+            (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+                match x.partial_cmp(y) {
+                    Some(Ordering::Greater) => b,
+                    Some(Ordering::Equal) if matches!(b, Bound::Excluded(_)) => b,
+                    _ => a,
                 }
-                Expression::Operation(operation) => match operation {
-                    //这里就是在解析索引！
-                    ast::Operation::Equal(l, r) => {
-                        let lv = Self::parse_scan_filter(Some(*l));
-                        let rv = Self::parse_scan_filter(Some(*r));
+            }
+        }
+    }
 
-                        Some((lv.unwrap().0, rv.unwrap().1))
+    // 构造排序节点
+    // ORDER BY 只有一个升序、NULL 在前面的列时，如果数据已经是按照这一列的顺序读取的，或者这一列是某个索引的第一列，
+    // 就直接按照主键或者索引的顺序读取数据，不需要再排序
+    fn build_order(
+        &self,
+        node: Node,
+        order_by: Vec<(Expression, OrderDirection, NullsOrder)>,
+    ) -> Result<Node> {
+        let field = match order_by.as_slice() {
+            [(Expression::Field(field), OrderDirection::Asc, NullsOrder::First)] => field.clone(),
+            _ => return Ok(Node::Order { source: Box::new(node), order_by }),
+        };
+        if self.is_ordered_by(&node, &field)? {
+            return Ok(node);
+        }
+        Ok(match node {
+            // 全表扫描换成整个索引的范围扫描，索引中 NULL 排在最前面
+            Node::Scan {
+                table_name,
+                alias,
+                filter,
+            } => {
+                let table = self.txn.must_get_table(table_name.clone())?;
+                match table.indexes.iter().find(|i| i.columns[0] == field) {
+                    Some(index) => {
+                        let scan = Node::IndexRangeScan {
+                            table_name,
+                            alias,
+                            index: index.clone(),
+                            range: (Bound::Unbounded, Bound::Unbounded),
+                        };
+                        match filter {
+                            Some(predicate) => Node::Filter {
+                                source: Box::new(scan),
+                                predicate,
+                            },
+                            None => scan,
+                        }
                     }
-                    _ => None,
-                },
-                _ => None,
+                    None => Node::Order {
+                        source: Box::new(Node::Scan {
+                            table_name,
+                            alias,
+                            filter,
+                        }),
+                        order_by,
+                    },
+                }
+            }
+            node => Node::Order {
+                source: Box::new(node),
+                order_by,
             },
-            None => None,
+        })
+    }
+
+    // 扫描节点输出的数据是否已经按照这一列升序排列了
+    // 行数据按照主键的顺序存储，索引按照索引列的顺序存储，前面几列等值时，下一列也是有序的
+    fn is_ordered_by(&self, node: &Node, field: &str) -> Result<bool> {
+        Ok(match node {
+            Node::Scan { table_name, .. } => {
+                self.txn.must_get_table(table_name.clone())?.primary_key[0] == field
+            }
+            Node::PrimaryKeyScan {
+                table_name, values, ..
+            } => self
+                .txn
+                .must_get_table(table_name.clone())?
+                .primary_key
+                .iter()
+                .take(values.len() + 1)
+                .any(|c| c == field),
+            Node::IndexScan { index, values, .. } => {
+                index.columns.iter().take(values.len() + 1).any(|c| c == field)
+            }
+            Node::IndexRangeScan { index, .. } => index.columns[0] == field,
+            Node::Filter { source, .. } => self.is_ordered_by(source, field)?,
+            _ => false,
+        })
+    }
+}
+
+// 可以用主键或者索引处理的比较符号
+enum CompareOp {
+    Eq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl CompareOp {
+    // 常量 op 字段 等价于 字段 flip(op) 常量
+    fn flip(self) -> Self {
+        match self {
+            CompareOp::Eq => CompareOp::Eq,
+            CompareOp::Gt => CompareOp::Lt,
+            CompareOp::Gte => CompareOp::Lte,
+            CompareOp::Lt => CompareOp::Gt,
+            CompareOp::Lte => CompareOp::Gte,
         }
     }
 }
@@ -11,6 +11,10 @@ use crate::log;
 pub struct Table {
     pub name: String,
     pub columns: Vec<Column>,
+    // 主键列，按照声明的顺序排列，多个列时为联合主键
+    pub primary_key: Vec<String>,
+    // 表上的二级索引目录，建表时列上声明的 index 和 CREATE INDEX 创建的索引都记录在这里
+    pub indexes: Vec<Index>,
 }
 
 // 单独校验的方法！！
@@ -27,26 +31,26 @@ impl Table {
         }
 
         // 校验是否有主键
-        match self.columns.iter().filter(|c| c.primary_key).count() {
-            1 => {}
-            0 => {
-                return Err(Error::Internal(format!(
-                    "No primary key for table {}",
-                    self.name
-                )))
-            }
-            _ => {
+        if self.primary_key.is_empty() {
+            return Err(Error::Internal(format!(
+                "No primary key for table {}",
+                self.name
+            )));
+        }
+        for (i, pk) in self.primary_key.iter().enumerate() {
+            self.get_col_index(pk)?;
+            if self.primary_key[..i].contains(pk) {
                 return Err(Error::Internal(format!(
-                    "Multiple primary keys for table {}",
-                    self.name
-                )))
+                    "Duplicate primary key column {} in table {}",
+                    pk, self.name
+                )));
             }
         }
 
         // 校验列信息
         for col in &self.columns {
             // 主键不能为空
-            if col.primary_key && col.nullable {
+            if self.is_primary_key(&col.name) && col.nullable {
                 return Err(Error::Internal(format!(
                     "Primary key {} cannot be nullable in table{}",
                     col.name, self.name
@@ -68,17 +72,60 @@ impl Table {
             }
         }
 
+        // 校验索引信息
+        for (i, index) in self.indexes.iter().enumerate() {
+            if index.columns.is_empty() {
+                return Err(Error::Internal(format!(
+                    "Index {} has no columns in table {}",
+                    index.name, self.name
+                )));
+            }
+            for (j, col) in index.columns.iter().enumerate() {
+                self.get_col_index(col)?;
+                if index.columns[..j].contains(col) {
+                    return Err(Error::Internal(format!(
+                        "Duplicate column {} in index {}",
+                        col, index.name
+                    )));
+                }
+            }
+            // 主键本身就可以直接定位到数据，不需要再建立索引
+            if index.columns == self.primary_key {
+                return Err(Error::Internal(format!(
+                    "Cannot create index {} on primary key of table {}",
+                    index.name, self.name
+                )));
+            }
+            if self.indexes[..i].iter().any(|idx| idx.name == index.name) {
+                return Err(Error::Internal(format!(
+                    "Duplicate index {} in table {}",
+                    index.name, self.name
+                )));
+            }
+            if self.indexes[..i].iter().any(|idx| idx.columns == index.columns) {
+                return Err(Error::Internal(format!(
+                    "Columns ({}) already have an index in table {}",
+                    index.columns.join(", "),
+                    self.name
+                )));
+            }
+        }
+
         Ok(())
     }
 
-    pub fn get_primary_key(&self, row: &Row) -> Result<Value> {
+    // 列是否是主键（或者联合主键中的一列）
+    pub fn is_primary_key(&self, col_name: &str) -> bool {
+        self.primary_key.iter().any(|c| c == col_name)
+    }
+
+    // 获取一行数据的主键，联合主键按照声明的顺序取出每一列的值
+    pub fn get_primary_key(&self, row: &Row) -> Result<Vec<Value>> {
         __function!("获取主键~");
-        let pos = self
-            .columns
+        self.primary_key
             .iter()
-            .position(|c| c.primary_key)
-            .expect("No primary key found");
-        Ok(row[pos].clone())
+            .map(|c| Ok(row[self.get_col_index(c)?].clone()))
+            .collect()
     }
 
     pub fn get_col_index(&self, col_name: &str) -> Result<usize> {
@@ -92,34 +139,70 @@ impl Table {
 
 impl Display for Table {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 单列主键直接标注在列上，联合主键单独一行
         let col_desc = self
             .columns
             .iter()
-            .map(|c| format!("{}", c))
+            .map(|c| match self.primary_key.as_slice() {
+                [pk] if *pk == c.name => {
+                    let mut desc = format!("    {} {:?} PRIMARY KEY", c.name, c.datatype);
+                    if let Some(v) = &c.default {
+                        desc += &format!(" DEFAULT {}", v);
+                    }
+                    desc
+                }
+                _ => format!("{}", c),
+            })
             .collect::<Vec<_>>()
             .join(",\n");
-        write!(f, "CREATE TABLE {} (\n{}\n)", self.name, col_desc)
+        write!(f, "CREATE TABLE {} (\n{}", self.name, col_desc)?;
+        if self.primary_key.len() > 1 {
+            write!(f, ",\n    PRIMARY KEY ({})", self.primary_key.join(", "))?;
+        }
+        write!(f, "\n)")?;
+        for index in &self.indexes {
+            write!(
+                f,
+                ";\nCREATE INDEX {} ON {} ({})",
+                index.name,
+                self.name,
+                index.columns.join(", ")
+            )?;
+        }
+        Ok(())
     }
 }
 
+// 二级索引定义，索引数据存放在 Key::Index(表名, 索引名, 索引列的值) 中
+// 多列索引的 key 按照列的顺序编码，所以可以只用前面的几列做前缀扫描
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    pub name: String,
+    pub columns: Vec<String>,
+}
+
+// ALTER TABLE 对表结构的修改
+#[derive(Debug, PartialEq)]
+pub enum TableChange {
+    // 新增列，index 表示是否同时在新列上建立索引
+    AddColumn { column: Column, index: bool },
+    DropColumn(String),
+    RenameColumn { from: String, to: String },
+    RenameTable(String),
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Column {
     pub name: String,
     pub datatype: DataType,
     pub nullable: bool,
     pub default: Option<Value>,
-    // 在执行器中也要加上我们的主键字段，判断是否有索引，有的话后续查找就走索引！
-    pub primary_key: bool,
-    pub index: bool,
 }
 
 impl Display for Column {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut col_desc = format!("    {} {:?}", self.name, self.datatype);
-        if self.primary_key {
-            col_desc += " PRIMARY KEY";
-        }
-        if !self.nullable && !self.primary_key {
+        if !self.nullable {
             col_desc += " NOT NULL";
         }
         if let Some(v) = &self.default {
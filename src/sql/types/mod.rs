@@ -2,9 +2,11 @@ use std::{cmp::Ordering, fmt::Display, hash::Hash};
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::Result;
+
 use super::parser::ast::{Consts, Expression};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DataType {
     Boolean,
     Integer,
@@ -102,3 +104,6 @@ impl Hash for Value {
 impl Eq for Value {}
 
 pub type Row = Vec<Value>;
+
+// 行迭代器，执行器之间以及存储层向上层传递数据都使用它，只有在被拉取的时候才真正读取数据
+pub type Rows = Box<dyn Iterator<Item = Result<Row>>>;
@@ -0,0 +1,1031 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    ops::{Bound, RangeBounds},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    time::Instant,
+};
+
+use fs4::FileExt as _;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::{__function, pppg, pppr};
+
+use super::{
+    disk::SyncPolicy,
+    engine::{Engine as _, Status},
+};
+
+// 页的大小，数据文件按页读写
+const PAGE_SIZE: usize = 8 * 1024;
+// 页的头部：crc(4) | 数据长度(4)
+const PAGE_HEADER_SIZE: usize = 8;
+// key 的大小上限，保证一个页至少可以放下几个 key
+const MAX_KEY_SIZE: usize = 1024;
+// value 超过这个大小时放到溢出页中
+const MAX_INLINE_VALUE_SIZE: usize = 1024;
+// 每个溢出页中存放的数据大小，剩下的空间留给页头和序列化的开销
+const OVERFLOW_DATA_SIZE: usize = PAGE_SIZE - 64;
+// 缓冲池默认缓存的页数
+const CACHE_PAGES: usize = 1024;
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// B+ 树存储引擎定义
+// 数据文件由固定大小的页组成，第 0 页是元数据，记录根节点、页的数量和空闲页链表；
+// 内部节点只保存 key 和子节点的页号，数据都在叶子节点中，叶子节点之间用双向链表连接，用于范围扫描。
+// 所有的页都通过缓冲池读写，修改过的页在事务提交（sync）时先写入 journal 文件，再写回数据文件，
+// 写回的过程中崩溃的话，启动时用 journal 中完整的页覆盖数据文件
+pub struct BTreeEngine {
+    pool: BufferPool,
+    // 刷盘策略，以及上一次写回数据文件的时间
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    syncs: u64,
+    // key 的数量和 key、value 的总大小，打开时统计一次，之后随写入更新
+    keys: u64,
+    size: u64,
+}
+
+// 页的内容，序列化之后存放在页中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Page {
+    Meta(Meta),
+    // 叶子节点：按照 key 排序的数据，以及前后两个叶子节点的页号，0 表示没有
+    Leaf {
+        entries: Vec<(Vec<u8>, Value)>,
+        prev: u32,
+        next: u32,
+    },
+    // 内部节点：children[i] 中的 key 都小于 keys[i]，children[i + 1] 中的 key 都大于等于 keys[i]
+    Internal {
+        keys: Vec<Vec<u8>>,
+        children: Vec<u32>,
+    },
+    // 溢出页，存放比较大的 value，多个溢出页组成一个链表
+    Overflow {
+        data: Vec<u8>,
+        next: u32,
+    },
+    // 被释放的页，组成空闲页链表，分配新页时优先使用
+    Free {
+        next: u32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Meta {
+    root: u32,
+    // 数据文件中页的数量，以及空闲页链表的头和长度
+    pages: u32,
+    free: u32,
+    free_pages: u32,
+}
+
+// 叶子节点中的 value，比较大的 value 放在溢出页中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Value {
+    Inline(Vec<u8>),
+    Overflow { page: u32, len: u32 },
+}
+
+impl Value {
+    fn len(&self) -> usize {
+        match self {
+            Value::Inline(value) => value.len(),
+            Value::Overflow { len, .. } => *len as usize,
+        }
+    }
+}
+
+impl BTreeEngine {
+    pub fn new(file_path: PathBuf) -> Result<Self> {
+        Self::new_with_sync(file_path, SyncPolicy::Always)
+    }
+
+    // 不是每次提交都写回数据文件时，修改过的页留在缓冲池中，直到缓冲池放不下、到了刷盘的时间或者关闭引擎，
+    // 进程崩溃会丢失这期间提交的事务，但是数据文件始终是一致的
+    pub fn new_with_sync(file_path: PathBuf, sync_policy: SyncPolicy) -> Result<Self> {
+        __function!("新建 B+ 树存储引擎中~~~");
+        let pool = BufferPool::open(file_path, CACHE_PAGES)?;
+        let mut eng = Self {
+            pool,
+            sync_policy,
+            last_sync: Instant::now(),
+            syncs: 0,
+            keys: 0,
+            size: 0,
+        };
+        let (mut keys, mut size) = (0, 0);
+        for item in eng.scan(..) {
+            let (key, value) = item?;
+            keys += 1;
+            size += (key.len() + value.len()) as u64;
+        }
+        (eng.keys, eng.size) = (keys, size);
+        pppg!("B+ 树存储引擎构建成功，正式启动~~~");
+        Ok(eng)
+    }
+
+    // 设置缓冲池缓存的页数
+    pub fn set_cache_pages(&mut self, pages: usize) {
+        self.pool.capacity = pages;
+    }
+
+    // 在 id 为根的子树中插入数据，节点分裂的话返回新节点的第一个 key 和页号
+    fn insert(&mut self, id: u32, key: Vec<u8>, value: Value) -> Result<Option<(Vec<u8>, u32)>> {
+        match self.pool.read(id)? {
+            Page::Leaf {
+                mut entries,
+                prev,
+                next,
+            } => {
+                match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                    Ok(i) => {
+                        let old = std::mem::replace(&mut entries[i].1, value);
+                        self.keys -= 1;
+                        self.size -= (key.len() + old.len()) as u64;
+                        self.free_value(&old)?;
+                    }
+                    Err(i) => entries.insert(i, (key, value)),
+                }
+                if leaf_size(&entries)? <= PAGE_SIZE {
+                    self.pool.write(id, Page::Leaf { entries, prev, next });
+                    return Ok(None);
+                }
+
+                // 按照大小从中间分裂，右半部分放到新的叶子节点中
+                let sizes = entries
+                    .iter()
+                    .map(bincode::serialized_size)
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let right_entries = entries.split_off(split_point(&sizes));
+                let sep = right_entries[0].0.clone();
+                let right = self.pool.allocate(Page::Leaf {
+                    entries: right_entries,
+                    prev: id,
+                    next,
+                })?;
+                if next != 0 {
+                    self.set_prev(next, right)?;
+                }
+                self.pool.write(id, Page::Leaf { entries, prev, next: right });
+                Ok(Some((sep, right)))
+            }
+            Page::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let i = keys.partition_point(|k| *k <= key);
+                let (sep, right) = match self.insert(children[i], key, value)? {
+                    Some(split) => split,
+                    None => return Ok(None),
+                };
+                keys.insert(i, sep);
+                children.insert(i + 1, right);
+                if internal_size(&keys, &children)? <= PAGE_SIZE {
+                    self.pool.write(id, Page::Internal { keys, children });
+                    return Ok(None);
+                }
+
+                // 中间的 key 提升到父节点中
+                let sizes = keys
+                    .iter()
+                    .map(bincode::serialized_size)
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let mid = split_point(&sizes);
+                let right_keys = keys.split_off(mid + 1);
+                let sep = keys.pop().unwrap();
+                let right_children = children.split_off(mid + 1);
+                let right = self.pool.allocate(Page::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                })?;
+                self.pool.write(id, Page::Internal { keys, children });
+                Ok(Some((sep, right)))
+            }
+            page => Err(unexpected_page(id, &page)),
+        }
+    }
+
+    // 在 id 为根的子树中删除数据，返回这个节点是否已经为空
+    // 节点不足半满的时候不做合并，只有变为空的时候才从父节点中摘除
+    fn delete_at(&mut self, id: u32, key: &[u8]) -> Result<bool> {
+        match self.pool.read(id)? {
+            Page::Leaf {
+                mut entries,
+                prev,
+                next,
+            } => match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                Ok(i) => {
+                    let (key, value) = entries.remove(i);
+                    self.keys -= 1;
+                    self.size -= (key.len() + value.len()) as u64;
+                    self.free_value(&value)?;
+                    let empty = entries.is_empty();
+                    self.pool.write(id, Page::Leaf { entries, prev, next });
+                    Ok(empty)
+                }
+                Err(_) => Ok(false),
+            },
+            Page::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let i = keys.partition_point(|k| k.as_slice() <= key);
+                if !self.delete_at(children[i], key)? {
+                    return Ok(false);
+                }
+                self.remove_page(children[i])?;
+                children.remove(i);
+                if !keys.is_empty() {
+                    keys.remove(i.saturating_sub(1));
+                }
+                let empty = children.is_empty();
+                self.pool.write(id, Page::Internal { keys, children });
+                Ok(empty)
+            }
+            page => Err(unexpected_page(id, &page)),
+        }
+    }
+
+    // 释放一个空的节点，叶子节点还要从链表中摘除
+    fn remove_page(&mut self, id: u32) -> Result<()> {
+        if let Page::Leaf { prev, next, .. } = self.pool.read(id)? {
+            if prev != 0 {
+                match self.pool.read(prev)? {
+                    Page::Leaf { entries, prev: p, .. } => {
+                        self.pool.write(prev, Page::Leaf { entries, prev: p, next })
+                    }
+                    page => return Err(unexpected_page(prev, &page)),
+                }
+            }
+            if next != 0 {
+                self.set_prev(next, prev)?;
+            }
+        }
+        self.pool.free(id)
+    }
+
+    fn set_prev(&mut self, id: u32, prev: u32) -> Result<()> {
+        match self.pool.read(id)? {
+            Page::Leaf { entries, next, .. } => {
+                self.pool.write(id, Page::Leaf { entries, prev, next });
+                Ok(())
+            }
+            page => Err(unexpected_page(id, &page)),
+        }
+    }
+
+    // 比较大的 value 切分之后写入溢出页链表
+    fn write_value(&mut self, value: Vec<u8>) -> Result<Value> {
+        if value.len() <= MAX_INLINE_VALUE_SIZE {
+            return Ok(Value::Inline(value));
+        }
+        let mut next = 0;
+        for chunk in value.chunks(OVERFLOW_DATA_SIZE).rev() {
+            next = self.pool.allocate(Page::Overflow {
+                data: chunk.to_vec(),
+                next,
+            })?;
+        }
+        Ok(Value::Overflow {
+            page: next,
+            len: value.len() as u32,
+        })
+    }
+
+    fn free_value(&mut self, value: &Value) -> Result<()> {
+        if let Value::Overflow { page, .. } = value {
+            let mut id = *page;
+            while id != 0 {
+                let next = match self.pool.read(id)? {
+                    Page::Overflow { next, .. } => next,
+                    page => return Err(unexpected_page(id, &page)),
+                };
+                self.pool.free(id)?;
+                id = next;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BTreeEngine {
+    fn drop(&mut self) {
+        if let Err(e) = self.pool.checkpoint() {
+            pppr!(format!("关闭时写回数据失败: {}", e));
+        }
+    }
+}
+
+impl super::engine::Engine for BTreeEngine {
+    type EngineIterator<'a> = BTreeEngineIterator<'a>;
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(Error::Internal(format!(
+                "key size {} exceeds the limit {}",
+                key.len(),
+                MAX_KEY_SIZE
+            )));
+        }
+        let size = (key.len() + value.len()) as u64;
+        let value = self.write_value(value)?;
+        let root = self.pool.meta.root;
+        // 根节点分裂，树的高度加一
+        if let Some((sep, right)) = self.insert(root, key, value)? {
+            self.pool.meta.root = self.pool.allocate(Page::Internal {
+                keys: vec![sep],
+                children: vec![root, right],
+            })?;
+        }
+        self.keys += 1;
+        self.size += size;
+        self.pool.shrink()
+    }
+
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let leaf = find_leaf(&mut self.pool, Bound::Included(&key), false)?;
+        let value = match self.pool.read(leaf)? {
+            Page::Leaf { entries, .. } => entries
+                .binary_search_by(|(k, _)| k.cmp(&key))
+                .ok()
+                .map(|i| entries[i].1.clone()),
+            page => return Err(unexpected_page(leaf, &page)),
+        };
+        value.map(|v| read_value(&mut self.pool, &v)).transpose()
+    }
+
+    fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        let root = self.pool.meta.root;
+        if self.delete_at(root, &key)? {
+            // 根节点的子节点都被删除了，重新变成一个空的叶子节点
+            if let Page::Internal { .. } = self.pool.read(root)? {
+                self.pool.write(root, Page::Leaf { entries: Vec::new(), prev: 0, next: 0 });
+            }
+        }
+        // 根节点只剩一个子节点，树的高度减一
+        loop {
+            let root = self.pool.meta.root;
+            match self.pool.read(root)? {
+                Page::Internal { children, .. } if children.len() == 1 => {
+                    self.pool.free(root)?;
+                    self.pool.meta.root = children[0];
+                }
+                _ => break,
+            }
+        }
+        self.pool.shrink()
+    }
+
+    fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
+        BTreeEngineIterator {
+            pool: &mut self.pool,
+            range: (range.start_bound().cloned(), range.end_bound().cloned()),
+            front_leaf: None,
+            back_leaf: None,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            front_key: None,
+            back_key: None,
+        }
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        let meta = &self.pool.meta;
+        Ok(Status {
+            name: "btree".into(),
+            keys: self.keys,
+            size: self.size,
+            disk_size: meta.pages as u64 * PAGE_SIZE as u64,
+            garbage_disk_size: meta.free_pages as u64 * PAGE_SIZE as u64,
+            segments: meta.pages as u64,
+            compactions: 0,
+            compacting: false,
+            syncs: self.syncs,
+        })
+    }
+
+    // 事务提交时按照刷盘策略把修改过的页写回数据文件
+    fn sync(&mut self) -> Result<()> {
+        let sync = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Periodic(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            self.pool.checkpoint()?;
+            self.last_sync = Instant::now();
+            self.syncs += 1;
+        }
+        Ok(())
+    }
+}
+
+// 从根节点开始找到 key 所在的叶子节点，Unbounded 时按照方向找到最左边或者最右边的叶子节点
+fn find_leaf(pool: &mut BufferPool, key: Bound<&Vec<u8>>, from_back: bool) -> Result<u32> {
+    let mut id = pool.meta.root;
+    loop {
+        match pool.read(id)? {
+            Page::Leaf { .. } => return Ok(id),
+            Page::Internal { keys, children } => {
+                let i = match key {
+                    Bound::Included(key) | Bound::Excluded(key) => keys.partition_point(|k| k <= key),
+                    Bound::Unbounded if from_back => children.len() - 1,
+                    Bound::Unbounded => 0,
+                };
+                id = children[i];
+            }
+            page => return Err(unexpected_page(id, &page)),
+        }
+    }
+}
+
+fn read_value(pool: &mut BufferPool, value: &Value) -> Result<Vec<u8>> {
+    match value {
+        Value::Inline(value) => Ok(value.clone()),
+        Value::Overflow { page, len } => {
+            let mut data = Vec::with_capacity(*len as usize);
+            let mut id = *page;
+            while id != 0 {
+                match pool.read(id)? {
+                    Page::Overflow { data: chunk, next } => {
+                        data.extend_from_slice(&chunk);
+                        id = next;
+                    }
+                    page => return Err(unexpected_page(id, &page)),
+                }
+            }
+            Ok(data)
+        }
+    }
+}
+
+fn leaf_size(entries: &Vec<(Vec<u8>, Value)>) -> Result<usize> {
+    // 叶子节点的大小，加上前后指针和枚举标签
+    Ok(bincode::serialized_size(entries)? as usize + 12 + PAGE_HEADER_SIZE)
+}
+
+fn internal_size(keys: &Vec<Vec<u8>>, children: &Vec<u32>) -> Result<usize> {
+    Ok(bincode::serialized_size(&(keys, children))? as usize + 4 + PAGE_HEADER_SIZE)
+}
+
+// 按照大小找到分裂的位置，左右两边至少各有一个元素
+fn split_point(sizes: &[u64]) -> usize {
+    let total: u64 = sizes.iter().sum();
+    let mut acc = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        acc += size;
+        if acc > total / 2 {
+            return i.clamp(1, sizes.len() - 1);
+        }
+    }
+    sizes.len() - 1
+}
+
+fn unexpected_page(id: u32, page: &Page) -> Error {
+    let kind = match page {
+        Page::Meta(_) => "meta",
+        Page::Leaf { .. } => "leaf",
+        Page::Internal { .. } => "internal",
+        Page::Overflow { .. } => "overflow",
+        Page::Free { .. } => "free",
+    };
+    Error::Internal(format!("unexpected {} page {}", kind, id))
+}
+
+// 缓冲池，缓存最近使用的页，超过容量时淘汰最久没有使用的页（LRU）
+// 修改过的页在写回数据文件之前不会被淘汰，写回由 checkpoint 统一完成
+struct BufferPool {
+    file: File,
+    journal: File,
+    capacity: usize,
+    frames: HashMap<u32, Frame>,
+    // 访问时间 -> 页号，时间最早的最先被淘汰
+    lru: BTreeMap<u64, u32>,
+    tick: u64,
+    meta: Meta,
+}
+
+struct Frame {
+    page: Page,
+    dirty: bool,
+    tick: u64,
+}
+
+impl BufferPool {
+    fn open(file_path: PathBuf, capacity: usize) -> Result<Self> {
+        if let Some(dir) = file_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&file_path)?;
+        // 加文件锁，保证同时只能有一个服务去使用这个文件
+        file.try_lock_exclusive()?;
+        let mut journal_path = file_path.into_os_string();
+        journal_path.push(".journal");
+        let journal = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(journal_path)?;
+
+        let mut pool = Self {
+            file,
+            journal,
+            capacity,
+            frames: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            meta: Meta {
+                root: 1,
+                pages: 2,
+                free: 0,
+                free_pages: 0,
+            },
+        };
+        pool.recover()?;
+        if pool.file.metadata()?.len() == 0 {
+            // 新的数据文件，只有一个空的叶子节点作为根节点
+            pool.write(1, Page::Leaf { entries: Vec::new(), prev: 0, next: 0 });
+            pool.checkpoint()?;
+        } else {
+            match pool.read_page(0)? {
+                Page::Meta(meta) => pool.meta = meta,
+                page => return Err(unexpected_page(0, &page)),
+            }
+        }
+        Ok(pool)
+    }
+
+    // 读取一个页，不在缓冲池中的话从数据文件中读取
+    fn read(&mut self, id: u32) -> Result<Page> {
+        if let Some(frame) = self.frames.get(&id) {
+            let page = frame.page.clone();
+            self.touch(id);
+            return Ok(page);
+        }
+        let page = self.read_page(id)?;
+        self.frames.insert(
+            id,
+            Frame {
+                page: page.clone(),
+                dirty: false,
+                tick: 0,
+            },
+        );
+        self.touch(id);
+        self.evict();
+        Ok(page)
+    }
+
+    // 修改一个页，只写入缓冲池中
+    fn write(&mut self, id: u32, page: Page) {
+        match self.frames.get_mut(&id) {
+            Some(frame) => {
+                frame.page = page;
+                frame.dirty = true;
+            }
+            None => {
+                self.frames.insert(id, Frame { page, dirty: true, tick: 0 });
+            }
+        }
+        self.touch(id);
+    }
+
+    // 分配一个新页，优先使用空闲页
+    fn allocate(&mut self, page: Page) -> Result<u32> {
+        let id = if self.meta.free != 0 {
+            let id = self.meta.free;
+            match self.read(id)? {
+                Page::Free { next } => self.meta.free = next,
+                page => return Err(unexpected_page(id, &page)),
+            }
+            self.meta.free_pages -= 1;
+            id
+        } else {
+            self.meta.pages += 1;
+            self.meta.pages - 1
+        };
+        self.write(id, page);
+        Ok(id)
+    }
+
+    fn free(&mut self, id: u32) -> Result<()> {
+        self.write(id, Page::Free { next: self.meta.free });
+        self.meta.free = id;
+        self.meta.free_pages += 1;
+        Ok(())
+    }
+
+    fn touch(&mut self, id: u32) {
+        self.tick += 1;
+        if let Some(frame) = self.frames.get_mut(&id) {
+            self.lru.remove(&frame.tick);
+            frame.tick = self.tick;
+            self.lru.insert(self.tick, id);
+        }
+    }
+
+    // 淘汰最久没有使用的、没有被修改过的页
+    fn evict(&mut self) {
+        while self.frames.len() > self.capacity {
+            let victim = self
+                .lru
+                .iter()
+                .find(|(_, id)| !self.frames[*id].dirty)
+                .map(|(tick, id)| (*tick, *id));
+            match victim {
+                Some((tick, id)) => {
+                    self.lru.remove(&tick);
+                    self.frames.remove(&id);
+                }
+                None => break,
+            }
+        }
+    }
+
+    // 每次写操作完成之后调用，修改过的页太多、缓冲池放不下时先写回数据文件
+    fn shrink(&mut self) -> Result<()> {
+        self.evict();
+        if self.frames.len() > self.capacity {
+            self.checkpoint()?;
+            self.evict();
+        }
+        Ok(())
+    }
+
+    // 把修改过的页写回数据文件
+    // 先把所有的页写入 journal 并刷盘，再写回数据文件，最后清空 journal，
+    // 这样写回的过程中崩溃的话，重启时可以用 journal 恢复，数据文件不会只写了一部分
+    // 写回数据文件并刷盘之后才清除页的修改标记，中途出错的话这些页还会留在缓冲池中，下次重新写回
+    fn checkpoint(&mut self) -> Result<()> {
+        let pages = self.write_journal()?;
+        if pages.is_empty() {
+            return Ok(());
+        }
+        for (id, page) in &pages {
+            self.file.write_all_at(page, *id as u64 * PAGE_SIZE as u64)?;
+        }
+        self.file.sync_data()?;
+        for (id, _) in &pages {
+            if let Some(frame) = self.frames.get_mut(id) {
+                frame.dirty = false;
+            }
+        }
+        self.journal.set_len(0)?;
+        Ok(())
+    }
+
+    // 把修改过的页和元数据写入 journal，返回需要写回的页
+    // journal 的格式：页数(4) | 页号(4) + 页 ... | crc(4)
+    fn write_journal(&mut self) -> Result<Vec<(u32, Vec<u8>)>> {
+        let mut pages = self
+            .frames
+            .iter()
+            .filter(|(_, f)| f.dirty)
+            .map(|(id, f)| Ok((*id, encode_page(&f.page)?)))
+            .collect::<Result<Vec<_>>>()?;
+        if pages.is_empty() {
+            return Ok(pages);
+        }
+        pages.push((0, encode_page(&Page::Meta(self.meta.clone()))?));
+        pages.sort_by_key(|(id, _)| *id);
+
+        let mut data = Vec::with_capacity(8 + pages.len() * (4 + PAGE_SIZE));
+        data.extend_from_slice(&(pages.len() as u32).to_be_bytes());
+        for (id, page) in &pages {
+            data.extend_from_slice(&id.to_be_bytes());
+            data.extend_from_slice(page);
+        }
+        data.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+        self.journal.set_len(0)?;
+        self.journal.write_all_at(&data, 0)?;
+        self.journal.sync_data()?;
+        Ok(pages)
+    }
+
+    // 上次写回数据文件时崩溃，用 journal 中的页覆盖数据文件
+    // journal 不完整说明还没有开始写回，数据文件没有被修改，直接丢掉
+    fn recover(&mut self) -> Result<()> {
+        let len = self.journal.metadata()?.len() as usize;
+        if len < 8 {
+            return Ok(());
+        }
+        let mut data = vec![0; len];
+        self.journal.read_exact_at(&mut data, 0)?;
+        let count = u32::from_be_bytes(data[0..4].try_into()?) as usize;
+        let crc = u32::from_be_bytes(data[len - 4..].try_into()?);
+        if len == 8 + count * (4 + PAGE_SIZE) && crc32fast::hash(&data[..len - 4]) == crc {
+            pppr!(format!("从 journal 中恢复 {} 个页", count));
+            for page in data[4..len - 4].chunks(4 + PAGE_SIZE) {
+                let id = u32::from_be_bytes(page[0..4].try_into()?);
+                self.file.write_all_at(&page[4..], id as u64 * PAGE_SIZE as u64)?;
+            }
+            self.file.sync_data()?;
+        }
+        self.journal.set_len(0)?;
+        Ok(())
+    }
+
+    fn read_page(&self, id: u32) -> Result<Page> {
+        let mut buf = vec![0; PAGE_SIZE];
+        self.file.read_exact_at(&mut buf, id as u64 * PAGE_SIZE as u64)?;
+        let crc = u32::from_be_bytes(buf[0..4].try_into()?);
+        let len = u32::from_be_bytes(buf[4..8].try_into()?) as usize;
+        let data = buf
+            .get(PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + len)
+            .filter(|data| crc32fast::hash(data) == crc)
+            .ok_or(Error::Internal(format!("page {} is corrupted", id)))?;
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+// 页的格式：crc(4) | 数据长度(4) | 序列化之后的数据 | 补齐到页的大小
+fn encode_page(page: &Page) -> Result<Vec<u8>> {
+    let data = bincode::serialize(page)?;
+    if data.len() + PAGE_HEADER_SIZE > PAGE_SIZE {
+        return Err(Error::Internal(format!("page size {} exceeds {}", data.len(), PAGE_SIZE)));
+    }
+    let mut buf = Vec::with_capacity(PAGE_SIZE);
+    buf.write_all(&crc32fast::hash(&data).to_be_bytes())?;
+    buf.write_all(&(data.len() as u32).to_be_bytes())?;
+    buf.write_all(&data)?;
+    buf.resize(PAGE_SIZE, 0);
+    Ok(buf)
+}
+
+// B+ 树的迭代器，沿着叶子节点的链表从两端向中间读取，每次只读取一个叶子节点
+pub struct BTreeEngineIterator<'a> {
+    pool: &'a mut BufferPool,
+    range: KeyRange,
+    // 两端下一个要读取的叶子节点，None 表示还没有定位，0 表示已经读完
+    front_leaf: Option<u32>,
+    back_leaf: Option<u32>,
+    // 已经读取出来、还没有返回的数据
+    front: VecDeque<(Vec<u8>, Value)>,
+    back: VecDeque<(Vec<u8>, Value)>,
+    // 两端最后返回的 key，两端相遇的时候结束
+    front_key: Option<Vec<u8>>,
+    back_key: Option<Vec<u8>>,
+}
+
+impl<'a> BTreeEngineIterator<'a> {
+    fn before_start(&self, key: &Vec<u8>) -> bool {
+        match &self.range.0 {
+            Bound::Included(start) => key < start,
+            Bound::Excluded(start) => key <= start,
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn after_end(&self, key: &Vec<u8>) -> bool {
+        match &self.range.1 {
+            Bound::Included(end) => key > end,
+            Bound::Excluded(end) => key >= end,
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if let Some((key, value)) = self.front.pop_front() {
+                if self.before_start(&key) {
+                    continue;
+                }
+                if self.after_end(&key) || self.back_key.as_ref().is_some_and(|k| key >= *k) {
+                    self.front.clear();
+                    self.front_leaf = Some(0);
+                    return Ok(None);
+                }
+                let value = read_value(self.pool, &value)?;
+                self.front_key = Some(key.clone());
+                return Ok(Some((key, value)));
+            }
+            let leaf = match self.front_leaf {
+                Some(0) => return Ok(None),
+                Some(leaf) => leaf,
+                None => find_leaf(self.pool, self.range.0.as_ref(), false)?,
+            };
+            match self.pool.read(leaf)? {
+                Page::Leaf { entries, next, .. } => {
+                    self.front = entries.into();
+                    self.front_leaf = Some(next);
+                }
+                page => return Err(unexpected_page(leaf, &page)),
+            }
+        }
+    }
+
+    fn next_back_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if let Some((key, value)) = self.back.pop_back() {
+                if self.after_end(&key) {
+                    continue;
+                }
+                if self.before_start(&key) || self.front_key.as_ref().is_some_and(|k| key <= *k) {
+                    self.back.clear();
+                    self.back_leaf = Some(0);
+                    return Ok(None);
+                }
+                let value = read_value(self.pool, &value)?;
+                self.back_key = Some(key.clone());
+                return Ok(Some((key, value)));
+            }
+            let leaf = match self.back_leaf {
+                Some(0) => return Ok(None),
+                Some(leaf) => leaf,
+                None => find_leaf(self.pool, self.range.1.as_ref(), true)?,
+            };
+            match self.pool.read(leaf)? {
+                Page::Leaf { entries, prev, .. } => {
+                    self.back = entries.into();
+                    self.back_leaf = Some(prev);
+                }
+                page => return Err(unexpected_page(leaf, &page)),
+            }
+        }
+    }
+}
+
+impl<'a> super::engine::EngineIterator for BTreeEngineIterator<'a> {}
+
+impl<'a> Iterator for BTreeEngineIterator<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+impl<'a> DoubleEndedIterator for BTreeEngineIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, ops::Bound, time::Duration};
+
+    use super::{BTreeEngine, Page, SyncPolicy, PAGE_SIZE};
+    use crate::{error::Result, storage::engine::Engine};
+
+    // 简单的伪随机数，保证每次测试的数据一样
+    fn next_rand(seed: &mut u64) -> u64 {
+        *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        *seed >> 33
+    }
+
+    #[test]
+    fn test_btree_engine() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-btree");
+        let mut eng = BTreeEngine::new(p.clone())?;
+        // 缓冲池很小，读写的过程中会不断淘汰页、写回数据文件
+        eng.set_cache_pages(8);
+
+        // 随机写入、覆盖、删除，和 BTreeMap 的结果对比，部分 value 需要溢出页
+        let mut expect = BTreeMap::new();
+        let mut seed = 1;
+        for i in 0..3000 {
+            let key = format!("key{:04}", next_rand(&mut seed) % 1000).into_bytes();
+            if next_rand(&mut seed).is_multiple_of(4) {
+                eng.delete(key.clone())?;
+                expect.remove(&key);
+            } else {
+                let len = if i % 50 == 0 { 20000 } else { 100 };
+                let value = format!("value{}", i).repeat(len / 8).into_bytes();
+                eng.set(key.clone(), value.clone())?;
+                expect.insert(key, value);
+            }
+        }
+        assert_eq!(eng.status()?.keys, expect.len() as u64);
+
+        let check = |eng: &mut BTreeEngine| -> Result<()> {
+            for i in 0..1000 {
+                let key = format!("key{:04}", i).into_bytes();
+                assert_eq!(eng.get(key.clone())?, expect.get(&key).cloned());
+            }
+            let all = eng.scan(..).collect::<Result<Vec<_>>>()?;
+            assert_eq!(all, expect.clone().into_iter().collect::<Vec<_>>());
+            let rev = eng.scan(..).rev().collect::<Result<Vec<_>>>()?;
+            assert_eq!(rev, expect.clone().into_iter().rev().collect::<Vec<_>>());
+
+            // 从两端交替读取
+            let range = (Bound::Excluded(b"key0100".to_vec()), Bound::Included(b"key0300".to_vec()));
+            let mut iter = eng.scan(range.clone());
+            let mut front = Vec::new();
+            let mut back = Vec::new();
+            while let Some(item) = iter.next() {
+                front.push(item?);
+                match iter.next_back() {
+                    Some(item) => back.push(item?),
+                    None => break,
+                }
+            }
+            drop(iter);
+            front.extend(back.into_iter().rev());
+            assert_eq!(front, expect.range(range).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>());
+            Ok(())
+        };
+        check(&mut eng)?;
+        drop(eng);
+
+        // 重新打开之后数据不变
+        let mut eng = BTreeEngine::new(p.clone())?;
+        check(&mut eng)?;
+        assert_eq!(eng.status()?.keys, expect.len() as u64);
+        assert_eq!(eng.status()?.size, expect.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum::<u64>());
+
+        // 全部删除之后树只剩一个叶子节点，其他的页都进入空闲链表
+        for key in expect.keys() {
+            eng.delete(key.clone())?;
+        }
+        assert_eq!(eng.scan(..).count(), 0);
+        assert!(matches!(eng.pool.read(eng.pool.meta.root)?, Page::Leaf { .. }));
+        let status = eng.status()?;
+        assert_eq!(status.garbage_disk_size, status.disk_size - 2 * PAGE_SIZE as u64);
+        drop(eng);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree_engine_journal() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-btree");
+        let mut eng = BTreeEngine::new(p.clone())?;
+        eng.set(b"a".to_vec(), b"1".to_vec())?;
+        eng.sync()?;
+        drop(eng);
+
+        // 写 journal 的时候崩溃，journal 不完整，数据文件没有被修改
+        let mut journal = p.clone().into_os_string();
+        journal.push(".journal");
+        std::fs::write(&journal, [0, 0, 0, 3, 1, 2, 3, 4, 5])?;
+        let mut eng = BTreeEngine::new(p.clone())?;
+        assert_eq!(eng.get(b"a".to_vec())?, Some(b"1".to_vec()));
+
+        // journal 已经写完，写回数据文件之前崩溃，重启时用 journal 恢复
+        eng.set(b"b".to_vec(), b"2".to_vec())?;
+        eng.pool.write_journal()?;
+        // 丢掉缓冲池中修改过的页，关闭时就不会再写回
+        eng.pool.frames.clear();
+        drop(eng);
+        assert!(std::fs::metadata(&journal)?.len() > 0);
+        let mut eng = BTreeEngine::new(p.clone())?;
+        assert_eq!(std::fs::metadata(&journal)?.len(), 0);
+        assert_eq!(eng.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        assert_eq!(eng.get(b"b".to_vec())?, Some(b"2".to_vec()));
+
+        // 写回数据文件失败时，修改过的页仍然留在缓冲池中，下次提交时重新写回
+        eng.set(b"c".to_vec(), b"3".to_vec())?;
+        let file = std::mem::replace(&mut eng.pool.file, std::fs::File::open(&p)?);
+        assert!(eng.sync().is_err());
+        assert!(eng.pool.frames.values().any(|f| f.dirty));
+        eng.pool.file = file;
+        eng.sync()?;
+        assert!(eng.pool.frames.values().all(|f| !f.dirty));
+        eng.pool.frames.clear();
+        assert_eq!(eng.get(b"c".to_vec())?, Some(b"3".to_vec()));
+        drop(eng);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree_engine_sync_policy() -> Result<()> {
+        // 不需要刷盘时修改过的页留在缓冲池中，关闭时再写回数据文件
+        let commit = |policy: SyncPolicy| -> Result<(u64, bool)> {
+            let p = tempfile::tempdir()?.keep().join("sqldb-btree");
+            let mut eng = BTreeEngine::new_with_sync(p.clone(), policy)?;
+            for i in 0..3 {
+                eng.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+                eng.sync()?;
+            }
+            let syncs = eng.status()?.syncs;
+            let dirty = eng.pool.frames.values().any(|f| f.dirty);
+            drop(eng);
+            let mut eng = BTreeEngine::new(p.clone())?;
+            assert_eq!(eng.scan(..).count(), 3);
+            drop(eng);
+            std::fs::remove_dir_all(p.parent().unwrap())?;
+            Ok((syncs, dirty))
+        };
+        assert_eq!(commit(SyncPolicy::Always)?, (3, false));
+        assert_eq!(commit(SyncPolicy::Never)?, (0, true));
+        assert_eq!(commit(SyncPolicy::Periodic(Duration::from_secs(3600)))?, (0, true));
+        assert_eq!(commit(SyncPolicy::Periodic(Duration::ZERO))?, (3, false));
+        Ok(())
+    }
+}
@@ -1,36 +1,190 @@
 use std::{
     collections::{btree_map, BTreeMap}, fmt::format, fs::{File, OpenOptions}, io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write}, path::PathBuf, vec
 };
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 use fs4::FileExt;
 use serde::de::value;
 
 use crate::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, pr, py};
-use crate::{error::Result, pppp};
-pub type KeyDir = BTreeMap<Vec<u8>, (u64, u32)>;
-const LOG_HEADER_SIZE: u32 = 8;
+use crate::error::{Error, Result};
+
+use super::engine::Status;
+// key -> (段编号, value 在段文件中的偏移, value 大小)
+pub type KeyDir = BTreeMap<Vec<u8>, (u32, u64, u32)>;
+// 日志条目的 key 和 value，value 为 None 表示删除标记
+type LogEntry = (Vec<u8>, Option<Vec<u8>>);
+// hint 文件中的条目：key 和 (value 偏移, value 大小)，None 表示删除标记
+type HintEntry = (Vec<u8>, Option<(u64, u32)>);
+const LOG_HEADER_SIZE: u32 = 12;
+// 每个日志文件开头的文件头：魔数(8) | 格式版本(4)
+// 分段之前的单个日志文件没有文件头，是加上 CRC 之前的旧格式（每条日志只有 key len 和 val len），打开时转换成当前的格式
+const LOG_MAGIC: &[u8; 8] = b"SQLDBLOG";
+const LOG_VERSION: u32 = 1;
+pub(crate) const LOG_FILE_HEADER_SIZE: u64 = 12;
+// 旧格式每条日志的头部大小
+const LEGACY_HEADER_SIZE: u64 = 8;
+
+// 当前段的大小超过这个值时，封存当前段，之后的数据写入新的段
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+// 段中无效数据的比例超过这个值时，自动在后台压缩这个段
+const COMPACT_GARBAGE_RATIO: f64 = 0.5;
+// 无效数据太少的时候压缩没有意义，至少要达到这个大小才会触发
+const COMPACT_MIN_GARBAGE: u64 = 4 * 1024 * 1024;
 
 // 磁盘存储引擎定义
+// 日志被切分成多个段文件：前缀.00000001、前缀.00000002 ...，只有编号最大的段可以写入，
+// 其他的段都已经封存，不会再修改，每个封存的段都有一个 hint 文件（前缀.00000001.hint），
+// 记录段中每条日志的 key 和 value 的位置，启动时读取 hint 文件就可以构建内存索引，不需要读取 value
 pub struct DiskEngine {
+    // 日志文件的前缀
+    file_path: PathBuf,
     keydir: KeyDir,
-   pub log: Log,
+    segments: BTreeMap<u32, Segment>,
+    // 段的大小上限
+    segment_size: u64,
+    // 自动压缩的阈值
+    compact_ratio: f64,
+    compact_min_garbage: u64,
+    // 正在后台进行的压缩
+    compaction: Option<Compaction>,
+    // 已经完成的压缩次数
+    compactions: u64,
+    // 刷盘策略，以及上一次刷盘的时间
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    syncs: u64,
+}
+
+// 日志段
+struct Segment {
+    log: Log,
+    // 段文件的大小，以及其中已经失效的数据（被覆盖、被删除的条目和删除标记）大小
+    size: u64,
+    garbage: u64,
+}
+
+// 后台压缩的状态
+// 压缩的段已经封存，压缩线程只读取这个段，期间的读写照常进行
+struct Compaction {
+    segment: u32,
+    handle: JoinHandle<Result<Rewritten>>,
+}
+
+// 压缩线程的结果
+struct Rewritten {
+    // 被保留下来的数据：key，旧的 value 偏移，新的 value 偏移
+    moved: Vec<(Vec<u8>, u64, u64)>,
+    // 被保留下来的数据在 hint 文件中的条目
+    hint: Vec<HintEntry>,
+    // 数据写完之后的文件大小，删除标记都写在这之后
+    data_size: u64,
+    // 写在最后的删除标记：key，大小
+    tombstones: Vec<(Vec<u8>, u64)>,
+}
+
+// 数据刷盘的策略
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    // 每次事务提交都刷盘，提交成功的事务断电也不会丢失
+    Always,
+    // 事务提交时距离上次刷盘超过给定的时间才刷盘，断电最多丢失这段时间内提交的事务
+    Periodic(Duration),
+    // 从不主动刷盘，交给操作系统决定什么时候写回磁盘
+    Never,
+}
+
+// 格式为 always、never 或者 periodic:<毫秒>
+impl FromStr for SyncPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            s => match s.strip_prefix("periodic:") {
+                Some(ms) => Ok(SyncPolicy::Periodic(Duration::from_millis(ms.parse()?))),
+                None => Err(Error::Parse(format!("invalid sync policy {}", s))),
+            },
+        }
+    }
 }
 
 impl DiskEngine {
     pub fn new(file_path: PathBuf) -> Result<Self> {
+        Self::new_with_sync(file_path, SyncPolicy::Always)
+    }
+
+    pub fn new_with_sync(file_path: PathBuf, sync_policy: SyncPolicy) -> Result<Self> {
         __function!("新建磁盘存储引擎中~~~");
         pppg!("开始构建日志文件系统~~~");
-        let mut log = Log::new(file_path)?;
+        let mut ids = list_segments(&file_path)?;
+        // 旧版本只有一个日志文件，转换成当前的格式之后作为第一个段
+        if ids.is_empty() && file_path.is_file() {
+            Log::migrate_legacy(&file_path, &segment_path(&file_path, 1))?;
+        }
+        if ids.is_empty() {
+            ids.push(1);
+        }
         pppg!("日志文件系统构建成功~~~");
         pppg!("开始构建内存索引系统~~~");
 
-        // 从 log 中去恢复的 keydir
-        let keydir = log.build_keydir()?;
+        // 按照段的顺序恢复 keydir，封存的段优先读取 hint 文件
+        let mut keydir = KeyDir::new();
+        let mut segments = BTreeMap::new();
+        let active = *ids.last().unwrap();
+        for id in ids {
+            let mut log = Log::new(segment_path(&file_path, id))?;
+            let size = log.file.metadata()?.len();
+            let hint = hint_path(&segment_path(&file_path, id));
+            let hinted = if id != active { read_hint(&hint, size)? } else { None };
+            let entries = match hinted {
+                Some(entries) => entries,
+                None => {
+                    let entries = log.load(id == active)?;
+                    if id != active {
+                        write_hint(&hint, size, &entries)?;
+                    }
+                    entries
+                }
+            };
+            for (key, pos) in entries {
+                match pos {
+                    Some((offset, val_size)) => keydir.insert(key, (id, offset, val_size)),
+                    None => keydir.remove(&key),
+                };
+            }
+            let size = log.file.metadata()?.len();
+            segments.insert(id, Segment { log, size, garbage: 0 });
+        }
+        let live = live_sizes(&keydir);
+        for (id, segment) in segments.iter_mut() {
+            segment.garbage =
+                segment.size - LOG_FILE_HEADER_SIZE - live.get(id).copied().unwrap_or(0);
+        }
         pppg!("内存索引系统构建成功~~");
 
         ppg!("现在的内存索引条目=>", keydir);
         pppg!("磁盘存储引擎构建成功，正式启动~~~");
-        Ok(Self { keydir, log })
+        Ok(Self {
+            file_path,
+            keydir,
+            segments,
+            segment_size: SEGMENT_SIZE,
+            compact_ratio: COMPACT_GARBAGE_RATIO,
+            compact_min_garbage: COMPACT_MIN_GARBAGE,
+            compaction: None,
+            compactions: 0,
+            sync_policy,
+            last_sync: Instant::now(),
+            syncs: 0,
+        })
     }
 
     pub fn new_compact(file_path: PathBuf) -> Result<Self> {
@@ -39,57 +193,390 @@ impl DiskEngine {
         Ok(eng)
     }
 
+    // 设置自动压缩的阈值：段中无效数据的比例，以及无效数据的最小大小
+    pub fn set_compact_threshold(&mut self, ratio: f64, min_garbage: u64) {
+        self.compact_ratio = ratio;
+        self.compact_min_garbage = min_garbage;
+    }
+
+    // 设置段的大小上限
+    pub fn set_segment_size(&mut self, segment_size: u64) {
+        self.segment_size = segment_size;
+    }
+
+    // 打印所有段中的日志
+    pub fn read_log(&mut self) -> Result<()> {
+        for segment in self.segments.values_mut() {
+            segment.log.read_log()?;
+        }
+        Ok(())
+    }
+
+    // 正在写入的段
+    fn active_id(&self) -> u32 {
+        *self.segments.keys().next_back().unwrap()
+    }
+
+    fn active(&mut self) -> &mut Segment {
+        self.segments.values_mut().next_back().unwrap()
+    }
+
+    // 封存当前段，写入它的 hint 文件，然后新建一个段用于写入
+    fn rotate(&mut self) -> Result<()> {
+        let id = self.active_id();
+        let size = self.active().size;
+        let active = self.active();
+        // 封存的段不会再写入，必须是完整的
+        active.log.file.sync_all()?;
+        let entries = active.log.load(false)?;
+        write_hint(&hint_path(&segment_path(&self.file_path, id)), size, &entries)?;
+
+        let log = Log::new(segment_path(&self.file_path, id + 1))?;
+        self.segments.insert(
+            id + 1,
+            Segment {
+                log,
+                size: LOG_FILE_HEADER_SIZE,
+                garbage: 0,
+            },
+        );
+        Ok(())
+    }
+
+    // 写入一条日志，返回 段编号、日志的偏移、日志的大小
+    fn write(&mut self, key: &[u8], value: Option<&Vec<u8>>) -> Result<(u32, u64, u32)> {
+        if self.active().size >= self.segment_size {
+            self.rotate()?;
+        }
+        let id = self.active_id();
+        let active = self.active();
+        let (offset, size) = active.log.write_entry(key, value)?;
+        active.size = offset + size as u64;
+        Ok((id, offset, size))
+    }
+
+    // 被覆盖或者被删除的数据变成了无效数据
+    fn add_garbage(&mut self, key: &[u8], (id, _, val_size): (u32, u64, u32)) {
+        if let Some(segment) = self.segments.get_mut(&id) {
+            segment.garbage += LOG_HEADER_SIZE as u64 + key.len() as u64 + val_size as u64;
+        }
+    }
+
+    // 立即压缩所有的段，如果后台有正在进行的压缩，先等待它完成
     fn compact(&mut self) -> Result<()> {
-        // 新打开一个临时日志文件
-        let mut new_path = self.log.file_path.clone();
-        new_path.set_extension("compact");
-
-        let mut new_log = Log::new(new_path)?;
-        let mut new_keydir = KeyDir::new();
-
-        // 重写数据到临时文件中
-        for (key, (offset, val_size)) in self.keydir.iter() {
-            // 读取 value
-            let value = self.log.read_value(*offset, *val_size)?;
-            let (new_offset, new_size) = new_log.write_entry(key, Some(&value))?;
-
-            new_keydir.insert(
-                key.clone(),
-                (new_offset + new_size as u64 - *val_size as u64, *val_size),
-            );
+        if let Some(compaction) = self.compaction.take() {
+            self.finish_compaction(compaction)?;
+        }
+        // 当前段也封存起来，这样所有的数据都可以被压缩
+        if self.active().size > LOG_FILE_HEADER_SIZE {
+            self.rotate()?;
+        }
+        let active = self.active_id();
+        let ids = self.segments.keys().copied().filter(|id| *id != active).collect::<Vec<_>>();
+        for id in ids {
+            let compaction = self.start_compaction(id);
+            self.finish_compaction(compaction)?;
+        }
+        Ok(())
+    }
+
+    // 每次写入之后检查：后台压缩完成了就切换到新的段文件，有封存的段无效数据超过阈值就开始新的压缩
+    fn maybe_compact(&mut self) -> Result<()> {
+        match self.compaction.take() {
+            Some(compaction) if compaction.handle.is_finished() => {
+                self.finish_compaction(compaction)
+            }
+            Some(compaction) => {
+                self.compaction = Some(compaction);
+                Ok(())
+            }
+            None => {
+                // 挑选无效数据比例最高的封存段
+                let active = self.active_id();
+                let candidate = self
+                    .segments
+                    .iter()
+                    .filter(|(id, s)| {
+                        **id != active
+                            && s.garbage >= self.compact_min_garbage
+                            && s.garbage as f64 >= s.size as f64 * self.compact_ratio
+                    })
+                    .max_by(|(_, a), (_, b)| {
+                        (a.garbage as f64 / a.size as f64).total_cmp(&(b.garbage as f64 / b.size as f64))
+                    })
+                    .map(|(id, _)| *id);
+                if let Some(id) = candidate {
+                    self.compaction = Some(self.start_compaction(id));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // 在后台线程中把段中的有效数据重写到临时文件中
+    // 只把内存索引中指向这个段的条目交给压缩线程，不复制整个内存索引
+    fn start_compaction(&mut self, id: u32) -> Compaction {
+        let path = segment_path(&self.file_path, id);
+        let live = self
+            .keydir
+            .iter()
+            .filter(|(_, (seg, _, _))| *seg == id)
+            .map(|(key, (_, offset, val_size))| (key.clone(), (*offset, *val_size)))
+            .collect();
+        // 最早的段之前没有数据了，删除标记也可以丢掉
+        let keep_tombstones = self.segments.keys().next() != Some(&id);
+        Compaction {
+            segment: id,
+            handle: std::thread::spawn(move || rewrite(path, live, keep_tombstones)),
+        }
+    }
+
+    // 等待压缩线程结束，用新的段文件替换旧的段文件，并且更新内存索引中的位置
+    fn finish_compaction(&mut self, compaction: Compaction) -> Result<()> {
+        let rewritten = compaction
+            .handle
+            .join()
+            .map_err(|_| Error::Internal("compaction thread panicked".into()))??;
+        let id = compaction.segment;
+        let path = segment_path(&self.file_path, id);
+        let hint = hint_path(&path);
+
+        // 压缩线程不知道 key 在其他段中是否存在，这里再检查一遍删除标记：
+        // key 在之后的段中又被写入了，删除标记就没有用了，重写文件末尾的删除标记，只保留需要的
+        let (tombstones, unneeded): (Vec<_>, Vec<_>) = rewritten
+            .tombstones
+            .into_iter()
+            .partition(|(key, _)| !self.keydir.contains_key(key));
+        if !unneeded.is_empty() {
+            let mut new_log = Log::new(compact_path(&path))?;
+            new_log.file.set_len(rewritten.data_size)?;
+            let mut entries = rewritten.hint;
+            for (key, _) in &tombstones {
+                new_log.write_entry(key, None)?;
+                entries.push((key.clone(), None));
+            }
+            new_log.file.sync_all()?;
+            let size = new_log.file.metadata()?.len();
+            write_hint(&compact_path(&hint), size, &entries)?;
         }
 
-        // 将临时文件更改为正式文件
-        std::fs::rename(&new_log.file_path, &self.log.file_path)?;
+        // 先删除旧的 hint 文件，保证任何时候崩溃，hint 文件都不会和段文件对不上
+        if hint.exists() {
+            std::fs::remove_file(&hint)?;
+        }
+        std::fs::rename(compact_path(&path), &path)?;
+        std::fs::rename(compact_path(&hint), &hint)?;
 
-        new_log.file_path = self.log.file_path.clone();
-        self.keydir = new_keydir;
-        self.log = new_log;
+        // 压缩期间没有被修改过的 key 指向新的位置，它们就是段中的有效数据
+        let mut live = 0;
+        for (key, old_offset, new_offset) in rewritten.moved {
+            if let Some((seg, offset, val_size)) = self.keydir.get_mut(&key) {
+                if *seg == id && *offset == old_offset {
+                    *offset = new_offset;
+                    live += LOG_HEADER_SIZE as u64 + key.len() as u64 + *val_size as u64;
+                }
+            }
+        }
+        let log = Log::new(path)?;
+        let size = log.file.metadata()?.len();
+        self.compactions += 1;
+        // 压缩之后为空的段直接删除
+        if size == LOG_FILE_HEADER_SIZE {
+            self.segments.remove(&id);
+            drop(log);
+            std::fs::remove_file(segment_path(&self.file_path, id))?;
+            std::fs::remove_file(hint)?;
+            return Ok(());
+        }
+        // 保留下来的删除标记是必要的，不算作无效数据
+        let tombstone_size: u64 = tombstones.iter().map(|(_, size)| size).sum();
+        let garbage = size - LOG_FILE_HEADER_SIZE - live - tombstone_size;
+        self.segments.insert(id, Segment { log, size, garbage });
 
         Ok(())
     }
 }
 
+impl Drop for DiskEngine {
+    fn drop(&mut self) {
+        // 不能留下还在写临时文件的线程，关闭前把压缩做完
+        if let Some(compaction) = self.compaction.take() {
+            if let Err(err) = self.finish_compaction(compaction) {
+                pppr!(format!("压缩日志失败:{}", err));
+            }
+        }
+        // 定期刷盘时，最后一次刷盘之后提交的数据在关闭时刷到磁盘上
+        if let SyncPolicy::Periodic(_) = self.sync_policy {
+            if let Err(err) = self.active().log.file.sync_data() {
+                pppr!(format!("刷盘失败:{}", err));
+            }
+        }
+    }
+}
+
+// 段文件的路径：前缀.段编号
+fn segment_path(file_path: &Path, id: u32) -> PathBuf {
+    let mut name = file_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{:08}", id));
+    file_path.with_file_name(name)
+}
+
+// 段的 hint 文件：段文件.hint
+fn hint_path(segment_path: &Path) -> PathBuf {
+    let mut path = segment_path.to_path_buf().into_os_string();
+    path.push(".hint");
+    PathBuf::from(path)
+}
+
+// 压缩时使用的临时文件
+fn compact_path(file_path: &Path) -> PathBuf {
+    let mut path = file_path.to_path_buf().into_os_string();
+    path.push(".compact");
+    PathBuf::from(path)
+}
+
+// 找到所有段的编号，从小到大排列
+fn list_segments(file_path: &Path) -> Result<Vec<u32>> {
+    let dir = match file_path.parent() {
+        Some(dir) if dir.exists() => dir,
+        _ => return Ok(Vec::new()),
+    };
+    let prefix = format!("{}.", file_path.file_name().unwrap_or_default().to_string_lossy());
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Some(id) = name.strip_prefix(&prefix) {
+            if id.len() == 8 && id.bytes().all(|b| b.is_ascii_digit()) {
+                ids.push(id.parse()?);
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+// 每个段中有效数据占用的大小
+fn live_sizes(keydir: &KeyDir) -> HashMap<u32, u64> {
+    let mut live = HashMap::new();
+    for (key, (id, _, val_size)) in keydir.iter() {
+        *live.entry(*id).or_default() +=
+            LOG_HEADER_SIZE as u64 + key.len() as u64 + *val_size as u64;
+    }
+    live
+}
+
+// hint 文件格式：crc(4) | bincode(段文件大小, 条目)
+// 段文件大小和 hint 文件记录的不一致，或者校验失败时，不使用 hint 文件
+fn write_hint(path: &Path, segment_size: u64, entries: &Vec<HintEntry>) -> Result<()> {
+    let data = bincode::serialize(&(segment_size, entries))?;
+    let mut file = File::create(path)?;
+    file.write_all(&crc32fast::hash(&data).to_be_bytes())?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn read_hint(path: &Path, segment_size: u64) -> Result<Option<Vec<HintEntry>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read(path)?;
+    if data.len() < 4 || u32::from_be_bytes(data[0..4].try_into()?) != crc32fast::hash(&data[4..]) {
+        return Ok(None);
+    }
+    let (size, entries): (u64, Vec<HintEntry>) = bincode::deserialize(&data[4..])?;
+    if size != segment_size {
+        return Ok(None);
+    }
+    Ok(Some(entries))
+}
+
+// 把段中的有效数据重写到临时文件中，同时写好新的 hint 文件
+// 在压缩线程中执行，使用单独打开的文件读取段，不会和引擎的读写互相影响
+// live 是开始压缩时内存索引中指向这个段的条目：key -> (value 偏移, value 大小)
+fn rewrite(
+    path: PathBuf,
+    live: HashMap<Vec<u8>, (u64, u32)>,
+    keep_tombstones: bool,
+) -> Result<Rewritten> {
+    let new_path = compact_path(&path);
+    // 上次没有完成的临时文件直接丢弃
+    if new_path.exists() {
+        std::fs::remove_file(&new_path)?;
+    }
+    let mut file = File::open(&path)?;
+    let entries = Log::read_positions(&file)?;
+    let mut new_log = Log::new(new_path)?;
+    let mut hint = Vec::new();
+    let mut rewritten = Rewritten {
+        moved: Vec::new(),
+        hint: Vec::new(),
+        data_size: 0,
+        tombstones: Vec::new(),
+    };
+    let mut tombstones = Vec::new();
+    let mut seen = HashSet::new();
+
+    for (key, pos) in entries {
+        match pos {
+            // 只保留内存索引中还指向这里的数据
+            Some((offset, val_size)) => {
+                if live.get(&key) != Some(&(offset, val_size)) {
+                    continue;
+                }
+                let value = Log::read_value_from(&mut file, offset, val_size)?;
+                let (new_offset, new_size) = new_log.write_entry(&key, Some(&value))?;
+                let new_offset = new_offset + new_size as u64 - val_size as u64;
+                hint.push((key.clone(), Some((new_offset, val_size))));
+                rewritten.moved.push((key, offset, new_offset));
+            }
+            // 之前的段中可能还有这个 key 的数据，删除标记需要保留，直到这个 key 又被写入
+            // 这里只知道这个段中的数据，key 在之后的段中是否存在等到安装新的段文件时再检查
+            None => {
+                if keep_tombstones && !live.contains_key(&key) && seen.insert(key.clone()) {
+                    tombstones.push(key);
+                }
+            }
+        }
+    }
+    // 删除标记之间没有先后关系，统一写在最后，安装时需要去掉其中一部分也只用重写文件末尾
+    rewritten.hint = hint.clone();
+    rewritten.data_size = new_log.file.metadata()?.len();
+    for key in tombstones {
+        let (_, size) = new_log.write_entry(&key, None)?;
+        rewritten.tombstones.push((key.clone(), size as u64));
+        hint.push((key, None));
+    }
+    new_log.file.sync_all()?;
+    let size = new_log.file.metadata()?.len();
+    write_hint(&compact_path(&hint_path(&path)), size, &hint)?;
+    Ok(rewritten)
+}
+
 impl super::engine::Engine for DiskEngine {
     type EngineIterator<'a> = DiskEngineIterator<'a>;
 
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         // 先写日志
-        let (offset, size) = self.log.write_entry(&key, Some(&value))?;
+        let (id, offset, size) = self.write(&key, Some(&value))?;
         // 更新内存索引
         // 100----------------|-----150
         //                   130
         // val size = 20
         let val_size = value.len() as u32;
-        self.keydir
-            .insert(key, (offset + size as u64 - val_size as u64, val_size));
-        Ok(())
+        if let Some(old) = self
+            .keydir
+            .insert(key.clone(), (id, offset + size as u64 - val_size as u64, val_size))
+        {
+            self.add_garbage(&key, old);
+        }
+        self.maybe_compact()
     }
 
     fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         match self.keydir.get(&key) {
-            Some((offset, val_size)) => {
-                let val = self.log.read_value(*offset, *val_size)?;
+            Some((id, offset, val_size)) => {
+                let val = read_value(&mut self.segments, *id, *offset, *val_size)?;
                 Ok(Some(val))
             }
             None => Ok(None),
@@ -97,28 +584,77 @@ impl super::engine::Engine for DiskEngine {
     }
 
     fn delete(&mut self, key: Vec<u8>) -> Result<()> {
-        self.log.write_entry(&key, None)?;
-        self.keydir.remove(&key);
-        Ok(())
+        let (id, _, size) = self.write(&key, None)?;
+        // 删除标记本身以及被删除的数据都是无效数据
+        self.segments.get_mut(&id).unwrap().garbage += size as u64;
+        if let Some(old) = self.keydir.remove(&key) {
+            self.add_garbage(&key, old);
+        }
+        self.maybe_compact()
     }
 
     fn scan(&mut self, range: impl std::ops::RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
         DiskEngineIterator {
             inner: self.keydir.range(range),
-            log: &mut self.log,
+            segments: &mut self.segments,
+        }
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        Ok(Status {
+            name: "disk".into(),
+            keys: self.keydir.len() as u64,
+            size: self
+                .keydir
+                .iter()
+                .map(|(key, (_, _, val_size))| key.len() as u64 + *val_size as u64)
+                .sum(),
+            disk_size: self.segments.values().map(|s| s.size).sum(),
+            garbage_disk_size: self.segments.values().map(|s| s.garbage).sum(),
+            segments: self.segments.len() as u64,
+            compactions: self.compactions,
+            compacting: self.compaction.is_some(),
+            syncs: self.syncs,
+        })
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let sync = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Periodic(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            self.active().log.file.sync_data()?;
+            self.last_sync = Instant::now();
+            self.syncs += 1;
         }
+        Ok(())
+    }
+}
+
+// 从段中读取 value
+fn read_value(
+    segments: &mut BTreeMap<u32, Segment>,
+    id: u32,
+    offset: u64,
+    val_size: u32,
+) -> Result<Vec<u8>> {
+    match segments.get_mut(&id) {
+        Some(segment) => segment.log.read_value(offset, val_size),
+        None => Err(Error::Internal(format!("segment {} does not exist", id))),
     }
 }
 
 pub struct DiskEngineIterator<'a> {
-    inner: btree_map::Range<'a, Vec<u8>, (u64, u32)>,
-    log: &'a mut Log,
+    inner: btree_map::Range<'a, Vec<u8>, (u32, u64, u32)>,
+    segments: &'a mut BTreeMap<u32, Segment>,
 }
 
 impl<'a> DiskEngineIterator<'a> {
-    fn map(&mut self, item: (&Vec<u8>, &(u64, u32))) -> <Self as Iterator>::Item {
-        let (k, (offset, val_size)) = item;
-        let value = self.log.read_value(*offset, *val_size)?;
+    fn map(&mut self, item: (&Vec<u8>, &(u32, u64, u32))) -> <Self as Iterator>::Item {
+        let (k, (id, offset, val_size)) = item;
+        let value = read_value(self.segments, *id, *offset, *val_size)?;
         Ok((k.clone(), value))
     }
 }
@@ -141,11 +677,11 @@ impl<'a> DoubleEndedIterator for DiskEngineIterator<'a> {
 
 pub struct Log {
     file_path: PathBuf,
-    file: std::fs::File,
+    pub(crate) file: std::fs::File,
 }
 
 impl Log {
-    fn new(file_path: PathBuf) -> Result<Self> {
+    pub(crate) fn new(file_path: PathBuf) -> Result<Self> {
         // pppp!(40);
         __function!("启动日志文件系统~~");
 
@@ -166,94 +702,224 @@ impl Log {
         // 加文件锁，保证同时只能有一个服务去使用这个文件
         file.try_lock_exclusive()?;
 
-        Ok(Self { file, file_path })
+        let mut log = Self { file, file_path };
+        log.check_header()?;
+        Ok(log)
     }
 
-    // 遍历数据文件，构建内存索引
-
-    fn build_keydir(&mut self) -> Result<KeyDir> {
-        __function!("构建内存索引");
-        let mut keydir = KeyDir::new();
+    // 检查文件头：新文件写入文件头，文件头不对的说明文件损坏了，直接报错
+    // 旧格式的日志只会出现在分段之前的单个日志文件中，打开引擎时已经转换过了
+    fn check_header(&mut self) -> Result<()> {
         let file_size = self.file.metadata()?.len();
-        let mut buf_reader = BufReader::new(&self.file);
-        pppg!("初次启动引擎，循环读取日志条目，构建索引");
-        let mut offset = 0;
-        let mut count = 1;
-        loop {
-            if offset >= file_size {
-                pppg!("日志为空，未有数据，直接退出");
-                break;
-            }
-            let s = format!("第{}条数据:", count);
-            count+=1;
-            pppg!(s);
-            let (key, val_size) = Self::read_entry(&mut buf_reader, offset)?;
-            let key_size = key.len() as u32;
-            // pppy!(key, val_size);
-            pppy!(format!("key={:?},val_size={:?}",key,val_size));
-            if val_size == -1 {
-                pppg!("val_size=-1，这是删除的数据，不用他来构建索引");
-                keydir.remove(&key);
-                offset += key_size as u64 + LOG_HEADER_SIZE as u64;
-            } else {
-                pppg!("这是有效的数据，要用他来构建索引");
-                keydir.insert(
-                    key,
-                    (
-                        offset + LOG_HEADER_SIZE as u64 + key_size as u64,
-                        val_size as u32,
-                    ),
-                );
-                offset += key_size as u64 + val_size as u64 + LOG_HEADER_SIZE as u64;
+        let mut header = vec![0; file_size.min(LOG_FILE_HEADER_SIZE) as usize];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut header)?;
+        let mut expect = LOG_MAGIC.to_vec();
+        expect.extend(LOG_VERSION.to_be_bytes());
+        // 新文件，或者写文件头的时候崩溃了，里面还没有任何日志
+        if file_size < LOG_FILE_HEADER_SIZE && expect.starts_with(&header) {
+            self.file.set_len(0)?;
+            return self.write_header();
+        }
+        if header.len() == expect.len() && header[..8] == LOG_MAGIC[..] {
+            let version = u32::from_be_bytes(header[8..].try_into()?);
+            if version != LOG_VERSION {
+                return Err(Error::Internal(format!(
+                    "log {:?} has unsupported format version {}",
+                    self.file_path, version
+                )));
             }
+            return Ok(());
         }
+        Err(Error::Internal(format!(
+            "log {:?} is corrupted: invalid file header",
+            self.file_path
+        )))
+    }
 
-        // pppy!("首次读取得到的索引内容为:", keydir);
-        pppy!(format!("首次读到的索引内容为:{:?}",keydir));
-        Ok(keydir)
+    fn write_header(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(LOG_MAGIC)?;
+        self.file.write_all(&LOG_VERSION.to_be_bytes())?;
+        self.file.sync_all()?;
+        Ok(())
     }
 
-    // 遍历数据文件，构建内存索引
+    // 把分段之前的单个日志文件转换成当前格式的段文件：先写到临时文件中，刷盘之后再重命名成段文件，
+    // 最后删除原来的文件。已经有文件头的直接重命名
+    fn migrate_legacy(from: &Path, to: &Path) -> Result<()> {
+        let mut file = OpenOptions::new().read(true).write(true).open(from)?;
+        file.try_lock_exclusive()?;
+        let mut header = [0; 8];
+        if file.read_exact(&mut header).is_ok() && header == *LOG_MAGIC {
+            drop(file);
+            std::fs::rename(from, to)?;
+            return Ok(());
+        }
+        pppr!(format!("日志 {:?} 是旧的格式，转换成当前的格式", from));
+        let entries = Self::read_legacy_entries(&file, from)?;
+        let mut path = to.to_path_buf().into_os_string();
+        path.push(".migrate");
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        file.try_lock_exclusive()?;
+        let mut log = Self { file, file_path: path };
+        log.write_header()?;
+        for (key, value) in &entries {
+            log.write_entry(key, value.as_ref())?;
+        }
+        log.file.sync_all()?;
+        std::fs::rename(&log.file_path, to)?;
+        std::fs::remove_file(from)?;
+        Ok(())
+    }
 
-   pub  fn read_log(&mut self) -> Result<()> {
-        __function!("读取日志文件");
-        // let mut keydir = KeyDir::new();
-        let file_size = self.file.metadata()?.len();
-        let mut buf_reader = BufReader::new(&self.file);
+    // 读出旧格式的日志：key len(4) | val len(4) | key | value，没有校验和
+    // 旧格式没有办法发现损坏的日志，只有正好读完整个文件时才认为是旧格式，否则当作文件损坏报错
+    fn read_legacy_entries(file: &File, path: &Path) -> Result<Vec<LogEntry>> {
+        let file_size = file.metadata()?.len();
+        let mut buf_reader = BufReader::new(file);
+        buf_reader.seek(SeekFrom::Start(0))?;
+        let mut entries = Vec::new();
         let mut offset = 0;
-        let mut count =1;
-        loop {
-            if offset >= file_size {
-                pppg!("日志读取完毕，直接退出");
+        while offset + LEGACY_HEADER_SIZE <= file_size {
+            let mut header = [0; LEGACY_HEADER_SIZE as usize];
+            buf_reader.read_exact(&mut header)?;
+            let key_size = u32::from_be_bytes(header[0..4].try_into()?);
+            let val_size = i32::from_be_bytes(header[4..8].try_into()?);
+            let end = offset + LEGACY_HEADER_SIZE + key_size as u64 + val_size.max(0) as u64;
+            if val_size < -1 || end > file_size {
                 break;
             }
-            let s = format!("第{}条数据:", count);
-            count+=1;
-            pppg!(s);
-            let (key_size, val_size) = Self::read_log_entry(&mut buf_reader, offset)?;
-            if val_size == -1 {
-                offset += key_size as u64 + LOG_HEADER_SIZE as u64;
+            let mut key = vec![0; key_size as usize];
+            buf_reader.read_exact(&mut key)?;
+            let value = if val_size == -1 {
+                None
             } else {
-                offset += key_size as u64 + val_size as u64 + LOG_HEADER_SIZE as u64;
+                let mut value = vec![0; val_size as usize];
+                buf_reader.read_exact(&mut value)?;
+                Some(value)
+            };
+            entries.push((key, value));
+            offset = end;
+        }
+        if offset != file_size {
+            return Err(Error::Internal(format!(
+                "log {:?} is corrupted at offset {}",
+                path, offset
+            )));
+        }
+        Ok(entries)
+    }
+
+    // 清空日志，只保留文件头
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        self.file.set_len(LOG_FILE_HEADER_SIZE)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    // 遍历段文件，读出每条日志的 key 和 value 的位置，用于构建内存索引
+    // 遇到无效的日志（长度超出文件末尾、校验失败）时：
+    // 正在写入的段（truncate_tail 为 true）可能是上次崩溃时没有写完，或者文件系统预分配了全是 0 的尾部，
+    // 把文件截断到最后一条有效的日志；封存的段在封存前已经刷盘，出现无效的日志说明文件损坏了，直接报错
+    pub(crate) fn load(&mut self, truncate_tail: bool) -> Result<Vec<HintEntry>> {
+        __function!("构建内存索引");
+        let file_size = self.file.metadata()?.len();
+        let (entries, end) = Self::scan_positions(&self.file, file_size)?;
+        if end < file_size {
+            if !truncate_tail {
+                return Err(Error::Internal(format!(
+                    "log {:?} is corrupted at offset {}",
+                    self.file_path, end
+                )));
             }
+            pppr!(format!("日志在 {} 处不完整，截断掉 {} 字节", end, file_size - end));
+            self.file.set_len(end)?;
+            self.file.sync_all()?;
         }
+        pppy!(format!("首次读到的索引内容为:{:?}", entries));
+        Ok(entries)
+    }
+
+    // 读出一个完整的段文件中每条日志的位置
+    fn read_positions(file: &File) -> Result<Vec<HintEntry>> {
+        let file_size = file.metadata()?.len();
+        let (entries, end) = Self::scan_positions(file, file_size)?;
+        if end < file_size {
+            return Err(Error::Internal(format!("log is corrupted at offset {}", end)));
+        }
+        Ok(entries)
+    }
+
+    // 顺序读取日志，返回每条日志的位置，以及第一条无效的日志的位置（都有效时为文件末尾）
+    fn scan_positions(file: &File, file_size: u64) -> Result<(Vec<HintEntry>, u64)> {
+        let mut buf_reader = BufReader::new(file);
+        let mut entries = Vec::new();
+        let mut offset = LOG_FILE_HEADER_SIZE;
+        while offset < file_size {
+            let (key, value) = match Self::read_entry(&mut buf_reader, offset, file_size)? {
+                Some(entry) => entry,
+                None => break,
+            };
+            let key_size = key.len() as u64;
+            match value {
+                None => {
+                    entries.push((key, None));
+                    offset += key_size + LOG_HEADER_SIZE as u64;
+                }
+                Some(value) => {
+                    let val_size = value.len() as u32;
+                    entries.push((key, Some((offset + LOG_HEADER_SIZE as u64 + key_size, val_size))));
+                    offset += key_size + val_size as u64 + LOG_HEADER_SIZE as u64;
+                }
+            }
+        }
+        Ok((entries, offset))
+    }
+
+    // 遍历数据文件，打印每一条日志
+    pub fn read_log(&mut self) -> Result<()> {
+        __function!("读取日志文件");
+        for (i, (key, value)) in self.read_entries()?.into_iter().enumerate() {
+            pppg!(format!("第{}条数据:", i + 1));
+            pppy!(format!("key={:?},value={:?}", key, value));
+        }
+        pppg!("日志读取完毕，直接退出");
         Ok(())
     }
-    // +-------------+-------------+----------------+----------------+
-    // | key len(4)    val len(4)     key(varint)       val(varint)  |
-    // +-------------+-------------+----------------+----------------+
-    fn write_entry(&mut self, key: &Vec<u8>, value: Option<&Vec<u8>>) -> Result<(u64, u32)> {
+
+    // +-------------+-------------+-------------+----------------+----------------+
+    // |   crc(4)      key len(4)    val len(4)      key(varint)       val(varint)  |
+    // +-------------+-------------+-------------+----------------+----------------+
+    // crc 是对后面所有内容（长度、key、value）计算的 CRC32 校验和，删除标记的 val len 为 -1
+    pub(crate) fn write_entry(&mut self, key: &[u8], value: Option<&Vec<u8>>) -> Result<(u64, u32)> {
         // 首先将文件偏移移动到文件末尾
         let offset = self.file.seek(SeekFrom::End(0))?;
         let key_size = key.len() as u32;
         let val_size = value.map_or(0, |v| v.len() as u32);
         let total_size = key_size + val_size + LOG_HEADER_SIZE;
+        let val_len = value.map_or(-1, |v| v.len() as i32);
 
-        // 写入 key size、value size、key、value
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&key_size.to_be_bytes());
+        hasher.update(&val_len.to_be_bytes());
+        hasher.update(key);
+        if let Some(v) = value {
+            hasher.update(v);
+        }
+
+        // 写入 crc、key size、value size、key、value
         let mut writer = BufWriter::with_capacity(total_size as usize, &self.file);
+        writer.write_all(&hasher.finalize().to_be_bytes())?;
         writer.write_all(&key_size.to_be_bytes())?;
-        writer.write_all(&value.map_or(-1, |v| v.len() as i32).to_be_bytes())?;
-        writer.write_all(&key)?;
+        writer.write_all(&val_len.to_be_bytes())?;
+        writer.write_all(key)?;
         if let Some(v) = value {
             writer.write_all(v)?;
         }
@@ -263,109 +929,81 @@ impl Log {
     }
 
     fn read_value(&mut self, offset: u64, val_size: u32) -> Result<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(offset))?;
+        Self::read_value_from(&mut self.file, offset, val_size)
+    }
+
+    fn read_value_from(file: &mut File, offset: u64, val_size: u32) -> Result<Vec<u8>> {
+        file.seek(SeekFrom::Start(offset))?;
         let mut buf = vec![0; val_size as usize];
-        self.file.read_exact(&mut buf)?;
+        file.read_exact(&mut buf)?;
         Ok(buf)
     }
 
-    fn read_entry(buf_reader: &mut BufReader<&File>, offset: u64) -> Result<(Vec<u8>, i32)> {
-        buf_reader.seek(SeekFrom::Start(offset))?;
-        let mut len_buf = [0; 4];
-
-        // 读取 key size
-        buf_reader.read_exact(&mut len_buf)?;
-        let key_size = u32::from_be_bytes(len_buf);
-
-        // 读取 value size
-        buf_reader.read_exact(&mut len_buf)?;
-        let val_size = i32::from_be_bytes(len_buf);
-
-        // 读取 key
-        let mut key = vec![0; key_size as usize];
-        buf_reader.read_exact(&mut key)?;
-
-        if val_size != -1 {
-            // 读取 value
-            let mut value = vec![0; val_size as usize];
-            buf_reader.read_exact(&mut value)?;
-            pppy!(format!("本次读取到的日志条目信息:key_size={},val_size={},key={:?},value={:?}",key_size,val_size,key,value));
-            // pppy!(
-            //     "",
-            //     "key_size=",
-            //     key_size,
-            //     "val_size=",
-            //     val_size,
-            //     "key=",
-            //     key,
-            //     "value",
-            //     value
-            // );
-        } else {
-            pppy!(format!("本次读取到的日志条目信息:key_size={},val_size={},key={:?},value=None",key_size,val_size,key));
-
-            // pppy!(
-            //     "本次读取到的日志条目信息:",
-            //     "key_size=",
-            //     key_size,
-            //     "val_size=",
-            //     val_size,
-            //     "key=",
-            //     key,
-            //     "value",
-            //     "None"
-            // );
+    // 按顺序读出所有的日志条目
+    pub(crate) fn read_entries(&mut self) -> Result<Vec<LogEntry>> {
+        let file_size = self.file.metadata()?.len();
+        let mut buf_reader = BufReader::new(&self.file);
+        let mut entries = Vec::new();
+        let mut offset = LOG_FILE_HEADER_SIZE;
+        while offset < file_size {
+            let (key, value) = Self::read_entry(&mut buf_reader, offset, file_size)?.ok_or(
+                Error::Internal(format!("log entry at offset {} is corrupted", offset)),
+            )?;
+            offset += LOG_HEADER_SIZE as u64
+                + key.len() as u64
+                + value.as_ref().map_or(0, |v| v.len() as u64);
+            entries.push((key, value));
         }
-
-        Ok((key, val_size))
+        Ok(entries)
     }
 
-    fn read_log_entry(buf_reader: &mut BufReader<&File>, offset: u64) -> Result<(u32, i32)> {
+    // 读取 offset 处的一条日志，长度超出了文件末尾或者校验失败时返回 None，由调用者决定是截断还是报错
+    fn read_entry(
+        buf_reader: &mut BufReader<&File>,
+        offset: u64,
+        file_size: u64,
+    ) -> Result<Option<LogEntry>> {
+        // 剩下的数据连头部都不够
+        if offset + LOG_HEADER_SIZE as u64 > file_size {
+            return Ok(None);
+        }
         buf_reader.seek(SeekFrom::Start(offset))?;
-        let mut len_buf = [0; 4];
-
-        // 读取 key size
-        buf_reader.read_exact(&mut len_buf)?;
-        let key_size = u32::from_be_bytes(len_buf);
-
-        // 读取 value size
-        buf_reader.read_exact(&mut len_buf)?;
-        let val_size = i32::from_be_bytes(len_buf);
+        let mut header = [0; LOG_HEADER_SIZE as usize];
+        buf_reader.read_exact(&mut header)?;
+        let crc = u32::from_be_bytes(header[0..4].try_into()?);
+        let key_size = u32::from_be_bytes(header[4..8].try_into()?);
+        let val_size = i32::from_be_bytes(header[8..12].try_into()?);
+        let end = offset + LOG_HEADER_SIZE as u64 + key_size as u64 + val_size.max(0) as u64;
+        if val_size < -1 || end > file_size {
+            return Ok(None);
+        }
 
-        // 读取 key
+        // 读取 key 和 value
         let mut key = vec![0; key_size as usize];
         buf_reader.read_exact(&mut key)?;
-
-        if val_size != -1 {
-            // 读取 value
+        let value = if val_size == -1 {
+            None
+        } else {
             let mut value = vec![0; val_size as usize];
             buf_reader.read_exact(&mut value)?;
-            pppy!(
-                "本次读取到的日志条目信息:",
-                "key_size=",
-                key_size,
-                "val_size=",
-                val_size,
-                "key=",
-                key,
-                "value",
-                value
-            );
-        } else {
-            pppy!(
-                "本次读取到的日志条目信息:",
-                "key_size=",
-                key_size,
-                "val_size=",
-                val_size,
-                "key=",
-                key,
-                "value",
-                "None"
-            );
+            Some(value)
+        };
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&key);
+        if let Some(v) = &value {
+            hasher.update(v);
         }
+        if hasher.finalize() != crc {
+            return Ok(None);
+        }
+        pppy!(format!(
+            "本次读取到的日志条目信息:key_size={},val_size={},key={:?},value={:?}",
+            key_size, val_size, key, value
+        ));
 
-        Ok((key_size, val_size))
+        Ok(Some((key, value)))
     }
 }
 
@@ -376,11 +1014,13 @@ mod tets {
     use crate::{
         error::Result,
         pppp,
-        storage::{disk::DiskEngine, engine::Engine},
+        storage::{disk::DiskEngine, engine::Engine, mvcc::Mvcc},
     };
-    use std::path::PathBuf;
+    use std::{path::PathBuf, time::Duration};
 
-    use super::Log;
+    use super::{
+        hint_path, segment_path, Log, SyncPolicy, LOG_FILE_HEADER_SIZE, LOG_MAGIC, LOG_VERSION,
+    };
     use std::fs;
 
     #[test]
@@ -411,7 +1051,7 @@ mod tets {
         pppg!("开启临时重写~~~");
         let mut eng = DiskEngine::new_compact(PathBuf::from("/tmp/sqldb/sqldb-log"))?;
         // let mut log = Log::new(PathBuf::from("/tmp/sqldb/sqldb-log"))?;
-        eng.log.read_log()?;
+        eng.read_log()?;
         pppg!("读取日志文件完毕~~~"); 
         drop(eng);
         Ok(())
@@ -502,4 +1142,304 @@ mod tets {
 
         Ok(())
     }
+
+    #[test]
+    fn test_disk_engine_background_compact() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let mut eng = DiskEngine::new(p.clone())?;
+        eng.set_segment_size(1024);
+        eng.set_compact_threshold(0.5, 0);
+
+        // 反复覆盖同一批 key，封存的段中无效数据超过一半时会自动触发压缩
+        for i in 0..1000 {
+            eng.set(format!("key{}", i % 10).into_bytes(), format!("value{}", i).into_bytes())?;
+        }
+        // 后台压缩一次只处理一个段，等待所有满足条件的段都压缩完
+        loop {
+            if let Some(compaction) = eng.compaction.take() {
+                eng.finish_compaction(compaction)?;
+            }
+            eng.maybe_compact()?;
+            if eng.compaction.is_none() {
+                break;
+            }
+        }
+        let status = eng.status()?;
+        assert!(status.compactions > 0);
+        // 压缩之后为空的段会被删除
+        assert!(status.segments < 10);
+        assert!(status.disk_size < 4 * 1024);
+
+        // 压缩期间的写入照常进行
+        let id = *eng.segments.keys().next().unwrap();
+        eng.compaction = Some(eng.start_compaction(id));
+        eng.set(b"key0".to_vec(), b"new".to_vec())?;
+        eng.delete(b"key1".to_vec())?;
+        eng.set(b"key10".to_vec(), b"value".to_vec())?;
+        if let Some(compaction) = eng.compaction.take() {
+            eng.finish_compaction(compaction)?;
+        }
+
+        assert_eq!(eng.status()?.keys, 10);
+        assert_eq!(eng.get(b"key0".to_vec())?, Some(b"new".to_vec()));
+        assert_eq!(eng.get(b"key1".to_vec())?, None);
+        assert_eq!(eng.get(b"key9".to_vec())?, Some(b"value999".to_vec()));
+        let expect = eng.scan(..).collect::<Result<Vec<_>>>()?;
+        let ids = eng.segments.keys().copied().collect::<Vec<_>>();
+        drop(eng);
+
+        // 重新打开之后数据不变，封存的段从 hint 文件中恢复
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        drop(eng);
+
+        // hint 文件损坏的时候从段文件中恢复
+        for id in &ids[..ids.len() - 1] {
+            let hint = hint_path(&segment_path(&p, *id));
+            assert!(hint.exists());
+            fs::write(hint, b"broken")?;
+        }
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        drop(eng);
+
+        // 全部压缩之后数据不变
+        let mut eng = DiskEngine::new_compact(p.clone())?;
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        assert_eq!(eng.status()?.garbage_disk_size, 0);
+        drop(eng);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_compact_tombstones() -> Result<()> {
+        let p = tempfile::tempdir()?.keep().join("sqldb-log");
+        let mut eng = DiskEngine::new(p.clone())?;
+        // 段 1：a、b 的数据；段 2：a、b 的删除标记和被覆盖的 c；段 3：a 又被写入
+        eng.set(b"a".to_vec(), b"1".to_vec())?;
+        eng.set(b"b".to_vec(), b"1".to_vec())?;
+        eng.rotate()?;
+        eng.delete(b"a".to_vec())?;
+        eng.delete(b"b".to_vec())?;
+        eng.set(b"c".to_vec(), b"1".to_vec())?;
+        eng.set(b"c".to_vec(), b"2".to_vec())?;
+        eng.rotate()?;
+        let ids = eng.segments.keys().copied().collect::<Vec<_>>();
+
+        // 压缩段 2 期间 a 在段 3 中被写入，a 的删除标记不再需要，b 的删除标记还要保留
+        let compaction = eng.start_compaction(ids[1]);
+        eng.set(b"a".to_vec(), b"2".to_vec())?;
+        eng.finish_compaction(compaction)?;
+        assert_eq!(eng.segments[&ids[1]].garbage, 0);
+        let entries = Log::read_positions(&fs::File::open(segment_path(&p, ids[1]))?)?;
+        assert_eq!(
+            entries.iter().map(|(k, pos)| (k.clone(), pos.is_some())).collect::<Vec<_>>(),
+            vec![(b"c".to_vec(), true), (b"b".to_vec(), false)]
+        );
+        let expect = eng.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            expect,
+            vec![(b"a".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"2".to_vec())]
+        );
+        drop(eng);
+
+        // 重新打开之后 b 仍然是删除的，hint 文件和段文件一致
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        fs::remove_file(hint_path(&segment_path(&p, ids[1])))?;
+        drop(eng);
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(eng.scan(..).collect::<Result<Vec<_>>>()?, expect);
+        drop(eng);
+
+        std::fs::remove_dir_all(p.parent().unwrap())?;
+        Ok(())
+    }
+
+    // 模拟写入最后一条日志时崩溃：把文件截断在最后一条日志中间的每一个位置，或者改坏其中的一个字节，
+    // 重新打开之后最后一条日志被丢弃，文件截断到上一条完整的日志，之后可以继续正常写入
+    #[test]
+    fn test_disk_engine_torn_write() -> Result<()> {
+        let dir = tempfile::tempdir()?.keep();
+        let p = dir.join("sqldb-log");
+        let mut eng = DiskEngine::new(p.clone())?;
+        eng.set(b"key1".to_vec(), b"value1".to_vec())?;
+        eng.set(b"key2".to_vec(), b"value2".to_vec())?;
+        eng.delete(b"key1".to_vec())?;
+        drop(eng);
+        let segment = segment_path(&p, 1);
+        let valid_size = fs::metadata(&segment)?.len();
+        let mut eng = DiskEngine::new(p.clone())?;
+        eng.set(b"key3".to_vec(), b"value3".to_vec())?;
+        drop(eng);
+        let data = fs::read(&segment)?;
+
+        let check = |torn: &[u8]| -> Result<()> {
+            let p = dir.join("sqldb-log-torn");
+            let segment = segment_path(&p, 1);
+            fs::write(&segment, torn)?;
+            let mut eng = DiskEngine::new(p.clone())?;
+            assert_eq!(fs::metadata(&segment)?.len(), valid_size);
+            assert_eq!(
+                eng.scan(..).collect::<Result<Vec<_>>>()?,
+                vec![(b"key2".to_vec(), b"value2".to_vec())]
+            );
+            eng.set(b"key4".to_vec(), b"value4".to_vec())?;
+            drop(eng);
+
+            let mut eng = DiskEngine::new(p.clone())?;
+            assert_eq!(eng.get(b"key4".to_vec())?, Some(b"value4".to_vec()));
+            drop(eng);
+            fs::remove_file(&segment)?;
+            Ok(())
+        };
+        for len in valid_size as usize..data.len() {
+            check(&data[..len])?;
+        }
+        for i in valid_size as usize..data.len() {
+            let mut torn = data.clone();
+            torn[i] ^= 0xff;
+            check(&torn)?;
+        }
+
+        // 文件系统预分配的尾部全是 0，也当作没有写完的日志截断掉
+        let mut zeros = data[..valid_size as usize].to_vec();
+        zeros.extend(vec![0; 4096]);
+        check(&zeros)?;
+        let mut zeros = data.clone();
+        zeros[valid_size as usize..].fill(0);
+        check(&zeros)?;
+
+        // 封存的段中出现无效的日志说明文件损坏了，直接报错，不能截断掉后面有效的日志
+        let p = dir.join("sqldb-log-sealed");
+        let mut eng = DiskEngine::new(p.clone())?;
+        eng.set_segment_size(64);
+        for i in 0..10 {
+            eng.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+        }
+        drop(eng);
+        let sealed = segment_path(&p, 1);
+        let sealed_data = fs::read(&sealed)?;
+        fs::remove_file(hint_path(&sealed))?;
+        // 校验和错误
+        let mut corrupted = sealed_data.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        fs::write(&sealed, &corrupted)?;
+        assert!(DiskEngine::new(p.clone()).is_err());
+        // 长度错误，超出了文件末尾
+        let mut corrupted = sealed_data.clone();
+        corrupted[LOG_FILE_HEADER_SIZE as usize + 4] = 0xff;
+        fs::write(&sealed, &corrupted)?;
+        assert!(DiskEngine::new(p.clone()).is_err());
+        assert_eq!(fs::metadata(&sealed)?.len(), sealed_data.len() as u64);
+        fs::write(&sealed, &sealed_data)?;
+        // 文件头损坏，不能当作旧格式的日志转换，hint 文件还在的时候也要报错
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(eng.scan(..).count(), 10);
+        drop(eng);
+        assert!(hint_path(&sealed).exists());
+        for i in [0, LOG_FILE_HEADER_SIZE as usize - 1] {
+            let mut corrupted = sealed_data.clone();
+            corrupted[i] ^= 0xff;
+            fs::write(&sealed, &corrupted)?;
+            assert!(DiskEngine::new(p.clone()).is_err());
+            assert_eq!(fs::read(&sealed)?, corrupted);
+        }
+        fs::write(&sealed, &sealed_data)?;
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(eng.scan(..).count(), 10);
+        drop(eng);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    // 没有文件头的旧格式日志（没有校验和），打开时转换成当前的格式
+    #[test]
+    fn test_disk_engine_legacy_log() -> Result<()> {
+        let dir = tempfile::tempdir()?.keep();
+        let p = dir.join("sqldb-log");
+        let mut legacy = Vec::new();
+        for (key, value) in [
+            (&b"key1"[..], Some(&b"value1"[..])),
+            (b"key2", Some(b"value2")),
+            (b"key1", None),
+        ] {
+            legacy.extend((key.len() as u32).to_be_bytes());
+            legacy.extend(value.map_or(-1, |v| v.len() as i32).to_be_bytes());
+            legacy.extend(key);
+            legacy.extend(value.unwrap_or_default());
+        }
+        fs::write(&p, &legacy)?;
+
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(
+            eng.scan(..).collect::<Result<Vec<_>>>()?,
+            vec![(b"key2".to_vec(), b"value2".to_vec())]
+        );
+        eng.set(b"key3".to_vec(), b"value3".to_vec())?;
+        drop(eng);
+        assert!(fs::read(segment_path(&p, 1))?.starts_with(LOG_MAGIC));
+
+        let mut eng = DiskEngine::new(p.clone())?;
+        assert_eq!(eng.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+        drop(eng);
+        assert!(!p.exists());
+
+        // 旧格式的日志不能正好读完整个文件，说明文件损坏了，原来的文件保持不变
+        let p3 = dir.join("sqldb-log-broken");
+        let broken = legacy[..legacy.len() - 1].to_vec();
+        fs::write(&p3, &broken)?;
+        assert!(DiskEngine::new(p3.clone()).is_err());
+        assert_eq!(fs::read(&p3)?, broken);
+        assert!(!segment_path(&p3, 1).exists());
+
+        // 不认识的格式版本直接报错
+        let mut future = LOG_MAGIC.to_vec();
+        future.extend((LOG_VERSION + 1).to_be_bytes());
+        let p2 = dir.join("sqldb-log-future");
+        fs::write(segment_path(&p2, 1), future)?;
+        assert!(DiskEngine::new(p2).is_err());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_engine_sync_policy() -> Result<()> {
+        assert_eq!("always".parse::<SyncPolicy>()?, SyncPolicy::Always);
+        assert_eq!("NEVER".parse::<SyncPolicy>()?, SyncPolicy::Never);
+        assert_eq!(
+            "periodic:100".parse::<SyncPolicy>()?,
+            SyncPolicy::Periodic(Duration::from_millis(100))
+        );
+        assert!("periodic".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+
+        // 提交有写入的事务时按照策略刷盘，只读事务和回滚的事务不刷盘
+        let commit = |policy: SyncPolicy| -> Result<u64> {
+            let p = tempfile::tempdir()?.keep().join("sqldb-log");
+            let mvcc = Mvcc::new(DiskEngine::new_with_sync(p.clone(), policy)?);
+            for _ in 0..3 {
+                let tx = mvcc.begin()?;
+                tx.set(b"key".to_vec(), b"value".to_vec())?;
+                tx.commit()?;
+            }
+            mvcc.begin()?.commit()?;
+            let tx = mvcc.begin()?;
+            tx.set(b"key".to_vec(), b"value".to_vec())?;
+            tx.rollback()?;
+            let syncs = mvcc.status()?.syncs;
+            drop(mvcc);
+            std::fs::remove_dir_all(p.parent().unwrap())?;
+            Ok(syncs)
+        };
+        assert_eq!(commit(SyncPolicy::Always)?, 3);
+        assert_eq!(commit(SyncPolicy::Never)?, 0);
+        assert_eq!(commit(SyncPolicy::Periodic(Duration::from_secs(3600)))?, 0);
+        assert_eq!(commit(SyncPolicy::Periodic(Duration::ZERO))?, 3);
+        Ok(())
+    }
 }
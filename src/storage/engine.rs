@@ -1,9 +1,12 @@
-use std::ops::{Bound, RangeBounds};
+use std::{
+    fmt::Display,
+    ops::{Bound, RangeBounds},
+};
 
 use crate::error::Result;
 use crate::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, pr, py};
 
-// 抽象存储引擎接口定义，接入不同的存储引擎，目前支持内存和简单的磁盘 KV 存储
+// 抽象存储引擎接口定义，接入不同的存储引擎，目前支持内存、简单的磁盘 KV 存储、LSM 树和 B+ 树
 pub trait Engine {
     type EngineIterator<'a>: EngineIterator
     where
@@ -27,27 +30,76 @@ pub trait Engine {
     //  representing the key prefix to scan, and returns an iterator that traverses all key-value pairs starting with that prefix.
 
     fn scan_prefix(&mut self, prefix: Vec<u8>) -> Self::EngineIterator<'_> {
-        // start: aaaa
-        // end:   aaab
-        let start = Bound::Included(prefix.clone());//Included（包含边界值）
-        let mut bound_prefix = prefix.clone();
-        // 前缀包含了 255，例如 10,2,3,255
-        // 右边界就是 10,2,4
-        // 如果全都是 255
-        // 右边界 Unbounded cause no one greater than 255
-        // this is same to the next num odf 319, which is absoultely 320,but 318,it return 319 ! this is details in the prefix scan !
-        let end = match bound_prefix.iter().rposition(|b| *b != 255) {
-            // iterator the byte arrays reversely, find the first one less than 255,then let it plus 1!this is the right bound!
-            Some(pos) => {
-                bound_prefix[pos] += 1;
-                bound_prefix.truncate(pos + 1);
-                Bound::Excluded(bound_prefix)
-            }
-            None => Bound::Unbounded,//if all 
-        };
-
-        self.scan((start, end))
+        self.scan(prefix_range(prefix))
     }
+
+    // 存储引擎的运行状态
+    fn status(&mut self) -> Result<Status>;
+
+    // 事务提交时调用，按照存储引擎的持久化策略把数据刷到磁盘上
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// 存储引擎的统计信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    // 存储引擎的名字
+    pub name: String,
+    // 有效的 key 的数量
+    pub keys: u64,
+    // 有效的 key 和 value 的总大小
+    pub size: u64,
+    // 数据文件的总大小，内存引擎为 0
+    pub disk_size: u64,
+    // 数据文件中已经失效、可以被压缩掉的数据大小
+    pub garbage_disk_size: u64,
+    // 日志段的数量
+    pub segments: u64,
+    // 已经完成的压缩次数
+    pub compactions: u64,
+    // 后台是否正在压缩
+    pub compacting: bool,
+    // 事务提交时刷盘的次数
+    pub syncs: u64,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "engine: {}", self.name)?;
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "size: {}", self.size)?;
+        writeln!(f, "disk size: {}", self.disk_size)?;
+        writeln!(f, "garbage disk size: {}", self.garbage_disk_size)?;
+        writeln!(f, "segments: {}", self.segments)?;
+        writeln!(f, "compactions: {}", self.compactions)?;
+        writeln!(f, "compacting: {}", self.compacting)?;
+        write!(f, "syncs: {}", self.syncs)
+    }
+}
+
+// 根据前缀计算出扫描的左右边界，供 scan_prefix 以及上层需要分批扫描的迭代器使用
+pub fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // start: aaaa
+    // end:   aaab
+    let start = Bound::Included(prefix.clone());//Included（包含边界值）
+    let mut bound_prefix = prefix;
+    // 前缀包含了 255，例如 10,2,3,255
+    // 右边界就是 10,2,4
+    // 如果全都是 255
+    // 右边界 Unbounded cause no one greater than 255
+    // this is same to the next num odf 319, which is absoultely 320,but 318,it return 319 ! this is details in the prefix scan !
+    let end = match bound_prefix.iter().rposition(|b| *b != 255) {
+        // iterator the byte arrays reversely, find the first one less than 255,then let it plus 1!this is the right bound!
+        Some(pos) => {
+            bound_prefix[pos] += 1;
+            bound_prefix.truncate(pos + 1);
+            Bound::Excluded(bound_prefix)
+        }
+        None => Bound::Unbounded,//if all
+    };
+    (start, end)
 }
 
 pub trait EngineIterator: DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> {}
@@ -58,7 +110,7 @@ mod tests {
     use crate::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, pr, py};
     use crate::{
         error::Result,
-        storage::{disk::DiskEngine, memory::MemoryEngine},
+        storage::{btree::BTreeEngine, disk::DiskEngine, lsm::LsmEngine, memory::MemoryEngine},
     };
 
     use std::{ops::Bound, path::PathBuf};
@@ -194,4 +246,24 @@ mod tests {
         std::fs::remove_dir_all(PathBuf::from("/tmp/sqldb3"))?;
         Ok(())
     }
+
+    #[test]
+    fn test_lsm() -> Result<()> {
+        let dir = tempfile::tempdir()?.keep();
+        test_point_opt(LsmEngine::new(dir.join("lsm1"))?)?;
+        test_scan(LsmEngine::new(dir.join("lsm2"))?)?;
+        test_scan_prefix(LsmEngine::new(dir.join("lsm3"))?)?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree() -> Result<()> {
+        let dir = tempfile::tempdir()?.keep();
+        test_point_opt(BTreeEngine::new(dir.join("btree1"))?)?;
+        test_scan(BTreeEngine::new(dir.join("btree2"))?)?;
+        test_scan_prefix(BTreeEngine::new(dir.join("btree3"))?)?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
 }
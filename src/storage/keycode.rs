@@ -57,8 +57,11 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 
     // 这里必须加上i64 的编码，因为不实现这个序列化就不行！
+    // 翻转符号位，这样负数编码后也比正数小，保证编码后的字节序和数值的大小顺序一致
     fn serialize_i64(self, v: i64) -> Result<()> {
-        self.output.extend(v.to_be_bytes());
+        let mut bytes = v.to_be_bytes();
+        bytes[0] ^= 1 << 7;
+        self.output.extend(bytes);
         Ok(())
     }
 
@@ -83,8 +86,15 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         todo!()
     }
 
+    // 正数翻转符号位，负数翻转所有的位，保证编码后的字节序和数值的大小顺序一致
     fn serialize_f64(self, v: f64) -> Result<()> {
-        self.output.extend(v.to_be_bytes());
+        let mut bytes = v.to_be_bytes();
+        if v.is_sign_negative() {
+            bytes.iter_mut().for_each(|b| *b = !*b);
+        } else {
+            bytes[0] ^= 1 << 7;
+        }
+        self.output.extend(bytes);
         Ok(())
     }
 
@@ -342,9 +352,9 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: de::Visitor<'de>,
     {
-        let bytes = self.take_bytes(8);
-        let v = i64::from_be_bytes(bytes.try_into()?);
-        visitor.visit_i64(v)
+        let mut bytes: [u8; 8] = self.take_bytes(8).try_into()?;
+        bytes[0] ^= 1 << 7;
+        visitor.visit_i64(i64::from_be_bytes(bytes))
     }
 
     fn deserialize_u8<V>(self, _visitor: V) -> Result<V::Value>
@@ -390,9 +400,14 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: de::Visitor<'de>,
     {
-        let bytes = self.take_bytes(8);
-        let v = f64::from_be_bytes(bytes.try_into()?);
-        visitor.visit_f64(v)
+        let mut bytes: [u8; 8] = self.take_bytes(8).try_into()?;
+        // 编码后最高位为 0 说明原来是负数
+        if bytes[0] >> 7 == 0 {
+            bytes.iter_mut().for_each(|b| *b = !*b);
+        } else {
+            bytes[0] ^= 1 << 7;
+        }
+        visitor.visit_f64(f64::from_be_bytes(bytes))
     }
 
     fn deserialize_char<V>(self, _visitor: V) -> Result<V::Value>
@@ -647,6 +662,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_encode_numeric_order() {
+        let ints = [i64::MIN, -100, -1, 0, 1, 100, i64::MAX];
+        let encoded = ints
+            .iter()
+            .map(|v| serialize_key(v).unwrap())
+            .collect::<Vec<_>>();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+        for (v, e) in ints.iter().zip(encoded.iter()) {
+            assert_eq!(deserialize_key::<i64>(e).unwrap(), *v);
+        }
+
+        let floats = [f64::NEG_INFINITY, -10.5, -1.0, -0.5, 0.0, 0.5, 1.0, 10.5, f64::INFINITY];
+        let encoded = floats
+            .iter()
+            .map(|v| serialize_key(v).unwrap())
+            .collect::<Vec<_>>();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+        for (v, e) in floats.iter().zip(encoded.iter()) {
+            assert_eq!(deserialize_key::<f64>(e).unwrap(), *v);
+        }
+    }
+
     // #[test]
     // fn test_u8_convert() {
     //     let v = [1 as u8, 2, 3];
@@ -0,0 +1,186 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::error::{Error, Result};
+
+use super::mvcc::Version;
+
+// 锁的模式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    // 共享锁：SELECT ... FOR SHARE，多个事务可以同时持有
+    Shared,
+    // 排他锁：SELECT ... FOR UPDATE 以及所有的写入，同一时刻只能有一个事务持有
+    Exclusive,
+}
+
+// 悲观锁管理器，按照 key 加锁，锁一直持有到事务结束
+// 拿不到锁的事务会阻塞等待，直到锁被释放或者超时；
+// 开始等待之前会检查等待图，如果等待会形成环（死锁），当前事务直接报错
+pub struct LockManager {
+    table: Mutex<LockTable>,
+    // 有锁被释放的时候唤醒所有等待中的事务重新检查
+    released: Condvar,
+    timeout: Duration,
+}
+
+#[derive(Default)]
+struct LockTable {
+    // 每个 key 上持有锁的事务以及锁的模式
+    locks: HashMap<Vec<u8>, HashMap<Version, LockMode>>,
+    // 每个事务持有锁的 key，事务结束时一起释放
+    held: HashMap<Version, HashSet<Vec<u8>>>,
+    // 等待图：等待中的事务 -> 阻塞它的事务
+    waits: HashMap<Version, HashSet<Version>>,
+}
+
+impl LockTable {
+    // 阻塞当前事务加锁的其他事务
+    fn blockers(&self, version: Version, key: &[u8], mode: LockMode) -> HashSet<Version> {
+        match self.locks.get(key) {
+            Some(holders) => holders
+                .iter()
+                .filter(|(v, m)| {
+                    **v != version && (mode == LockMode::Exclusive || **m == LockMode::Exclusive)
+                })
+                .map(|(v, _)| *v)
+                .collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    // 从 version 出发沿着等待图能否回到 version
+    fn has_cycle(&self, version: Version) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![version];
+        while let Some(v) = stack.pop() {
+            for next in self.waits.get(&v).into_iter().flatten() {
+                if *next == version {
+                    return true;
+                }
+                if visited.insert(*next) {
+                    stack.push(*next);
+                }
+            }
+        }
+        false
+    }
+}
+
+impl LockManager {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            table: Mutex::new(LockTable::default()),
+            released: Condvar::new(),
+            timeout,
+        }
+    }
+
+    // 加锁，已经持有的共享锁可以升级成排他锁
+    pub fn lock(&self, version: Version, key: &[u8], mode: LockMode) -> Result<()> {
+        let deadline = Instant::now() + self.timeout;
+        let mut table = self.table.lock()?;
+        loop {
+            let blockers = table.blockers(version, key, mode);
+            if blockers.is_empty() {
+                table.waits.remove(&version);
+                let holders = table.locks.entry(key.to_vec()).or_default();
+                let held = holders.entry(version).or_insert(mode);
+                if mode == LockMode::Exclusive {
+                    *held = mode;
+                }
+                table.held.entry(version).or_default().insert(key.to_vec());
+                return Ok(());
+            }
+
+            // 持有锁的事务可能已经变了，每次等待之前都重新检查死锁
+            table.waits.insert(version, blockers);
+            if table.has_cycle(version) {
+                table.waits.remove(&version);
+                return Err(Error::Deadlock);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                table.waits.remove(&version);
+                return Err(Error::LockTimeout);
+            }
+            table = self.released.wait_timeout(table, deadline - now)?.0;
+        }
+    }
+
+    // 释放事务持有的所有锁
+    pub fn release(&self, version: Version) -> Result<()> {
+        let mut table = self.table.lock()?;
+        table.waits.remove(&version);
+        for key in table.held.remove(&version).unwrap_or_default() {
+            if let Some(holders) = table.locks.get_mut(&key) {
+                holders.remove(&version);
+                if holders.is_empty() {
+                    table.locks.remove(&key);
+                }
+            }
+        }
+        self.released.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use crate::error::{Error, Result};
+
+    use super::{LockManager, LockMode};
+
+    #[test]
+    fn test_lock_manager() -> Result<()> {
+        let locks = Arc::new(LockManager::new(Duration::from_millis(100)));
+        // 共享锁可以同时持有，排他锁需要等待
+        locks.lock(1, b"a", LockMode::Shared)?;
+        locks.lock(2, b"a", LockMode::Shared)?;
+        assert_eq!(locks.lock(3, b"a", LockMode::Exclusive), Err(Error::LockTimeout));
+        locks.release(2)?;
+        locks.lock(1, b"a", LockMode::Exclusive)?;
+        assert_eq!(locks.lock(2, b"a", LockMode::Shared), Err(Error::LockTimeout));
+
+        // 锁释放之后等待中的事务拿到锁
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(2, b"a", LockMode::Exclusive))
+        };
+        thread::sleep(Duration::from_millis(20));
+        locks.release(1)?;
+        waiter.join().unwrap()?;
+        locks.release(2)?;
+
+        // 死锁：1 等 2，2 再等 1
+        let locks = Arc::new(LockManager::new(Duration::from_secs(10)));
+        locks.lock(1, b"a", LockMode::Exclusive)?;
+        locks.lock(2, b"b", LockMode::Exclusive)?;
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(1, b"b", LockMode::Exclusive))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(locks.lock(2, b"a", LockMode::Exclusive), Err(Error::Deadlock));
+        locks.release(2)?;
+        waiter.join().unwrap()?;
+
+        // 两个事务同时把共享锁升级成排他锁也是死锁
+        locks.lock(3, b"c", LockMode::Shared)?;
+        locks.lock(4, b"c", LockMode::Shared)?;
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || locks.lock(3, b"c", LockMode::Exclusive))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(locks.lock(4, b"c", LockMode::Exclusive), Err(Error::Deadlock));
+        locks.release(4)?;
+        waiter.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1064 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    ops::{Bound, RangeBounds},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use fs4::FileExt as _;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::{__function, pppg, pppr, pppy};
+
+use super::{
+    disk::{Log, SyncPolicy},
+    engine::{Engine as _, Status},
+};
+
+// 内存表的大小超过这个值时，写成 L0 层的 SSTable
+const MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+// SSTable 中数据块的大小
+const BLOCK_SIZE: usize = 4 * 1024;
+// 压缩时输出的 SSTable 的大小上限
+const TABLE_SIZE: u64 = 2 * 1024 * 1024;
+// L0 层的 SSTable 达到这个数量时合并到 L1 层
+const L0_COMPACT_TABLES: usize = 4;
+// L1 层的大小上限，之后每一层是上一层的 10 倍
+const LEVEL_BASE_SIZE: u64 = 10 * 1024 * 1024;
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+// 布隆过滤器中每个 key 占用的位数，误判率大约 1%
+const BLOOM_BITS_PER_KEY: usize = 10;
+// 数据块中每条数据的头部：key len(4) | val len(4)
+const ENTRY_HEADER_SIZE: usize = 8;
+// SSTable 的尾部：元数据偏移(8) | 元数据大小(8) | 元数据的 crc(4)
+const FOOTER_SIZE: u64 = 20;
+
+// key 和 value，value 为 None 表示删除标记
+type Entry = (Vec<u8>, Option<Vec<u8>>);
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// LSM 树存储引擎定义
+// 写入先追加到 WAL，再写入内存表；内存表写满之后变成 L0 层的一个 SSTable，然后清空 WAL。
+// L0 层的 SSTable 之间 key 的范围可能重叠，数量达到阈值之后和 L1 层合并；
+// L1 及以下每一层的 SSTable 互不重叠，大小超过上限之后挑选一个 SSTable 和下一层合并。
+// 内存中只有内存表，以及每个 SSTable 的稀疏索引和布隆过滤器，key 的数量可以超过内存的大小
+pub struct LsmEngine {
+    dir: PathBuf,
+    // 目录锁，保证同时只能有一个服务去使用这个目录
+    _lock: File,
+    memtable: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    memtable_size: u64,
+    wal: Log,
+    // 每一层的 SSTable：L0 层按照生成的顺序排列，越往后越新；其他层按照 key 的范围排列
+    levels: Vec<Vec<Table>>,
+    next_id: u64,
+    // 每一层上次压缩到的 key，下次从它之后挑选 SSTable，轮流压缩
+    compact_pointers: Vec<Vec<u8>>,
+    // 内存表、SSTable、L1 层的大小上限
+    memtable_limit: u64,
+    table_size: u64,
+    level_base_size: u64,
+    // 已经完成的压缩次数
+    compactions: u64,
+    // 刷盘策略，以及上一次刷盘的时间
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    syncs: u64,
+    // 有效的 key 的数量和 key、value 的总大小，打开时统计一次，之后随写入更新
+    keys: u64,
+    size: u64,
+}
+
+// MANIFEST 文件记录每一层有哪些 SSTable，每次修改都写入临时文件再重命名
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+impl LsmEngine {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        Self::new_with_sync(dir, SyncPolicy::Always)
+    }
+
+    pub fn new_with_sync(dir: PathBuf, sync_policy: SyncPolicy) -> Result<Self> {
+        __function!("新建 LSM 存储引擎中~~~");
+        std::fs::create_dir_all(&dir)?;
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(dir.join("LOCK"))?;
+        lock.try_lock_exclusive()?;
+
+        // 按照 MANIFEST 打开每一层的 SSTable
+        let manifest = match std::fs::read(dir.join("MANIFEST")) {
+            Ok(data) => bincode::deserialize(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest {
+                next_id: 1,
+                levels: vec![Vec::new()],
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let mut levels = Vec::new();
+        for ids in &manifest.levels {
+            let mut tables = Vec::new();
+            for id in ids {
+                tables.push(Table::open(table_path(&dir, *id), *id)?);
+            }
+            levels.push(tables);
+        }
+        // 压缩或者写 MANIFEST 的时候崩溃，会留下没有被引用的文件
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let stale = match name.strip_suffix(".sst") {
+                Some(id) => id
+                    .parse::<u64>()
+                    .is_ok_and(|id| !manifest.levels.iter().flatten().any(|i| *i == id)),
+                None => name == "MANIFEST.tmp",
+            };
+            if stale {
+                pppr!(format!("删除没有被引用的文件 {:?}", path));
+                std::fs::remove_file(&path)?;
+            }
+        }
+        pppg!("SSTable 加载成功~~~");
+
+        // 重放 WAL，恢复上次关闭时还在内存表中的数据
+        let mut wal = Log::new(dir.join("wal"))?;
+        wal.load(true)?;
+        let mut memtable = BTreeMap::new();
+        let mut memtable_size = 0;
+        for (key, value) in wal.read_entries()? {
+            memtable_size += (key.len() + value.as_ref().map_or(0, |v| v.len())) as u64;
+            memtable.insert(key, value);
+        }
+        pppg!("WAL 重放成功，LSM 存储引擎正式启动~~~");
+
+        let mut eng = Self {
+            dir,
+            _lock: lock,
+            memtable,
+            memtable_size,
+            wal,
+            levels,
+            next_id: manifest.next_id,
+            compact_pointers: Vec::new(),
+            memtable_limit: MEMTABLE_SIZE,
+            table_size: TABLE_SIZE,
+            level_base_size: LEVEL_BASE_SIZE,
+            compactions: 0,
+            sync_policy,
+            last_sync: Instant::now(),
+            syncs: 0,
+            keys: 0,
+            size: 0,
+        };
+        let (mut keys, mut size) = (0, 0);
+        for item in eng.scan(..) {
+            let (key, value) = item?;
+            keys += 1;
+            size += (key.len() + value.len()) as u64;
+        }
+        (eng.keys, eng.size) = (keys, size);
+        Ok(eng)
+    }
+
+    // 设置内存表、SSTable、L1 层的大小上限
+    pub fn set_size_limits(&mut self, memtable_size: u64, table_size: u64, level_base_size: u64) {
+        self.memtable_limit = memtable_size;
+        self.table_size = table_size;
+        self.level_base_size = level_base_size;
+    }
+
+    // 写入一条数据，value 为 None 表示删除
+    // 先查一次旧的数据来更新统计，新的 key 大多数时候布隆过滤器就能排除，不需要读取 SSTable
+    fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
+        let old = self.get(key.clone())?;
+        self.wal.write_entry(&key, value.as_ref())?;
+        if let Some(old) = old {
+            self.keys -= 1;
+            self.size -= (key.len() + old.len()) as u64;
+        }
+        if let Some(value) = &value {
+            self.keys += 1;
+            self.size += (key.len() + value.len()) as u64;
+        }
+        self.memtable_size += (key.len() + value.as_ref().map_or(0, |v| v.len())) as u64;
+        self.memtable.insert(key, value);
+        if self.memtable_size >= self.memtable_limit {
+            self.flush()?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // 把内存表写成 L0 层的 SSTable，然后清空 WAL
+    fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        // 没有更早的数据的话，删除标记不需要写入
+        let drop_tombstones = self.levels.iter().all(|l| l.is_empty());
+        let entries = self.memtable.iter().map(|(k, v)| Ok((k.clone(), v.clone())));
+        let tables = write_tables(&self.dir, &mut self.next_id, u64::MAX, entries, drop_tombstones)?;
+        self.levels[0].extend(tables);
+        self.save_manifest()?;
+
+        // 内存表中的数据已经持久化到 SSTable 中了
+        self.wal.clear()?;
+        self.memtable.clear();
+        self.memtable_size = 0;
+        Ok(())
+    }
+
+    // 按层检查是否需要压缩，直到每一层都满足条件
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            if self.levels[0].len() >= L0_COMPACT_TABLES {
+                self.compact_level(0)?;
+                continue;
+            }
+            let level = (1..self.levels.len()).find(|l| {
+                let size: u64 = self.levels[*l].iter().map(|t| t.size).sum();
+                size > self.level_base_size * LEVEL_SIZE_MULTIPLIER.pow(*l as u32 - 1)
+            });
+            match level {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // 把 level 层的 SSTable 和下一层中 key 的范围重叠的 SSTable 合并，写入下一层
+    // L0 层的 SSTable 之间互相重叠，全部参与合并；其他层每次挑选一个
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        while self.levels.len() < level + 2 {
+            self.levels.push(Vec::new());
+        }
+        while self.compact_pointers.len() < level + 1 {
+            self.compact_pointers.push(Vec::new());
+        }
+        let inputs = if level == 0 {
+            (0..self.levels[0].len()).collect::<Vec<_>>()
+        } else {
+            let pointer = &self.compact_pointers[level];
+            let tables = &self.levels[level];
+            vec![tables.iter().position(|t| t.meta.first_key > *pointer).unwrap_or(0)]
+        };
+        let first = inputs.iter().map(|i| &self.levels[level][*i].meta.first_key).min().unwrap().clone();
+        let last = inputs.iter().map(|i| &self.levels[level][*i].meta.last_key).max().unwrap().clone();
+        let overlapping = self.levels[level + 1]
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.meta.last_key >= first && t.meta.first_key <= last)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        pppy!(format!(
+            "压缩 L{} 层的 {} 个 SSTable，以及 L{} 层的 {} 个 SSTable",
+            level,
+            inputs.len(),
+            level + 1,
+            overlapping.len()
+        ));
+
+        // 新的数据优先：L0 层越往后越新，上一层比下一层新
+        let mut sources = Vec::new();
+        for i in inputs.iter().rev() {
+            sources.push(Source::new(self.levels[level][*i].iter((Bound::Unbounded, Bound::Unbounded))));
+        }
+        for i in &overlapping {
+            sources.push(Source::new(self.levels[level + 1][*i].iter((Bound::Unbounded, Bound::Unbounded))));
+        }
+        // 输出层下面没有数据的话，删除标记可以丢掉
+        let drop_tombstones = self.levels.iter().skip(level + 2).all(|l| l.is_empty());
+        let outputs = write_tables(
+            &self.dir,
+            &mut self.next_id,
+            self.table_size,
+            MergeIterator { sources },
+            drop_tombstones,
+        )?;
+
+        // 替换掉参与合并的 SSTable
+        let mut obsolete = Vec::new();
+        for i in inputs.iter().rev() {
+            obsolete.push(self.levels[level].remove(*i));
+        }
+        for i in overlapping.iter().rev() {
+            obsolete.push(self.levels[level + 1].remove(*i));
+        }
+        self.levels[level + 1].extend(outputs);
+        self.levels[level + 1].sort_by(|a, b| a.meta.first_key.cmp(&b.meta.first_key));
+        self.compact_pointers[level] = last;
+        self.save_manifest()?;
+        for table in obsolete {
+            let path = table_path(&self.dir, table.id);
+            drop(table);
+            std::fs::remove_file(path)?;
+        }
+        self.compactions += 1;
+        Ok(())
+    }
+
+    // 写入临时文件再重命名，保证 MANIFEST 总是完整的
+    fn save_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|l| l.iter().map(|t| t.id).collect())
+                .collect(),
+        };
+        let tmp = self.dir.join("MANIFEST.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bincode::serialize(&manifest)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, self.dir.join("MANIFEST"))?;
+        Ok(())
+    }
+}
+
+impl Drop for LsmEngine {
+    fn drop(&mut self) {
+        // 定期刷盘的策略下，关闭前把最后一段时间的数据刷到磁盘上
+        if let SyncPolicy::Periodic(_) = self.sync_policy {
+            if let Err(e) = self.wal.file.sync_data() {
+                pppr!(format!("关闭时刷盘失败: {}", e));
+            }
+        }
+    }
+}
+
+impl super::engine::Engine for LsmEngine {
+    type EngineIterator<'a> = LsmEngineIterator<'a>;
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(key, Some(value))
+    }
+
+    // 依次查找内存表、L0 层（从新到旧）、L1 及以下的每一层，找到的第一条数据就是最新的
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+        for table in self.levels[0].iter().rev() {
+            if let Some(value) = table.get(&key)? {
+                return Ok(value);
+            }
+        }
+        for tables in self.levels.iter().skip(1) {
+            let i = tables.partition_point(|t| t.meta.last_key < key);
+            if let Some(table) = tables.get(i) {
+                if let Some(value) = table.get(&key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // 删除的 key 可能在 SSTable 中，总是写入删除标记
+    fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        self.write(key, None)
+    }
+
+    fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> Self::EngineIterator<'_> {
+        let range: KeyRange = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut sources = vec![Source::new(
+            self.memtable
+                .range(range.clone())
+                .map(|(k, v)| Ok((k.clone(), v.clone()))),
+        )];
+        for table in self.levels[0].iter().rev() {
+            if table.overlaps(&range) {
+                sources.push(Source::new(table.iter(range.clone())));
+            }
+        }
+        for table in self.levels.iter().skip(1).flatten() {
+            if table.overlaps(&range) {
+                sources.push(Source::new(table.iter(range.clone())));
+            }
+        }
+        LsmEngineIterator {
+            inner: MergeIterator { sources },
+        }
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        let (keys, size) = (self.keys, self.size);
+        let tables = self.levels.iter().flatten();
+        let data_size: u64 = tables.clone().map(|t| t.meta.data_size).sum::<u64>() + self.memtable_size;
+        Ok(Status {
+            name: "lsm".into(),
+            keys,
+            size,
+            disk_size: tables.clone().map(|t| t.size).sum::<u64>() + self.wal.file.metadata()?.len(),
+            // 被覆盖、被删除的数据在压缩之前一直占用空间，这里按照数据的总大小估算
+            garbage_disk_size: data_size.saturating_sub(size),
+            segments: tables.count() as u64,
+            compactions: self.compactions,
+            compacting: false,
+            syncs: self.syncs,
+        })
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let sync = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Periodic(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            self.wal.file.sync_data()?;
+            self.last_sync = Instant::now();
+            self.syncs += 1;
+        }
+        Ok(())
+    }
+}
+
+// SSTable 文件名：目录/00000001.sst
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:08}.sst", id))
+}
+
+// 把有序的数据写成一个或多个 SSTable，每个 SSTable 的大小不超过 table_size
+fn write_tables(
+    dir: &Path,
+    next_id: &mut u64,
+    table_size: u64,
+    entries: impl Iterator<Item = Result<Entry>>,
+    drop_tombstones: bool,
+) -> Result<Vec<Table>> {
+    let mut tables = Vec::new();
+    let mut builder: Option<TableBuilder> = None;
+    for entry in entries {
+        let (key, value) = entry?;
+        if value.is_none() && drop_tombstones {
+            continue;
+        }
+        if builder.is_none() {
+            builder = Some(TableBuilder::new(dir, *next_id)?);
+            *next_id += 1;
+        }
+        let b = builder.as_mut().unwrap();
+        b.add(&key, value.as_deref())?;
+        if b.size() >= table_size {
+            tables.push(builder.take().unwrap().finish()?);
+        }
+    }
+    if let Some(b) = builder {
+        tables.push(b.finish()?);
+    }
+    Ok(tables)
+}
+
+// 布隆过滤器，判断 key 是否可能在 SSTable 中，不在的话就不需要读取数据块
+#[derive(Debug, Serialize, Deserialize)]
+struct Bloom {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl Bloom {
+    fn new(keys: &[(u32, u32)]) -> Self {
+        let mut bits = vec![0; (keys.len() * BLOOM_BITS_PER_KEY).max(64).div_ceil(8)];
+        // 哈希函数的个数取 位数 * ln2 时误判率最低
+        let hashes = (BLOOM_BITS_PER_KEY as f64 * std::f64::consts::LN_2) as u32;
+        let nbits = bits.len() * 8;
+        for (h1, h2) in keys {
+            for i in 0..hashes {
+                let bit = h1.wrapping_add(i.wrapping_mul(*h2)) as usize % nbits;
+                bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Self { bits, hashes }
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        let (h1, h2) = Self::hash(key);
+        let nbits = self.bits.len() * 8;
+        (0..self.hashes).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) as usize % nbits;
+            self.bits[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    // 用两个不同初始值的 CRC32 模拟多个哈希函数，保证重启之后结果不变
+    fn hash(key: &[u8]) -> (u32, u32) {
+        let mut hasher = crc32fast::Hasher::new_with_initial(0x9747_b28c);
+        hasher.update(key);
+        (crc32fast::hash(key), hasher.finalize())
+    }
+}
+
+// 数据块的索引，记录每个数据块中最大的 key 和数据块的位置
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u32,
+}
+
+// SSTable 的元数据，打开 SSTable 时读到内存中
+#[derive(Debug, Serialize, Deserialize)]
+struct TableMeta {
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+    // 数据（包括删除标记）的条数，以及 key 和 value 的总大小
+    entries: u64,
+    data_size: u64,
+}
+
+// SSTable 的文件格式：
+// +---------+-----+---------+-------------------+-----------+
+// | block 1 | ... | block n | meta（索引、布隆过滤器） | footer(20) |
+// +---------+-----+---------+-------------------+-----------+
+// 每个数据块后面跟着 4 字节的 crc，数据块中每条数据为 key len(4) | val len(4) | key | value，删除标记的 val len 为 -1
+struct Table {
+    id: u64,
+    file: File,
+    size: u64,
+    meta: TableMeta,
+}
+
+impl Table {
+    fn open(path: PathBuf, id: u64) -> Result<Self> {
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE {
+            return Err(Error::Internal(format!("sstable {:?} is corrupted", path)));
+        }
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, size - FOOTER_SIZE)?;
+        let meta_offset = u64::from_be_bytes(footer[0..8].try_into()?);
+        let meta_size = u64::from_be_bytes(footer[8..16].try_into()?);
+        let crc = u32::from_be_bytes(footer[16..20].try_into()?);
+        if meta_offset + meta_size + FOOTER_SIZE != size {
+            return Err(Error::Internal(format!("sstable {:?} is corrupted", path)));
+        }
+        let mut meta = vec![0; meta_size as usize];
+        file.read_exact_at(&mut meta, meta_offset)?;
+        if crc32fast::hash(&meta) != crc {
+            return Err(Error::Internal(format!("sstable {:?} is corrupted", path)));
+        }
+        Ok(Self {
+            id,
+            file,
+            size,
+            meta: bincode::deserialize(&meta)?,
+        })
+    }
+
+    // 读取第 i 个数据块中的所有数据
+    fn read_block(&self, i: usize) -> Result<Vec<Entry>> {
+        let handle = &self.meta.index[i];
+        let mut buf = vec![0; handle.size as usize + 4];
+        self.file.read_exact_at(&mut buf, handle.offset)?;
+        let (data, crc) = buf.split_at(handle.size as usize);
+        if crc32fast::hash(data) != u32::from_be_bytes(crc.try_into()?) {
+            return Err(Error::Internal(format!(
+                "block at offset {} of sstable {} is corrupted",
+                handle.offset, self.id
+            )));
+        }
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let header = data
+                .get(offset..offset + ENTRY_HEADER_SIZE)
+                .ok_or(Error::Internal(format!("sstable {} is corrupted", self.id)))?;
+            let key_size = u32::from_be_bytes(header[0..4].try_into()?) as usize;
+            let val_size = i32::from_be_bytes(header[4..8].try_into()?);
+            offset += ENTRY_HEADER_SIZE;
+            let key = data[offset..offset + key_size].to_vec();
+            offset += key_size;
+            let value = if val_size == -1 {
+                None
+            } else {
+                let value = data[offset..offset + val_size as usize].to_vec();
+                offset += val_size as usize;
+                Some(value)
+            };
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    // 查找 key，外层的 None 表示不在这个 SSTable 中，内层的 None 表示删除标记
+    fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if key < self.meta.first_key.as_slice()
+            || key > self.meta.last_key.as_slice()
+            || !self.meta.bloom.may_contain(key)
+        {
+            return Ok(None);
+        }
+        let i = self.meta.index.partition_point(|b| b.last_key.as_slice() < key);
+        if i == self.meta.index.len() {
+            return Ok(None);
+        }
+        let entries = self.read_block(i)?;
+        Ok(entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|i| entries[i].1.clone()))
+    }
+
+    // key 的范围是否和 SSTable 重叠
+    fn overlaps(&self, range: &KeyRange) -> bool {
+        let before = match &range.1 {
+            Bound::Included(end) => *end < self.meta.first_key,
+            Bound::Excluded(end) => *end <= self.meta.first_key,
+            Bound::Unbounded => false,
+        };
+        let after = match &range.0 {
+            Bound::Included(start) => *start > self.meta.last_key,
+            Bound::Excluded(start) => *start >= self.meta.last_key,
+            Bound::Unbounded => false,
+        };
+        !before && !after
+    }
+
+    fn iter(&self, range: KeyRange) -> TableIterator<'_> {
+        let index = &self.meta.index;
+        let start = match &range.0 {
+            Bound::Included(start) => index.partition_point(|b| b.last_key < *start),
+            Bound::Excluded(start) => index.partition_point(|b| b.last_key <= *start),
+            Bound::Unbounded => 0,
+        };
+        let end = match &range.1 {
+            Bound::Included(end) | Bound::Excluded(end) => {
+                (index.partition_point(|b| b.last_key < *end) + 1).min(index.len())
+            }
+            Bound::Unbounded => index.len(),
+        };
+        TableIterator {
+            table: self,
+            range,
+            blocks: start..end.max(start),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+        }
+    }
+}
+
+// 按照 key 的顺序写入数据，生成 SSTable
+struct TableBuilder {
+    id: u64,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    index: Vec<BlockHandle>,
+    // 每个 key 的哈希值，写完之后生成布隆过滤器
+    hashes: Vec<(u32, u32)>,
+    first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    entries: u64,
+    data_size: u64,
+}
+
+impl TableBuilder {
+    fn new(dir: &Path, id: u64) -> Result<Self> {
+        Ok(Self {
+            id,
+            writer: BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .read(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(table_path(dir, id))?,
+            ),
+            offset: 0,
+            block: Vec::new(),
+            index: Vec::new(),
+            hashes: Vec::new(),
+            first_key: None,
+            last_key: Vec::new(),
+            entries: 0,
+            data_size: 0,
+        })
+    }
+
+    fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        self.hashes.push(Bloom::hash(key));
+        self.block.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        self.block.extend_from_slice(&value.map_or(-1, |v| v.len() as i32).to_be_bytes());
+        self.block.extend_from_slice(key);
+        if let Some(v) = value {
+            self.block.extend_from_slice(v);
+        }
+        self.last_key = key.to_vec();
+        self.entries += 1;
+        self.data_size += (key.len() + value.map_or(0, |v| v.len())) as u64;
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    // 当前已经写入的大小
+    fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.writer.write_all(&crc32fast::hash(&self.block).to_be_bytes())?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            size: self.block.len() as u32,
+        });
+        self.offset += self.block.len() as u64 + 4;
+        self.block.clear();
+        Ok(())
+    }
+
+    // 写入元数据和尾部，刷盘之后 SSTable 就不会再修改了
+    fn finish(mut self) -> Result<Table> {
+        self.flush_block()?;
+        let meta = TableMeta {
+            bloom: Bloom::new(&self.hashes),
+            index: self.index,
+            first_key: self.first_key.unwrap_or_default(),
+            last_key: self.last_key,
+            entries: self.entries,
+            data_size: self.data_size,
+        };
+        let data = bincode::serialize(&meta)?;
+        self.writer.write_all(&data)?;
+        self.writer.write_all(&self.offset.to_be_bytes())?;
+        self.writer.write_all(&(data.len() as u64).to_be_bytes())?;
+        self.writer.write_all(&crc32fast::hash(&data).to_be_bytes())?;
+        let file = self.writer.into_inner().map_err(|e| Error::Internal(e.to_string()))?;
+        file.sync_all()?;
+        Ok(Table {
+            id: self.id,
+            file,
+            size: self.offset + data.len() as u64 + FOOTER_SIZE,
+            meta,
+        })
+    }
+}
+
+// SSTable 的迭代器，每次只读取一个数据块
+struct TableIterator<'a> {
+    table: &'a Table,
+    range: KeyRange,
+    // 还没有读取的数据块
+    blocks: std::ops::Range<usize>,
+    // 从前面和后面读取出来、还没有返回的数据
+    front: VecDeque<Entry>,
+    back: VecDeque<Entry>,
+}
+
+impl<'a> TableIterator<'a> {
+    fn read_block(&self, i: usize) -> Result<VecDeque<Entry>> {
+        let entries = self.table.read_block(i)?;
+        Ok(entries.into_iter().filter(|(k, _)| self.range.contains(k)).collect())
+    }
+}
+
+impl<'a> Iterator for TableIterator<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.front.pop_front() {
+                return Some(Ok(entry));
+            }
+            match self.blocks.next() {
+                Some(i) => match self.read_block(i) {
+                    Ok(entries) => self.front = entries,
+                    Err(e) => return Some(Err(e)),
+                },
+                None => return self.back.pop_front().map(Ok),
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for TableIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.back.pop_back() {
+                return Some(Ok(entry));
+            }
+            match self.blocks.next_back() {
+                Some(i) => match self.read_block(i) {
+                    Ok(entries) => self.back = entries,
+                    Err(e) => return Some(Err(e)),
+                },
+                None => return self.front.pop_back().map(Ok),
+            }
+        }
+    }
+}
+
+// 参与合并的一个有序数据源（内存表或者 SSTable），以及从两端预读出来的数据
+struct Source<'a> {
+    iter: Box<dyn DoubleEndedIterator<Item = Result<Entry>> + 'a>,
+    front: Option<Entry>,
+    back: Option<Entry>,
+}
+
+impl<'a> Source<'a> {
+    fn new(iter: impl DoubleEndedIterator<Item = Result<Entry>> + 'a) -> Self {
+        Self {
+            iter: Box::new(iter),
+            front: None,
+            back: None,
+        }
+    }
+
+    // 数据源中剩下的数据是 front + iter + back，iter 读完之后另一端预读的数据也属于这一端
+    fn peek_front(&mut self) -> Result<()> {
+        if self.front.is_none() {
+            self.front = match self.iter.next() {
+                Some(entry) => Some(entry?),
+                None => self.back.take(),
+            };
+        }
+        Ok(())
+    }
+
+    fn peek_back(&mut self) -> Result<()> {
+        if self.back.is_none() {
+            self.back = match self.iter.next_back() {
+                Some(entry) => Some(entry?),
+                None => self.front.take(),
+            };
+        }
+        Ok(())
+    }
+}
+
+// 多路归并迭代器，数据源按照从新到旧的顺序排列，同一个 key 只返回最新的数据（可能是删除标记）
+struct MergeIterator<'a> {
+    sources: Vec<Source<'a>>,
+}
+
+impl<'a> MergeIterator<'a> {
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        for source in self.sources.iter_mut() {
+            source.peek_front()?;
+        }
+        let key = match self.sources.iter().filter_map(|s| s.front.as_ref().map(|(k, _)| k)).min() {
+            Some(key) => key.clone(),
+            None => return Ok(None),
+        };
+        // 旧的数据源中相同的 key 被覆盖了，直接丢掉
+        let mut entry = None;
+        for source in self.sources.iter_mut() {
+            if source.front.as_ref().is_some_and(|(k, _)| *k == key) {
+                let e = source.front.take();
+                entry = entry.or(e);
+            }
+        }
+        Ok(entry)
+    }
+
+    fn next_back_entry(&mut self) -> Result<Option<Entry>> {
+        for source in self.sources.iter_mut() {
+            source.peek_back()?;
+        }
+        let key = match self.sources.iter().filter_map(|s| s.back.as_ref().map(|(k, _)| k)).max() {
+            Some(key) => key.clone(),
+            None => return Ok(None),
+        };
+        let mut entry = None;
+        for source in self.sources.iter_mut() {
+            if source.back.as_ref().is_some_and(|(k, _)| *k == key) {
+                let e = source.back.take();
+                entry = entry.or(e);
+            }
+        }
+        Ok(entry)
+    }
+}
+
+impl<'a> Iterator for MergeIterator<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+impl<'a> DoubleEndedIterator for MergeIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_entry().transpose()
+    }
+}
+
+// 跳过删除标记
+pub struct LsmEngineIterator<'a> {
+    inner: MergeIterator<'a>,
+}
+
+impl<'a> super::engine::EngineIterator for LsmEngineIterator<'a> {}
+
+impl<'a> Iterator for LsmEngineIterator<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next()? {
+                Ok((key, Some(value))) => return Some(Ok((key, value))),
+                Ok((_, None)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for LsmEngineIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next_back()? {
+                Ok((key, Some(value))) => return Some(Ok((key, value))),
+                Ok((_, None)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs::OpenOptions, io::Write, ops::Bound};
+
+    use super::{Bloom, LsmEngine};
+    use crate::{error::Result, storage::engine::Engine};
+
+    // 简单的伪随机数，保证每次测试的数据一样
+    fn next_rand(seed: &mut u64) -> u64 {
+        *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        *seed >> 33
+    }
+
+    #[test]
+    fn test_lsm_engine() -> Result<()> {
+        let dir = tempfile::tempdir()?.keep().join("sqldb-lsm");
+        let mut eng = LsmEngine::new(dir.clone())?;
+        eng.set_size_limits(1024, 2048, 8 * 1024);
+
+        // 随机写入、覆盖、删除，和 BTreeMap 的结果对比
+        let mut expect = BTreeMap::new();
+        let mut seed = 1;
+        for i in 0..5000 {
+            let key = format!("key{:04}", next_rand(&mut seed) % 500).into_bytes();
+            if next_rand(&mut seed).is_multiple_of(4) {
+                eng.delete(key.clone())?;
+                expect.remove(&key);
+            } else {
+                let value = format!("value{}", i).into_bytes();
+                eng.set(key.clone(), value.clone())?;
+                expect.insert(key, value);
+            }
+        }
+        let status = eng.status()?;
+        assert!(eng.levels.len() > 2);
+        assert!(status.compactions > 0);
+        assert_eq!(status.keys, expect.len() as u64);
+
+        let check = |eng: &mut LsmEngine| -> Result<()> {
+            for i in 0..500 {
+                let key = format!("key{:04}", i).into_bytes();
+                assert_eq!(eng.get(key.clone())?, expect.get(&key).cloned());
+            }
+            let all = eng.scan(..).collect::<Result<Vec<_>>>()?;
+            assert_eq!(all, expect.clone().into_iter().collect::<Vec<_>>());
+
+            // 从两端交替读取
+            let range = (Bound::Included(b"key0100".to_vec()), Bound::Excluded(b"key0300".to_vec()));
+            let mut iter = eng.scan(range.clone());
+            let mut front = Vec::new();
+            let mut back = Vec::new();
+            while let Some(item) = iter.next() {
+                front.push(item?);
+                match iter.next_back() {
+                    Some(item) => back.push(item?),
+                    None => break,
+                }
+            }
+            drop(iter);
+            front.extend(back.into_iter().rev());
+            assert_eq!(front, expect.range(range).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>());
+
+            let prefix = eng.scan_prefix(b"key02".to_vec()).collect::<Result<Vec<_>>>()?;
+            assert_eq!(prefix.len(), expect.keys().filter(|k| k.starts_with(b"key02")).count());
+            Ok(())
+        };
+        check(&mut eng)?;
+        drop(eng);
+
+        // 重启之后从 MANIFEST 和 WAL 恢复
+        let mut eng = LsmEngine::new(dir.clone())?;
+        check(&mut eng)?;
+        assert_eq!(eng.status()?.keys, expect.len() as u64);
+        assert_eq!(eng.status()?.size, expect.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum::<u64>());
+        drop(eng);
+
+        std::fs::remove_dir_all(dir.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsm_engine_torn_wal() -> Result<()> {
+        let dir = tempfile::tempdir()?.keep().join("sqldb-lsm");
+        let mut eng = LsmEngine::new(dir.clone())?;
+        eng.set(b"a".to_vec(), b"1".to_vec())?;
+        eng.set(b"b".to_vec(), b"2".to_vec())?;
+        eng.delete(b"a".to_vec())?;
+        drop(eng);
+
+        // 追加写入 WAL 时崩溃，最后一条日志不完整
+        let mut wal = OpenOptions::new().append(true).open(dir.join("wal"))?;
+        wal.write_all(&[0, 0, 0, 1, 0, 0])?;
+        drop(wal);
+
+        let mut eng = LsmEngine::new(dir.clone())?;
+        assert_eq!(eng.get(b"a".to_vec())?, None);
+        assert_eq!(eng.get(b"b".to_vec())?, Some(b"2".to_vec()));
+        eng.set(b"c".to_vec(), b"3".to_vec())?;
+        drop(eng);
+
+        let mut eng = LsmEngine::new(dir.clone())?;
+        let all = eng.scan(..).collect::<Result<Vec<_>>>()?;
+        assert_eq!(all, vec![(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]);
+        drop(eng);
+
+        std::fs::remove_dir_all(dir.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsm_bloom() {
+        let keys = (0..1000).map(|i| format!("key{}", i).into_bytes()).collect::<Vec<_>>();
+        let bloom = Bloom::new(&keys.iter().map(|k| Bloom::hash(k)).collect::<Vec<_>>());
+        assert!(keys.iter().all(|k| bloom.may_contain(k)));
+        let false_positives = (1000..11000)
+            .filter(|i| bloom.may_contain(format!("key{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300);
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::{btree_map, BTreeMap};
 
 use crate::{__function, error::Result};
 
+use super::engine::Status;
+
 // 内存存储引擎定义
 pub struct MemoryEngine {
     data: BTreeMap<Vec<u8>, Vec<u8>>,
@@ -39,6 +41,20 @@ impl super::engine::Engine for MemoryEngine {
             inner: self.data.range(range),
         }
     }
+
+    fn status(&mut self) -> Result<Status> {
+        Ok(Status {
+            name: "memory".into(),
+            keys: self.data.len() as u64,
+            size: self.data.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum(),
+            disk_size: 0,
+            garbage_disk_size: 0,
+            segments: 0,
+            compactions: 0,
+            compacting: false,
+            syncs: 0,
+        })
+    }
 }
 
 // 内存存储引擎迭代器--双向迭代器
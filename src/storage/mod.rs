@@ -1,5 +1,8 @@
+pub mod btree;
 pub mod disk;
 pub mod engine;
 pub mod keycode;
+pub mod lock;
+pub mod lsm;
 pub mod memory;
 pub mod mvcc;
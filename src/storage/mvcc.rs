@@ -1,7 +1,9 @@
 use crate::{__function, pb, pg, ppb, ppg, pppb, pppg, pppr, pppy, ppr, ppy, pr, py};
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    ops::Bound,
     sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
     u64,
 };
 
@@ -10,8 +12,9 @@ use serde::{Deserialize, Serialize};
 use crate::error::{Error, Result};
 
 use super::{
-    engine::Engine,
+    engine::{prefix_range, Engine, Status},
     keycode::{deserialize_key, serialize_key},
+    lock::{LockManager, LockMode},
 };
 
 pub type Version = u64; //Each transaction is assigned a uniquely increasing version number!
@@ -20,12 +23,18 @@ pub type Version = u64; //Each transaction is assigned a uniquely increasing ver
 // Arc=>多线程引用计数指针
 pub struct Mvcc<E: Engine> {
     engine: Arc<Mutex<E>>,
+    // 只读事务不会写入 TxnAcvtive，它们需要保留的最小版本号记录在内存中，清理旧版本时一起计算水位线
+    snapshots: Arc<Mutex<BTreeMap<Version, usize>>>,
+    // 可选的悲观锁管理器，开启之后写入会先加排他锁，遇到其他事务未提交的写入时等待而不是直接冲突
+    locks: Option<Arc<LockManager>>,
 }
 
 impl<E: Engine> Clone for Mvcc<E> {
     fn clone(&self) -> Self {
         Self {
             engine: self.engine.clone(),
+            snapshots: self.snapshots.clone(),
+            locks: self.locks.clone(),
         }
     }
 }
@@ -35,12 +44,168 @@ impl<E: Engine> Mvcc<E> {
         // __function!("在MVCC理念下实现Arc的新建引擎系统");
         Self {
             engine: Arc::new(Mutex::new(eng)),
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            locks: None,
+        }
+    }
+
+    // 开启锁管理器，timeout 是等待锁的最长时间
+    pub fn new_with_lock_timeout(eng: E, timeout: Duration) -> Self {
+        Self {
+            locks: Some(Arc::new(LockManager::new(timeout))),
+            ..Self::new(eng)
         }
     }
     // 在mvcc下启动事务
     pub fn begin(&self) -> Result<MvccTransaction<E>> {
         // __function!("在MVCC理念下启动事务管理系统");
-        MvccTransaction::begin(self.engine.clone())
+        self.begin_with(IsolationLevel::Snapshot)
+    }
+
+    // 按照给定的隔离级别启动事务
+    pub fn begin_with(&self, isolation: IsolationLevel) -> Result<MvccTransaction<E>> {
+        MvccTransaction::begin(self.engine.clone(), self.locks.clone(), isolation)
+    }
+
+    // 启动只读事务，不分配新的版本号，也不写入任何数据
+    // as_of 为空时读取当前最新的快照，否则读取指定版本提交之后的历史快照
+    pub fn begin_read_only(&self, as_of: Option<Version>) -> Result<MvccTransaction<E>> {
+        MvccTransaction::begin_read_only(self.engine.clone(), self.snapshots.clone(), as_of)
+    }
+
+    // 清理所有事务都不再需要的旧版本数据，返回删除的版本数
+    // 版本号小于水位线的数据，对当前所有活跃事务以及之后开启的事务都是可见的，
+    // 所以同一个 key 在水位线以下只需要保留最新的一个版本，更旧的版本都可以删除；
+    // 如果保留的这个版本是删除标记，那么它本身也可以删除，因为读到删除标记和什么都读不到是一样的
+    pub fn vacuum(&self) -> Result<usize> {
+        // 整个清理过程都持有锁，期间不会有新的事务开启或者写入
+        let mut engine = self.engine.lock()?;
+        let mut watermark = Self::watermark(&mut engine)?;
+        if let Some((snapshot, _)) = self.snapshots.lock()?.first_key_value() {
+            watermark = watermark.min(*snapshot);
+        }
+        // 记录清理过的水位线，更早的历史版本已经不完整了，不能再读取
+        let vacuumed: Version = match engine.get(MvccKey::Vacuumed.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 0,
+        };
+        if watermark > vacuumed {
+            engine.set(MvccKey::Vacuumed.encode()?, bincode::serialize(&watermark)?)?;
+            // 水位线减一之前的历史快照已经不能读取了，它们记录的活跃事务集合也不再需要
+            let mut delete_keys = Vec::new();
+            let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnSnapshot.encode()?);
+            while let Some((key, _)) = iter.next().transpose()? {
+                if let MvccKey::TxnSnapshot(version) = MvccKey::decode(key.clone())? {
+                    if version + 1 < watermark {
+                        delete_keys.push(key);
+                    }
+                }
+            }
+            drop(iter);
+            for key in delete_keys {
+                engine.delete(key)?;
+            }
+        }
+
+        let mut version_prefix = MvccKeyPrefix::Version(Vec::new()).encode()?;
+        version_prefix.truncate(version_prefix.len() - 2);
+        let mut delete_keys = Vec::new();
+        // 当前 key 在水位线以下最新的版本：原始 key，版本 key，是否是删除标记
+        let mut latest: Option<(Vec<u8>, Vec<u8>, bool)> = None;
+        let mut iter = engine.scan_prefix(version_prefix);
+        while let Some((key, value)) = iter.next().transpose()? {
+            let (raw_key, version) = match MvccKey::decode(key.clone())? {
+                MvccKey::Version(raw_key, version) => (raw_key, version),
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            };
+            // 遇到了新的 key，上一个 key 最新的版本如果是删除标记就可以删除
+            if latest.as_ref().is_some_and(|(k, _, _)| *k != raw_key) {
+                if let Some((_, latest_key, true)) = latest.take() {
+                    delete_keys.push(latest_key);
+                }
+            }
+            if version < watermark {
+                let deleted = bincode::deserialize::<Option<Vec<u8>>>(&value)?.is_none();
+                if let Some((_, older_key, _)) = latest.replace((raw_key, key, deleted)) {
+                    delete_keys.push(older_key);
+                }
+            }
+        }
+        drop(iter);
+        if let Some((_, latest_key, true)) = latest {
+            delete_keys.push(latest_key);
+        }
+
+        let count = delete_keys.len();
+        for key in delete_keys {
+            engine.delete(key)?;
+        }
+        Ok(count)
+    }
+
+    // 检查存储引擎中数据的格式版本号：新的数据目录记录为 version，
+    // 版本号不一致，或者已经有数据却没有记录版本号（最早的格式）时报错
+    pub fn check_format(&self, version: u32) -> Result<()> {
+        let mut engine = self.engine.lock()?;
+        let found: u32 = match engine.get(MvccKey::Format.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None if engine.get(MvccKey::NextVersion.encode()?)?.is_none() => {
+                engine.set(MvccKey::Format.encode()?, bincode::serialize(&version)?)?;
+                engine.sync()?;
+                return Ok(());
+            }
+            None => 1,
+        };
+        if found != version {
+            return Err(Error::Internal(format!(
+                "data format version {} is not supported, expected {}; export the data with the old version and import it again",
+                found, version
+            )));
+        }
+        Ok(())
+    }
+
+    // 存储引擎的运行状态
+    pub fn status(&self) -> Result<Status> {
+        let mut engine = self.engine.lock()?;
+        engine.status()
+    }
+
+    // 计算清理的水位线：所有活跃事务的版本号，以及它们开启时活跃事务的版本号中最小的一个
+    // 没有活跃事务的时候就是下一个事务的版本号
+    fn watermark(engine: &mut MutexGuard<E>) -> Result<Version> {
+        let mut watermark = match engine.get(MvccKey::NextVersion.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 1,
+        };
+        let mut iter = engine.scan_prefix(MvccKeyPrefix::TxnAcvtive.encode()?);
+        while let Some((key, value)) = iter.next().transpose()? {
+            match MvccKey::decode(key.clone())? {
+                MvccKey::TxnAcvtive(version) => {
+                    watermark = watermark.min(version);
+                    // 事务开启时还活跃的事务，它们写入的版本对这个事务不可见，即使后来已经提交了也不能删除更旧的版本
+                    // 旧的日志中这里没有记录，当作空集合处理
+                    if !value.is_empty() {
+                        let active: HashSet<Version> = bincode::deserialize(&value)?;
+                        if let Some(min) = active.iter().min() {
+                            watermark = watermark.min(*min);
+                        }
+                    }
+                }
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "unexpected key: {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            }
+        }
+        Ok(watermark)
     }
 }
 
@@ -48,9 +213,58 @@ impl<E: Engine> Mvcc<E> {
 pub struct MvccTransaction<E: Engine> {
     engine: Arc<Mutex<E>>,
     state: TransactionState,
+    isolation: IsolationLevel,
+    // 可串行化的事务读取过的原始 key 的范围，单个 key 的读取记为只包含这个 key 的范围
+    reads: Mutex<Vec<KeyRange>>,
+    // 只读事务在内存中登记的快照，事务结束（drop）时自动注销
+    snapshot: Option<Snapshot>,
+    // 保存点，后创建的在后面
+    savepoints: Mutex<Vec<Savepoint>>,
+    locks: Option<Arc<LockManager>>,
+}
+
+// 事务中的保存点
+// 同一个事务对同一个 key 只有一个版本，后面的写入会覆盖前面的，
+// 所以记录保存点之后第一次写入的每个 key 在保存点时的值（当前事务自己写入的版本，编码后的原始数据），
+// 回滚到保存点时按照记录恢复；None 表示保存点时当前事务还没有写入这个 key
+struct Savepoint {
+    name: String,
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+// 只读事务登记的快照，记录的是清理旧版本时需要保留的最小版本号
+struct Snapshot {
+    snapshots: Arc<Mutex<BTreeMap<Version, usize>>>,
+    watermark: Version,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Ok(mut snapshots) = self.snapshots.lock() {
+            if let Some(count) = snapshots.get_mut(&self.watermark) {
+                *count -= 1;
+                if *count == 0 {
+                    snapshots.remove(&self.watermark);
+                }
+            }
+        }
+    }
+}
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// 事务的隔离级别
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsolationLevel {
+    // 快照隔离：读取事务开启时的快照，检测写写冲突，但是可能出现写偏斜
+    Snapshot,
+    // 可串行化：在快照隔离的基础上记录读取过的 key 和范围，提交时如果发现其中有数据
+    // 被并发执行并且已经提交的事务修改过（读写反依赖），就中止当前事务
+    Serializable,
 }
 
 // this is the read_view which called transactive as well!
+#[derive(Clone)]
 pub struct TransactionState {
     // the verison of current transaction
     pub version: Version,
@@ -77,6 +291,12 @@ pub enum MvccKey {
     TxnAcvtive(Version),
     TxnWrite(Version, #[serde(with = "serde_bytes")] Vec<u8>),
     Version(#[serde(with = "serde_bytes")] Vec<u8>, Version),
+    // 最近一次清理旧版本的水位线
+    Vacuumed,
+    // 事务开启时的活跃事务集合，提交之后也保留，用来构造 AS OF 的历史快照
+    TxnSnapshot(Version),
+    // 数据的格式版本号，不属于任何事务
+    Format,
 }
 
 impl MvccKey {
@@ -95,6 +315,9 @@ pub enum MvccKeyPrefix {
     TxnAcvtive,
     TxnWrite(Version),
     Version(#[serde(with = "serde_bytes")] Vec<u8>),
+    Vacuumed,
+    TxnSnapshot,
+    Format,
 }
 
 impl MvccKeyPrefix {
@@ -108,7 +331,11 @@ impl MvccKeyPrefix {
 
 impl<E: Engine> MvccTransaction<E> {
     // start the transaction!
-    pub fn begin(eng: Arc<Mutex<E>>) -> Result<Self> {
+    pub fn begin(
+        eng: Arc<Mutex<E>>,
+        locks: Option<Arc<LockManager>>,
+        isolation: IsolationLevel,
+    ) -> Result<Self> {
         // __function!();
         // __function!("MvccTransaction::begin()=>");
         // pppg!("MVCC事务的开启=>");
@@ -147,7 +374,15 @@ impl<E: Engine> MvccTransaction<E> {
         let active_versions = Self::scan_active(&mut engine)?;
 
         // put my own transaction version into the active transaction set!
-        engine.set(MvccKey::TxnAcvtive(next_version).encode()?, vec![])?;
+        // 同时记录开启时的活跃事务集合，清理旧版本的时候需要用到
+        engine.set(
+            MvccKey::TxnAcvtive(next_version).encode()?,
+            bincode::serialize(&active_versions)?,
+        )?;
+        engine.set(
+            MvccKey::TxnSnapshot(next_version).encode()?,
+            bincode::serialize(&active_versions)?,
+        )?;
         // pppb!("成功完成Mvcc事务开启");
         // pppb!("现在我们才完成了开启事务前的准备工作，返回一个自身事务的版本号，和当前或活跃事务集合=>");
 
@@ -157,9 +392,100 @@ impl<E: Engine> MvccTransaction<E> {
                 version: next_version,
                 active_versions,
             },
+            isolation,
+            reads: Mutex::new(Vec::new()),
+            snapshot: None,
+            savepoints: Mutex::new(Vec::new()),
+            locks,
         })
     }
 
+    // 开启只读事务：读取下一个版本号和活跃事务集合构造快照，但是不修改它们
+    // 快照的版本号就是能看到的最新版本，所以当前最新的快照是下一个版本号减一，
+    // 历史快照和版本 as_of 的事务开启时看到的一样：它开启时还活跃的事务即使后来提交了也看不到，
+    // 另外能看到 as_of 自己写入的数据（还没有提交时除外）
+    fn begin_read_only(
+        eng: Arc<Mutex<E>>,
+        snapshots: Arc<Mutex<BTreeMap<Version, usize>>>,
+        as_of: Option<Version>,
+    ) -> Result<Self> {
+        let mut engine = eng.lock()?;
+        let next_version: Version = match engine.get(MvccKey::NextVersion.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 1,
+        };
+        let version = match as_of {
+            Some(version) if version >= next_version => {
+                return Err(Error::Internal(format!(
+                    "Version {} does not exist yet",
+                    version
+                )))
+            }
+            Some(version) => version,
+            None => next_version - 1,
+        };
+        let vacuumed: Version = match engine.get(MvccKey::Vacuumed.encode()?)? {
+            Some(value) => bincode::deserialize(&value)?,
+            None => 0,
+        };
+        if version + 1 < vacuumed {
+            return Err(Error::Internal(format!(
+                "Version {} has been vacuumed",
+                version
+            )));
+        }
+        let mut active_versions = Self::scan_active(&mut engine)?;
+        if as_of.is_some() {
+            let still_active = active_versions.contains(&version);
+            // 旧的数据中没有记录事务开启时的活跃事务集合，无法构造一致的历史快照
+            active_versions = match engine.get(MvccKey::TxnSnapshot(version).encode()?)? {
+                Some(value) => bincode::deserialize(&value)?,
+                // 版本 0 在所有事务之前，什么都看不到
+                None if version == 0 => HashSet::new(),
+                None => {
+                    return Err(Error::Internal(format!(
+                        "Version {} has no recorded snapshot",
+                        version
+                    )))
+                }
+            };
+            if still_active {
+                active_versions.insert(version);
+            }
+        }
+
+        // 和普通事务一样，快照中活跃的事务以及快照之后的版本都不能清理
+        // 清理时只保留水位线以下每个 key 最新的版本，水位线超过这里时快照需要的版本可能已经删除了
+        let watermark = active_versions.iter().copied().fold(version + 1, Version::min);
+        if watermark < vacuumed {
+            return Err(Error::Internal(format!(
+                "Version {} has been vacuumed",
+                version
+            )));
+        }
+        *snapshots.lock()?.entry(watermark).or_insert(0) += 1;
+        Ok(Self {
+            engine: eng.clone(),
+            state: TransactionState {
+                version,
+                active_versions,
+            },
+            isolation: IsolationLevel::Snapshot,
+            reads: Mutex::new(Vec::new()),
+            snapshot: Some(Snapshot {
+                snapshots,
+                watermark,
+            }),
+            savepoints: Mutex::new(Vec::new()),
+            locks: None,
+        })
+    }
+
+    // 是否是只读事务
+    pub fn read_only(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
     // get my own version
     pub fn version(&self) -> u64 {
         self.state.version
@@ -169,9 +495,21 @@ impl<E: Engine> MvccTransaction<E> {
     pub fn commit(&self) -> Result<()> {
         __function!("MvccTransaction::commit()=>");
         pppg!("提交MVCC管理下的事务");
+        // 只读事务没有写入任何数据，快照在事务 drop 时注销
+        if self.read_only() {
+            return Ok(());
+        }
 
         // get the storge engine
         let mut engine = self.engine.lock()?;
+        // 可串行化的事务先检查读写反依赖，检查和提交都在锁内完成，期间不会有其他事务提交
+        if self.isolation == IsolationLevel::Serializable {
+            if let Err(err) = self.check_serializable(&mut engine) {
+                drop(engine);
+                self.rollback()?;
+                return Err(err);
+            }
+        }
         // pppb!("提交数据也要先获取存储引擎!获取成功！");
         // pppb!("txnWrite是解决我们当前事务记录了哪些信息，每个事务开启时都会先构造read_view，而且是私有的，所以这里直接在事务结束后消除记录的信息即可，因为事物都结束了，这个的信息也就没用了，要回滚早回滚了~~");
         // pppb!("找到这个当前事务的 TxnWrite 信息，并将之删除，那通过什么找呢？");
@@ -186,16 +524,26 @@ impl<E: Engine> MvccTransaction<E> {
         ppy!(delete_keys);
         drop(iter);
         // iteratively delete the info of this transaction by the transaction prefix!
+        let has_writes = !delete_keys.is_empty();
         for key in delete_keys.into_iter() {
             engine.delete(key)?;
         }
 
         // transaction over! remove from the active transaction naturally!
-        engine.delete(MvccKey::TxnAcvtive(self.state.version).encode()?)
+        engine.delete(MvccKey::TxnAcvtive(self.state.version).encode()?)?;
+        // 只读事务没有需要持久化的数据
+        if has_writes {
+            engine.sync()?;
+        }
+        drop(engine);
+        self.release_locks()
     }
 
     // rollback transaction!
     pub fn rollback(&self) -> Result<()> {
+        if self.read_only() {
+            return Ok(());
+        }
         // get the storage engine！
         let mut engine = self.engine.lock()?;
 
@@ -223,7 +571,89 @@ impl<E: Engine> MvccTransaction<E> {
         }
 
         // transaction over! remove from the active transaction naturally!
-        engine.delete(MvccKey::TxnAcvtive(self.state.version).encode()?)
+        engine.delete(MvccKey::TxnAcvtive(self.state.version).encode()?)?;
+        drop(engine);
+        self.release_locks()
+    }
+
+    // 释放事务持有的所有锁，事务提交、回滚或者被丢弃的时候调用
+    fn release_locks(&self) -> Result<()> {
+        match &self.locks {
+            Some(locks) => locks.release(self.state.version),
+            None => Ok(()),
+        }
+    }
+
+    // 给 key 加锁（SELECT ... FOR UPDATE / FOR SHARE），锁一直持有到事务结束
+    // 拿到锁之后还要检查 key 是否已经被并发的事务修改过，和写入时的冲突检测一样
+    pub fn lock(&self, key: Vec<u8>, mode: LockMode) -> Result<()> {
+        if self.read_only() {
+            return Err(Error::Internal(
+                "Cannot lock rows in a read-only transaction".into(),
+            ));
+        }
+        match &self.locks {
+            Some(locks) => locks.lock(self.state.version, &key, mode)?,
+            None => return Err(Error::Internal("Lock manager is not enabled".into())),
+        }
+        let mut engine = self.engine.lock()?;
+        self.check_conflict(&mut engine, key)
+    }
+
+    // 创建保存点，可以和已有的保存点重名，回滚和释放时使用最近创建的那个
+    pub fn savepoint(&self, name: String) -> Result<()> {
+        self.savepoints.lock()?.push(Savepoint {
+            name,
+            writes: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    // 回滚到保存点：撤销保存点之后的所有写入，并删除之后创建的保存点，这个保存点本身保留
+    pub fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        let mut engine = self.engine.lock()?;
+        let mut savepoints = self.savepoints.lock()?;
+        let index = Self::find_savepoint(&savepoints, name)?;
+        // 从后往前恢复，同一个 key 最终恢复成最早的保存点记录的值
+        for savepoint in savepoints.drain(index..).rev() {
+            for (key, value) in savepoint.writes {
+                let version_key = MvccKey::Version(key.clone(), self.state.version).encode()?;
+                match value {
+                    Some(value) => engine.set(version_key, value)?,
+                    None => {
+                        engine.delete(version_key)?;
+                        engine.delete(MvccKey::TxnWrite(self.state.version, key).encode()?)?;
+                    }
+                }
+            }
+        }
+        savepoints.push(Savepoint {
+            name: name.to_string(),
+            writes: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    // 释放保存点：删除这个保存点以及之后创建的保存点，它们之后的写入归入上一个保存点
+    pub fn release_savepoint(&self, name: &str) -> Result<()> {
+        let mut savepoints = self.savepoints.lock()?;
+        let index = Self::find_savepoint(&savepoints, name)?;
+        let released = savepoints.split_off(index);
+        if let Some(previous) = savepoints.last_mut() {
+            for savepoint in released {
+                for (key, value) in savepoint.writes {
+                    previous.writes.entry(key).or_insert(value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn find_savepoint(savepoints: &[Savepoint], name: &str) -> Result<usize> {
+        savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+            .ok_or(Error::Internal(format!("savepoint {} does not exist", name)))
     }
 
     pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
@@ -237,6 +667,7 @@ impl<E: Engine> MvccTransaction<E> {
     }
     // the method is to find the first visible data which key is specified as the param！
     pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.record_read((Bound::Included(key.clone()), Bound::Included(key.clone())))?;
         // get the engine!
         let mut engine = self.engine.lock()?;
 
@@ -263,9 +694,11 @@ impl<E: Engine> MvccTransaction<E> {
         Ok(None)
     }
 
-    // why not? cause the
-    pub fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<ScanResult>> {
-        let mut eng = self.engine.lock()?;
+    // 前缀扫描返回的是一个迭代器，并不会一次性把所有数据读到内存中
+    // 迭代器内部分批从存储引擎中拉取数据，每一批读完就释放引擎的锁，
+    // 这样上层执行器在逐行消费数据的同时，事务的其他读写操作也能正常拿到锁
+    pub fn scan_prefix(&self, prefix: Vec<u8>) -> Result<ScanIterator<E>> {
+        self.record_read(prefix_range(prefix.clone()))?;
         let mut enc_prefix = MvccKeyPrefix::Version(prefix).encode()?;
         // 原始值           编码后
         // 97 98 99     -> 97 98 99 0 0
@@ -274,31 +707,71 @@ impl<E: Engine> MvccTransaction<E> {
         // 去掉最后的 [0, 0] 后缀
         enc_prefix.truncate(enc_prefix.len() - 2);
 
-        let mut iter = eng.scan_prefix(enc_prefix);
-        let mut results = BTreeMap::new();
-        while let Some((key, value)) = iter.next().transpose()? {
-            match MvccKey::decode(key.clone())? {
-                MvccKey::Version(raw_key, version) => {
-                    if self.state.is_visible(version) {
-                        match bincode::deserialize(&value)? {
-                            Some(raw_value) => results.insert(raw_key, raw_value),
-                            None => results.remove(&raw_key),
-                        };
+        Ok(ScanIterator::new(
+            self.engine.clone(),
+            self.state.clone(),
+            prefix_range(enc_prefix),
+        ))
+    }
+
+    // 范围扫描，range 是原始 key 的范围，同样返回分批读取数据的迭代器
+    pub fn scan(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<ScanIterator<E>> {
+        self.record_read(range.clone())?;
+        let range = match version_range(range)? {
+            Some(range) => range,
+            // 起点大于终点的范围（例如 a > 5 and a < 3）一定是空的，
+            // 底层 BTreeMap::range 遇到这种范围会 panic，所以换成一个等价的空范围
+            None => {
+                let key = MvccKey::Version(Vec::new(), 0).encode()?;
+                (Bound::Included(key.clone()), Bound::Excluded(key))
+            }
+        };
+        Ok(ScanIterator::new(self.engine.clone(), self.state.clone(), range))
+    }
+
+    // 可串行化的事务记录读取过的范围
+    fn record_read(&self, range: KeyRange) -> Result<()> {
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.lock()?.push(range);
+        }
+        Ok(())
+    }
+
+    // 检查读取过的范围中，是否有对当前事务不可见、并且已经提交的版本
+    // 这样的版本说明有并发的事务 U 修改了当前事务 T 读取过的数据（T -rw-> U），T 必须排在 U 之前，
+    // 但是 U 已经先提交了，T 再提交可能形成环，所以中止 T。
+    // 还没有提交的事务 U 可以排在 T 之后，由 U 提交时检查它自己的读取
+    fn check_serializable(&self, engine: &mut MutexGuard<E>) -> Result<()> {
+        let mut versions = HashSet::new();
+        for range in self.reads.lock()?.iter() {
+            let range = match version_range(range.clone())? {
+                Some(range) => range,
+                None => continue,
+            };
+            let mut iter = engine.scan(range);
+            while let Some((key, _)) = iter.next().transpose()? {
+                match MvccKey::decode(key.clone())? {
+                    MvccKey::Version(_, version) => {
+                        if !self.state.is_visible(version) {
+                            versions.insert(version);
+                        }
+                    }
+                    _ => {
+                        return Err(Error::Internal(format!(
+                            "unexpected key: {:?}",
+                            String::from_utf8(key)
+                        )))
                     }
-                }
-                _ => {
-                    return Err(Error::Internal(format!(
-                        "Unexepected key {:?}",
-                        String::from_utf8(key)
-                    )))
                 }
             }
         }
-
-        Ok(results
-            .into_iter()
-            .map(|(key, value)| ScanResult { key, value })
-            .collect())
+        // 回滚的事务会删除自己写入的版本，所以版本存在并且事务已经不活跃，说明已经提交了
+        for version in versions {
+            if engine.get(MvccKey::TxnAcvtive(version).encode()?)?.is_none() {
+                return Err(Error::SerializationFailure);
+            }
+        }
+        Ok(())
     }
 
     // mothed of set/delete we see earliy all called the write_inner method as the core operation!
@@ -307,9 +780,46 @@ impl<E: Engine> MvccTransaction<E> {
     // when the current transaction is writing or deleting data, and the modification result is invisible to the current transaction.
     // 这里的冲突检测主要是判断当前事务在写入或删除数据时，是否有其他事务已经对该数据进行了修改且修改结果对当前事务不可见。
     fn write_inner(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
+        if self.read_only() {
+            return Err(Error::Internal(
+                "Cannot write in a read-only transaction".into(),
+            ));
+        }
+        // 开启了锁管理器的话先加排他锁，其他事务持有锁（包括还没有提交的写入）时等待
+        // 等到锁之后仍然按照自己的快照检查冲突：持有锁的事务回滚了就可以继续写入，
+        // 提交了的话它的写入对当前事务不可见，还是写冲突（和 PostgreSQL 的 REPEATABLE READ 一样），
+        // 这时等待只是推迟了报错，自动提交的语句会用新的快照重试
+        if let Some(locks) = &self.locks {
+            locks.lock(self.state.version, &key, LockMode::Exclusive)?;
+        }
         //get the storge engine because all data was set in its memory!
         let mut engine = self.engine.lock()?;
+        self.check_conflict(&mut engine, key.clone())?;
+
+        // 有保存点的话，记录这个 key 在最近的保存点时的值
+        if let Some(savepoint) = self.savepoints.lock()?.last_mut() {
+            if !savepoint.writes.contains_key(&key) {
+                let value = engine.get(MvccKey::Version(key.clone(), self.state.version).encode()?)?;
+                savepoint.writes.insert(key.clone(), value);
+            }
+        }
+
+        // we must record the writeInfo of this key so that to rollback the transaction maybe laterly!
+        engine.set(
+            MvccKey::TxnWrite(self.state.version, key.clone()).encode()?,
+            vec![],
+        )?;
 
+        //set the real key-value
+        engine.set(
+            MvccKey::Version(key.clone(), self.state.version).encode()?,
+            bincode::serialize(&value)?,
+        )?;
+        Ok(())
+    }
+
+    // 写冲突检测：key 最新的版本对当前事务不可见，说明有其他事务修改了它
+    fn check_conflict(&self, engine: &mut MutexGuard<E>, key: Vec<u8>) -> Result<()> {
         // 检测冲突
         //  3 4 5
         //  6
@@ -353,18 +863,6 @@ impl<E: Engine> MvccTransaction<E> {
                 }
             }
         }
-
-        // we must record the writeInfo of this key so that to rollback the transaction maybe laterly!
-        engine.set(
-            MvccKey::TxnWrite(self.state.version, key.clone()).encode()?,
-            vec![],
-        )?;
-
-        //set the real key-value
-        engine.set(
-            MvccKey::Version(key.clone(), self.state.version).encode()?,
-            bincode::serialize(&value)?,
-        )?;
         Ok(())
     }
 
@@ -391,25 +889,168 @@ impl<E: Engine> MvccTransaction<E> {
     }
 }
 
+// 事务没有提交也没有回滚就被丢弃时（例如客户端断开连接），也要释放持有的锁，避免其他事务一直等待
+impl<E: Engine> Drop for MvccTransaction<E> {
+    fn drop(&mut self) {
+        let _ = self.release_locks();
+    }
+}
+
+// 把原始 key 的范围换算成版本 key 的范围，范围为空时返回 None
+// 同一个 key 的所有版本编码后都是相邻的，所以只需要换算边界：
+// Included(k) -> 从 k 的最小版本开始，Excluded(k) -> 跳过 k 的所有版本
+fn version_range(range: KeyRange) -> Result<Option<KeyRange>> {
+    // 没有边界的一侧，使用所有版本 key 的公共前缀作为边界，同样要去掉最后的 [0, 0] 后缀
+    let mut version_prefix = MvccKeyPrefix::Version(Vec::new()).encode()?;
+    version_prefix.truncate(version_prefix.len() - 2);
+    let (all_start, all_end) = prefix_range(version_prefix);
+    let start = match range.0 {
+        Bound::Included(k) => Bound::Included(MvccKey::Version(k, 0).encode()?),
+        Bound::Excluded(k) => Bound::Excluded(MvccKey::Version(k, u64::MAX).encode()?),
+        Bound::Unbounded => all_start,
+    };
+    let end = match range.1 {
+        Bound::Included(k) => Bound::Included(MvccKey::Version(k, u64::MAX).encode()?),
+        Bound::Excluded(k) => Bound::Excluded(MvccKey::Version(k, 0).encode()?),
+        Bound::Unbounded => all_end,
+    };
+    let empty = match (&start, &end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s >= e,
+        _ => false,
+    };
+    Ok(if empty { None } else { Some((start, end)) })
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ScanResult {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
 }
 
+// 每次从存储引擎中拉取的条目数
+const SCAN_BATCH_SIZE: usize = 256;
+
+// MVCC 前缀扫描迭代器
+// 同一个 key 的多个版本在存储引擎中是相邻且按版本号递增排列的：
+// key1-1 key1-3 key1-6 key2-2 key2-5 ...
+// 所以只需要顺序遍历，遇到新的 key 时，上一个 key 最后一个可见版本就是它对当前事务可见的值
+pub struct ScanIterator<E: Engine> {
+    engine: Arc<Mutex<E>>,
+    state: TransactionState,
+    // 下一批扫描的左边界，以及整个扫描的右边界
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // 当前批次还没有处理的原始条目
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    // 正在处理的 key，以及到目前为止它对当前事务可见的最新值（None 表示不可见或者已被删除）
+    pending: Option<(Vec<u8>, Option<Vec<u8>>)>,
+    // 存储引擎中已经没有更多数据了
+    exhausted: bool,
+}
+
+impl<E: Engine> ScanIterator<E> {
+    fn new(
+        engine: Arc<Mutex<E>>,
+        state: TransactionState,
+        (start, end): (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Self {
+        Self {
+            engine,
+            state,
+            start,
+            end,
+            buffer: VecDeque::new(),
+            pending: None,
+            exhausted: false,
+        }
+    }
+
+    // 从存储引擎中拉取下一批数据，拉取完毕后立即释放锁
+    fn fill_buffer(&mut self) -> Result<()> {
+        let mut engine = self.engine.lock()?;
+        let mut iter = engine.scan((self.start.clone(), self.end.clone()));
+        while self.buffer.len() < SCAN_BATCH_SIZE {
+            match iter.next().transpose()? {
+                Some(item) => self.buffer.push_back(item),
+                None => {
+                    self.exhausted = true;
+                    break;
+                }
+            }
+        }
+        if let Some((key, _)) = self.buffer.back() {
+            self.start = Bound::Excluded(key.clone());
+        }
+        Ok(())
+    }
+
+    fn try_next(&mut self) -> Result<Option<ScanResult>> {
+        loop {
+            let (key, value) = match self.buffer.pop_front() {
+                Some(item) => item,
+                None if self.exhausted => {
+                    // 所有数据都处理完了，最后一个 key 还没有输出
+                    return Ok(self.pending.take().and_then(|(key, value)| {
+                        value.map(|value| ScanResult { key, value })
+                    }));
+                }
+                None => {
+                    self.fill_buffer()?;
+                    continue;
+                }
+            };
+
+            let (raw_key, version) = match MvccKey::decode(key.clone())? {
+                MvccKey::Version(raw_key, version) => (raw_key, version),
+                _ => {
+                    return Err(Error::Internal(format!(
+                        "Unexepected key {:?}",
+                        String::from_utf8(key)
+                    )))
+                }
+            };
+
+            // 遇到了新的 key，说明上一个 key 的所有版本都已经处理完了
+            let finished = match &self.pending {
+                Some((pending_key, _)) if *pending_key == raw_key => None,
+                _ => self.pending.replace((raw_key, None)),
+            };
+
+            if self.state.is_visible(version) {
+                if let Some((_, pending_value)) = self.pending.as_mut() {
+                    *pending_value = bincode::deserialize(&value)?;
+                }
+            }
+
+            if let Some((key, Some(value))) = finished {
+                return Ok(Some(ScanResult { key, value }));
+            }
+        }
+    }
+}
+
+impl<E: Engine> Iterator for ScanIterator<E> {
+    type Item = Result<ScanResult>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
         __function,
-        error::Result,
+        error::{Error, Result},
         pppg, pppp, pppy,
         storage::{disk::DiskEngine,disk::Log, engine::Engine, memory::MemoryEngine},
     };
-    use std::path::PathBuf;
+    use std::{ops::Bound, path::PathBuf, thread, time::Duration};
 
     // use super::d;
     use std::fs;
-    use super::Mvcc;
+    use super::{IsolationLevel, LockMode, Mvcc};
 
     // use std::{fmt::format, fs};
     //delete the data in disk
@@ -434,7 +1075,7 @@ mod tests {
         pppg!("开启临时重写~~~");
         let mut eng = DiskEngine::new_compact(PathBuf::from("/tmp/sqldb/sqldb-log"))?;
         // let mut log = Log::new(PathBuf::from("/tmp/sqldb/sqldb-log"))?;
-        eng.log.read_log()?;
+        eng.read_log()?;
         pppg!("读取日志文件完毕~~~"); 
         drop(eng);
         Ok(())
@@ -536,13 +1177,17 @@ mod tests {
         pppg!("事务3开始");
         let tx2 = mvcc.begin()?;
         pppg!("事务1读取prefixkey='xidian'的数据集合");
-        let iter1: Vec<super::ScanResult> = tx1.scan_prefix(b"xidian".to_vec())?;
+        let iter1: Vec<super::ScanResult> = tx1
+            .scan_prefix(b"xidian".to_vec())?
+            .collect::<Result<Vec<_>>>()?;
         pppy!(format!("共有{}条数据，分别是{:?}", iter1.len(), iter1));
         pppg!("事务2新增了一条数据{xidian-4,211-4}");
         tx2.set(b"xidian-4".to_vec(), b"211-4".to_vec())?;
         tx2.commit()?;
         pppg!("事务1再次读取prefixkey='xidian'的数据集合");
-        let iter2 = tx1.scan_prefix(b"xidian".to_vec())?;
+        let iter2 = tx1
+            .scan_prefix(b"xidian".to_vec())?
+            .collect::<Result<Vec<_>>>()?;
         pppy!(format!("共有{}条数据，分别是{:?}", iter2.len(), iter2));
 
         Ok(())
@@ -575,7 +1220,9 @@ mod tests {
         tx.set(b"xidian-2".to_vec(), b"985-2".to_vec())?;
         tx.set(b"xidian-3".to_vec(), b"985-3".to_vec())?;
         pppg!("事务2欲存入的3条数据被回滚了");
-        let iter1: Vec<super::ScanResult> = tx1.scan_prefix(b"xidian".to_vec())?;
+        let iter1: Vec<super::ScanResult> = tx1
+            .scan_prefix(b"xidian".to_vec())?
+            .collect::<Result<Vec<_>>>()?;
         tx1.rollback()?;
         pppg!("事务1读取prefixkey='xidian'的数据集合");
         pppy!(format!("共有{}条数据，分别是{:?}", iter1.len(), iter1));
@@ -655,4 +1302,361 @@ mod tests {
         // std::fs::remove_dir_all(p.parent().unwrap())?;
         Ok(())
     }
+
+    // 7. 扫描的数据跨越多个批次
+    fn scan_across_batches(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng);
+        let tx = mvcc.begin()?;
+        for i in 0..300u32 {
+            tx.set(format!("key{:03}", i).into_bytes(), b"v1".to_vec())?;
+        }
+        tx.commit()?;
+
+        // 每个 key 再写一个新版本，偶数 key 直接删除，这样扫描时总条目数会超过好几个批次
+        let tx = mvcc.begin()?;
+        for i in 0..300u32 {
+            let key = format!("key{:03}", i).into_bytes();
+            if i % 2 == 0 {
+                tx.delete(key)?;
+            } else {
+                tx.set(key, b"v2".to_vec())?;
+            }
+        }
+
+        // 未提交的修改对其他事务不可见
+        let tx2 = mvcc.begin()?;
+        let res = tx2.scan_prefix(b"key".to_vec())?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(res.len(), 300);
+        assert!(res.iter().all(|r| r.value == b"v1".to_vec()));
+
+        // 当前事务能看到自己的修改
+        let res = tx.scan_prefix(b"key".to_vec())?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(res.len(), 150);
+        assert_eq!(res[0].key, b"key001".to_vec());
+        assert_eq!(res[149].key, b"key299".to_vec());
+        assert!(res.iter().all(|r| r.value == b"v2".to_vec()));
+
+        // 迭代的过程中，事务依然可以进行其他的读写操作
+        let mut iter = tx.scan_prefix(b"key".to_vec())?;
+        iter.next().transpose()?;
+        tx.set(b"other".to_vec(), b"v".to_vec())?;
+        assert_eq!(iter.count(), 149);
+        tx.commit()?;
+        tx2.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_across_batches() -> Result<()> {
+        scan_across_batches(MemoryEngine::new())?;
+        Ok(())
+    }
+
+    // 8. 清理旧版本数据
+    fn vacuum(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng);
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"a".to_vec(), b"1".to_vec())?;
+        tx1.set(b"b".to_vec(), b"1".to_vec())?;
+        tx1.commit()?;
+
+        let tx2 = mvcc.begin()?;
+        tx2.set(b"a".to_vec(), b"2".to_vec())?;
+        tx2.delete(b"b".to_vec())?;
+        tx2.commit()?;
+
+        // tx4 开启时 tx3 还是活跃的，即使 tx3 提交了，它写入的版本对 tx4 也不可见
+        let tx3 = mvcc.begin()?;
+        tx3.set(b"a".to_vec(), b"3".to_vec())?;
+        let tx4 = mvcc.begin()?;
+        tx3.commit()?;
+
+        // a 的版本 1，b 的版本 1 和删除标记可以删除，a 的版本 2 对 tx4 还可见
+        assert_eq!(mvcc.vacuum()?, 3);
+        assert_eq!(tx4.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        assert_eq!(tx4.get(b"b".to_vec())?, None);
+        tx4.commit()?;
+
+        // 没有活跃事务之后，每个 key 只保留最新的版本
+        assert_eq!(mvcc.vacuum()?, 1);
+        assert_eq!(mvcc.vacuum()?, 0);
+        let tx5 = mvcc.begin()?;
+        assert_eq!(tx5.get(b"a".to_vec())?, Some(b"3".to_vec()));
+        let res = tx5.scan_prefix(Vec::new())?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(res.len(), 1);
+        tx5.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum() -> Result<()> {
+        vacuum(MemoryEngine::new())?;
+        Ok(())
+    }
+
+    // 9. 写偏斜：两个事务都读取了 x 和 y，各自修改其中一个
+    fn write_skew(eng: impl Engine, isolation: IsolationLevel) -> Result<Result<()>> {
+        let mvcc = Mvcc::new(eng);
+        let tx = mvcc.begin()?;
+        tx.set(b"x".to_vec(), b"1".to_vec())?;
+        tx.set(b"y".to_vec(), b"1".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin_with(isolation)?;
+        let tx2 = mvcc.begin_with(isolation)?;
+        for tx in [&tx1, &tx2] {
+            assert_eq!(tx.get(b"x".to_vec())?, Some(b"1".to_vec()));
+            assert_eq!(tx.get(b"y".to_vec())?, Some(b"1".to_vec()));
+        }
+        tx1.set(b"x".to_vec(), b"0".to_vec())?;
+        tx2.set(b"y".to_vec(), b"0".to_vec())?;
+        tx1.commit()?;
+        let res = tx2.commit();
+
+        // 失败的事务已经自动回滚，y 没有被修改
+        let tx3 = mvcc.begin()?;
+        assert_eq!(tx3.get(b"x".to_vec())?, Some(b"0".to_vec()));
+        if res.is_err() {
+            assert_eq!(tx3.get(b"y".to_vec())?, Some(b"1".to_vec()));
+        }
+        tx3.commit()?;
+        Ok(res)
+    }
+
+    // 10. 幻读导致的写偏斜：两个事务都扫描同一个前缀，再各自插入新的 key
+    fn phantom_skew(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng);
+        let tx1 = mvcc.begin_with(IsolationLevel::Serializable)?;
+        let tx2 = mvcc.begin_with(IsolationLevel::Serializable)?;
+        assert_eq!(tx1.scan_prefix(b"oncall".to_vec())?.count(), 0);
+        assert_eq!(tx2.scan_prefix(b"oncall".to_vec())?.count(), 0);
+        tx1.set(b"oncall1".to_vec(), b"alice".to_vec())?;
+        tx2.set(b"oncall2".to_vec(), b"bob".to_vec())?;
+        tx1.commit()?;
+        assert_eq!(tx2.commit(), Err(Error::SerializationFailure));
+
+        // 没有读写重叠的可串行化事务可以同时提交，还没有提交的并发修改也不影响提交
+        let tx3 = mvcc.begin_with(IsolationLevel::Serializable)?;
+        let tx4 = mvcc.begin_with(IsolationLevel::Serializable)?;
+        let tx5 = mvcc.begin()?;
+        assert_eq!(tx3.get(b"a".to_vec())?, None);
+        assert_eq!(tx4.scan((Bound::Included(b"b".to_vec()), Bound::Excluded(b"c".to_vec())))?.count(), 0);
+        tx3.set(b"b1".to_vec(), b"1".to_vec())?;
+        tx4.set(b"c".to_vec(), b"1".to_vec())?;
+        tx5.set(b"a".to_vec(), b"1".to_vec())?;
+        tx4.commit()?;
+        tx3.commit()?;
+        tx5.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_serializable() -> Result<()> {
+        // 快照隔离下两个事务都能提交，可串行化下后提交的事务失败
+        assert_eq!(write_skew(MemoryEngine::new(), IsolationLevel::Snapshot)?, Ok(()));
+        assert_eq!(
+            write_skew(MemoryEngine::new(), IsolationLevel::Serializable)?,
+            Err(Error::SerializationFailure)
+        );
+        phantom_skew(MemoryEngine::new())?;
+        Ok(())
+    }
+
+    // 11. 只读事务和历史快照
+    fn read_only(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng);
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"a".to_vec(), b"1".to_vec())?;
+        tx1.commit()?;
+        let tx2 = mvcc.begin()?;
+        tx2.set(b"a".to_vec(), b"2".to_vec())?;
+        tx2.set(b"b".to_vec(), b"2".to_vec())?;
+        tx2.commit()?;
+        let tx3 = mvcc.begin()?;
+        tx3.set(b"a".to_vec(), b"3".to_vec())?;
+
+        // 只读事务不分配版本号，看不到还没有提交的 tx3
+        let ro = mvcc.begin_read_only(None)?;
+        assert!(ro.read_only());
+        assert_eq!(ro.version(), 3);
+        assert_eq!(ro.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        assert!(ro.set(b"c".to_vec(), vec![]).is_err());
+        tx3.commit()?;
+        assert_eq!(ro.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        ro.commit()?;
+        drop(ro);
+        assert_eq!(mvcc.begin()?.version(), 4);
+
+        // 历史快照
+        let ro1 = mvcc.begin_read_only(Some(1))?;
+        assert_eq!(ro1.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        assert_eq!(ro1.scan_prefix(Vec::new())?.count(), 1);
+        let ro2 = mvcc.begin_read_only(Some(2))?;
+        assert_eq!(ro2.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        assert_eq!(ro2.scan_prefix(Vec::new())?.count(), 2);
+        assert!(mvcc.begin_read_only(Some(5)).is_err());
+
+        // 只读事务还在的时候，清理旧版本会保留它需要的版本
+        assert_eq!(mvcc.vacuum()?, 0);
+        drop(ro1);
+        assert_eq!(mvcc.vacuum()?, 1);
+        assert_eq!(ro2.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        drop(ro2);
+        // tx4 还没有结束，版本 3 的快照还可以读取，更早的已经清理掉了
+        assert_eq!(mvcc.vacuum()?, 1);
+        assert!(mvcc.begin_read_only(Some(2)).is_err());
+        assert_eq!(mvcc.begin_read_only(Some(3))?.get(b"a".to_vec())?, Some(b"3".to_vec()));
+        Ok(())
+    }
+
+    // 历史快照中交错提交的事务：版本小的事务在版本大的事务之后提交
+    fn read_only_interleaved(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng);
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"a".to_vec(), b"1".to_vec())?;
+        tx1.commit()?;
+        let tx2 = mvcc.begin()?;
+        tx2.set(b"a".to_vec(), b"2".to_vec())?;
+        let tx3 = mvcc.begin()?;
+        tx3.set(b"b".to_vec(), b"3".to_vec())?;
+        tx3.commit()?;
+
+        // tx2 还没有提交，版本 2 的快照也看不到它写入的数据
+        let ro = mvcc.begin_read_only(Some(2))?;
+        assert_eq!(ro.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        drop(ro);
+        tx2.commit()?;
+
+        // tx3 开启时 tx2 还是活跃的，版本 3 的快照在 tx2 提交之后也看不到它
+        let ro3 = mvcc.begin_read_only(Some(3))?;
+        assert_eq!(ro3.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        assert_eq!(ro3.get(b"b".to_vec())?, Some(b"3".to_vec()));
+        let ro2 = mvcc.begin_read_only(Some(2))?;
+        assert_eq!(ro2.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        assert_eq!(ro2.get(b"b".to_vec())?, None);
+
+        // 版本 3 的快照还在，清理时不能删除 a 的版本 1
+        assert_eq!(mvcc.vacuum()?, 0);
+        assert_eq!(ro3.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        drop(ro3);
+        drop(ro2);
+        assert_eq!(mvcc.vacuum()?, 1);
+        assert!(mvcc.begin_read_only(Some(3)).is_err());
+        let ro = mvcc.begin_read_only(None)?;
+        assert_eq!(ro.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        read_only(MemoryEngine::new())?;
+        read_only_interleaved(MemoryEngine::new())?;
+        Ok(())
+    }
+
+    // 12. 保存点
+    fn savepoint(eng: impl Engine) -> Result<()> {
+        let mvcc = Mvcc::new(eng);
+        let tx = mvcc.begin()?;
+        tx.set(b"a".to_vec(), b"1".to_vec())?;
+        tx.commit()?;
+
+        let tx = mvcc.begin()?;
+        tx.set(b"a".to_vec(), b"2".to_vec())?;
+        tx.savepoint("sp1".into())?;
+        tx.set(b"a".to_vec(), b"3".to_vec())?;
+        tx.set(b"b".to_vec(), b"3".to_vec())?;
+        tx.savepoint("sp2".into())?;
+        tx.delete(b"a".to_vec())?;
+        tx.set(b"c".to_vec(), b"4".to_vec())?;
+        assert_eq!(tx.get(b"a".to_vec())?, None);
+
+        // 回滚到 sp2 之后 sp2 还在，可以再次回滚
+        tx.rollback_to_savepoint("sp2")?;
+        assert_eq!(tx.get(b"a".to_vec())?, Some(b"3".to_vec()));
+        assert_eq!(tx.get(b"c".to_vec())?, None);
+        tx.set(b"c".to_vec(), b"5".to_vec())?;
+        tx.rollback_to_savepoint("sp2")?;
+        assert_eq!(tx.get(b"c".to_vec())?, None);
+
+        // 释放 sp2 之后，它之后的写入归入 sp1，回滚到 sp1 时一起撤销
+        tx.set(b"a".to_vec(), b"6".to_vec())?;
+        tx.release_savepoint("sp2")?;
+        assert!(tx.rollback_to_savepoint("sp2").is_err());
+        tx.rollback_to_savepoint("sp1")?;
+        assert_eq!(tx.get(b"a".to_vec())?, Some(b"2".to_vec()));
+        assert_eq!(tx.get(b"b".to_vec())?, None);
+        tx.commit()?;
+
+        // 回滚撤销的写入不会留下多余的数据
+        let tx = mvcc.begin()?;
+        let res = tx.scan_prefix(Vec::new())?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].value, b"2".to_vec());
+        tx.savepoint("sp".into())?;
+        tx.set(b"d".to_vec(), b"1".to_vec())?;
+        tx.rollback()?;
+        assert_eq!(mvcc.begin()?.get(b"d".to_vec())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_savepoint() -> Result<()> {
+        savepoint(MemoryEngine::new())?;
+        Ok(())
+    }
+
+    // 13. 行锁：写入遇到未提交的写入时等待，而不是直接冲突
+    #[test]
+    fn test_lock() -> Result<()> {
+        let mvcc = Mvcc::new_with_lock_timeout(MemoryEngine::new(), Duration::from_secs(10));
+        let tx = mvcc.begin()?;
+        tx.set(b"a".to_vec(), b"1".to_vec())?;
+        tx.commit()?;
+
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"a".to_vec(), b"2".to_vec())?;
+        let waiter = {
+            let mvcc = mvcc.clone();
+            thread::spawn(move || -> Result<()> {
+                let tx2 = mvcc.begin()?;
+                tx2.set(b"a".to_vec(), b"3".to_vec())?;
+                tx2.commit()
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        // tx1 回滚之后 tx2 拿到锁，写入成功
+        tx1.rollback()?;
+        waiter.join().unwrap()?;
+        assert_eq!(mvcc.begin()?.get(b"a".to_vec())?, Some(b"3".to_vec()));
+
+        // 持有锁的事务提交了，等待的事务拿到锁之后发现写入对自己的快照不可见，仍然是写冲突
+        let tx1 = mvcc.begin()?;
+        tx1.set(b"a".to_vec(), b"5".to_vec())?;
+        let tx2 = mvcc.begin()?;
+        let waiter = thread::spawn(move || -> Result<()> {
+            let result = tx2.set(b"a".to_vec(), b"6".to_vec());
+            tx2.rollback()?;
+            result
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        tx1.commit()?;
+        assert_eq!(waiter.join().unwrap(), Err(Error::WriteConflict));
+        assert_eq!(mvcc.begin()?.get(b"a".to_vec())?, Some(b"5".to_vec()));
+
+        // 加锁之后发现已经被并发的事务修改过，仍然是写冲突
+        let tx3 = mvcc.begin()?;
+        let tx4 = mvcc.begin()?;
+        tx4.lock(b"a".to_vec(), LockMode::Exclusive)?;
+        tx4.set(b"a".to_vec(), b"4".to_vec())?;
+        tx4.commit()?;
+        assert_eq!(tx3.lock(b"a".to_vec(), LockMode::Shared), Err(Error::WriteConflict));
+        tx3.rollback()?;
+
+        // 没有开启锁管理器时不能加锁
+        let mvcc = Mvcc::new(MemoryEngine::new());
+        assert!(mvcc.begin()?.lock(b"a".to_vec(), LockMode::Exclusive).is_err());
+        Ok(())
+    }
 }